@@ -1,22 +1,44 @@
-use clap::{self, crate_name, crate_version, value_t, App, Arg, ArgGroup, ArgMatches};
+use clap::{self, crate_name, crate_version, value_t, values_t, App, Arg, ArgGroup};
 use log::*;
 use rand::distributions::{Distribution, Uniform};
+use std::fs;
 use std::fs::File;
-use std::net::TcpListener;
-use std::path::Path;
-use std::sync::{mpsc, Arc, RwLock};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use stderrlog;
 use torrent::choking::Choke;
-use torrent::connection::{ConnInfo, Connection};
-use torrent::metainfo::Metainfo;
-use torrent::selection::{Bitos, Inorder, Rare};
-use torrent::storage::PieceStore;
-use torrent::tracker::http::HTTP;
-use torrent::tracker::{Discover, TorrentState};
-
-fn setup() -> ArgMatches<'static> {
+use torrent::connection::{BufferAccount, ConnInfo, Connection, ProtocolStrictness, UploadSlots};
+use torrent::control::Control;
+use torrent::dialer::Dialer;
+use torrent::files::{self, create_output, MultiFile};
+use torrent::logfile::FileLogger;
+use torrent::metainfo::{self, Metainfo};
+use torrent::pool::{PeerPool, Source};
+use torrent::reputation::Reputation;
+use torrent::selection;
+use torrent::stats;
+use torrent::storage::{Output, PieceStore, Sink};
+use torrent::swarm::SavedPeers;
+use torrent::tracker::http;
+use torrent::tracker::source::{Custom, PeerSource};
+use torrent::tracker::{Discover, TorrentState, TrackerSet};
+use torrent::verify::Verifier;
+use torrent::watchdog::StallWatchdog;
+use torrent::webseed::WebSeed;
+
+mod config;
+use config::Config;
+
+fn app() -> App<'static, 'static> {
     App::new(crate_name!())
         .version(crate_version!())
         .arg(
@@ -25,6 +47,12 @@ fn setup() -> ArgMatches<'static> {
                 .long("seed")
                 .help("Enable seeding after download completion"),
         )
+        .arg(
+            Arg::with_name("no_upload")
+                .long("no-upload")
+                .conflicts_with("seed")
+                .help("Never upload to peers, only download"),
+        )
         .arg(
             Arg::with_name("file")
                 .short("f")
@@ -42,6 +70,53 @@ fn setup() -> ArgMatches<'static> {
                 .help("File used to bypass download phase"),
         )
         .group(ArgGroup::with_name("seedmode").args(&["seed", "file"]))
+        .arg(
+            Arg::with_name("verify_bootstrap")
+                .long("verify-bootstrap")
+                .requires("file")
+                .help("Hash the bootstrap file against the torrent and refuse it if any piece is bad"),
+        )
+        .arg(
+            Arg::with_name("mmap")
+                .long("mmap")
+                .requires("file")
+                .help("Memory map the bootstrap file instead of reading it into memory"),
+        )
+        .arg(
+            Arg::with_name("on_complete")
+                .long("on-complete")
+                .takes_value(true)
+                .value_name("ACTION")
+                .help("What to do once downloaded: seed, exit or exec:CMD (run CMD NAME [OUTPUT])"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Write pieces to FILE as they complete instead of to stdout in order"),
+        )
+        .arg(
+            Arg::with_name("output_dir")
+                .long("output-dir")
+                .takes_value(true)
+                .value_name("DIR")
+                .help(
+                    "Write the torrent under DIR as it completes: the file DIR/NAME, or for \
+                     multi-file torrents the directory DIR/NAME holding its files",
+                ),
+        )
+        .group(ArgGroup::with_name("out").args(&["output", "output_dir"]))
+        .arg(
+            Arg::with_name("write_strategy")
+                .long("write-strategy")
+                .takes_value(true)
+                .value_name("STRATEGY")
+                // No default value, since clap applies requires to defaults too
+                .requires("out")
+                .help("When to sync the output file: buffered (default), sync (every piece) or batch:N"),
+        )
         .arg(
             Arg::with_name("torrent")
                 .takes_value(true)
@@ -59,6 +134,58 @@ fn setup() -> ArgMatches<'static> {
                 .default_value("8888")
                 .help("Port to listen for new connections"),
         )
+        .arg(
+            Arg::with_name("bind")
+                .long("bind")
+                .takes_value(true)
+                .value_name("ADDRESS")
+                .help("Source address for connections to peers and the tracker"),
+        )
+        .arg(
+            Arg::with_name("discover")
+                .long("discover")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("SOURCE")
+                .validator(|s| s.parse::<PeerSource>().map(|_| ()))
+                .help(
+                    "Also get peers from SOURCE, either file:PATH or an http(s) URL answering \
+                     with a JSON list of ip:port strings. May be given more than once.",
+                ),
+        )
+        .arg(
+            Arg::with_name("tracker")
+                .short("t")
+                .long("tracker")
+                .takes_value(true)
+                .value_name("URL")
+                .validator(|url| match http::parse_announce(&url) {
+                    Err(e) => Err(format!("{}", e)),
+                    Ok(_) => Ok(()),
+                })
+                .help("Tracker to use instead of the trackers in the torrent"),
+        )
+        .arg(
+            Arg::with_name("user_agent")
+                .long("user-agent")
+                .takes_value(true)
+                .value_name("AGENT")
+                .help("User-Agent to announce to the tracker with"),
+        )
+        .arg(
+            Arg::with_name("header")
+                .long("header")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAME: VALUE")
+                .validator(|h| match h.find(':') {
+                    Some(_) => Ok(()),
+                    None => Err(format!("{} is not of the form NAME: VALUE", h)),
+                })
+                .help("Extra header to send to the tracker"),
+        )
         .arg(
             Arg::with_name("selector")
                 .short("a")
@@ -66,10 +193,278 @@ fn setup() -> ArgMatches<'static> {
                 .takes_value(true)
                 .multiple(false)
                 .value_name("ALGORITHM")
-                .default_value("inorder")
-                .possible_values(&["inorder", "rarest", "bitos"])
+                .default_value("smart")
+                .possible_values(&["smart", "inorder", "rarest", "bitos", "stream"])
                 .help("Piece Selection strategy to use"),
         )
+        .arg(
+            Arg::with_name("stream")
+                .long("stream")
+                .conflicts_with("selector")
+                .help("Download in order for playback, fetching the first and last pieces first"),
+        )
+        .arg(
+            Arg::with_name("selector_seed")
+                .long("selector-seed")
+                .takes_value(true)
+                .value_name("N")
+                .help("Seed the piece selection strategy, for reproducible runs"),
+        )
+        .arg(
+            Arg::with_name("endgame")
+                .long("endgame")
+                .takes_value(true)
+                .value_name("PIECES")
+                .default_value("4")
+                .help("Ignore the selection strategy when fewer than PIECES pieces remain"),
+        )
+        .arg(
+            Arg::with_name("verify_on_complete")
+                .long("verify-on-complete")
+                .requires("out")
+                .help("Hash the whole output again once downloaded, downloading bad pieces again"),
+        )
+        .arg(
+            Arg::with_name("upload_quota")
+                .long("upload-quota")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("Choke every peer once BYTES have been uploaded this session"),
+        )
+        .arg(
+            Arg::with_name("download_quota")
+                .long("download-quota")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("Stop requesting pieces once BYTES have been downloaded this session"),
+        )
+        .arg(
+            Arg::with_name("stop_on_quota")
+                .long("stop-on-quota")
+                .help("Announce stopped to the trackers and exit once a quota is reached"),
+        )
+        .arg(
+            Arg::with_name("max_peers")
+                .long("max-peers")
+                .takes_value(true)
+                .value_name("PEERS")
+                .default_value("50")
+                .help("Maximum number of peers to connect to"),
+        )
+        .arg(
+            Arg::with_name("prefer_ipv6")
+                .long("prefer-ipv6")
+                .help("Dial IPv6 peers before IPv4 peers"),
+        )
+        .arg(
+            Arg::with_name("ipv4_only")
+                .long("ipv4-only")
+                .conflicts_with("prefer_ipv6")
+                .help("Never dial IPv6 peers"),
+        )
+        .arg(
+            Arg::with_name("dial_concurrency")
+                .long("dial-concurrency")
+                .takes_value(true)
+                .value_name("DIALS")
+                .default_value("10")
+                .help("Maximum number of peers to connect to at the same time"),
+        )
+        .arg(
+            Arg::with_name("connect_timeout")
+                .long("connect-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .default_value("10")
+                .help("Give up connecting to a peer after SECONDS (0 to wait for the OS)"),
+        )
+        .arg(
+            Arg::with_name("handshake_timeout")
+                .long("handshake-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .default_value("10")
+                .help("Drop peers we connect to that send no handshake for SECONDS (0 to wait forever)"),
+        )
+        .arg(
+            Arg::with_name("min_peers")
+                .long("min-peers")
+                .takes_value(true)
+                .value_name("PEERS")
+                .default_value("1")
+                .help("Wait for the availability of PEERS peers before requesting pieces"),
+        )
+        .arg(
+            Arg::with_name("score_weights")
+                .long("score-weights")
+                .takes_value(true)
+                .value_name("RATE,RELIABILITY,AVAILABILITY")
+                .default_value("1,1,1")
+                .help("Weights of the peer score that breaks ties when choosing peers to unchoke"),
+        )
+        .arg(
+            Arg::with_name("seed_optimistic")
+                .long("seed-optimistic")
+                .takes_value(true)
+                .value_name("POLICY")
+                .default_value("random")
+                .possible_values(&["random", "least-complete", "off"])
+                .help("How the optimistic unchoke is picked while seeding"),
+        )
+        .arg(
+            Arg::with_name("seed_optimistic_rounds")
+                .long("seed-optimistic-rounds")
+                .takes_value(true)
+                .value_name("ROUNDS")
+                .default_value("3")
+                .help("Choking rounds between optimistic unchokes while seeding"),
+        )
+        .arg(
+            Arg::with_name("max_torrent_size")
+                .long("max-torrent-size")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("Refuse torrents larger than BYTES"),
+        )
+        .arg(
+            Arg::with_name("buffer_limit")
+                .long("buffer-limit")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("Maximum bytes of partially downloaded pieces held in memory"),
+        )
+        .arg(
+            Arg::with_name("upload_slots")
+                .long("upload-slots")
+                .takes_value(true)
+                .value_name("PIECES")
+                .help("Maximum pieces being sent at once across all peers"),
+        )
+        .arg(
+            Arg::with_name("upload_batch")
+                .long("upload-batch")
+                .takes_value(true)
+                .value_name("PIECES")
+                .default_value("4")
+                .help("Queued pieces sent to a peer at a time before handling other work"),
+        )
+        .arg(
+            Arg::with_name("hash_threads")
+                .long("hash-threads")
+                .takes_value(true)
+                .value_name("THREADS")
+                .default_value("2")
+                .help("Number of threads verifying downloaded pieces"),
+        )
+        .arg(
+            Arg::with_name("read_buffer")
+                .long("read-buffer")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help(
+                    "Per connection read buffer, larger buffers mean fewer syscalls on fast links",
+                ),
+        )
+        .arg(
+            Arg::with_name("write_buffer")
+                .long("write-buffer")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help(
+                    "Per connection write buffer, larger buffers mean fewer syscalls on fast links",
+                ),
+        )
+        .arg(
+            Arg::with_name("stats_csv")
+                .long("stats-csv")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Write per-piece download statistics to FILE as CSV"),
+        )
+        .arg(
+            Arg::with_name("interested_timeout")
+                .long("interested-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .default_value("300")
+                .help("Drop peers that keep us choked while interested for SECONDS (0 to disable)"),
+        )
+        .arg(
+            Arg::with_name("snub_timeout")
+                .long("snub-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .default_value("60")
+                .help("Stop requesting from peers that leave requests unanswered for SECONDS (0 to disable)"),
+        )
+        .arg(
+            Arg::with_name("write_timeout")
+                .long("write-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .default_value("60")
+                .help("Drop peers that stop reading what we send for SECONDS (0 to disable)"),
+        )
+        .arg(
+            Arg::with_name("dump_wire")
+                .long("dump-wire")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Append the raw bytes exchanged with each peer to files in DIR, for debugging"),
+        )
+        .arg(
+            Arg::with_name("stall_timeout")
+                .long("stall-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .default_value("300")
+                .help("Warn and announce again when no piece arrives for SECONDS despite unchoked peers (0 to disable)"),
+        )
+        .arg(
+            Arg::with_name("max_bad_pieces")
+                .long("max-bad-pieces")
+                .takes_value(true)
+                .value_name("PIECES")
+                .default_value("3")
+                .help("Disconnect peers once they have sent PIECES pieces that fail verification"),
+        )
+        .arg(
+            Arg::with_name("initial_pieces")
+                .long("initial-pieces")
+                .takes_value(true)
+                .value_name("PIECES")
+                .help("Advertise at most PIECES pieces to each peer at first, revealing the rest one at a time"),
+        )
+        .arg(
+            Arg::with_name("protocol")
+                .long("protocol")
+                .takes_value(true)
+                .value_name("MODE")
+                .default_value("strict")
+                .possible_values(&["strict", "permissive"])
+                .help("Disconnect peers that deviate from the protocol, or ignore the bad messages"),
+        )
+        .arg(
+            Arg::with_name("reputation")
+                .long("reputation")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Load and save the history of peer behaviour in FILE"),
+        )
+        .arg(
+            Arg::with_name("peers_file")
+                .long("peers-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Save connected peers to FILE and dial them first on the next start"),
+        )
+        .arg(
+            Arg::with_name("control")
+                .long("control")
+                .takes_value(true)
+                .value_name("SOCKET")
+                .help("Accept commands such as pause or set-selector on the unix socket SOCKET"),
+        )
         .arg(
             Arg::with_name("logged_modules")
                 .short("m")
@@ -78,19 +473,175 @@ fn setup() -> ArgMatches<'static> {
                 .multiple(true)
                 .value_name("MODULE"),
         )
+        .arg(
+            Arg::with_name("log_file")
+                .long("log-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Write logs to PATH instead of stderr"),
+        )
+        .arg(
+            Arg::with_name("log_max_size")
+                .long("log-max-size")
+                .takes_value(true)
+                .value_name("BYTES")
+                .default_value("10485760")
+                .help("Move the log file to PATH.1 once it reaches BYTES"),
+        )
         .arg(
             Arg::with_name("verbosity")
                 .short("v")
                 .multiple(true)
                 .help("Increase message verbosity"),
         )
-        .get_matches()
+}
+
+#[derive(Debug, PartialEq)]
+enum OnComplete {
+    Seed,
+    Exit,
+    Exec(String),
+}
+
+impl FromStr for OnComplete {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "seed" => Ok(OnComplete::Seed),
+            "exit" => Ok(OnComplete::Exit),
+            _ if s.starts_with("exec:") && s.len() > "exec:".len() => {
+                Ok(OnComplete::Exec(s["exec:".len()..].to_owned()))
+            }
+            _ => Err(format!("{} is not a valid completion action", s)),
+        }
+    }
+}
+
+// Carry out the completion action, returning whether to continue into the seed loop
+// A failing hook is only logged, since the download itself succeeded
+fn complete(action: &OnComplete, name: &str, output: Option<&str>) -> bool {
+    match action {
+        OnComplete::Seed => true,
+        OnComplete::Exit => false,
+        OnComplete::Exec(cmd) => {
+            let mut c = process::Command::new(cmd);
+            c.arg(name);
+            if let Some(path) = output {
+                c.arg(path);
+            }
+            match c.status() {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!("{} exited with {}", cmd, status),
+                Err(e) => error!("failed to run {}: {}", cmd, e),
+            }
+            false
+        }
+    }
 }
 
 enum Event {
     Conn(Connection),
 }
 
+// Hands connections to the choker as they arrive, rather than once per round of the rate limited
+// loops, so new peers are unchoked and requested from in the very next round
+fn spawn_intake(rx: mpsc::Receiver<Event>, choker: Arc<Mutex<Choke>>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for event in rx {
+            match event {
+                Event::Conn(conn) => choker.lock().unwrap().add(conn),
+            }
+        }
+    })
+}
+
+// Dial peers that have not been tried yet until max_peers connections are open or being opened
+fn dial(pool: &mut PeerPool, dialer: &Dialer, choker: &Choke, max_peers: usize) {
+    for peer in pool.candidates(max_peers.saturating_sub(choker.len() + dialer.in_flight())) {
+        if let Err(e) = dialer.dial(peer) {
+            error!(
+                "Not dialing {}: connections are no longer accepted",
+                e.0.addr
+            );
+            return;
+        }
+    }
+}
+
+// How often --discover sources are queried again
+const DISCOVER_INTERVAL: Duration = Duration::from_secs(60);
+// Peer history is written out this often, so a crash loses little of it
+const REPUTATION_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+fn discover(pool: &mut PeerPool, sources: &mut [Custom], state: &TorrentState) {
+    for source in sources.iter_mut() {
+        match source.get_peers(state, None) {
+            Ok(peers) => {
+                debug!("Got {} peers from {:?}", peers.len(), source.source());
+                pool.add(Source::Custom, peers);
+            }
+            Err(e) => warn!("Peer source {:?} failed: {}", source.source(), e),
+        }
+    }
+}
+
+// Saved peers that have not expired, leaving out peers banned since they were saved
+fn add_saved_peers(pool: &mut PeerPool, saved: &SavedPeers, reputation: &Reputation, now: u64) {
+    let peers: Vec<_> = saved
+        .candidates(now)
+        .into_iter()
+        .filter(|p| !reputation.is_banned(p.addr.ip()))
+        .collect();
+    info!("Dialing {} saved peers", peers.len());
+    pool.add(Source::Saved, peers);
+}
+
+// Record the peers we are connected to with their reputation, and write the best of every peer
+// seen recently
+fn save_peers<P: AsRef<Path>>(
+    path: P,
+    saved: &mut SavedPeers,
+    pool: &PeerPool,
+    choker: &Choke,
+    reputation: &Reputation,
+    now: u64,
+) -> io::Result<()> {
+    // Inbound connections are named by the peer's source port, which it does not listen on, so
+    // only addresses that were dialed are kept
+    for id in choker.peer_ids() {
+        match id.parse::<SocketAddr>() {
+            Ok(addr) if pool.contains(&addr) => {
+                saved.record(addr, reputation.score(addr.ip()), now)
+            }
+            _ => {}
+        }
+    }
+    saved.expire(now);
+    saved.save(path)
+}
+
+// A socket left behind by a previous run would make bind fail. Anything else at the path is
+// left alone, so a mistyped path cannot delete the user's files.
+fn remove_stale_socket<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    match fs::symlink_metadata(&path) {
+        Ok(m) if m.file_type().is_socket() => fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "path exists and is not a socket",
+        )),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 fn make_id() -> String {
     let mut rng = rand::thread_rng();
     let num_gen = Uniform::new('1' as u8, '9' as u8);
@@ -104,114 +655,367 @@ fn make_id() -> String {
     )
 }
 
+// Inbound connections waiting for a handshake are limited to avoid exhausting threads
+const MAX_HANDSHAKES: usize = 16;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 struct Listener {
     conn: TcpListener,
     tx: mpsc::Sender<Event>,
     metainfo: Arc<Metainfo>,
     client_id: Arc<String>,
     store: Arc<RwLock<PieceStore>>,
+    handshakes: Arc<AtomicUsize>,
+    // Set before waking the listener with a connection, so that it stops accepting
+    shutdown: Arc<AtomicBool>,
+    buffers: Arc<BufferAccount>,
+    uploads: Arc<UploadSlots>,
+    upload_batch: usize,
+    reputation: Arc<Mutex<Reputation>>,
+    verifier: Arc<Verifier>,
+    reader_buffer_len: Option<usize>,
+    writer_buffer_len: Option<usize>,
+    snub_timeout: Option<Duration>,
+    max_bad_pieces: u32,
+    strictness: ProtocolStrictness,
+    write_timeout: Option<Duration>,
+    wire_dir: Option<PathBuf>,
+    bitfield_cap: Option<usize>,
+}
+
+// Pause after a failed accept, doubled while accepting keeps failing
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+// Accepting again will fail the same way, as the listening socket is unusable or we may not
+// use it. Anything else, e.g. running out of file descriptors or a connection reset before it
+// was accepted, may clear up.
+fn accept_error_is_fatal(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::InvalidInput
+        | io::ErrorKind::PermissionDenied
+        | io::ErrorKind::AddrNotAvailable
+        | io::ErrorKind::NotConnected => true,
+        // EBADF, the listening socket was closed
+        _ => e.raw_os_error() == Some(9),
+    }
+}
+
+// Hands accepted connections to handle until shutdown is set or accept fails fatally.
+// Transient errors are retried after a backoff so that they do not become a busy loop.
+fn accept_loop<T, A, H>(shutdown: &AtomicBool, mut accept: A, mut handle: H) -> io::Result<()>
+where
+    A: FnMut() -> io::Result<T>,
+    H: FnMut(T),
+{
+    let mut backoff = ACCEPT_BACKOFF;
+    loop {
+        let res = accept();
+        if shutdown.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        match res {
+            Ok(conn) => {
+                backoff = ACCEPT_BACKOFF;
+                handle(conn);
+            }
+            Err(e) => {
+                if accept_error_is_fatal(&e) {
+                    return Err(e);
+                }
+                warn!("Accept failed, retrying in {:?}: {}", backoff, e);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+        }
+    }
 }
 
 impl Listener {
     fn start(self) -> Result<(), failure::Error> {
-        for stream in self.conn.incoming() {
-            if let Ok(stream) = stream {
-                debug!("New connection: {}", stream.peer_addr().unwrap());
-                let id = Arc::new(stream.peer_addr().unwrap().to_string());
-                match self.tx.send(Event::Conn(
-                    match Connection::new(
-                        stream,
-                        ConnInfo {
-                            store: self.store.clone(),
-                            metainfo: self.metainfo.clone(),
-                            reader_buffer_len: None,
-                            writer_buffer_len: None,
-                            client_id: self.client_id.clone(),
-                            id,
-                        },
-                    ) {
-                        Ok(c) => c,
-                        Err(e) => {
-                            error!("connection error: {}", e);
-                            continue;
-                        }
-                    },
-                )) {
-                    Err(_) => return Ok(()),
-                    _ => continue,
-                }
+        let res = accept_loop(
+            &self.shutdown,
+            || self.conn.accept(),
+            |(stream, addr)| self.handle(stream, addr),
+        );
+        if let Err(e) = &res {
+            error!("Listener stopped: {}", e);
+        }
+        Ok(res?)
+    }
+
+    // Stops a listener started on another thread, which is blocked in accept
+    fn stop(shutdown: &AtomicBool, addr: SocketAddr) {
+        shutdown.store(true, Ordering::SeqCst);
+        let addr = match addr.ip().is_unspecified() {
+            true => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()),
+            false => addr,
+        };
+        if let Err(e) = TcpStream::connect(addr) {
+            warn!("Failed to wake the listener: {}", e);
+        }
+    }
+
+    fn handle(&self, stream: TcpStream, addr: SocketAddr) {
+        debug!("New connection: {}", addr);
+        // Checked by address, as a reconnecting peer comes from a new port
+        if self.reputation.lock().unwrap().is_banned(addr.ip()) {
+            info!("{}: Refusing banned peer", addr);
+            return;
+        }
+        if self.handshakes.fetch_add(1, Ordering::SeqCst) >= MAX_HANDSHAKES {
+            self.handshakes.fetch_sub(1, Ordering::SeqCst);
+            warn!("Too many pending handshakes, dropping {}", addr);
+            return;
+        }
+
+        let id = Arc::new(addr.to_string());
+        let ci = ConnInfo {
+            store: self.store.clone(),
+            metainfo: self.metainfo.clone(),
+            reader_buffer_len: self.reader_buffer_len,
+            writer_buffer_len: self.writer_buffer_len,
+            client_id: self.client_id.clone(),
+            id,
+            buffers: self.buffers.clone(),
+            uploads: self.uploads.clone(),
+            upload_batch: self.upload_batch,
+            reputation: self.reputation.clone(),
+            verifier: self.verifier.clone(),
+            bind_addr: None,
+            dht_port: None,
+            snub_timeout: self.snub_timeout,
+            max_bad_pieces: self.max_bad_pieces,
+            strictness: self.strictness,
+            connect_timeout: None,
+            handshake_timeout: Some(HANDSHAKE_TIMEOUT),
+            handshake_slots: Some(self.handshakes.clone()),
+            write_timeout: self.write_timeout,
+            wire_dir: self.wire_dir.clone(),
+            bitfield_cap: self.bitfield_cap,
+        };
+        // The handshake is read on the connection's own thread, so a peer stalling it does not
+        // hold up accepting others
+        match Connection::new(stream, ci) {
+            Ok(c) => {
+                let _ = self.tx.send(Event::Conn(c));
+            }
+            Err(e) => {
+                self.handshakes.fetch_sub(1, Ordering::SeqCst);
+                error!("connection error: {}", e);
             }
         }
-        Ok(())
     }
 }
 
 fn main() -> Result<(), failure::Error> {
-    let matches = setup();
-    stderrlog::new()
-        .module(module_path!())
-        .modules(matches.values_of("logged_modules").unwrap_or_default())
-        .verbosity(matches.occurrences_of("verbosity") as usize)
-        .init()
-        .unwrap();
+    let matches = app().get_matches();
+    let config = Config::from_matches(&matches).unwrap_or_else(|e| {
+        clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue).exit()
+    });
+    let verbosity = matches.occurrences_of("verbosity") as usize;
+    let modules = matches.values_of("logged_modules").unwrap_or_default();
+    match matches.value_of("log_file") {
+        Some(path) => {
+            let mut logger = FileLogger::new(path, config.log_max_size).unwrap_or_else(|e| {
+                clap::Error::with_description(
+                    &format!("cannot open log file {}: {}", path, e),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit()
+            });
+            logger.set_verbosity(verbosity);
+            logger.add_module(module_path!());
+            modules.for_each(|m| logger.add_module(m));
+            logger.init().unwrap();
+        }
+        None => stderrlog::new()
+            .module(module_path!())
+            .modules(modules)
+            .verbosity(verbosity)
+            .init()
+            .unwrap(),
+    }
 
     // Parse metainfo
     let metainfo =
         Arc::new(value_t!(matches.value_of("torrent"), Metainfo).unwrap_or_else(|e| e.exit()));
     debug!("Parsed metainfo for {}", metainfo.info.name);
+    if let Some(max) = config.max_torrent_size {
+        if let Err(e) = metainfo.check_size(max) {
+            clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue).exit();
+        }
+    }
 
     // Piece Selector
-    let store;
-    match matches.value_of("selector").unwrap() {
-        "inorder" => {
-            store = Arc::new(RwLock::new(PieceStore::new(
-                &metainfo,
-                Box::new(Inorder::default()),
-            )))
+    let selector = &config.selector;
+    let store = match selection::from_name_seeded(selector, config.selector_seed) {
+        Some(s) => Arc::new(RwLock::new(PieceStore::new(&metainfo, s))),
+        None => clap::Error::with_description(
+            &format!("{} is an invalid piece selection strategy", selector),
+            clap::ErrorKind::InvalidValue,
+        )
+        .exit(),
+    };
+
+    store.write().unwrap().set_endgame_threshold(config.endgame);
+    store.write().unwrap().set_min_peers(config.min_peers);
+
+    // Bootstrap file
+    match matches.value_of("file") {
+        Some(f) => {
+            debug!("Bootstrap from {}", f);
+            let verify = matches.is_present("verify_bootstrap");
+            let res = match matches.is_present("mmap") {
+                true => store
+                    .write()
+                    .unwrap()
+                    .bootstrap_mapped(&metainfo, f, verify),
+                false => store.write().unwrap().bootstrap_with(&metainfo, f, verify),
+            };
+            if let Err(e) = res {
+                clap::Error::with_description(
+                    &format!("cannot bootstrap from {}: {}", f, e),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit();
+            }
         }
-        "rarest" => {
-            store = Arc::new(RwLock::new(PieceStore::new(
-                &metainfo,
-                Box::new(Rare::default()),
-            )))
+        None => {}
+    }
+
+    // Output file
+    let output: Option<Box<dyn Output>> = match matches.value_of("output") {
+        Some(f) => {
+            debug!("Writing output to {}", f);
+            // Readable too, for --verify-on-complete
+            Some(Box::new(create_output(
+                f,
+                metainfo.info.total_length() as u64,
+            )?))
         }
-        "bitos" => {
-            store = Arc::new(RwLock::new(PieceStore::new(
-                &metainfo,
-                Box::new(Bitos::default()),
-            )))
+        None => match matches.value_of("output_dir") {
+            Some(dir) => {
+                debug!("Writing output under {}", dir);
+                Some(Box::new(MultiFile::create(files::layout(
+                    &metainfo.info,
+                    dir,
+                )?)?))
+            }
+            None => None,
+        },
+    };
+    // Pieces are streamed to stdout without an output
+    let sink = match output {
+        Some(output) => Sink::Seekable(output),
+        None => Sink::Stdout,
+    };
+    {
+        let mut s = store.write().unwrap();
+        s.set_sink(sink);
+        s.set_write_strategy(config.write_strategy);
+    }
+
+    // Download statistics
+    let stats_handle = match matches.value_of("stats_csv") {
+        Some(f) => {
+            debug!("Writing statistics to {}", f);
+            let (tx, handle) = stats::spawn(File::create(f)?);
+            store.write().unwrap().set_stats(tx);
+            Some(handle)
         }
-        s => {
+        None => None,
+    };
+
+    // Global limit on memory used by partially downloaded pieces
+    let buffers = Arc::new(match config.buffer_limit {
+        Some(limit) => BufferAccount::new(limit),
+        None => BufferAccount::default(),
+    });
+    // Global limit on pieces being sent at once
+    let uploads = Arc::new(match config.upload_slots {
+        Some(slots) => UploadSlots::new(slots),
+        None => UploadSlots::default(),
+    });
+
+    // Admin commands
+    let stalled = Arc::new(AtomicBool::new(false));
+    if let Some(path) = matches.value_of("control") {
+        if let Err(e) = remove_stale_socket(path) {
             clap::Error::with_description(
-                &format!("{} is an invalid piece selection strategy", s),
+                &format!("cannot use control socket {}: {}", path, e),
                 clap::ErrorKind::InvalidValue,
             )
             .exit();
         }
-    };
+        let control_listener = UnixListener::bind(path)?;
+        let control = Control {
+            store: store.clone(),
+            buffers: buffers.clone(),
+            stalled: stalled.clone(),
+        };
+        info!("Control socket listening on {}", path);
+        thread::spawn(move || {
+            for stream in control_listener.incoming() {
+                if let Err(e) = stream.and_then(|s| control.serve(s)) {
+                    warn!("control error: {}", e);
+                }
+            }
+        });
+    }
 
-    // Bootstrap file
-    match matches.value_of("file") {
+    let verifier = Arc::new(Verifier::new(metainfo.clone(), config.hash_threads));
+
+    // Peer history from previous sessions
+    let reputation = Arc::new(Mutex::new(match matches.value_of("reputation") {
         Some(f) => {
-            debug!("Bootstrap from {}", f);
-            store.write().unwrap().bootstrap(&metainfo, f)?;
+            debug!("Loading peer reputation from {}", f);
+            Reputation::load(f)?
         }
-        None => {}
+        None => Reputation::default(),
+    }));
+    if let Some(f) = matches.value_of("reputation") {
+        let path = PathBuf::from(f);
+        let reputation = reputation.clone();
+        thread::spawn(move || loop {
+            thread::sleep(REPUTATION_SAVE_INTERVAL);
+            if let Err(e) = reputation.lock().unwrap().save(&path) {
+                error!("reputation error: {}", e);
+            }
+        });
     }
 
+    let wire_dir = matches.value_of("dump_wire").map(PathBuf::from);
+
     let (tx, rx) = mpsc::channel::<Event>();
     let client_id = Arc::new(make_id());
     info!("Client ID: {}", &client_id);
-    let port = value_t!(matches.value_of("port"), u16).unwrap_or_else(|e| e.exit());
+    let port = config.port;
     let listener = Listener {
         conn: TcpListener::bind(format!("0.0.0.0:{}", port))?,
         tx: tx.clone(),
         metainfo: metainfo.clone(),
         store: store.clone(),
         client_id: client_id.clone(),
+        handshakes: Arc::new(AtomicUsize::new(0)),
+        shutdown: Arc::new(AtomicBool::new(false)),
+        buffers: buffers.clone(),
+        uploads: uploads.clone(),
+        upload_batch: config.upload_batch,
+        reputation: reputation.clone(),
+        verifier: verifier.clone(),
+        reader_buffer_len: config.read_buffer,
+        writer_buffer_len: config.write_buffer,
+        snub_timeout: config.snub_timeout,
+        max_bad_pieces: config.max_bad_pieces,
+        strictness: config.strictness,
+        write_timeout: config.write_timeout,
+        wire_dir: wire_dir.clone(),
+        bitfield_cap: config.initial_pieces,
     };
     let listen_addr = listener.conn.local_addr().unwrap();
-    let _listener_handle = thread::spawn(move || listener.start());
+    let listener_shutdown = listener.shutdown.clone();
+    let listener_handle = thread::spawn(move || listener.start());
     info!("Listener started on {}", listen_addr);
 
     // Start metric server
@@ -219,96 +1023,667 @@ fn main() -> Result<(), failure::Error> {
     //     unimplemented!()
     // });
 
-    // Announce to tracker
-    let c = reqwest::Client::new();
-    let mut http = HTTP::new(metainfo.clone(), client_id.clone(), port, &c);
-    let peers = http.get_peers(
-        &TorrentState {
-            uploaded: 0,
-            downloaded: 0,
-            left: metainfo.info.length as u64,
-        },
-        None,
-    )?;
-    info!("Got {} peers from tracker", peers.len() - 1); // One of the peers is always self
+    // Source address for outbound connections, chosen by the OS when unset
+    let bind_addr = config.bind;
 
     let mut limiter = ratelimit::Builder::new()
         .capacity(1)
         .quantum(1)
         .interval(Duration::from_secs(10))
         .build();
-    let mut choker = Choke::new();
+    let mut choker = Choke::with_reputation(reputation.clone());
+    if config.interested_timeout.is_some() {
+        choker.set_interested_timeout(config.interested_timeout);
+    }
+    choker.set_score_weights(config.score_weights);
+    choker.set_seed_optimistic(config.seed_optimistic);
+    if matches.is_present("no_upload") {
+        warn!("Uploading is disabled, so peers will get nothing in return for their pieces");
+        choker.set_upload(false);
+    }
+    if config.upload_quota.is_some() {
+        choker.set_upload_quota(config.upload_quota);
+    }
+    if config.download_quota.is_some() {
+        choker.set_download_quota(config.download_quota);
+    }
+    let mut download_quota_reached = false;
     let mut optimistic_unchoke_counter = 0;
+    let choker = Arc::new(Mutex::new(choker));
+    let _intake_handle = spawn_intake(rx, choker.clone());
 
     // Connect to available peers
-    for peer in peers.into_iter() {
-        let conn = match Connection::connect(
-            &peer.addr,
-            ConnInfo {
-                store: store.clone(),
-                metainfo: metainfo.clone(),
-                reader_buffer_len: None,
-                writer_buffer_len: None,
-                client_id: client_id.clone(),
-                id: Arc::new(peer.addr.to_string()),
-            },
-        ) {
-            Ok(c) => c,
+    let max_peers = config.max_peers;
+    let mut pool = PeerPool::default();
+    pool.set_family(config.family);
+    let mut saved_peers = match matches.value_of("peers_file") {
+        Some(f) => {
+            debug!("Loading saved peers from {}", f);
+            SavedPeers::load(f)?
+        }
+        None => SavedPeers::default(),
+    };
+    add_saved_peers(
+        &mut pool,
+        &saved_peers,
+        &reputation.lock().unwrap(),
+        unix_time(),
+    );
+    let outbound = ConnInfo {
+        store: store.clone(),
+        metainfo: metainfo.clone(),
+        reader_buffer_len: config.read_buffer,
+        writer_buffer_len: config.write_buffer,
+        client_id: client_id.clone(),
+        id: Arc::new(String::new()),
+        buffers: buffers.clone(),
+        uploads,
+        upload_batch: config.upload_batch,
+        reputation: reputation.clone(),
+        verifier: verifier.clone(),
+        bind_addr,
+        dht_port: None,
+        snub_timeout: config.snub_timeout,
+        max_bad_pieces: config.max_bad_pieces,
+        strictness: config.strictness,
+        connect_timeout: config.connect_timeout,
+        handshake_timeout: config.handshake_timeout,
+        handshake_slots: None,
+        write_timeout: config.write_timeout,
+        wire_dir: wire_dir.clone(),
+        bitfield_cap: config.initial_pieces,
+    };
+    let dialer = Dialer::new(
+        config.dial_concurrency,
+        move |peer| {
+            let mut ci = outbound.clone();
+            ci.id = Arc::new(peer.addr.to_string());
+            let conn = Connection::connect(&peer.addr, ci)?;
+            debug!("New connection: {}", peer.addr);
+            Ok(Event::Conn(conn))
+        },
+        tx.clone(),
+    );
+    // Saved peers are dialed while waiting for the tracker
+    dial(&mut pool, &dialer, &choker.lock().unwrap(), max_peers);
+
+    // Announce to tracker
+    let mut builder = reqwest::Client::builder().redirect(reqwest::RedirectPolicy::none());
+    if let Some(addr) = bind_addr {
+        builder = builder.local_address(addr);
+    }
+    let c = builder.build()?;
+    let mut trackers = match matches.value_of("tracker") {
+        Some(url) => {
+            debug!("Overriding tracker with {}", url);
+            let url = http::parse_announce(url)?;
+            TrackerSet::with_urls(metainfo.clone(), client_id.clone(), port, &c, vec![url])
+        }
+        None => match TrackerSet::new(metainfo.clone(), client_id.clone(), port, &c) {
+            // Peers can still come from the --discover sources
+            Err(http::Error::Metainfo(metainfo::Error::NoTrackers))
+                if matches.is_present("discover") =>
+            {
+                warn!("Torrent has no trackers, relying on --discover sources");
+                TrackerSet::with_urls(metainfo.clone(), client_id.clone(), port, &c, Vec::new())
+            }
+            res => res?,
+        },
+    };
+    for http in trackers.trackers_mut() {
+        if let Some(ua) = matches.value_of("user_agent") {
+            http.set_user_agent(ua)?;
+        }
+        for h in matches.values_of("header").unwrap_or_default() {
+            let (name, value) = h.split_at(h.find(':').unwrap());
+            http.add_header(name.trim(), value[1..].trim())?;
+        }
+    }
+    // Web seeds download alongside peers on their own threads
+    for url in metainfo.web_seeds() {
+        let seed = reqwest::Url::parse(&url)
+            .map_err(|e| e.to_string())
+            .and_then(|base| WebSeed::new(c.clone(), &base, &metainfo).map_err(|e| e.to_string()));
+        let mut seed = match seed {
+            Ok(s) => s,
             Err(e) => {
-                warn!("{}", e);
+                warn!("Ignoring web seed {}: {}", url, e);
                 continue;
             }
         };
-        debug!("New connection: {}", peer.addr);
-        choker.add(conn)
+        let metainfo = metainfo.clone();
+        let store = store.clone();
+        thread::spawn(move || seed.download(&metainfo, &store));
     }
+    let peers = trackers.get_peers(
+        &TorrentState {
+            uploaded: 0,
+            downloaded: 0,
+            // Zero when bootstrapped from a complete file, so no completion is announced later
+            left: store.read().unwrap().remaining_bytes(),
+            corrupt: store.read().unwrap().corrupt(),
+        },
+        None,
+    )?;
+    info!("Got {} peers from tracker", peers.len().saturating_sub(1)); // One of the peers is always self
+    pool.add(Source::Tracker, peers);
+    let mut sources: Vec<Custom> = match matches.values_of("discover") {
+        Some(_) => values_t!(matches.values_of("discover"), PeerSource)
+            .unwrap_or_else(|e| e.exit())
+            .into_iter()
+            .map(|source| Custom::new(source, &c))
+            .collect(),
+        None => Vec::new(),
+    };
+    let mut discovered = Instant::now();
+    discover(
+        &mut pool,
+        &mut sources,
+        &TorrentState {
+            uploaded: 0,
+            downloaded: 0,
+            left: store.read().unwrap().remaining_bytes(),
+            corrupt: store.read().unwrap().corrupt(),
+        },
+    );
+    dial(&mut pool, &dialer, &choker.lock().unwrap(), max_peers);
+
+    let mut watchdog = config
+        .stall_timeout
+        .map(|timeout| StallWatchdog::new(timeout, store.read().unwrap().left, Instant::now()));
 
+    let verify_on_complete = matches.is_present("verify_on_complete");
+    // Set when --stop-on-quota ends the download early, which then skips completion and seeding
+    let mut stopped_on_quota = false;
     // Download Loop
     // Rate limited loop with alternate channel trigger
-    while { store.read().unwrap().left != 0 } {
-        debug!("Download loop");
-        // Rate limited loop
-        while let Ok(event) = rx.try_recv() {
-            match event {
-                Event::Conn(conn) => choker.add(conn),
+    loop {
+        while { !store.read().unwrap().is_complete() } {
+            debug!("Download loop");
+            // Sources may block on the network or disk, so they are queried without the choker
+            // locked, which would hold up new connections
+            if !download_quota_reached
+                && !sources.is_empty()
+                && discovered.elapsed() >= DISCOVER_INTERVAL
+            {
+                discovered = Instant::now();
+                let state = {
+                    let choker = choker.lock().unwrap();
+                    TorrentState {
+                        uploaded: choker.uploaded(),
+                        downloaded: choker.downloaded(),
+                        left: store.read().unwrap().remaining_bytes(),
+                        corrupt: store.read().unwrap().corrupt(),
+                    }
+                };
+                discover(&mut pool, &mut sources, &state);
+            }
+
+            // Rate limited loop
+            let mut choker = choker.lock().unwrap();
+
+            // Replace peers that disconnected
+            if !download_quota_reached {
+                dial(&mut pool, &dialer, &choker, max_peers);
+            }
+            info!("Request pipeline: {:?}", choker.concurrency());
+
+            if optimistic_unchoke_counter == 0 {
+                debug!("Optimistic Unchoke");
+                optimistic_unchoke_counter = 3;
+                choker.download(true);
+            } else {
+                choker.download(false);
+            }
+
+            if !download_quota_reached && choker.download_quota_reached() {
+                download_quota_reached = true;
+                warn!(
+                    "Download quota reached after {} bytes, only uploading from now on",
+                    choker.downloaded()
+                );
+                store.write().unwrap().set_paused(true);
+                if config.stop_on_quota {
+                    stopped_on_quota = true;
+                    break;
+                }
+            }
+            // Without requests no progress is made, so stall and availability announces are
+            // skipped too. Peers already connected are still uploaded to.
+            if download_quota_reached {
+                drop(choker);
+                limiter.wait();
+                continue;
+            }
+
+            // Pieces whose only source left are re-announced for as soon as the trackers' min
+            // interval allows, rather than waiting for the next interval or a stall
+            let unavailable = store.read().unwrap().unavailable().len();
+            if unavailable > 0 && trackers.may_announce_early(Instant::now()) {
+                warn!(
+                    "{} needed pieces have no connected source, announcing again",
+                    unavailable
+                );
+                let state = TorrentState {
+                    uploaded: choker.uploaded(),
+                    downloaded: choker.downloaded(),
+                    left: store.read().unwrap().remaining_bytes(),
+                    corrupt: store.read().unwrap().corrupt(),
+                };
+                match trackers.get_peers_early(&state, Instant::now()) {
+                    Ok(peers) => pool.add(Source::Tracker, peers),
+                    Err(e) => warn!("Announce failed: {}", e),
+                }
+            }
+
+            if let Some(w) = watchdog.as_mut() {
+                let now = Instant::now();
+                if w.update(store.read().unwrap().left, choker.downloading(), now) {
+                    warn!(
+                        "No pieces downloaded for {} seconds from {} unchoked peers, announcing again",
+                        w.since_progress(now).as_secs(),
+                        choker.downloading()
+                    );
+                    let state = TorrentState {
+                        uploaded: choker.uploaded(),
+                        downloaded: choker.downloaded(),
+                        left: store.read().unwrap().remaining_bytes(),
+                        corrupt: store.read().unwrap().corrupt(),
+                    };
+                    match trackers.get_peers(&state, None) {
+                        Ok(peers) => pool.add(Source::Tracker, peers),
+                        Err(e) => warn!("Announce failed: {}", e),
+                    }
+                }
+                stalled.store(w.is_stalled(), Ordering::SeqCst);
             }
+
+            // New connections are added while waiting
+            drop(choker);
+            limiter.wait();
         }
 
-        if optimistic_unchoke_counter == 0 {
-            debug!("Optimistic Unchoke");
-            optimistic_unchoke_counter = 3;
-            choker.download(true);
-        } else {
-            choker.download(false);
+        if stopped_on_quota || !verify_on_complete {
+            break;
+        }
+        let bad = PieceStore::verify_stored(&store, &metainfo);
+        if bad.is_empty() {
+            info!("All pieces verified");
+            break;
         }
+        error!(
+            "{} pieces failed verification after completion, downloading again: {:?}",
+            bad.len(),
+            bad
+        );
+    }
 
-        limiter.wait();
+    // Peers are told we want nothing more, whether we go on to seed or exit
+    choker.lock().unwrap().become_seed();
+
+    // Flush remaining statistics, and pieces before the completion hook reads them
+    store.write().unwrap().close_stats();
+    store.write().unwrap().close_output();
+    if let Some(handle) = stats_handle {
+        if let Err(e) = handle.join().expect("Statistics thread panicked") {
+            error!("statistics error: {}", e);
+        }
+    }
+
+    if let Some(f) = matches.value_of("reputation") {
+        if let Err(e) = reputation.lock().unwrap().save(f) {
+            error!("reputation error: {}", e);
+        }
+    }
+    if let Some(f) = matches.value_of("peers_file") {
+        let reputation = reputation.lock().unwrap();
+        if let Err(e) = save_peers(
+            f,
+            &mut saved_peers,
+            &pool,
+            &choker.lock().unwrap(),
+            &reputation,
+            unix_time(),
+        ) {
+            error!("peers file error: {}", e);
+        }
+    }
+
+    let done = match stopped_on_quota {
+        true => TorrentState {
+            uploaded: choker.lock().unwrap().uploaded(),
+            downloaded: choker.lock().unwrap().downloaded(),
+            left: store.read().unwrap().remaining_bytes(),
+            corrupt: store.read().unwrap().corrupt(),
+        },
+        false => TorrentState {
+            uploaded: choker.lock().unwrap().uploaded(),
+            downloaded: metainfo.info.total_length() as u64,
+            left: 0,
+            corrupt: store.read().unwrap().corrupt(),
+        },
+    };
+    if !stopped_on_quota {
+        trackers.completed(&done);
     }
 
     // Seed loop
     // Change choking metrics to use download rate rather than upload
-    if matches.is_present("file") || matches.is_present("seed") {
+    let output_path = match matches.value_of("output_dir") {
+        Some(dir) => Some(
+            Path::new(dir)
+                .join(&metainfo.info.name)
+                .display()
+                .to_string(),
+        ),
+        None => matches.value_of("output").map(str::to_owned),
+    };
+    if !stopped_on_quota
+        && complete(
+            &config.on_complete,
+            &metainfo.info.name,
+            output_path.as_ref().map(String::as_str),
+        )
+    {
         optimistic_unchoke_counter = 0;
         loop {
             // Rate limited loop
             debug!("Seed loop");
-            while let Ok(event) = rx.try_recv() {
-                match event {
-                    Event::Conn(conn) => choker.add(conn),
-                }
-            }
+            let mut choker = choker.lock().unwrap();
 
             if optimistic_unchoke_counter == 0 {
                 debug!("Optimistic Unchoke");
-                optimistic_unchoke_counter = 3;
+                optimistic_unchoke_counter = config.seed_optimistic_rounds;
                 choker.upload(true);
             } else {
                 choker.upload(false);
             }
+            optimistic_unchoke_counter -= 1;
 
+            if config.stop_on_quota && choker.upload_quota_reached() {
+                info!("Upload quota reached after {} bytes", choker.uploaded());
+                break;
+            }
+
+            drop(choker);
             limiter.wait();
         }
     }
+    trackers.stopped(&TorrentState {
+        uploaded: choker.lock().unwrap().uploaded(),
+        ..done
+    });
 
+    Listener::stop(&listener_shutdown, listen_addr);
+    if let Err(e) = listener_handle.join().expect("Listener thread panicked") {
+        error!("listener error: {}", e);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::net::SocketAddrV4;
+    use torrent::peer::{Handshake, Reserved};
+    use torrent::selection::Inorder;
+
+    #[test]
+    fn test_saved_peers_dialed() -> Result<(), failure::Error> {
+        let path = std::env::temp_dir().join("continuity_test_peers_file");
+        let now = unix_time();
+        let mut saved = SavedPeers::default();
+        for port in 1..4 {
+            saved.record(
+                SocketAddrV4::new([127, 0, 0, port as u8].into(), port).into(),
+                0,
+                now,
+            );
+        }
+        saved.save(&path)?;
+        // Banned after it was saved
+        let mut reputation = Reputation::default();
+        for _ in 0..2 {
+            reputation.bad_piece([127, 0, 0, 2].into());
+        }
+
+        let saved = SavedPeers::load(&path)?;
+        fs::remove_file(&path)?;
+        let mut pool = PeerPool::default();
+        add_saved_peers(&mut pool, &saved, &reputation, now);
+        let (tx, rx) = mpsc::channel();
+        let dialer = Dialer::new(2, |peer| Ok(peer.addr.port()), tx);
+        dial(&mut pool, &dialer, &Choke::new(), 10);
+        let mut dialed: Vec<_> = rx.iter().take(2).collect();
+        dialed.sort();
+        assert_eq!(dialed, vec![1, 3]);
+        assert_eq!(pool.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_complete() {
+        assert_eq!("exit".parse(), Ok(OnComplete::Exit));
+        assert_eq!(
+            "exec:/bin/notify".parse(),
+            Ok(OnComplete::Exec("/bin/notify".to_owned()))
+        );
+        assert!("exec:".parse::<OnComplete>().is_err());
+
+        assert!(complete(&OnComplete::Seed, "test", None));
+        assert!(!complete(&OnComplete::Exit, "test", None));
+        // Hook failures do not stop the client
+        assert!(!complete(
+            &OnComplete::Exec("/nonexistent/hook".to_owned()),
+            "test",
+            None
+        ));
+        assert!(!complete(
+            &OnComplete::Exec("false".to_owned()),
+            "test",
+            Some("out")
+        ));
+    }
+
+    #[test]
+    fn test_remove_stale_socket() -> io::Result<()> {
+        let path = std::env::temp_dir().join("continuity_test_control");
+        let _ = fs::remove_file(&path);
+        remove_stale_socket(&path)?;
+
+        drop(UnixListener::bind(&path)?);
+        remove_stale_socket(&path)?;
+        assert!(!path.exists());
+
+        fs::write(&path, b"data")?;
+        assert_eq!(
+            remove_stale_socket(&path).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(fs::read(&path)?, b"data");
+        fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_fatal_accept_errors() {
+        for kind in vec![
+            io::ErrorKind::InvalidInput,
+            io::ErrorKind::PermissionDenied,
+            io::ErrorKind::AddrNotAvailable,
+            io::ErrorKind::NotConnected,
+        ] {
+            assert!(accept_error_is_fatal(&kind.into()), "{:?}", kind);
+        }
+        assert!(accept_error_is_fatal(&io::Error::from_raw_os_error(9)));
+        // EMFILE and ENFILE
+        assert!(!accept_error_is_fatal(&io::Error::from_raw_os_error(24)));
+        assert!(!accept_error_is_fatal(&io::Error::from_raw_os_error(23)));
+        assert!(!accept_error_is_fatal(
+            &io::ErrorKind::ConnectionAborted.into()
+        ));
+    }
+
+    #[test]
+    fn test_accept_errors() {
+        let mut results: VecDeque<io::Result<u32>> = vec![
+            Err(io::Error::new(io::ErrorKind::Other, "Too many open files")),
+            Err(io::ErrorKind::ConnectionAborted.into()),
+            Ok(1),
+            Err(io::Error::new(io::ErrorKind::Other, "Too many open files")),
+            Ok(2),
+            Err(io::ErrorKind::InvalidInput.into()),
+            Ok(3),
+        ]
+        .into_iter()
+        .collect();
+        let mut accepted = Vec::new();
+        let start = Instant::now();
+        let e = accept_loop(
+            &AtomicBool::new(false),
+            || results.pop_front().unwrap(),
+            |n| accepted.push(n),
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(accepted, vec![1, 2]);
+        // Backed off after each transient error, doubling until an accept succeeded
+        assert!(start.elapsed() >= 4 * ACCEPT_BACKOFF);
+        assert_eq!(results.len(), 1);
+    }
+
+    fn test_listener(tx: mpsc::Sender<Event>, metainfo: &Arc<Metainfo>) -> io::Result<Listener> {
+        Ok(Listener {
+            conn: TcpListener::bind("127.0.0.1:0")?,
+            tx,
+            metainfo: metainfo.clone(),
+            client_id: Arc::new(make_id()),
+            store: Arc::new(RwLock::new(PieceStore::new(
+                &metainfo,
+                Box::new(Inorder::default()),
+            ))),
+            handshakes: Arc::new(AtomicUsize::new(0)),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            buffers: Arc::new(BufferAccount::default()),
+            uploads: Arc::new(UploadSlots::default()),
+            upload_batch: 1,
+            reputation: Arc::new(Mutex::new(Reputation::default())),
+            verifier: Arc::new(Verifier::new(metainfo.clone(), 1)),
+            reader_buffer_len: None,
+            writer_buffer_len: None,
+            snub_timeout: None,
+            max_bad_pieces: 1,
+            strictness: ProtocolStrictness::Strict,
+            write_timeout: None,
+            wire_dir: None,
+            bitfield_cap: None,
+        })
+    }
+
+    #[test]
+    fn test_stalled_handshake() -> Result<(), failure::Error> {
+        let metainfo = Arc::new(Metainfo::from_file("data/test.torrent")?);
+        let (tx, rx) = mpsc::channel();
+        let listener = test_listener(tx, &metainfo)?;
+        let addr = listener.conn.local_addr()?;
+        let handshakes = listener.handshakes.clone();
+        thread::spawn(move || listener.start());
+
+        let _stalled = TcpStream::connect(addr)?;
+        let mut peer = TcpStream::connect(addr)?;
+        Handshake::send(
+            &metainfo.info_hash()?,
+            Some(make_id().as_bytes()),
+            Reserved::default(),
+            &mut peer,
+        )?;
+        // Dropping a connection would close it
+        let _conns = vec![
+            rx.recv_timeout(HANDSHAKE_TIMEOUT / 2)?,
+            rx.recv_timeout(HANDSHAKE_TIMEOUT / 2)?,
+        ];
+        // Only the stalled handshake still holds a slot
+        let start = Instant::now();
+        while handshakes.load(Ordering::SeqCst) != 1 {
+            assert!(start.elapsed() < HANDSHAKE_TIMEOUT / 2);
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_stop_listener() -> Result<(), failure::Error> {
+        let metainfo = Arc::new(Metainfo::from_file("data/test.torrent")?);
+        let (tx, rx) = mpsc::channel();
+        let listener = test_listener(tx, &metainfo)?;
+        let addr = listener.conn.local_addr()?;
+        let shutdown = listener.shutdown.clone();
+        let handle = thread::spawn(move || listener.start());
+
+        Listener::stop(&shutdown, addr);
+        assert!(handle.join().unwrap().is_ok());
+        // The connection waking the listener is not handed on
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_banned_address() -> Result<(), failure::Error> {
+        let metainfo = Arc::new(Metainfo::from_file("data/test.torrent")?);
+        let (tx, rx) = mpsc::channel();
+        let listener = test_listener(tx, &metainfo)?;
+        let addr = listener.conn.local_addr()?;
+        let reputation = listener.reputation.clone();
+        thread::spawn(move || listener.start());
+
+        let handshake = |peer: &mut TcpStream| {
+            Handshake::send(
+                &metainfo.info_hash().unwrap(),
+                Some(make_id().as_bytes()),
+                Reserved::default(),
+                peer,
+            )
+        };
+        let mut first = TcpStream::connect(addr)?;
+        handshake(&mut first)?;
+        let Event::Conn(first) = rx.recv_timeout(HANDSHAKE_TIMEOUT / 2)?;
+        for _ in 0..2 {
+            reputation.lock().unwrap().bad_piece(first.ip);
+        }
+
+        // The same address from a new source port is still banned
+        let mut again = TcpStream::connect(addr)?;
+        assert_ne!(again.local_addr()?, first.id.parse()?);
+        // The listener may already have closed the connection
+        let _ = handshake(&mut again);
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_intake() -> Result<(), failure::Error> {
+        let metainfo = Arc::new(Metainfo::from_file("data/test.torrent")?);
+        let (tx, rx) = mpsc::channel();
+        let listener = test_listener(tx, &metainfo)?;
+        let addr = listener.conn.local_addr()?;
+        thread::spawn(move || listener.start());
+        let choker = Arc::new(Mutex::new(Choke::new()));
+        spawn_intake(rx, choker.clone());
+
+        let mut peer = TcpStream::connect(addr)?;
+        Handshake::send(
+            &metainfo.info_hash()?,
+            Some(make_id().as_bytes()),
+            Reserved::default(),
+            &mut peer,
+        )?;
+        // Added long before the 10 second round of the main loop would have picked it up
+        let start = Instant::now();
+        while choker.lock().unwrap().len() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(choker.lock().unwrap().len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+use super::OnComplete;
+use clap::ArgMatches;
+use failure::Fail;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use torrent::choking::SeedOptimistic;
+use torrent::connection::ProtocolStrictness;
+use torrent::pool::Family;
+use torrent::score::ScoreWeights;
+use torrent::storage::WriteStrategy;
+
+// Smaller buffers turn every block into many syscalls
+const MIN_BUFFER_LEN: usize = 1024;
+
+#[derive(Fail, Debug, PartialEq)]
+pub enum ConfigError {
+    #[fail(display = "invalid value for --{}: {}", _0, _1)]
+    Invalid(String, String),
+    #[fail(display = "--{} must be a positive number", _0)]
+    NotPositive(String),
+    #[fail(display = "--{} must be at least {} bytes", _0, _1)]
+    BufferTooSmall(String, usize),
+    #[fail(display = "--port must not be 0")]
+    ZeroPort,
+    #[fail(
+        display = "--min-peers {} is more than --max-peers {}, so nothing would be requested",
+        _0, _1
+    )]
+    MinPeersAboveMax(usize, usize),
+    #[fail(display = "--stop-on-quota needs --upload-quota or --download-quota")]
+    NoQuota,
+}
+
+// Every setting given as a value on the command line, parsed and checked against each other.
+// Paths and other free-form strings are read from the matches where they are used.
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub port: u16,
+    pub bind: Option<IpAddr>,
+    pub selector: String,
+    pub selector_seed: Option<u64>,
+    pub endgame: u32,
+    pub min_peers: usize,
+    pub max_peers: usize,
+    pub family: Family,
+    pub dial_concurrency: usize,
+    // None where 0 disables the timeout
+    pub connect_timeout: Option<Duration>,
+    pub handshake_timeout: Option<Duration>,
+    pub snub_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub interested_timeout: Option<Duration>,
+    pub stall_timeout: Option<Duration>,
+    pub upload_quota: Option<u64>,
+    pub download_quota: Option<u64>,
+    pub stop_on_quota: bool,
+    pub max_torrent_size: Option<u64>,
+    pub buffer_limit: Option<usize>,
+    pub read_buffer: Option<usize>,
+    pub write_buffer: Option<usize>,
+    pub upload_slots: Option<usize>,
+    pub upload_batch: usize,
+    pub hash_threads: usize,
+    pub max_bad_pieces: u32,
+    pub initial_pieces: Option<usize>,
+    pub strictness: ProtocolStrictness,
+    pub score_weights: ScoreWeights,
+    pub seed_optimistic: SeedOptimistic,
+    pub seed_optimistic_rounds: u32,
+    pub write_strategy: WriteStrategy,
+    pub on_complete: OnComplete,
+    pub log_max_size: u64,
+}
+
+impl Config {
+    pub fn from_matches(m: &ArgMatches) -> Result<Self, ConfigError> {
+        let config = Config {
+            port: parse(m, "port")?,
+            bind: parse_opt(m, "bind")?,
+            selector: match m.is_present("stream") {
+                true => "stream".to_owned(),
+                false => parse(m, "selector")?,
+            },
+            selector_seed: parse_opt(m, "selector_seed")?,
+            endgame: parse(m, "endgame")?,
+            min_peers: parse(m, "min_peers")?,
+            max_peers: parse(m, "max_peers")?,
+            family: if m.is_present("prefer_ipv6") {
+                Family::PreferIpv6
+            } else if m.is_present("ipv4_only") {
+                Family::Ipv4Only
+            } else {
+                Family::Any
+            },
+            dial_concurrency: positive(m, "dial_concurrency")?,
+            connect_timeout: timeout(m, "connect_timeout")?,
+            handshake_timeout: timeout(m, "handshake_timeout")?,
+            snub_timeout: timeout(m, "snub_timeout")?,
+            write_timeout: timeout(m, "write_timeout")?,
+            interested_timeout: timeout(m, "interested_timeout")?,
+            stall_timeout: timeout(m, "stall_timeout")?,
+            upload_quota: parse_opt(m, "upload_quota")?,
+            download_quota: parse_opt(m, "download_quota")?,
+            stop_on_quota: m.is_present("stop_on_quota"),
+            max_torrent_size: parse_opt(m, "max_torrent_size")?,
+            buffer_limit: parse_opt(m, "buffer_limit")?,
+            read_buffer: buffer_len(m, "read_buffer")?,
+            write_buffer: buffer_len(m, "write_buffer")?,
+            upload_slots: match m.is_present("upload_slots") {
+                true => Some(positive(m, "upload_slots")?),
+                false => None,
+            },
+            upload_batch: positive(m, "upload_batch")?,
+            hash_threads: positive(m, "hash_threads")?,
+            max_bad_pieces: positive(m, "max_bad_pieces")?,
+            initial_pieces: parse_opt(m, "initial_pieces")?,
+            strictness: parse(m, "protocol")?,
+            score_weights: parse(m, "score_weights")?,
+            seed_optimistic: parse(m, "seed_optimistic")?,
+            seed_optimistic_rounds: positive(m, "seed_optimistic_rounds")?,
+            write_strategy: parse_opt(m, "write_strategy")?.unwrap_or_default(),
+            on_complete: match parse_opt(m, "on_complete")? {
+                Some(action) => action,
+                None if m.is_present("seedmode") => OnComplete::Seed,
+                None => OnComplete::Exit,
+            },
+            log_max_size: positive(m, "log_max_size")?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Constraints between settings, each of which is valid on its own
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.port == 0 {
+            return Err(ConfigError::ZeroPort);
+        }
+        if self.min_peers > self.max_peers {
+            return Err(ConfigError::MinPeersAboveMax(
+                self.min_peers,
+                self.max_peers,
+            ));
+        }
+        if self.stop_on_quota && self.upload_quota.is_none() && self.download_quota.is_none() {
+            return Err(ConfigError::NoQuota);
+        }
+        Ok(())
+    }
+}
+
+fn flag(name: &str) -> String {
+    name.replace('_', "-")
+}
+
+// For options with a default value
+fn parse<T>(m: &ArgMatches, name: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    parse_opt(m, name)?.ok_or_else(|| ConfigError::Invalid(flag(name), "missing".to_owned()))
+}
+
+fn parse_opt<T>(m: &ArgMatches, name: &str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match m.value_of(name) {
+        Some(s) => s
+            .parse()
+            .map(Some)
+            .map_err(|e: T::Err| ConfigError::Invalid(flag(name), format!("{}: {}", s, e))),
+        None => Ok(None),
+    }
+}
+
+fn positive<T>(m: &ArgMatches, name: &str) -> Result<T, ConfigError>
+where
+    T: FromStr + Default + PartialEq,
+    T::Err: Display,
+{
+    let n = parse(m, name)?;
+    if n == T::default() {
+        return Err(ConfigError::NotPositive(flag(name)));
+    }
+    Ok(n)
+}
+
+fn timeout(m: &ArgMatches, name: &str) -> Result<Option<Duration>, ConfigError> {
+    match parse(m, name)? {
+        0 => Ok(None),
+        secs => Ok(Some(Duration::from_secs(secs))),
+    }
+}
+
+fn buffer_len(m: &ArgMatches, name: &str) -> Result<Option<usize>, ConfigError> {
+    match parse_opt(m, name)? {
+        Some(n) if n < MIN_BUFFER_LEN => {
+            Err(ConfigError::BufferTooSmall(flag(name), MIN_BUFFER_LEN))
+        }
+        n => Ok(n),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::app;
+    use super::*;
+    use matches::matches;
+
+    fn config(args: &[&str]) -> Result<Config, ConfigError> {
+        let mut argv = vec!["continuity", "test.torrent", "--output", "out"];
+        argv.extend_from_slice(args);
+        Config::from_matches(&app().get_matches_from_safe(argv).unwrap())
+    }
+
+    #[test]
+    fn test_defaults() -> Result<(), ConfigError> {
+        let c = config(&[])?;
+        assert_eq!(c.port, 8888);
+        assert_eq!(c.selector, "smart");
+        assert_eq!(c.family, Family::Any);
+        assert_eq!(c.handshake_timeout, Some(Duration::from_secs(10)));
+        assert_eq!(c.upload_slots, None);
+        assert_eq!(c.on_complete, OnComplete::Exit);
+
+        let c = config(&[
+            "--stream",
+            "--seed",
+            "--ipv4-only",
+            "--snub-timeout",
+            "0",
+            "--read-buffer",
+            "4096",
+            "--upload-slots",
+            "4",
+            "--download-quota",
+            "100",
+            "--stop-on-quota",
+        ])?;
+        assert_eq!(c.selector, "stream");
+        assert_eq!(c.on_complete, OnComplete::Seed);
+        assert_eq!(c.family, Family::Ipv4Only);
+        assert_eq!(c.snub_timeout, None);
+        assert_eq!(c.read_buffer, Some(4096));
+        assert_eq!(c.upload_slots, Some(4));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(config(&["--port", "0"]), Err(ConfigError::ZeroPort));
+        assert!(matches!(
+            config(&["--port", "65536"]),
+            Err(ConfigError::Invalid(ref f, _)) if f == "port"
+        ));
+        assert!(matches!(
+            config(&["--score-weights", "1,1"]),
+            Err(ConfigError::Invalid(ref f, _)) if f == "score-weights"
+        ));
+        assert_eq!(
+            config(&["--hash-threads", "0"]),
+            Err(ConfigError::NotPositive("hash-threads".to_owned()))
+        );
+        assert_eq!(
+            config(&["--write-buffer", "512"]),
+            Err(ConfigError::BufferTooSmall(
+                "write-buffer".to_owned(),
+                MIN_BUFFER_LEN
+            ))
+        );
+        assert_eq!(
+            config(&["--min-peers", "10", "--max-peers", "5"]),
+            Err(ConfigError::MinPeersAboveMax(10, 5))
+        );
+        assert_eq!(config(&["--stop-on-quota"]), Err(ConfigError::NoQuota));
+    }
+
+    #[test]
+    fn test_verify_needs_output() {
+        // Pieces written to stdout cannot be read back or replaced
+        let argv = vec!["continuity", "test.torrent", "--verify-on-complete"];
+        assert!(app().get_matches_from_safe(argv).is_err());
+        assert!(config(&["--verify-on-complete"]).is_ok());
+    }
+
+    #[test]
+    fn test_stdout_output() {
+        let argv = vec!["continuity", "test.torrent"];
+        let c = Config::from_matches(&app().get_matches_from_safe(argv).unwrap()).unwrap();
+        assert_eq!(c.write_strategy, WriteStrategy::Buffered);
+        let argv = vec!["continuity", "test.torrent", "--write-strategy", "sync"];
+        assert!(app().get_matches_from_safe(argv).is_err());
+    }
+
+    #[test]
+    fn test_stream_conflicts_with_selector() {
+        let argv = vec![
+            "continuity",
+            "test.torrent",
+            "--stream",
+            "--selector",
+            "rarest",
+        ];
+        let e = app().get_matches_from_safe(argv).unwrap_err();
+        assert_eq!(e.kind, clap::ErrorKind::ArgumentConflict);
+        assert_eq!(config(&["--stream"]).unwrap().selector, "stream");
+        assert_eq!(
+            config(&["--selector", "rarest"]).unwrap().selector,
+            "rarest"
+        );
+    }
+}
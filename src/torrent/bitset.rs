@@ -14,3 +14,7 @@ pub fn intersect(bv1: &BitVec, bv2: &BitVec) -> BitVec {
     let ret = bv2.clone();
     ret & bv1
 }
+
+pub fn count_ones(bv: &BitVec) -> usize {
+    bv.iter().filter(|b| *b).count()
+}
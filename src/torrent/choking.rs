@@ -1,54 +1,294 @@
-use crate::connection::Connection;
+use crate::connection::{reciprocation_bonus, Connection, PeerView};
+use crate::reputation::Reputation;
+use crate::score::{peer_score, ScoreWeights};
 use log::{self, debug, error, info, warn};
 use rand::distributions::{Distribution, Uniform};
-use std::cmp::Reverse;
+use std::cmp::{Ordering, Reverse};
 use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChokeReason {
+    // Unchoked as one of the fastest interested peers
+    Downloader,
+    // Unchoked as the rotating optimistic unchoke
+    Optimistic,
+    // Unchoked while not interested, as it is faster than the slowest downloader
+    Reciprocity,
+    // Choked as it is slower than the slowest downloader
+    BelowThreshold,
+    // Choked as uploading is disabled or over quota
+    NoUpload,
+}
+
+impl ChokeReason {
+    pub fn is_unchoked(self) -> bool {
+        match self {
+            ChokeReason::BelowThreshold | ChokeReason::NoUpload => false,
+            _ => true,
+        }
+    }
+}
+
+// How the optimistic unchoke is picked while seeding
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeedOptimistic {
+    Random,
+    // The peer with the fewest pieces, to spread data through the swarm
+    LeastComplete,
+    // No optimistic unchoke, every slot goes to the peers we upload to fastest
+    Off,
+}
+
+impl Default for SeedOptimistic {
+    fn default() -> Self {
+        SeedOptimistic::Random
+    }
+}
+
+impl FromStr for SeedOptimistic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(SeedOptimistic::Random),
+            "least-complete" => Ok(SeedOptimistic::LeastComplete),
+            "off" => Ok(SeedOptimistic::Off),
+            _ => Err(format!("{} is not a valid optimistic unchoke policy", s)),
+        }
+    }
+}
+
+// Request pipeline usage across all connections, as of their last snapshots
+#[derive(Debug, Default, PartialEq)]
+pub struct Concurrency {
+    // Pieces requested and not yet received
+    pub outstanding: usize,
+    // Request rounds that found nothing to request while the peer was unchoking us
+    pub starved: u64,
+}
+
+// Bytes received from the peer since the last snapshot, plus the bonus for reciprocating
+fn rank(c: &Connection) -> u64 {
+    let snapshot = &c.snapshot;
+    snapshot.downloaded_bytes
+        + reciprocation_bonus(snapshot.downloaded_bytes, !snapshot.state.client_choked)
+}
 
 pub struct Choke {
     connections: Vec<Connection>,
     optimistic_unchoke: Option<Connection>,
+    reputation: Arc<Mutex<Reputation>>,
+    interested_timeout: Option<Duration>,
+    // When unset every peer stays choked, so we only download
+    upload: bool,
+    // Piece payload transferred this session, and the limits on it
+    uploaded_bytes: u64,
+    downloaded_bytes: u64,
+    upload_quota: Option<u64>,
+    download_quota: Option<u64>,
+    score_weights: ScoreWeights,
+    seed_optimistic: SeedOptimistic,
 }
 
 impl Choke {
     pub fn new() -> Self {
+        Choke::with_reputation(Arc::new(Mutex::new(Reputation::default())))
+    }
+
+    // Peers with a bad history are refused and peers with a good one are preferred
+    pub fn with_reputation(reputation: Arc<Mutex<Reputation>>) -> Self {
         Self {
             connections: Vec::new(),
             optimistic_unchoke: None,
+            reputation,
+            interested_timeout: None,
+            upload: true,
+            uploaded_bytes: 0,
+            downloaded_bytes: 0,
+            upload_quota: None,
+            download_quota: None,
+            score_weights: ScoreWeights::default(),
+            seed_optimistic: SeedOptimistic::default(),
         }
     }
 
+    // Every peer is choked once we have uploaded this many bytes
+    pub fn set_upload_quota(&mut self, quota: Option<u64>) {
+        self.upload_quota = quota;
+    }
+
+    // Only reported by download_quota_reached, stopping requests is up to the caller
+    pub fn set_download_quota(&mut self, quota: Option<u64>) {
+        self.download_quota = quota;
+    }
+
+    // Piece payload sent to and received from peers this session
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded_bytes
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded_bytes
+    }
+
+    pub fn upload_quota_reached(&self) -> bool {
+        self.upload_quota
+            .map_or(false, |q| self.uploaded_bytes >= q)
+    }
+
+    pub fn download_quota_reached(&self) -> bool {
+        self.download_quota
+            .map_or(false, |q| self.downloaded_bytes >= q)
+    }
+
+    pub fn can_upload(&self) -> bool {
+        self.upload && !self.upload_quota_reached()
+    }
+
+    pub fn set_upload(&mut self, upload: bool) {
+        self.upload = upload;
+    }
+
+    // Ties between peers that delivered as much are broken by their score
+    pub fn set_score_weights(&mut self, weights: ScoreWeights) {
+        self.score_weights = weights;
+    }
+
+    // Only used by upload, downloading always picks the optimistic unchoke at random
+    pub fn set_seed_optimistic(&mut self, policy: SeedOptimistic) {
+        self.seed_optimistic = policy;
+    }
+
+    // Drop connections that have kept us choked while interested for longer than timeout
+    pub fn set_interested_timeout(&mut self, timeout: Option<Duration>) {
+        self.interested_timeout = timeout;
+    }
+
     pub fn add(&mut self, conn: Connection) {
+        if self.reputation.lock().unwrap().is_banned(conn.ip) {
+            info!("{}: Refusing banned peer", conn.id);
+            return;
+        }
+        conn.set_upload_allowed(self.can_upload());
         self.connections.push(conn);
     }
 
-    fn pick_optimistic_unchoke(&mut self) -> Option<Connection> {
+    pub fn concurrency(&self) -> Concurrency {
+        self.connections
+            .iter()
+            .chain(self.optimistic_unchoke.iter())
+            .fold(Concurrency::default(), |mut c, conn| {
+                c.outstanding += conn.snapshot.pending;
+                c.starved += conn.snapshot.starved;
+                c
+            })
+    }
+
+    // Connections we are interested in that have unchoked us, as of their last snapshots
+    pub fn downloading(&self) -> usize {
+        self.connections
+            .iter()
+            .chain(self.optimistic_unchoke.iter())
+            .filter(|c| c.snapshot.state.client_interested && !c.snapshot.state.peer_choked)
+            .count()
+    }
+
+    // Ids of open connections, including the optimistic unchoke
+    pub fn peer_ids(&self) -> Vec<Arc<String>> {
+        self.connections
+            .iter()
+            .chain(self.optimistic_unchoke.iter())
+            .map(|c| c.id.clone())
+            .collect()
+    }
+
+    // Open connections, including the optimistic unchoke, as of their last snapshots
+    pub fn peers(&self) -> Vec<PeerView<'_>> {
+        self.connections
+            .iter()
+            .chain(self.optimistic_unchoke.iter())
+            .map(|c| c.view())
+            .collect()
+    }
+
+    // Tell every peer we no longer want anything. Closed connections are reaped as usual.
+    pub fn become_seed(&self) {
+        for c in self
+            .connections
+            .iter()
+            .chain(self.optimistic_unchoke.iter())
+        {
+            let _ = c.become_seed();
+        }
+    }
+
+    // Open connections, including the optimistic unchoke
+    pub fn len(&self) -> usize {
+        self.connections.len() + self.optimistic_unchoke.iter().count()
+    }
+
+    fn pick_optimistic_unchoke(&mut self, policy: SeedOptimistic) -> Option<Connection> {
         if self.connections.len() == 0 {
             return None;
         }
 
-        let unchoke = self
-            .connections
-            .swap_remove(Uniform::from(0..self.connections.len()).sample(&mut rand::thread_rng()));
-        Some(unchoke)
+        let i = match policy {
+            SeedOptimistic::Random => {
+                Uniform::from(0..self.connections.len()).sample(&mut rand::thread_rng())
+            }
+            // As of the last snapshots
+            SeedOptimistic::LeastComplete => self
+                .connections
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, c)| c.snapshot.availability.iter().filter(|b| *b).count())
+                .map(|(i, _)| i)
+                .unwrap(),
+            SeedOptimistic::Off => return None,
+        };
+        Some(self.connections.swap_remove(i))
+    }
+
+    // Drop closed connections, recording the disconnect against the peer
+    fn reap(&mut self) {
+        let reputation = self.reputation.clone();
+        self.connections.retain(|c| {
+            if c.is_shutdown() {
+                reputation.lock().unwrap().disconnect(c.ip);
+                return false;
+            }
+            true
+        });
     }
 
     pub fn setup(&mut self, optimistic_unchoke: bool) {
+        self.setup_with(optimistic_unchoke, SeedOptimistic::Random)
+    }
+
+    fn setup_with(&mut self, optimistic_unchoke: bool, policy: SeedOptimistic) {
         // Get rid of duplicate connections
         // After this point, assume any connection will stay valid until next time this loop
         // is run - i.e. ignore the errors when they aren't
-        self.connections.retain(|c| !c.is_shutdown());
+        self.reap();
 
         // Early optimistic unchoke
-        if self.optimistic_unchoke.is_none()
+        if policy == SeedOptimistic::Off {
+            if let Some(c) = self.optimistic_unchoke.take() {
+                self.connections.push(c);
+            }
+        } else if self.optimistic_unchoke.is_none()
             || self.optimistic_unchoke.as_ref().unwrap().is_shutdown()
         {
-            let c = self.pick_optimistic_unchoke();
+            let c = self.pick_optimistic_unchoke(policy);
             self.optimistic_unchoke = c;
         // Scheduled optimistic unchoke
         } else if optimistic_unchoke {
             let c = self.optimistic_unchoke.take().unwrap();
             self.connections.push(c);
-            self.optimistic_unchoke = self.pick_optimistic_unchoke();
+            self.optimistic_unchoke = self.pick_optimistic_unchoke(policy);
         }
 
         // Update the snapshots (including optimistic unchoke)
@@ -58,14 +298,43 @@ impl Choke {
         if self.optimistic_unchoke.is_some() {
             self.optimistic_unchoke.as_mut().unwrap().update_snapshot();
         }
+        for c in self
+            .connections
+            .iter()
+            .chain(self.optimistic_unchoke.iter())
+        {
+            self.uploaded_bytes += c.snapshot.uploaded_bytes;
+            self.downloaded_bytes += c.snapshot.downloaded_bytes;
+        }
+        // Snapshots mark connections whose threads panicked as dead
+        self.reap();
+
+        // Free slots held by peers that never unchoke us
+        if let Some(timeout) = self.interested_timeout {
+            self.connections.retain(|c| {
+                if c.snapshot.unproductive_for() >= timeout {
+                    info!("{}: Dropping unproductive connection", c.id);
+                    return false;
+                }
+                true
+            });
+        }
     }
 
     pub fn download(&mut self, optimistic_unchoke: bool) {
         self.setup(optimistic_unchoke);
 
-        // Determine downloaders
-        self.connections
-            .sort_by_key(|c| Reverse(c.snapshot.downloaded));
+        // Determine downloaders by the bytes they sent us, with a bonus for peers we are already
+        // uploading to, breaking ties with the peer's score
+        let reputation = self.reputation.lock().unwrap();
+        let weights = self.score_weights;
+        let score = |c: &Connection| peer_score(&c.view(), reputation.get(c.ip), &weights);
+        self.connections.sort_by(|a, b| {
+            rank(b)
+                .cmp(&rank(a))
+                .then_with(|| score(b).partial_cmp(&score(a)).unwrap_or(Ordering::Equal))
+        });
+        drop(reputation);
         // Determine the set of peers currently downloading from client
         let downloaders: HashSet<_> = self
             .connections
@@ -79,7 +348,7 @@ impl Choke {
         let downloader_threshold: u64 = downloaders
             .iter()
             .cloned()
-            .map(|i| self.connections[i].snapshot.downloaded)
+            .map(|i| rank(&self.connections[i]))
             .min()
             .unwrap_or(0);
         // Determine peers to unchoke
@@ -90,7 +359,7 @@ impl Choke {
             .filter(|(_, c)| {
                 !c.snapshot.state.peer_choked
                     && !c.snapshot.state.peer_interested
-                    && (c.snapshot.downloaded > downloader_threshold || downloader_threshold == 0)
+                    && (rank(c) > downloader_threshold || downloader_threshold == 0)
             })
             .map(|(i, _)| i)
             .collect();
@@ -103,31 +372,11 @@ impl Choke {
             "Downloaders: {:?}",
             &downloaders.iter().map(|i| &self.connections[*i])
         );
-        for i in 0..self.connections.len() {
-            let conn = &self.connections[i];
-            // Laid out in weird way to avoid multiple calls for set inclusion
-            // Match anything that should be unchoked
-            if downloaders.contains(&i) || unchoked.contains(&i) {
-                // Unchoke it if it is choked
-                if conn.snapshot.state.client_choked {
-                    let _ = conn.choke(false);
-                }
-            // Matches anything that shouldnt be choked, but is
-            } else if !conn.snapshot.state.client_choked {
-                let _ = conn.choke(true);
-            }
-        }
-
-        match &self.optimistic_unchoke {
-            Some(c) if c.snapshot.state.client_choked => {
-                let _ = c.choke(false);
-            }
-            _ => {}
-        }
+        self.apply(&downloaders, &unchoked);
     }
 
     pub fn upload(&mut self, optimistic_unchoke: bool) {
-        self.setup(optimistic_unchoke);
+        self.setup_with(optimistic_unchoke, self.seed_optimistic);
 
         // Determine uploaders
         self.connections
@@ -166,26 +415,590 @@ impl Choke {
             "Uploaders: {:?}",
             &uploaders.iter().map(|i| &self.connections[*i])
         );
+        self.apply(&uploaders, &unchoked);
+    }
+
+    // Choke or unchoke every connection, recording the reason in its snapshot
+    fn apply(&mut self, downloaders: &HashSet<usize>, unchoked: &HashSet<usize>) {
+        let upload = self.can_upload();
         for i in 0..self.connections.len() {
-            let conn = &self.connections[i];
-            // Laid out in weird way to avoid multiple calls for set inclusion
-            // Match anything that should be unchoked
-            if uploaders.contains(&i) || unchoked.contains(&i) {
-                // Unchoke it if it is choked
-                if conn.snapshot.state.client_choked {
-                    let _ = conn.choke(false);
+            let reason = if !upload {
+                ChokeReason::NoUpload
+            } else if downloaders.contains(&i) {
+                ChokeReason::Downloader
+            } else if unchoked.contains(&i) {
+                ChokeReason::Reciprocity
+            } else {
+                ChokeReason::BelowThreshold
+            };
+            let conn = &mut self.connections[i];
+            conn.set_upload_allowed(upload);
+            debug!("{}: {:?}", conn.id, reason);
+            conn.snapshot.choke_reason = Some(reason);
+            // Only send messages when the state changes
+            if reason.is_unchoked() == conn.snapshot.state.client_choked {
+                let _ = conn.choke(!reason.is_unchoked());
+            }
+        }
+
+        if let Some(c) = &mut self.optimistic_unchoke {
+            c.set_upload_allowed(upload);
+            let reason = match upload {
+                true => ChokeReason::Optimistic,
+                false => ChokeReason::NoUpload,
+            };
+            c.snapshot.choke_reason = Some(reason);
+            // Choked once uploading stops, since requests are served to any unchoked peer
+            if reason.is_unchoked() == c.snapshot.state.client_choked {
+                let _ = c.choke(!reason.is_unchoked());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{BufferAccount, ConnInfo, ProtocolStrictness, UploadSlots};
+    use crate::files::create_output;
+    use crate::hash;
+    use crate::metainfo::Metainfo;
+    use crate::peer::Message;
+    use crate::selection::{Inorder, Rare};
+    use crate::storage::{PieceStore, Sink};
+    use crate::verify::Verifier;
+    use bitvec::bitvec;
+    use std::net::{IpAddr, TcpListener, TcpStream};
+    use std::sync::RwLock;
+
+    fn conn_info(id: &str, reputation: &Arc<Mutex<Reputation>>) -> ConnInfo {
+        let metainfo = Arc::new(Metainfo::mock(1, 4));
+        ConnInfo {
+            store: Arc::new(RwLock::new(PieceStore::new(
+                &metainfo,
+                Box::new(Inorder::default()),
+            ))),
+            verifier: Arc::new(Verifier::new(metainfo.clone(), 1)),
+            metainfo,
+            reader_buffer_len: None,
+            writer_buffer_len: None,
+            id: Arc::new(id.to_owned()),
+            client_id: Arc::new("client".to_owned()),
+            strictness: ProtocolStrictness::default(),
+            buffers: Arc::new(BufferAccount::default()),
+            uploads: Arc::new(UploadSlots::default()),
+            upload_batch: 1,
+            reputation: reputation.clone(),
+            bind_addr: None,
+            dht_port: None,
+            snub_timeout: None,
+            max_bad_pieces: 1,
+            connect_timeout: None,
+            handshake_timeout: None,
+            handshake_slots: None,
+            write_timeout: None,
+            wire_dir: None,
+            bitfield_cap: None,
+        }
+    }
+
+    fn connection(id: &str, reputation: &Arc<Mutex<Reputation>>) -> (Connection, TcpStream) {
+        connection_to([127, 0, 0, 1].into(), id, reputation)
+    }
+
+    // The whole of 127.0.0.0/8 is loopback, so peers can have distinct addresses
+    fn connection_to(
+        ip: IpAddr,
+        id: &str,
+        reputation: &Arc<Mutex<Reputation>>,
+    ) -> (Connection, TcpStream) {
+        let listener = TcpListener::bind((ip, 0)).unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        (
+            Connection::new(stream, conn_info(id, reputation)).unwrap(),
+            peer,
+        )
+    }
+
+    fn ci_for(id: &str, metainfo: &Arc<Metainfo>, store: PieceStore) -> ConnInfo {
+        let mut ci = conn_info(id, &Arc::new(Mutex::new(Reputation::default())));
+        ci.metainfo = metainfo.clone();
+        ci.verifier = Arc::new(Verifier::new(metainfo.clone(), 2));
+        ci.store = Arc::new(RwLock::new(store));
+        // Peer ids are always 20 bytes on the wire
+        ci.client_id = Arc::new(format!("{:-<20}", id));
+        ci
+    }
+
+    // A seed bootstrapped from a file, read or memory mapped, uploads the whole torrent to a fresh
+    // instance over real connections, and the leecher's output matches the original byte for
+    // byte. Returns how long the download took.
+    fn seed_to_leech(name: &str, pieces: usize, mapped: bool) -> Result<Duration, failure::Error> {
+        // Several blocks per piece and a short final piece
+        let piece_length = 3 * 16 * 1024;
+        let data: Vec<u8> = (0..pieces * piece_length + 1234)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect();
+        let mut metainfo = Metainfo::mock(piece_length, data.len());
+        for (i, piece) in data.chunks(piece_length).enumerate() {
+            metainfo.info.pieces[20 * i..20 * (i + 1)].copy_from_slice(&hash::sha1(piece));
+        }
+        let metainfo = Arc::new(metainfo);
+        let dir = std::env::temp_dir();
+        let original = dir.join(format!("continuity_{}_original", name));
+        let output = dir.join(format!("continuity_{}_output", name));
+        std::fs::write(&original, &data)?;
+
+        let mut seed_store = PieceStore::new(&metainfo, Box::new(Inorder::default()));
+        match mapped {
+            true => seed_store.bootstrap_mapped(&metainfo, &original, true)?,
+            false => seed_store.bootstrap_with(&metainfo, &original, true)?,
+        }
+        let mut leech_store = PieceStore::new(&metainfo, Box::new(Rare::default()));
+        leech_store.set_sink(Sink::Seekable(Box::new(create_output(
+            &output,
+            data.len() as u64,
+        )?)));
+        let leech_ci = ci_for("leech", &metainfo, leech_store);
+        let store = leech_ci.store.clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let leech = Connection::new(TcpStream::connect(listener.local_addr()?)?, leech_ci)?;
+        let seed = Connection::new(listener.accept()?.0, ci_for("seed", &metainfo, seed_store))?;
+        let mut seed_choker = Choke::new();
+        seed_choker.add(seed);
+        let mut leech_choker = Choke::new();
+        leech_choker.add(leech);
+
+        let start = std::time::Instant::now();
+        while !store.read().unwrap().is_complete() {
+            assert!(
+                start.elapsed() < Duration::from_secs(10 + pieces as u64 / 10),
+                "download stalled"
+            );
+            seed_choker.upload(false);
+            leech_choker.download(false);
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let elapsed = start.elapsed();
+        assert!(PieceStore::verify_stored(&store, &metainfo).is_empty());
+        drop(leech_choker);
+        drop(store);
+
+        let written = std::fs::read(&output)?;
+        std::fs::remove_file(&original)?;
+        std::fs::remove_file(&output)?;
+        assert!(written == data, "output differs from the original");
+        for (i, piece) in written.chunks(piece_length).enumerate() {
+            assert!(metainfo.verify_piece(i as u32, piece));
+        }
+        Ok(elapsed)
+    }
+
+    #[test]
+    fn test_seed_to_leech() -> Result<(), failure::Error> {
+        seed_to_leech("test_seed_to_leech", 4, false)?;
+        seed_to_leech("test_seed_to_leech_mapped", 4, true)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_reputation() {
+        let reputation = Arc::new(Mutex::new(Reputation::default()));
+        let bad_ip = [127, 0, 0, 2].into();
+        let good_ip = [127, 0, 0, 3].into();
+        {
+            let mut r = reputation.lock().unwrap();
+            r.bad_piece(bad_ip);
+            r.bad_piece(bad_ip);
+            r.good_piece(good_ip);
+        }
+        let mut choker = Choke::with_reputation(reputation.clone());
+        let (optimistic, _optimistic_peer) = connection("optimistic", &reputation);
+        choker.optimistic_unchoke = Some(optimistic);
+        let (bad, _bad_peer) = connection_to(bad_ip, "bad", &reputation);
+        let (neutral, _neutral_peer) = connection_to([127, 0, 0, 4].into(), "neutral", &reputation);
+        let (good, _good_peer) = connection_to(good_ip, "good", &reputation);
+        choker.add(bad);
+        choker.add(neutral);
+        choker.add(good);
+
+        // Nothing has been downloaded yet, so history decides the order
+        choker.download(false);
+        let ids: Vec<_> = choker.connections.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, vec!["good", "neutral"]);
+    }
+
+    #[test]
+    fn test_poisoned_connection() {
+        let reputation = Arc::new(Mutex::new(Reputation::default()));
+        let mut choker = Choke::new();
+        let (optimistic, _optimistic_peer) = connection("optimistic", &reputation);
+        choker.optimistic_unchoke = Some(optimistic);
+        let (poisoned, _poisoned_peer) = connection("poisoned", &reputation);
+        let (healthy, _healthy_peer) = connection("healthy", &reputation);
+        let availability = poisoned.availability.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = availability.lock().unwrap();
+            panic!("receiver panicked");
+        })
+        .join();
+        choker.add(poisoned);
+        choker.add(healthy);
+
+        choker.download(false);
+        let ids: Vec<_> = choker.connections.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, vec!["healthy"]);
+    }
+
+    #[test]
+    fn test_choke_reason() {
+        let reputation = Arc::new(Mutex::new(Reputation::default()));
+        let mut choker = Choke::new();
+        let (optimistic, _optimistic_peer) = connection("optimistic", &reputation);
+        choker.optimistic_unchoke = Some(optimistic);
+        let peers: Vec<_> = vec![
+            ("downloader", false, true),
+            ("reciprocity", false, false),
+            ("choking", true, true),
+        ]
+        .into_iter()
+        .map(|(id, peer_choked, peer_interested)| {
+            let (conn, peer) = connection(id, &reputation);
+            {
+                let mut state = conn.state.write().unwrap();
+                state.peer_choked = peer_choked;
+                state.peer_interested = peer_interested;
+            }
+            choker.add(conn);
+            peer
+        })
+        .collect();
+
+        choker.download(false);
+        let mut reasons: Vec<_> = choker
+            .connections
+            .iter()
+            .chain(choker.optimistic_unchoke.iter())
+            .map(|c| (c.id.as_str(), c.snapshot.choke_reason))
+            .collect();
+        reasons.sort_by_key(|(id, _)| *id);
+        assert_eq!(
+            reasons,
+            vec![
+                ("choking", Some(ChokeReason::BelowThreshold)),
+                ("downloader", Some(ChokeReason::Downloader)),
+                ("optimistic", Some(ChokeReason::Optimistic)),
+                ("reciprocity", Some(ChokeReason::Reciprocity)),
+            ]
+        );
+        drop(peers);
+    }
+
+    #[test]
+    fn test_peer_view() -> Result<(), failure::Error> {
+        let reputation = Arc::new(Mutex::new(Reputation::default()));
+        let mut choker = Choke::new();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (mut peer, _) = listener.accept()?;
+        peer.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let conn = Connection::from_handshaked(
+            stream,
+            conn_info("peer", &reputation),
+            Arc::new("peer".to_owned()),
+        )?;
+        choker.add(conn);
+        let state = |choker: &Choke| {
+            let peers = choker.peers();
+            assert_eq!(peers.len(), 1);
+            assert_eq!(peers[0].id(), "peer");
+            let s = peers[0].state();
+            (
+                s.client_choked,
+                s.client_interested,
+                s.peer_choked,
+                s.peer_interested,
+            )
+        };
+
+        for &choke in [false, true].iter() {
+            choker.connections[0].choke(choke)?;
+            let expected = if choke {
+                Message::Choke
+            } else {
+                Message::Unchoke
+            };
+            while Message::recv(&mut peer)? != expected {}
+            // Unchanged until the next snapshot
+            assert_eq!(state(&choker), (!choke, false, true, false));
+            choker.connections[0].update_snapshot();
+            assert_eq!(state(&choker), (choke, false, true, false));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_become_seed() -> Result<(), failure::Error> {
+        let reputation = Arc::new(Mutex::new(Reputation::default()));
+        let mut choker = Choke::new();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut peers = Vec::new();
+        for id in ["optimistic", "peer"].iter() {
+            let stream = TcpStream::connect(listener.local_addr()?)?;
+            let (peer, _) = listener.accept()?;
+            peer.set_read_timeout(Some(Duration::from_secs(5)))?;
+            let conn = Connection::from_handshaked(
+                stream,
+                conn_info(id, &reputation),
+                Arc::new(id.to_string()),
+            )?;
+            peers.push(peer);
+            match choker.optimistic_unchoke {
+                None => choker.optimistic_unchoke = Some(conn),
+                Some(_) => choker.add(conn),
+            }
+        }
+
+        choker.become_seed();
+        for peer in peers.iter_mut() {
+            while Message::recv(&mut *peer)? != Message::NotInterested {}
+        }
+        for c in choker
+            .connections
+            .iter()
+            .chain(choker.optimistic_unchoke.iter())
+        {
+            // Set before the message is sent
+            assert!(!c.state.read().unwrap().client_interested);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_seed_optimistic() {
+        let reputation = Arc::new(Mutex::new(Reputation::default()));
+        let mut choker = Choke::new();
+        choker.set_seed_optimistic(SeedOptimistic::LeastComplete);
+        let mut peers = Vec::new();
+        for (id, have) in vec![("half", 2), ("none", 0), ("most", 3)] {
+            let (mut conn, peer) = connection(id, &reputation);
+            let mut bv = bitvec![0; 4];
+            for i in 0..have {
+                bv.set(i, true);
+            }
+            *conn.availability.lock().unwrap() = bv;
+            conn.update_snapshot();
+            choker.add(conn);
+            peers.push(peer);
+        }
+
+        choker.upload(true);
+        assert_eq!(
+            choker.optimistic_unchoke.as_ref().unwrap().id.as_str(),
+            "none"
+        );
+
+        // Turning it off returns the peer to the regular slots
+        choker.set_seed_optimistic(SeedOptimistic::Off);
+        choker.upload(true);
+        assert!(choker.optimistic_unchoke.is_none());
+        assert_eq!(choker.len(), 3);
+        assert!("least-complete".parse::<SeedOptimistic>().is_ok());
+        assert!("fastest".parse::<SeedOptimistic>().is_err());
+    }
+
+    #[test]
+    fn test_upload_quota() {
+        let reputation = Arc::new(Mutex::new(Reputation::default()));
+        let mut choker = Choke::new();
+        choker.set_upload_quota(Some(10));
+        let (optimistic, _optimistic_peer) = connection("optimistic", &reputation);
+        choker.optimistic_unchoke = Some(optimistic);
+        let (conn, _peer) = connection("downloader", &reputation);
+        conn.state.write().unwrap().peer_interested = true;
+        choker.add(conn);
+
+        // Waits for the sender to act on the choke decisions, then checks them against the
+        // peers' actual state
+        let unchoked = |choker: &Choke, expected: bool| {
+            let start = std::time::Instant::now();
+            for c in choker
+                .connections
+                .iter()
+                .chain(choker.optimistic_unchoke.iter())
+            {
+                assert_eq!(c.snapshot.choke_reason.unwrap().is_unchoked(), expected);
+                while c.state.read().unwrap().client_choked == expected {
+                    assert!(
+                        start.elapsed() < Duration::from_secs(5),
+                        "{} not choked",
+                        c.id
+                    );
+                    std::thread::sleep(Duration::from_millis(10));
                 }
-            // Matches anything that shouldnt be choked, but is
-            } else if !conn.snapshot.state.client_choked {
-                let _ = conn.choke(true);
             }
+        };
+        choker.upload(false);
+        unchoked(&choker, true);
+
+        choker.uploaded_bytes = 10;
+        assert!(choker.upload_quota_reached());
+        assert!(!choker.download_quota_reached());
+        // Snapshots taken in upload see the unchoked state
+        choker.upload(false);
+        unchoked(&choker, false);
+    }
+
+    #[test]
+    fn test_concurrency() {
+        let reputation = Arc::new(Mutex::new(Reputation::default()));
+        let mut choker = Choke::new();
+        let (first, _first_peer) = connection("first", &reputation);
+        let (second, _second_peer) = connection("second", &reputation);
+        first.pending.lock().unwrap().extend(0..2);
+        second.pending.lock().unwrap().extend(1..4);
+        choker.add(first);
+        choker.add(second);
+
+        assert_eq!(choker.concurrency(), Concurrency::default());
+        choker.download(false);
+        assert_eq!(choker.concurrency().outstanding, 5);
+    }
+
+    #[test]
+    fn test_interested_timeout() {
+        let reputation = Arc::new(Mutex::new(Reputation::default()));
+        let mut choker = Choke::new();
+        choker.set_interested_timeout(Some(Duration::from_millis(50)));
+        let (optimistic, _optimistic_peer) = connection("optimistic", &reputation);
+        choker.optimistic_unchoke = Some(optimistic);
+        let (stalled, _stalled_peer) = connection("stalled", &reputation);
+        let (idle, _idle_peer) = connection("idle", &reputation);
+        stalled.state.write().unwrap().client_interested = true;
+        choker.add(stalled);
+        choker.add(idle);
+
+        choker.download(false);
+        assert_eq!(choker.connections.len(), 2);
+        std::thread::sleep(Duration::from_millis(100));
+        choker.download(false);
+        let ids: Vec<_> = choker.connections.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, vec!["idle"]);
+    }
+
+    #[test]
+    fn test_no_upload() -> Result<(), failure::Error> {
+        let reputation = Arc::new(Mutex::new(Reputation::default()));
+        let mut choker = Choke::new();
+        choker.set_upload(false);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (mut peer, _) = listener.accept()?;
+        let conn = Connection::from_handshaked(
+            stream,
+            conn_info("peer", &reputation),
+            Arc::new("peer".to_owned()),
+        )?;
+        choker.add(conn);
+
+        Message::BitField(bitvec::bitvec![1; 8]).send(&mut peer)?;
+        Message::Unchoke.send(&mut peer)?;
+        Message::Interested.send(&mut peer)?;
+        for _ in 0..3 {
+            std::thread::sleep(Duration::from_millis(50));
+            choker.download(true);
         }
+        assert_eq!(
+            choker
+                .optimistic_unchoke
+                .as_ref()
+                .unwrap()
+                .snapshot
+                .choke_reason,
+            Some(ChokeReason::NoUpload)
+        );
+
+        peer.set_read_timeout(Some(Duration::from_millis(500)))?;
+        let mut received = Vec::new();
+        while let Ok(msg) = Message::recv(&mut peer) {
+            received.push(msg);
+        }
+        // Still downloading, but never uploading
+        assert!(received.contains(&Message::Interested));
+        assert!(received.iter().any(|m| match m {
+            Message::Request(..) => true,
+            _ => false,
+        }));
+        assert!(!received.iter().any(|m| match m {
+            Message::Unchoke | Message::Piece(..) => true,
+            _ => false,
+        }));
+        Ok(())
+    }
 
-        match &self.optimistic_unchoke {
-            Some(c) if c.snapshot.state.client_choked => {
-                let _ = c.choke(false);
+    #[test]
+    fn test_reciprocation_order() -> Result<(), failure::Error> {
+        let reputation = Arc::new(Mutex::new(Reputation::default()));
+        let mut choker = Choke::new();
+        let (optimistic, _optimistic_peer) = connection("optimistic", &reputation);
+        choker.optimistic_unchoke = Some(optimistic);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut peers = Vec::new();
+        // One more peer than there are downloader slots, and the one we already upload to sent
+        // the least
+        for &(id, sent, unchoked) in [
+            ("reciprocating", 90, true),
+            ("a", 100, false),
+            ("b", 100, false),
+            ("c", 100, false),
+            ("d", 100, false),
+        ]
+        .iter()
+        {
+            let stream = TcpStream::connect(listener.local_addr()?)?;
+            let (mut peer, _) = listener.accept()?;
+            peer.set_read_timeout(Some(Duration::from_secs(5)))?;
+            let conn = Connection::from_handshaked(
+                stream,
+                conn_info(id, &reputation),
+                Arc::new(id.to_owned()),
+            )?;
+            if unchoked {
+                conn.choke(false)?;
+                while Message::recv(&mut peer)? != Message::Unchoke {}
+            }
+            // Unsolicited blocks are dropped, but still count as received
+            Message::Unchoke.send(&mut peer)?;
+            Message::Piece(0, 0, Arc::new(vec![0; sent])).send(&mut peer)?;
+            Message::Interested.send(&mut peer)?;
+            while !conn.state.read().unwrap().peer_interested {
+                std::thread::sleep(Duration::from_millis(10));
             }
-            _ => {}
+            choker.add(conn);
+            peers.push(peer);
         }
+
+        choker.download(false);
+        assert_eq!(choker.connections[0].id.as_str(), "reciprocating");
+        let mut reasons: Vec<_> = choker
+            .connections
+            .iter()
+            .map(|c| c.snapshot.choke_reason)
+            .collect();
+        assert_eq!(reasons[0], Some(ChokeReason::Downloader));
+        reasons.sort_by_key(|r| format!("{:?}", r));
+        assert_eq!(
+            reasons,
+            vec![
+                Some(ChokeReason::BelowThreshold),
+                Some(ChokeReason::Downloader),
+                Some(ChokeReason::Downloader),
+                Some(ChokeReason::Downloader),
+                Some(ChokeReason::Downloader),
+            ]
+        );
+        Ok(())
     }
 }
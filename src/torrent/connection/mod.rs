@@ -1,18 +1,31 @@
 mod receiver;
 mod sender;
+mod tap;
 
+use crate::bitset;
+use crate::choking::ChokeReason;
 use crate::metainfo::Metainfo;
+use crate::peer::{self, Message, MessageKind};
+use crate::reputation::Reputation;
 use crate::storage::PieceStore;
+use crate::verify::Verifier;
 use bitvec::{bitvec, BitVec};
-use receiver::Receiver;
+use log::warn;
+use receiver::{LogSampler, Receiver};
 use sender::Sender;
+use socket2::{Domain, SockAddr, Socket, Type};
 use std::collections::HashSet;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt;
 use std::io::{self, BufReader, BufWriter};
-use std::net::{TcpStream, ToSocketAddrs};
-use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
+use tap::Tap;
 
 #[derive(Clone)]
 pub struct State {
@@ -20,14 +33,25 @@ pub struct State {
     pub client_interested: bool,
     pub peer_choked: bool,
     pub peer_interested: bool,
+    // Port of the peer's DHT node, from its Port message
+    pub peer_dht_port: Option<u16>,
+    // The peer stopped delivering the blocks we requested, so nothing more is requested from it
+    pub snubbing: bool,
+    // The peer has every piece, from its bitfield or its have messages
+    pub peer_seed: bool,
 }
 
 impl fmt::Debug for State {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "client(c: {}, i: {}) peer(c:{}, i:{})",
-            self.client_choked, self.client_interested, self.peer_choked, self.peer_interested
+            "client(c: {}, i: {}) peer(c:{}, i:{}{}{})",
+            self.client_choked,
+            self.client_interested,
+            self.peer_choked,
+            self.peer_interested,
+            if self.snubbing { ", snubbing" } else { "" },
+            if self.peer_seed { ", seed" } else { "" }
         )
     }
 }
@@ -39,6 +63,9 @@ impl Default for State {
             client_interested: false,
             peer_choked: true,
             peer_interested: false,
+            peer_dht_port: None,
+            snubbing: false,
+            peer_seed: false,
         }
     }
 }
@@ -47,6 +74,35 @@ impl Default for State {
 struct Metrics {
     pub downloaded: Arc<Mutex<u64>>,
     pub uploaded: Arc<Mutex<u64>>,
+    pub starved: Arc<Mutex<u64>>,
+    pub downloaded_bytes: Arc<Mutex<u64>>,
+    pub uploaded_bytes: Arc<Mutex<u64>>,
+}
+
+// Resets a counter, returning what it had reached
+fn take(counter: &Mutex<u64>) -> u64 {
+    std::mem::replace(&mut *counter.lock().unwrap(), 0)
+}
+
+// Pieces and piece payload transferred over the life of a connection, or since its stats were
+// last reset
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Totals {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub downloaded_bytes: u64,
+    pub uploaded_bytes: u64,
+}
+
+impl Totals {
+    fn since(&self, base: &Totals) -> Totals {
+        Totals {
+            downloaded: self.downloaded - base.downloaded,
+            uploaded: self.uploaded - base.uploaded,
+            downloaded_bytes: self.downloaded_bytes - base.downloaded_bytes,
+            uploaded_bytes: self.uploaded_bytes - base.uploaded_bytes,
+        }
+    }
 }
 
 #[derive(Default, Debug)]
@@ -55,6 +111,70 @@ pub struct Snapshot {
     pub uploaded: u64,
     pub availability: BitVec,
     pub state: State,
+    // When we last became interested in the peer while it was choking us
+    pub choked_since: Option<Instant>,
+    // Why the choker last (un)choked the peer
+    pub choke_reason: Option<ChokeReason>,
+    // Pieces requested from the peer and not yet received
+    pub pending: usize,
+    // Request rounds that found nothing to request while the peer was unchoking us
+    pub starved: u64,
+    // Piece payload received from and sent to the peer since the last snapshot
+    pub downloaded_bytes: u64,
+    pub uploaded_bytes: u64,
+    // Pieces the peer has that we do not, out of the pieces we still need
+    pub needed: u32,
+    pub left: u32,
+}
+
+impl Snapshot {
+    // Fraction of pieces the peer has, as of the last snapshot
+    pub fn completion(&self) -> f32 {
+        completion(&self.availability)
+    }
+
+    // How long we have been interested but choked, as of the last snapshot
+    pub fn unproductive_for(&self) -> Duration {
+        self.choked_since
+            .map(|t| t.elapsed())
+            .unwrap_or(Duration::from_secs(0))
+    }
+}
+
+// Read-only view of a connection as of its last snapshot, so status displays do not lock the
+// live state shared with the connection threads
+pub struct PeerView<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> PeerView<'a> {
+    pub fn id(&self) -> &'a str {
+        self.conn.id.as_str()
+    }
+
+    // Choking and interest on both sides, as of the last update_snapshot
+    pub fn state(&self) -> &'a State {
+        &self.conn.snapshot.state
+    }
+
+    pub fn choke_reason(&self) -> Option<ChokeReason> {
+        self.conn.snapshot.choke_reason
+    }
+
+    pub fn snapshot(&self) -> &'a Snapshot {
+        &self.conn.snapshot
+    }
+
+    pub fn totals(&self) -> Totals {
+        self.conn.totals()
+    }
+}
+
+fn completion(availability: &BitVec) -> f32 {
+    if availability.len() == 0 {
+        return 0.0;
+    }
+    bitset::count_ones(availability) as f32 / availability.len() as f32
 }
 
 #[derive(Debug)]
@@ -76,8 +196,275 @@ pub enum Command {
     PeerChoke(bool),
     // Triggered by receiver when piece requested
     SendChunk(u32, u32, u32),
+    // Triggered by receiver when the peer's handshake has the DHT bit set
+    PeerDht,
+    // Triggered by piece store when stored pieces failed verification and are needed again
+    Needed,
+    // Triggered by client when the download completes
+    BecomeSeed,
+    // Triggered by receiver once the peer has every piece
+    PeerBecameSeed,
+    // Triggered by send_raw, for messages the core loop does not send itself
+    SendMessage(Message),
+    // Triggered by piece store when the selector changes, so requests in flight are chosen again
+    Reprioritize,
+}
+
+// Pieces each peer may download while we are choking it
+const ALLOWED_FAST: usize = 5;
+
+// Share of the bytes received from a peer we are uploading to that is credited again, since a
+// peer that gets data back from us is unlikely to choke us
+const RECIPROCATION_SHARE: u64 = 4;
+
+// Extra credit, in bytes, for data received from a peer, used both to rank downloaders and to size
+// request rounds
+pub fn reciprocation_bonus(downloaded_bytes: u64, reciprocating: bool) -> u64 {
+    match reciprocating {
+        true => downloaded_bytes / RECIPROCATION_SHARE,
+        false => 0,
+    }
+}
+
+// Number of protocol problems kept per connection, older ones are dropped first
+const PROTOCOL_LOG_LEN: usize = 16;
+
+// Non-fatal protocol problems of a peer that were recovered from, kept for diagnosis
+#[derive(Debug, Default)]
+pub struct ProtocolLog {
+    entries: Mutex<VecDeque<String>>,
+}
+
+impl ProtocolLog {
+    fn record(&self, id: &str, entry: String) {
+        warn!("{}: {}", id, entry);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == PROTOCOL_LOG_LEN {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    // Oldest first
+    pub fn drain(&self) -> Vec<String> {
+        self.entries.lock().unwrap().drain(..).collect()
+    }
+}
+
+// Messages of each kind exchanged with a peer, for debugging interoperability problems
+#[derive(Debug, Default)]
+pub struct MessageCounts {
+    sent: Mutex<BTreeMap<MessageKind, u64>>,
+    received: Mutex<BTreeMap<MessageKind, u64>>,
+}
+
+impl MessageCounts {
+    fn record_sent(&self, kind: MessageKind) {
+        *self.sent.lock().unwrap().entry(kind).or_insert(0) += 1;
+    }
+
+    fn record_received(&self, kind: MessageKind) {
+        *self.received.lock().unwrap().entry(kind).or_insert(0) += 1;
+    }
+
+    pub fn sent(&self) -> BTreeMap<MessageKind, u64> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    pub fn received(&self) -> BTreeMap<MessageKind, u64> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+// Bytes held in partially received pieces across all connections
+#[derive(Debug)]
+pub struct BufferAccount {
+    used: AtomicUsize,
+    limit: AtomicUsize,
+}
+
+impl BufferAccount {
+    pub fn new(limit: usize) -> Self {
+        BufferAccount {
+            used: AtomicUsize::new(0),
+            limit: AtomicUsize::new(limit),
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::SeqCst)
+    }
+
+    // Lowering the limit does not evict buffered data, it only blocks new reservations
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::SeqCst);
+    }
+
+    // Returns false without reserving anything if the limit would be exceeded
+    fn reserve(&self, n: usize) -> bool {
+        let mut used = self.used();
+        loop {
+            if used.saturating_add(n) > self.limit() {
+                return false;
+            }
+            match self
+                .used
+                .compare_exchange(used, used + n, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return true,
+                Err(x) => used = x,
+            }
+        }
+    }
+
+    fn release(&self, n: usize) {
+        self.used.fetch_sub(n, Ordering::SeqCst);
+    }
 }
 
+// Like TcpStream::connect, optionally binding the socket to a local address first and giving up
+// on each address after the timeout
+fn connect_from<A: ToSocketAddrs>(
+    addr: A,
+    local: Option<IpAddr>,
+    timeout: Option<Duration>,
+) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to");
+    for addr in addr.to_socket_addrs()? {
+        let domain = match addr {
+            SocketAddr::V4(_) => Domain::ipv4(),
+            SocketAddr::V6(_) => Domain::ipv6(),
+        };
+        let res = Socket::new(domain, Type::stream(), None).and_then(|s| {
+            if let Some(local) = local {
+                s.bind(&SockAddr::from(SocketAddr::new(local, 0)))?;
+            }
+            match timeout {
+                Some(t) => s.connect_timeout(&SockAddr::from(addr), t)?,
+                None => s.connect(&SockAddr::from(addr))?,
+            }
+            Ok(s.into_tcp_stream())
+        });
+        match res {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+// Buffered halves of the stream, sized from the connection info
+fn buffered(stream: &TcpStream, ci: &ConnInfo) -> io::Result<(BufReader<Tap>, BufWriter<Tap>)> {
+    let tap = Tap::new(
+        stream.try_clone()?,
+        ci.wire_dir.as_ref().map(|d| d.as_path()),
+        &ci.id,
+    )?;
+    let reader = match ci.reader_buffer_len {
+        None => BufReader::new(tap.try_clone()?),
+        Some(x) => BufReader::with_capacity(x, tap.try_clone()?),
+    };
+
+    let writer = match ci.writer_buffer_len {
+        None => BufWriter::new(tap),
+        Some(x) => BufWriter::with_capacity(x, tap),
+    };
+    Ok((reader, writer))
+}
+
+impl Default for BufferAccount {
+    fn default() -> Self {
+        BufferAccount::new(usize::max_value())
+    }
+}
+
+// Pieces being written to peers across all connections, so that many unchoked peers do not all
+// send at once
+#[derive(Debug)]
+pub struct UploadSlots {
+    in_flight: Mutex<usize>,
+    freed: Condvar,
+    limit: usize,
+}
+
+impl UploadSlots {
+    pub fn new(limit: usize) -> Self {
+        assert!(limit > 0, "UploadSlots needs at least one slot");
+        UploadSlots {
+            in_flight: Mutex::new(0),
+            freed: Condvar::new(),
+            limit,
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        *self.in_flight.lock().unwrap()
+    }
+
+    // Waits up to timeout for a free slot, returning false if none was taken
+    fn acquire(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while *in_flight >= self.limit {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            in_flight = self
+                .freed
+                .wait_timeout(in_flight, deadline - now)
+                .unwrap()
+                .0;
+        }
+        *in_flight += 1;
+        true
+    }
+
+    fn release(&self) {
+        *self.in_flight.lock().unwrap() -= 1;
+        self.freed.notify_one();
+    }
+}
+
+impl Default for UploadSlots {
+    fn default() -> Self {
+        UploadSlots::new(usize::max_value())
+    }
+}
+
+// What to do when a peer deviates from the protocol in a way we can recover from, such as a
+// second bitfield or an index past the last piece
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolStrictness {
+    // Close the connection
+    Strict,
+    // Record the deviation in the protocol log and ignore the message
+    Permissive,
+}
+
+impl Default for ProtocolStrictness {
+    fn default() -> Self {
+        ProtocolStrictness::Strict
+    }
+}
+
+impl FromStr for ProtocolStrictness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(ProtocolStrictness::Strict),
+            "permissive" => Ok(ProtocolStrictness::Permissive),
+            _ => Err(format!("{} is not a valid protocol strictness", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct ConnInfo {
     pub store: Arc<RwLock<PieceStore>>,
     pub metainfo: Arc<Metainfo>,
@@ -85,17 +472,61 @@ pub struct ConnInfo {
     pub writer_buffer_len: Option<usize>,
     pub id: Arc<String>,
     pub client_id: Arc<String>,
+    pub strictness: ProtocolStrictness,
+    pub buffers: Arc<BufferAccount>,
+    pub uploads: Arc<UploadSlots>,
+    // Queued pieces sent to a peer before flushing and checking for commands again
+    pub upload_batch: usize,
+    pub reputation: Arc<Mutex<Reputation>>,
+    // Local address outbound connections are made from
+    pub bind_addr: Option<IpAddr>,
+    // Port of our DHT node, advertised to peers that support the DHT
+    pub dht_port: Option<u16>,
+    // Shared pool hashing completed pieces
+    pub verifier: Arc<Verifier>,
+    // How long a peer may leave our requests unanswered before we stop requesting from it
+    pub snub_timeout: Option<Duration>,
+    // A peer is disconnected once it has sent this many pieces that failed verification
+    pub max_bad_pieces: u32,
+    // Applied to each address of an outbound connection
+    pub connect_timeout: Option<Duration>,
+    // A peer that has not sent its handshake this long after the connection started is dropped
+    pub handshake_timeout: Option<Duration>,
+    // Inbound handshakes in progress, decremented once this connection's handshake is over
+    pub handshake_slots: Option<Arc<AtomicUsize>>,
+    // A peer that stops reading for this long is disconnected, rather than blocking the sender
+    pub write_timeout: Option<Duration>,
+    // Raw bytes sent to and received from the peer are appended to files in this directory
+    pub wire_dir: Option<PathBuf>,
+    // Most pieces advertised in the initial bitfield, the rest are revealed gradually
+    pub bitfield_cap: Option<usize>,
 }
 
 pub struct Connection {
     pub tx: mpsc::Sender<Command>,
     receiver_handle: thread::JoinHandle<()>,
     sender_handle: thread::JoinHandle<()>,
-    availability: Arc<Mutex<BitVec>>,
+    pub(crate) availability: Arc<Mutex<BitVec>>,
+    pub(crate) pending: Arc<Mutex<HashSet<u32>>>,
+    // Cleared when either the sender or receiver thread exits
+    alive: Arc<AtomicBool>,
+    // Set by the choker, allowed fast pieces are only served while uploading is allowed
+    upload_allowed: Arc<AtomicBool>,
     pub state: Arc<RwLock<State>>,
     metrics: Metrics,
+    store: Arc<RwLock<PieceStore>>,
     pub snapshot: Snapshot,
+    // Deltas already taken by snapshots
+    totals: Totals,
+    // Lifetime totals when the stats were last reset, and when that was
+    stats_base: Totals,
+    stats_reset: Instant,
+    stats_reset_interval: Option<Duration>,
     pub id: Arc<String>,
+    // Reputation is kept per address, the port changes between connections
+    pub ip: IpAddr,
+    protocol_log: Arc<ProtocolLog>,
+    message_counts: Arc<MessageCounts>,
 }
 
 impl fmt::Debug for Connection {
@@ -106,44 +537,103 @@ impl fmt::Debug for Connection {
 
 impl Connection {
     pub fn connect<A: ToSocketAddrs>(addr: A, ci: ConnInfo) -> Result<Self, io::Error> {
-        let stream = TcpStream::connect(addr)?;
+        let stream = connect_from(addr, ci.bind_addr, ci.connect_timeout)?;
         Connection::new(stream, ci)
     }
 
     pub fn new(stream: TcpStream, ci: ConnInfo) -> Result<Self, io::Error> {
-        let (tx, rx) = mpsc::channel();
-        let reader = match ci.reader_buffer_len {
-            None => BufReader::new(stream.try_clone()?),
-            Some(x) => BufReader::with_capacity(x, stream.try_clone()?),
-        };
+        Connection::start(stream, ci, false)
+    }
 
-        let writer = match ci.writer_buffer_len {
-            None => BufWriter::new(stream.try_clone()?),
-            Some(x) => BufWriter::with_capacity(x, stream.try_clone()?),
-        };
+    // The handshake has already been exchanged on the stream, so both threads go straight to
+    // the message loop and the given peer id is trusted
+    pub fn from_handshaked(
+        stream: TcpStream,
+        mut ci: ConnInfo,
+        peer_id: Arc<String>,
+    ) -> Result<Self, io::Error> {
+        ci.id = peer_id;
+        Connection::start(stream, ci, true)
+    }
+
+    fn start(stream: TcpStream, ci: ConnInfo, handshaked: bool) -> Result<Self, io::Error> {
+        // The sender already coalesces messages, flushing once per loop iteration, so Nagle's
+        // algorithm would only delay requests behind unacknowledged pieces
+        stream.set_nodelay(true)?;
+        stream.set_write_timeout(ci.write_timeout)?;
+        let (tx, rx) = mpsc::channel();
+        let (reader, writer) = buffered(&stream, &ci)?;
 
         let state = Arc::new(RwLock::new(State::default()));
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+        let alive = Arc::new(AtomicBool::new(true));
+        let upload_allowed = Arc::new(AtomicBool::new(false));
+        let num_downloaded = Arc::new(Mutex::new(0));
+        let downloaded_bytes = Arc::new(Mutex::new(0));
         let availability = Arc::new(Mutex::new(bitvec![0; ci.metainfo.num_pieces() as usize]));
+        let protocol_log = Arc::new(ProtocolLog::default());
+        let message_counts = Arc::new(MessageCounts::default());
+        let last_delivery = Arc::new(Mutex::new(Instant::now()));
+        let peer_addr = stream.peer_addr()?;
+        // The set is only defined for IPv4 peers
+        let allowed_fast: Arc<HashSet<u32>> = Arc::new(match peer_addr {
+            SocketAddr::V4(addr) => peer::allowed_fast(
+                *addr.ip(),
+                &ci.metainfo.info_hash().unwrap(),
+                ci.metainfo.num_pieces(),
+                ALLOWED_FAST,
+            )
+            .into_iter()
+            .collect(),
+            SocketAddr::V6(_) => HashSet::new(),
+        });
 
         let receiver = Receiver {
             tx: tx.clone(),
             piece_buffer: HashMap::new(),
+            pending: pending.clone(),
             state: state.clone(),
             store: ci.store.clone(),
             speed: Mutex::new(None),
             availability: availability.clone(),
             reader,
             peer_id: ci.id.clone(),
+            peer_ip: peer_addr.ip(),
             client_id: ci.client_id.clone(),
             metainfo: ci.metainfo.clone(),
             bitfield_received: false,
-            num_downloaded: Arc::new(Mutex::new(0)),
+            num_downloaded: num_downloaded.clone(),
+            alive: alive.clone(),
+            handshaked,
+            buffers: ci.buffers.clone(),
+            buffered: 0,
+            reputation: ci.reputation.clone(),
+            verifier: ci.verifier.clone(),
+            protocol_log: protocol_log.clone(),
+            downloaded_bytes: downloaded_bytes.clone(),
+            last_delivery: last_delivery.clone(),
+            message_counts: message_counts.clone(),
+            log_sampler: LogSampler::default(),
+            allowed_fast: allowed_fast.clone(),
+            peer_fast: false,
+            upload_allowed: upload_allowed.clone(),
+            bad_pieces: Arc::new(Mutex::new(0)),
+            max_bad_pieces: ci.max_bad_pieces,
+            strictness: ci.strictness,
+            handshake_timeout: ci.handshake_timeout,
+            handshake_slots: ci.handshake_slots.clone(),
+            duplicate_haves: 0,
         };
 
+        // Registered before the sender starts so that its initial bitfield and the haves that
+        // follow it cover every piece exactly once
+        let bitfield = ci.store.read().unwrap().register(tx.clone());
+
         let sender = Sender {
             rx,
+            bitfield,
             requests: VecDeque::new(),
-            pending: HashSet::new(),
+            pending: pending.clone(),
             pieces: VecDeque::new(),
             state: state.clone(),
             store: ci.store.clone(),
@@ -153,58 +643,186 @@ impl Connection {
             client_id: ci.client_id.clone(),
             writer,
             num_uploaded: Arc::new(Mutex::new(0)),
+            num_downloaded,
+            downloaded_bytes,
+            num_starved: Arc::new(Mutex::new(0)),
+            uploaded_bytes: Arc::new(Mutex::new(0)),
+            alive: alive.clone(),
+            handshaked,
+            dht_port: ci.dht_port,
+            protocol_log: protocol_log.clone(),
+            last_delivery,
+            snub_timeout: ci.snub_timeout,
+            message_counts: message_counts.clone(),
+            allowed_fast,
+            uploads: ci.uploads.clone(),
+            upload_batch: ci.upload_batch,
+            strictness: ci.strictness,
+            exhausted: None,
+            bitfield_cap: ci.bitfield_cap,
+            hidden: VecDeque::new(),
+            last_reveal: Instant::now(),
         };
 
         let metrics = Metrics {
             downloaded: receiver.num_downloaded.clone(),
             uploaded: sender.num_uploaded.clone(),
+            starved: sender.num_starved.clone(),
+            downloaded_bytes: receiver.downloaded_bytes.clone(),
+            uploaded_bytes: sender.uploaded_bytes.clone(),
         };
 
         let receiver_handle = thread::spawn(move || receiver.start());
         let sender_handle = thread::spawn(move || sender.start());
 
-        // Register with store
-        ci.store.read().unwrap().register(tx.clone());
-
         Ok(Connection {
             tx,
             receiver_handle,
             sender_handle,
             availability: availability.clone(),
+            pending,
+            alive,
+            upload_allowed,
             state: state.clone(),
             metrics,
+            store: ci.store.clone(),
             snapshot: Default::default(),
+            totals: Totals::default(),
+            stats_base: Totals::default(),
+            stats_reset: Instant::now(),
+            stats_reset_interval: None,
             id: ci.id,
+            ip: peer_addr.ip(),
+            protocol_log,
+            message_counts,
         })
     }
 
+    // A lock poisoned by a panicked connection thread marks the connection as dead instead of
+    // propagating the panic, leaving the previous snapshot in place
     pub fn update_snapshot(&mut self) {
-        self.snapshot.availability = { self.availability.lock().unwrap().clone() };
-        self.snapshot.state = { self.state.read().unwrap().clone() };
-        self.snapshot.downloaded = {
-            let mut x = self.metrics.downloaded.lock().unwrap();
-            let y = *x;
-            *x = 0;
-            y
-        };
-        self.snapshot.uploaded = {
-            let mut x = self.metrics.uploaded.lock().unwrap();
-            let y = *x;
-            *x = 0;
-            y
+        match (self.availability.lock(), self.state.read()) {
+            (Ok(availability), Ok(state)) => {
+                self.snapshot.availability = availability.clone();
+                self.snapshot.state = state.clone();
+            }
+            _ => {
+                warn!("{}: Connection thread panicked", self.id);
+                self.alive.store(false, Ordering::SeqCst);
+                return;
+            }
+        }
+        let stalled = self.snapshot.state.client_interested && self.snapshot.state.peer_choked;
+        self.snapshot.choked_since = match self.snapshot.choked_since {
+            Some(t) if stalled => Some(t),
+            _ if stalled => Some(Instant::now()),
+            _ => None,
         };
+        self.snapshot.downloaded = take(&self.metrics.downloaded);
+        self.snapshot.uploaded = take(&self.metrics.uploaded);
+        self.snapshot.starved = take(&self.metrics.starved);
+        self.snapshot.downloaded_bytes = take(&self.metrics.downloaded_bytes);
+        self.snapshot.uploaded_bytes = take(&self.metrics.uploaded_bytes);
+        self.snapshot.pending = self.pending.lock().unwrap().len();
+        {
+            let store = self.store.read().unwrap();
+            let have = store.as_bitvec(false);
+            self.snapshot.needed = self
+                .snapshot
+                .availability
+                .iter()
+                .zip(have.iter())
+                .filter(|(available, have)| *available && !*have)
+                .count() as u32;
+            self.snapshot.left = store.left;
+        }
+        // The deltas taken above are kept in the totals, which are never reset
+        self.totals.downloaded += self.snapshot.downloaded;
+        self.totals.uploaded += self.snapshot.uploaded;
+        self.totals.downloaded_bytes += self.snapshot.downloaded_bytes;
+        self.totals.uploaded_bytes += self.snapshot.uploaded_bytes;
+        if let Some(interval) = self.stats_reset_interval {
+            if self.stats_reset.elapsed() >= interval {
+                self.reset_stats();
+            }
+        }
+    }
+
+    // Counters since the connection started or its stats were last reset, including what the
+    // next snapshot will take. Reading them leaves the per-snapshot deltas used by the choker
+    // untouched.
+    pub fn totals(&self) -> Totals {
+        self.lifetime_totals().since(&self.stats_base)
+    }
+
+    fn lifetime_totals(&self) -> Totals {
+        let current = |counter: &Arc<Mutex<u64>>| *counter.lock().unwrap();
+        Totals {
+            downloaded: self.totals.downloaded + current(&self.metrics.downloaded),
+            uploaded: self.totals.uploaded + current(&self.metrics.uploaded),
+            downloaded_bytes: self.totals.downloaded_bytes
+                + current(&self.metrics.downloaded_bytes),
+            uploaded_bytes: self.totals.uploaded_bytes + current(&self.metrics.uploaded_bytes),
+        }
+    }
+
+    // Zeroes the totals, e.g. to measure the peer afresh after a choke cycle. The deltas the
+    // next snapshot takes for the choker are unaffected.
+    pub fn reset_stats(&mut self) {
+        self.stats_base = self.lifetime_totals();
+        self.stats_reset = Instant::now();
+    }
+
+    // Reset the stats automatically on the first snapshot after each interval
+    pub fn set_stats_reset_interval(&mut self, interval: Option<Duration>) {
+        self.stats_reset_interval = interval;
+        self.stats_reset = Instant::now();
+    }
+
+    pub fn completion(&self) -> f32 {
+        completion(&self.availability.lock().unwrap())
+    }
+
+    pub fn view(&self) -> PeerView<'_> {
+        PeerView { conn: self }
     }
 
     pub fn is_shutdown(&self) -> bool {
+        if !self.alive.load(Ordering::SeqCst) {
+            return true;
+        }
         if let Err(_) = self.tx.send(Command::Ping) {
             return true;
         }
         false
     }
 
+    // Protocol problems recorded since the last call
+    pub fn drain_errors(&self) -> Vec<String> {
+        self.protocol_log.drain()
+    }
+
+    pub fn message_counts(&self) -> &MessageCounts {
+        &self.message_counts
+    }
+
     pub fn choke(&self, choke: bool) -> Result<(), mpsc::SendError<Command>> {
         self.tx.send(Command::Choke(choke))
     }
+
+    pub fn set_upload_allowed(&self, allowed: bool) {
+        self.upload_allowed.store(allowed, Ordering::SeqCst);
+    }
+
+    pub fn become_seed(&self) -> Result<(), mpsc::SendError<Command>> {
+        self.tx.send(Command::BecomeSeed)
+    }
+
+    // Queue a message for the peer, e.g. to experiment with extensions. Messages that would
+    // break the protocol or the connection state are dropped by the sender.
+    pub fn send_raw(&self, msg: Message) -> Result<(), mpsc::SendError<Command>> {
+        self.tx.send(Command::SendMessage(msg))
+    }
 }
 
 impl Drop for Connection {
@@ -212,3 +830,622 @@ impl Drop for Connection {
         let _ = self.tx.send(Command::Shutdown);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash;
+    use crate::peer::{block_layout, Handshake, Message, MessageKind, Reserved, BLOCK_SIZE};
+    use crate::selection::Inorder;
+    use crate::storage::Sink;
+    use matches::matches;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::time;
+
+    fn conn_info(metainfo: Metainfo) -> ConnInfo {
+        let store = PieceStore::new(&metainfo, Box::new(Inorder::default()));
+        let metainfo = Arc::new(metainfo);
+        ConnInfo {
+            store: Arc::new(RwLock::new(store)),
+            verifier: Arc::new(Verifier::new(metainfo.clone(), 1)),
+            metainfo,
+            reader_buffer_len: None,
+            writer_buffer_len: None,
+            id: Arc::new("peer".to_owned()),
+            client_id: Arc::new("client".to_owned()),
+            strictness: ProtocolStrictness::default(),
+            buffers: Arc::new(BufferAccount::default()),
+            uploads: Arc::new(UploadSlots::default()),
+            upload_batch: 1,
+            reputation: Arc::new(Mutex::new(Reputation::default())),
+            bind_addr: None,
+            dht_port: None,
+            snub_timeout: None,
+            max_bad_pieces: 1,
+            connect_timeout: None,
+            handshake_timeout: None,
+            handshake_slots: None,
+            write_timeout: None,
+            wire_dir: None,
+            bitfield_cap: None,
+        }
+    }
+
+    // Returns the connection and the remote end of its stream
+    fn connection(metainfo: Metainfo) -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (remote, _) = listener.accept().unwrap();
+        (
+            Connection::new(stream, conn_info(metainfo)).unwrap(),
+            remote,
+        )
+    }
+
+    #[test]
+    fn test_liveness() {
+        let (conn, _remote) = connection(Metainfo::mock(4, 8));
+        assert!(!conn.is_shutdown());
+        // Only the sender handles commands, so this leaves the receiver running
+        conn.tx.send(Command::Shutdown).unwrap();
+        let start = time::Instant::now();
+        while conn.alive.load(Ordering::SeqCst) {
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert!(conn.is_shutdown());
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        let mut ci = conn_info(Metainfo::mock(4, 8));
+        ci.handshake_timeout = Some(time::Duration::from_millis(100));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        // Accepted but never handshakes
+        let (_remote, _) = listener.accept().unwrap();
+        let conn = Connection::new(stream, ci).unwrap();
+        let start = time::Instant::now();
+        while !conn.is_shutdown() {
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_totals() {
+        let (mut conn, _remote) = connection(Metainfo::mock(4, 8));
+        *conn.metrics.downloaded.lock().unwrap() += 2;
+        *conn.metrics.uploaded_bytes.lock().unwrap() += 100;
+        // Reading the totals does not take the choker's deltas
+        assert_eq!(conn.totals().downloaded, 2);
+        conn.update_snapshot();
+        assert_eq!(conn.snapshot.downloaded, 2);
+        assert_eq!(conn.snapshot.uploaded_bytes, 100);
+
+        *conn.metrics.downloaded.lock().unwrap() += 3;
+        assert_eq!(conn.totals().downloaded, 5);
+        conn.update_snapshot();
+        assert_eq!(conn.snapshot.downloaded, 3);
+        assert_eq!(conn.snapshot.uploaded_bytes, 0);
+        assert_eq!(
+            conn.totals(),
+            Totals {
+                downloaded: 5,
+                uploaded: 0,
+                downloaded_bytes: 0,
+                uploaded_bytes: 100,
+            }
+        );
+    }
+
+    #[test]
+    fn test_reset_stats() {
+        let (mut conn, _remote) = connection(Metainfo::mock(4, 8));
+        *conn.metrics.downloaded.lock().unwrap() += 2;
+        conn.update_snapshot();
+        *conn.metrics.downloaded.lock().unwrap() += 3;
+        *conn.metrics.uploaded_bytes.lock().unwrap() += 100;
+        conn.reset_stats();
+        assert_eq!(conn.totals(), Totals::default());
+
+        // The choker still sees everything since its last snapshot
+        *conn.metrics.downloaded.lock().unwrap() += 1;
+        conn.update_snapshot();
+        assert_eq!(conn.snapshot.downloaded, 4);
+        assert_eq!(conn.snapshot.uploaded_bytes, 100);
+        assert_eq!(conn.totals().downloaded, 1);
+        assert_eq!(conn.totals().uploaded_bytes, 0);
+
+        conn.set_stats_reset_interval(Some(Duration::from_secs(0)));
+        *conn.metrics.downloaded.lock().unwrap() += 1;
+        conn.update_snapshot();
+        assert_eq!(conn.snapshot.downloaded, 1);
+        assert_eq!(conn.totals(), Totals::default());
+    }
+
+    #[test]
+    fn test_completion() {
+        let mut snapshot = Snapshot::default();
+        assert_eq!(snapshot.completion(), 0.0);
+        snapshot.availability = bitvec![1, 0, 1, 1];
+        assert_eq!(snapshot.completion(), 0.75);
+        snapshot.availability = bitvec![1; 5];
+        assert_eq!(snapshot.completion(), 1.0);
+    }
+
+    #[test]
+    fn test_from_handshaked() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (mut remote, _) = listener.accept()?;
+        let conn = Connection::from_handshaked(
+            stream,
+            conn_info(Metainfo::mock(4, 8)),
+            Arc::new("handshaked".to_owned()),
+        )?;
+        assert_eq!(conn.id.as_str(), "handshaked");
+
+        // First message is the bitfield rather than a handshake
+        assert_eq!(
+            Message::recv(&mut remote)?,
+            Message::BitField(bitvec![0; 8])
+        );
+        Message::Have(1).send(&mut remote)?;
+        let start = time::Instant::now();
+        while conn.completion() == 0.0 {
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert_eq!(conn.completion(), 0.5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_protocol_log() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (mut remote, _) = listener.accept()?;
+        let conn = Connection::from_handshaked(
+            stream,
+            conn_info(Metainfo::mock(4, 8)),
+            Arc::new("peer".to_owned()),
+        )?;
+        conn.choke(false)?;
+        assert_eq!(
+            Message::recv(&mut remote)?,
+            Message::BitField(bitvec![0; 8])
+        );
+        assert_eq!(Message::recv(&mut remote)?, Message::Unchoke);
+
+        // Longer than the piece
+        Message::Request(0, 0, 1024).send(&mut remote)?;
+        let start = time::Instant::now();
+        let mut errors = Vec::new();
+        while errors.is_empty() {
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::sleep(time::Duration::from_millis(10));
+            errors = conn.drain_errors();
+        }
+        assert_eq!(
+            errors,
+            vec!["rejected request for 1024 bytes at 0 of piece 0"]
+        );
+        assert!(conn.drain_errors().is_empty());
+        assert!(!conn.is_shutdown());
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_counts() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (mut remote, _) = listener.accept()?;
+        let conn = Connection::from_handshaked(
+            stream,
+            conn_info(Metainfo::mock(4, 8)),
+            Arc::new("peer".to_owned()),
+        )?;
+        conn.choke(false)?;
+        assert_eq!(
+            Message::recv(&mut remote)?,
+            Message::BitField(bitvec![0; 8])
+        );
+        assert_eq!(Message::recv(&mut remote)?, Message::Unchoke);
+        Message::KeepAlive.send(&mut remote)?;
+        Message::Have(0).send(&mut remote)?;
+        Message::Have(1).send(&mut remote)?;
+
+        let start = time::Instant::now();
+        while conn.message_counts().received().get(&MessageKind::Have) != Some(&2) {
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        let received = conn.message_counts().received();
+        assert_eq!(received.get(&MessageKind::KeepAlive), Some(&1));
+        assert_eq!(received.len(), 2);
+        let sent = conn.message_counts().sent();
+        assert_eq!(sent.get(&MessageKind::BitField), Some(&1));
+        assert_eq!(sent.get(&MessageKind::Unchoke), Some(&1));
+        // Still choked by the peer, so nothing was requested
+        assert_eq!(sent.get(&MessageKind::Request), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_protocol_log_bounded() {
+        let log = ProtocolLog::default();
+        for i in 0..PROTOCOL_LOG_LEN + 2 {
+            log.record("peer", i.to_string());
+        }
+        let entries = log.drain();
+        assert_eq!(entries.len(), PROTOCOL_LOG_LEN);
+        assert_eq!(entries[0], "2");
+    }
+
+    // Serves requests the way block based clients do, one Piece message per block
+    // Returns the requests received
+    fn serve_blocks(mut remote: TcpStream, data: Vec<u8>, piece_length: usize) -> Vec<Message> {
+        let mut requests = Vec::new();
+        while let Ok(msg) = Message::recv(&mut remote) {
+            if let Message::Request(index, begin, length) = msg {
+                requests.push(Message::Request(index, begin, length));
+                let start = index as usize * piece_length + begin as usize;
+                assert!(start + length as usize <= data.len());
+                for (offset, len) in block_layout(length, BLOCK_SIZE) {
+                    let block = start + offset as usize;
+                    let piece = Message::Piece(
+                        index,
+                        begin + offset,
+                        Arc::new(data[block..block + len as usize].to_vec()),
+                    );
+                    if piece.send(&mut remote).is_err() {
+                        return requests;
+                    }
+                }
+            }
+        }
+        requests
+    }
+
+    #[test]
+    fn test_short_final_piece() -> Result<(), failure::Error> {
+        let piece_length = 2 * BLOCK_SIZE as usize;
+        let data: Vec<u8> = (0..2 * piece_length + 3000).map(|i| i as u8).collect();
+        let mut metainfo = Metainfo::mock(piece_length, data.len());
+        for (i, piece) in data.chunks(piece_length).enumerate() {
+            metainfo.info.pieces[20 * i..20 * (i + 1)].copy_from_slice(&hash::sha1(piece));
+        }
+        let path = std::env::temp_dir().join("continuity_test_short_final_piece");
+        let ci = conn_info(metainfo);
+        ci.store
+            .write()
+            .unwrap()
+            .set_sink(Sink::Seekable(Box::new(std::fs::File::create(&path)?)));
+        let store = ci.store.clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (mut remote, _) = listener.accept()?;
+        let conn = Connection::from_handshaked(stream, ci, Arc::new("peer".to_owned()))?;
+        // Bitfields are padded to whole bytes on the wire
+        assert_eq!(
+            Message::recv(&mut remote)?,
+            Message::BitField(bitvec![0; 8])
+        );
+        Message::BitField(bitvec![1; 8]).send(&mut remote)?;
+        Message::Unchoke.send(&mut remote)?;
+        let expected = data.clone();
+        let handle = thread::spawn(move || serve_blocks(remote, data, piece_length));
+
+        let start = time::Instant::now();
+        while store.read().unwrap().left != 0 {
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        store.write().unwrap().close_output();
+        drop(conn);
+        drop(store);
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![
+                Message::Request(0, 0, piece_length as u32),
+                Message::Request(1, 0, piece_length as u32),
+                Message::Request(2, 0, 3000),
+            ]
+        );
+        let written = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(written, expected);
+        Ok(())
+    }
+
+    // Whether a choked peer is sent the allowed fast piece it requested, along with a piece
+    // that is not allowed fast
+    fn serves_allowed_fast(fast: bool, upload: bool) -> Result<bool, failure::Error> {
+        let metainfo = Metainfo::mock(4, 64);
+        let info_hash = metainfo.info_hash()?;
+        let allowed = peer::allowed_fast(
+            [127, 0, 0, 1].into(),
+            &info_hash,
+            metainfo.num_pieces(),
+            ALLOWED_FAST,
+        );
+        let choked = (0..metainfo.num_pieces())
+            .find(|i| !allowed.contains(i))
+            .unwrap();
+        let path =
+            std::env::temp_dir().join(format!("continuity_test_allowed_fast_{}_{}", fast, upload));
+        let mut ci = conn_info(metainfo);
+        ci.client_id = Arc::new("-CN0010-123456789012".to_owned());
+        {
+            let mut store = ci.store.write().unwrap();
+            store.set_sink(Sink::Seekable(Box::new(std::fs::File::create(&path)?)));
+            for i in 0..16 {
+                store.store("seed", i, Arc::new(vec![i as u8; 4]));
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let conn = Connection::connect(listener.local_addr()?, ci)?;
+        conn.set_upload_allowed(upload);
+        let (mut remote, _) = listener.accept()?;
+        remote.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+        Handshake::recv(&info_hash, &[b'r'; 20], &mut remote);
+        let mut reserved = Reserved::default();
+        reserved.set_fast(fast);
+        Handshake::send(&info_hash, Some(&[b'r'; 20]), reserved, &mut remote)?;
+        assert_eq!(
+            Message::recv(&mut remote)?,
+            Message::BitField(bitvec![1; 16])
+        );
+
+        Message::Request(choked, 0, 4).send(&mut remote)?;
+        Message::Request(allowed[0], 0, 4).send(&mut remote)?;
+        remote.set_read_timeout(Some(time::Duration::from_millis(500)))?;
+        let served = match Message::recv(&mut remote) {
+            Ok(m) => {
+                assert_eq!(
+                    m,
+                    Message::Piece(allowed[0], 0, Arc::new(vec![allowed[0] as u8; 4]))
+                );
+                true
+            }
+            Err(_) => false,
+        };
+        std::fs::remove_file(&path)?;
+        Ok(served)
+    }
+
+    #[test]
+    fn test_allowed_fast() -> Result<(), failure::Error> {
+        // Still choked, so only the allowed fast piece is served
+        assert!(serves_allowed_fast(true, true)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowed_fast_unsupported() -> Result<(), failure::Error> {
+        // The set is only defined by the fast extension, which the peer did not advertise
+        assert!(!serves_allowed_fast(false, true)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowed_fast_no_upload() -> Result<(), failure::Error> {
+        // With --no-upload or the upload quota reached, nothing is served at all
+        assert!(!serves_allowed_fast(true, false)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bind_addr() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut ci = conn_info(Metainfo::mock(4, 8));
+        ci.bind_addr = Some("127.0.0.2".parse()?);
+        let _conn = Connection::connect(listener.local_addr()?, ci)?;
+        let (_, addr) = listener.accept()?;
+        assert_eq!(addr.ip(), "127.0.0.2".parse::<IpAddr>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_raw() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (mut remote, _) = listener.accept()?;
+        remote.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+        let conn = Connection::from_handshaked(
+            stream,
+            conn_info(Metainfo::mock(4, 8)),
+            Arc::new("peer".to_owned()),
+        )?;
+        assert_eq!(
+            Message::recv(&mut remote)?,
+            Message::BitField(bitvec![0; 8])
+        );
+
+        conn.send_raw(Message::Have(1))?;
+        assert_eq!(Message::recv(&mut remote)?, Message::Have(1));
+        // The peer is choked, and interest is tracked by the connection, so neither is sent
+        conn.send_raw(Message::Piece(0, 0, Arc::new(vec![0; 4])))?;
+        conn.send_raw(Message::Interested)?;
+        conn.send_raw(Message::Have(0))?;
+        assert_eq!(Message::recv(&mut remote)?, Message::Have(0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_wire_tap() -> Result<(), failure::Error> {
+        let dir = std::env::temp_dir().join("continuity_test_wire_tap");
+        let _ = std::fs::remove_dir_all(&dir);
+        let metainfo = Metainfo::mock(4, 8);
+        let info_hash = metainfo.info_hash()?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut ci = conn_info(metainfo);
+        ci.id = Arc::new("127.0.0.1:6881".to_owned());
+        ci.client_id = Arc::new("-CN0010-123456789012".to_owned());
+        ci.wire_dir = Some(dir.clone());
+        let _conn = Connection::connect(listener.local_addr()?, ci)?;
+        let (mut remote, _) = listener.accept()?;
+        remote.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+        let mut sent = vec![0; 68];
+        remote.read_exact(&mut sent)?;
+        Handshake::send(
+            &info_hash,
+            Some(&[b'r'; 20]),
+            Reserved::default(),
+            &mut remote,
+        )?;
+
+        // Only the handshakes are compared, since the bitfield may or may not be in the files yet
+        let file = |ext| dir.join(format!("127.0.0.1_6881.{}", ext));
+        let len = |ext| std::fs::metadata(file(ext)).map_or(0, |m| m.len());
+        let start = time::Instant::now();
+        while len("recv") < 68 || len("sent") < 68 {
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        let mut received = Vec::new();
+        Handshake::send(
+            &info_hash,
+            Some(&[b'r'; 20]),
+            Reserved::default(),
+            &mut received,
+        )?;
+        assert_eq!(std::fs::read(file("recv"))?[..68], received[..]);
+        assert_eq!(std::fs::read(file("sent"))?[..68], sent[..]);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_dht_port() -> Result<(), failure::Error> {
+        let metainfo = Metainfo::mock(4, 8);
+        let info_hash = metainfo.info_hash()?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut ci = conn_info(metainfo);
+        ci.dht_port = Some(6881);
+        ci.client_id = Arc::new("-CN0010-123456789012".to_owned());
+        let conn = Connection::connect(listener.local_addr()?, ci)?;
+        let (mut remote, _) = listener.accept()?;
+        remote.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+
+        let reserved = Handshake::recv(&info_hash, &[b'r'; 20], &mut remote);
+        assert!(reserved.map_or(false, |r| r.supports_dht()));
+        let mut reserved = Reserved::default();
+        reserved.set_dht(true);
+        Handshake::send(&info_hash, Some(&[b'r'; 20]), reserved, &mut remote)?;
+        assert_eq!(
+            Message::recv(&mut remote)?,
+            Message::BitField(bitvec![0; 8])
+        );
+        assert_eq!(Message::recv(&mut remote)?, Message::Port(6881));
+
+        Message::Port(7000).send(&mut remote)?;
+        let start = time::Instant::now();
+        while conn.state.read().unwrap().peer_dht_port.is_none() {
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert_eq!(conn.state.read().unwrap().peer_dht_port, Some(7000));
+        Ok(())
+    }
+
+    #[test]
+    fn test_snubbing() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (mut remote, _) = listener.accept()?;
+        let mut ci = conn_info(Metainfo::mock(4, 8));
+        ci.snub_timeout = Some(time::Duration::from_millis(200));
+        let store = ci.store.clone();
+        let conn = Connection::from_handshaked(stream, ci, Arc::new("peer".to_owned()))?;
+        assert_eq!(
+            Message::recv(&mut remote)?,
+            Message::BitField(bitvec![0; 8])
+        );
+        Message::BitField(bitvec![1; 8]).send(&mut remote)?;
+        Message::Unchoke.send(&mut remote)?;
+        assert_eq!(Message::recv(&mut remote)?, Message::Interested);
+        assert_eq!(Message::recv(&mut remote)?, Message::Request(0, 0, 4));
+        assert_eq!(Message::recv(&mut remote)?, Message::Request(1, 0, 4));
+
+        // Nothing is delivered, so the requests are released for other peers
+        let start = time::Instant::now();
+        while !conn.state.read().unwrap().snubbing {
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert!(store.read().unwrap().as_bitvec(true).iter().all(|b| !b));
+        assert_eq!(conn.pending.lock().unwrap().len(), 0);
+
+        // No new requests while the peer stays unchoked but silent
+        remote.set_read_timeout(Some(time::Duration::from_millis(500)))?;
+        while let Ok(msg) = Message::recv(&mut remote) {
+            assert!(!matches!(msg, Message::Request(..)), "requested {:?}", msg);
+        }
+
+        // A late block shows the peer is delivering again
+        remote.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+        Message::Piece(0, 0, Arc::new(vec![0; 4])).send(&mut remote)?;
+        loop {
+            if let Message::Request(index, ..) = Message::recv(&mut remote)? {
+                assert_eq!(index, 0);
+                break;
+            }
+        }
+        assert!(!conn.state.read().unwrap().snubbing);
+        Ok(())
+    }
+
+    #[test]
+    fn test_upload_slots() {
+        let slots = Arc::new(UploadSlots::new(3));
+        let peak = Arc::new(Mutex::new(0));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let slots = slots.clone();
+                let peak = peak.clone();
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        assert!(slots.acquire(Duration::from_secs(5)));
+                        {
+                            let mut peak = peak.lock().unwrap();
+                            *peak = std::cmp::max(*peak, slots.in_flight());
+                        }
+                        thread::sleep(Duration::from_millis(1));
+                        slots.release();
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert!(*peak.lock().unwrap() <= 3);
+        assert_eq!(slots.in_flight(), 0);
+
+        // Full, so acquiring gives up after the timeout
+        for _ in 0..3 {
+            assert!(slots.acquire(Duration::from_millis(0)));
+        }
+        assert!(!slots.acquire(Duration::from_millis(10)));
+        slots.release();
+        assert!(slots.acquire(Duration::from_millis(0)));
+    }
+
+    #[test]
+    fn test_buffer_sizes() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let mut ci = conn_info(Metainfo::mock(4, 8));
+        ci.reader_buffer_len = Some(64 * 1024);
+        ci.writer_buffer_len = Some(4 * 1024);
+        let (reader, writer) = buffered(&stream, &ci)?;
+        assert_eq!(reader.capacity(), 64 * 1024);
+        assert_eq!(writer.capacity(), 4 * 1024);
+        Ok(())
+    }
+}
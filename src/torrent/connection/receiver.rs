@@ -1,16 +1,83 @@
-use super::{Command, State};
+use super::sender::QUEUE_LENGTH;
+use super::tap::Tap;
+use super::{BufferAccount, Command, MessageCounts, ProtocolLog, ProtocolStrictness, State};
+use crate::hash::Sha1Stream;
 use crate::metainfo::Metainfo;
-use crate::peer::{self, Handshake, Message};
+use crate::peer::{
+    self, block_layout, Handshake, HandshakeError, HandshakeKind, Message, MessageKind, BLOCK_SIZE,
+};
+use crate::reputation::Reputation;
 use crate::storage::PieceStore;
+use crate::verify::Verifier;
 use bitvec::BitVec;
 use failure::Fail;
-use log::{self, debug, error, info, warn};
-use std::collections::HashMap;
-use std::io::{self, BufReader, Read, Write};
-use std::net::TcpStream;
+use log::{self, debug, error, info, log_enabled, warn};
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::time;
 
+// Only QUEUE_LENGTH pieces are requested at a time, so anything beyond this is unsolicited. Builders
+// are also limited to the pieces currently requested, which is normally fewer.
+const MAX_PIECE_BUILDERS: usize = 2 * QUEUE_LENGTH;
+
+// Messages of each frequent kind logged per interval, the rest are only counted
+const LOGGED_PER_INTERVAL: u32 = 10;
+const LOG_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+// Haves for pieces the peer already advertised, beyond which it is recorded as misbehaving
+const MAX_DUPLICATE_HAVES: u32 = 100;
+
+// Limits debug logging of frequent messages so busy connections do not flood the log. Other
+// messages are rare and always logged.
+pub struct LogSampler {
+    limit: u32,
+    interval: time::Duration,
+    // Start of the current interval and messages seen in it, per kind
+    windows: HashMap<MessageKind, (time::Instant, u32)>,
+}
+
+impl LogSampler {
+    pub fn new(limit: u32, interval: time::Duration) -> Self {
+        LogSampler {
+            limit,
+            interval,
+            windows: HashMap::new(),
+        }
+    }
+
+    // None if the message should not be logged, otherwise the number of messages of the same kind
+    // that were not logged before it
+    pub fn sample(&mut self, kind: MessageKind, now: time::Instant) -> Option<u32> {
+        match kind {
+            MessageKind::Piece | MessageKind::Request | MessageKind::Have => {}
+            _ => return Some(0),
+        }
+        let limit = self.limit;
+        let window = self.windows.entry(kind).or_insert((now, 0));
+        if now.duration_since(window.0) >= self.interval {
+            let dropped = window.1.saturating_sub(limit);
+            *window = (now, 1);
+            return Some(dropped);
+        }
+        window.1 += 1;
+        if window.1 <= limit {
+            Some(0)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for LogSampler {
+    fn default() -> Self {
+        LogSampler::new(LOGGED_PER_INTERVAL, LOG_INTERVAL)
+    }
+}
+
 struct Chunk {
     begin: u32,
     data: Vec<u8>,
@@ -27,8 +94,6 @@ enum PieceBuilderError {
         _0, _1, _2
     )]
     InsufficientSize(u32, u32, u32),
-    #[fail(display = "Info hash does not match")]
-    InvalidPiece,
 }
 
 pub struct PieceBuilder {
@@ -37,6 +102,12 @@ pub struct PieceBuilder {
     remaining: u32,
     size: u32,
     chunks: Vec<Chunk>,
+    // (begin, length, bytes received) of each block of the piece
+    blocks: Vec<(u32, u32, u32)>,
+    // Hash of the contiguous prefix received so far, and its length. Blocks after a gap are
+    // hashed once the gap is filled.
+    hasher: Sha1Stream,
+    hashed: u32,
 }
 
 impl PieceBuilder {
@@ -48,8 +119,64 @@ impl PieceBuilder {
             remaining: size,
             size,
             chunks: Vec::new(),
+            blocks: block_layout(size, BLOCK_SIZE)
+                .map(|(begin, len)| (begin, len, 0))
+                .collect(),
+            hasher: Sha1Stream::new(),
+            hashed: 0,
         }
     }
+
+    // Feed every received byte that directly follows the hashed prefix into the hasher
+    fn hash_prefix(&mut self) {
+        loop {
+            let hashed = self.hashed;
+            let next = self
+                .chunks
+                .iter()
+                .find(|c| c.begin <= hashed && hashed < c.begin + c.data.len() as u32);
+            match next {
+                Some(chunk) => {
+                    self.hasher
+                        .update(&chunk.data[(hashed - chunk.begin) as usize..]);
+                    self.hashed = chunk.begin + chunk.data.len() as u32;
+                }
+                None => return,
+            }
+        }
+    }
+
+    // Hash of the whole piece, once every byte has been hashed in order
+    fn digest(&self) -> Option<[u8; 20]> {
+        if self.hashed == self.size {
+            Some(self.hasher.digest())
+        } else {
+            None
+        }
+    }
+
+    // Number of fully received blocks and total number of blocks
+    fn progress(&self) -> (u32, u32) {
+        let received = self
+            .blocks
+            .iter()
+            .filter(|(_, len, received)| received >= len)
+            .count();
+        (received as u32, self.blocks.len() as u32)
+    }
+
+    fn record(&mut self, begin: u32, len: u32) {
+        let end = begin + len;
+        for (start, block_len, received) in self.blocks.iter_mut() {
+            if *start < end && begin < *start + *block_len {
+                *received += min(end, *start + *block_len) - max(begin, *start);
+            }
+        }
+    }
+    fn buffered(&self) -> usize {
+        (self.size - self.remaining) as usize
+    }
+
     fn add(&mut self, chunk: Chunk) -> Result<Option<Vec<u8>>, PieceBuilderError> {
         if chunk.data.len() > self.remaining as usize {
             return Err(PieceBuilderError::InvalidSize(
@@ -68,8 +195,10 @@ impl PieceBuilder {
             return Ok(Some(chunk.data));
         }
 
+        self.record(chunk.begin, chunk.data.len() as u32);
         self.remaining -= chunk.data.len() as u32;
         self.chunks.push(chunk);
+        self.hash_prefix();
         if self.remaining == 0 {
             let mut s = io::Cursor::new(vec![0; self.size as usize]);
             for chunk in self.chunks.iter() {
@@ -77,11 +206,7 @@ impl PieceBuilder {
                 s.write_all(&chunk.data).unwrap();
             }
 
-            let v = s.into_inner();
-            if !self.metainfo.verify_piece(self.index, &v) {
-                return Err(PieceBuilderError::InvalidPiece);
-            }
-            return Ok(Some(v));
+            return Ok(Some(s.into_inner()));
         }
 
         Ok(None)
@@ -96,6 +221,10 @@ enum ReceiverError {
     DuplicateBitfield,
     #[fail(display = "invalid handshake")]
     InvalidHandshake,
+    #[fail(display = "no handshake within {:?}", _0)]
+    HandshakeTimeout(time::Duration),
+    #[fail(display = "encrypted handshakes are not supported")]
+    EncryptedHandshake,
     #[fail(display = "invalid index {}", _0)]
     InvalidIndex(u32),
     #[fail(display = "invalid piece {}", _0)]
@@ -113,34 +242,125 @@ impl From<peer::Error> for ReceiverError {
 pub struct Receiver {
     pub tx: mpsc::Sender<Command>,
     pub piece_buffer: HashMap<u32, PieceBuilder>,
+    pub pending: Arc<Mutex<HashSet<u32>>>,
     pub state: Arc<RwLock<State>>,
     pub store: Arc<RwLock<PieceStore>>,
     pub speed: Mutex<Option<time::Duration>>,
     pub availability: Arc<Mutex<BitVec>>,
-    pub reader: BufReader<TcpStream>,
+    pub reader: BufReader<Tap>,
     pub peer_id: Arc<String>,
+    pub peer_ip: IpAddr,
     pub client_id: Arc<String>,
     pub metainfo: Arc<Metainfo>,
     pub bitfield_received: bool,
     pub num_downloaded: Arc<Mutex<u64>>,
+    pub alive: Arc<AtomicBool>,
+    pub handshaked: bool,
+    // Global accounting of buffered piece data and this receiver's share of it
+    pub buffers: Arc<BufferAccount>,
+    pub buffered: usize,
+    pub reputation: Arc<Mutex<Reputation>>,
+    pub verifier: Arc<Verifier>,
+    pub protocol_log: Arc<ProtocolLog>,
+    // Piece payload received, including blocks that were dropped
+    pub downloaded_bytes: Arc<Mutex<u64>>,
+    // When the peer last sent a block, shared with the sender to detect snubbing
+    pub last_delivery: Arc<Mutex<time::Instant>>,
+    pub message_counts: Arc<MessageCounts>,
+    pub log_sampler: LogSampler,
+    // Pieces served even while the peer is choked, if it supports the fast extension
+    pub allowed_fast: Arc<HashSet<u32>>,
+    pub peer_fast: bool,
+    // Cleared by the choker when uploading is disabled or the upload quota is reached
+    pub upload_allowed: Arc<AtomicBool>,
+    // Pieces from the peer that failed verification
+    pub bad_pieces: Arc<Mutex<u32>>,
+    pub max_bad_pieces: u32,
+    pub strictness: ProtocolStrictness,
+    // Read timeout while waiting for the handshake, there is none afterwards
+    pub handshake_timeout: Option<time::Duration>,
+    pub handshake_slots: Option<Arc<AtomicUsize>>,
+    // Haves for pieces the peer had already advertised
+    pub duplicate_haves: u32,
 }
 
 impl Receiver {
-    fn _start(&mut self) -> Result<(), ReceiverError> {
-        // Receive Handshake
-        if !Handshake::recv(
+    fn handshake(&mut self) -> Result<(), ReceiverError> {
+        let timeout = self.handshake_timeout;
+        let timed_out = |e: &io::Error| match (timeout, e.kind()) {
+            (Some(t), io::ErrorKind::WouldBlock) | (Some(t), io::ErrorKind::TimedOut) => {
+                Some(ReceiverError::HandshakeTimeout(t))
+            }
+            _ => None,
+        };
+        // The timeout is per read, so a peer trickling bytes could take a little longer in total
+        self.reader
+            .get_ref()
+            .get_ref()
+            .set_read_timeout(timeout)
+            .map_err(peer::Error::from)?;
+        let prefix = match self.reader.fill_buf() {
+            Ok(prefix) => prefix,
+            Err(e) => return Err(timed_out(&e).unwrap_or_else(|| peer::Error::from(e).into())),
+        };
+        if HandshakeKind::detect(prefix) == HandshakeKind::Encrypted {
+            return Err(ReceiverError::EncryptedHandshake);
+        }
+        let reserved = match Handshake::read(
             &self.metainfo.info_hash().unwrap(),
             self.client_id.as_bytes(),
             self.reader.by_ref(),
         ) {
-            return Err(ReceiverError::InvalidHandshake);
+            Ok(handshake) => handshake.reserved,
+            Err(HandshakeError::Truncated(e)) => {
+                return Err(timed_out(&e).unwrap_or(ReceiverError::InvalidHandshake))
+            }
+            Err(e) => {
+                warn!("Peer {}: {}", self.peer_id, e);
+                return Err(ReceiverError::InvalidHandshake);
+            }
+        };
+        self.reader
+            .get_ref()
+            .get_ref()
+            .set_read_timeout(None)
+            .map_err(peer::Error::from)?;
+        if reserved.supports_dht() {
+            self.send_command(Command::PeerDht)?;
+        }
+        self.peer_fast = reserved.supports_fast();
+        Ok(())
+    }
+
+    fn _start(&mut self) -> Result<(), ReceiverError> {
+        let res = match self.handshaked {
+            true => Ok(()),
+            false => self.handshake(),
+        };
+        // Freed whether or not the peer completed its handshake
+        if let Some(slots) = self.handshake_slots.take() {
+            slots.fetch_sub(1, Ordering::SeqCst);
         }
+        res?;
 
         // Parse messages in loop
         loop {
             let m = Message::recv(&mut self.reader)?;
+            self.message_counts.record_received(m.kind());
 
-            debug!("Message received from {:?}: {:?}", self.peer_id, &m);
+            if log_enabled!(log::Level::Debug) {
+                if let Some(dropped) = self.log_sampler.sample(m.kind(), time::Instant::now()) {
+                    if dropped > 0 {
+                        debug!(
+                            "{} {:?} messages from {:?} not logged",
+                            dropped,
+                            m.kind(),
+                            self.peer_id
+                        );
+                    }
+                    debug!("Message received from {:?}: {:?}", self.peer_id, &m);
+                }
+            }
             match m {
                 Message::KeepAlive => {
                     continue;
@@ -158,7 +378,8 @@ impl Receiver {
                     Arc::try_unwrap(piece).expect("Piece only has one owner"),
                 )?,
                 Message::Cancel(_, _, _) => continue,
-                Message::Port(_) => continue, // unhandled
+                // Kept for the DHT to add the peer to its routing table
+                Message::Port(port) => self.state.write().unwrap().peer_dht_port = Some(port),
             }
         }
     }
@@ -169,10 +390,26 @@ impl Receiver {
             _ => unreachable!(),
         }
 
+        self.alive.store(false, Ordering::SeqCst);
+        self.piece_buffer.clear();
+        self.sync_buffered();
         // Attempt to inform sender
         let _ = self.tx.send(Command::Shutdown);
     }
 
+    // A recoverable protocol deviation closes the connection when strict, otherwise the message
+    // is ignored
+    fn deviation(&self, e: ReceiverError) -> Result<(), ReceiverError> {
+        match self.strictness {
+            ProtocolStrictness::Strict => Err(e),
+            ProtocolStrictness::Permissive => {
+                self.protocol_log
+                    .record(&self.peer_id, format!("ignored {}", e));
+                Ok(())
+            }
+        }
+    }
+
     fn send_command(&self, cmd: Command) -> Result<(), ReceiverError> {
         debug!("Peer {}: Send {:?} to receiver", self.peer_id, cmd);
         self.tx.send(cmd).map_err(|_| ReceiverError::Channel)
@@ -185,7 +422,12 @@ impl Receiver {
         drop(s);
         self.send_command(Command::PeerChoke(state))?;
         if state {
+            // The peer discards our requests, so stop accepting blocks for them here rather than
+            // waiting for the sender. Late blocks are then dropped as unsolicited instead of
+            // starting builders that can never complete.
+            self.pending.lock().unwrap().clear();
             self.piece_buffer.clear();
+            self.sync_buffered();
         }
         Ok(())
     }
@@ -198,27 +440,67 @@ impl Receiver {
 
     fn have(&mut self, index: u32) -> Result<(), ReceiverError> {
         if index >= self.metainfo.num_pieces() {
-            return Err(ReceiverError::InvalidIndex(index));
+            return self.deviation(ReceiverError::InvalidIndex(index));
         }
 
         let mut bv = self.availability.lock().unwrap();
+        // Nothing changed, so the sender need not recompute its interest
+        if bv[index as usize] {
+            drop(bv);
+            self.duplicate_haves += 1;
+            if self.duplicate_haves == MAX_DUPLICATE_HAVES {
+                self.protocol_log.record(
+                    &self.peer_id,
+                    format!(
+                        "sent {} haves for pieces it already had",
+                        MAX_DUPLICATE_HAVES
+                    ),
+                );
+            }
+            return Ok(());
+        }
         bv.set(index as usize, true);
         drop(bv);
         self.send_command(Command::PeerHave(index))?;
-        Ok(())
+        self.check_seed()
+    }
+
+    // Signalled once, when the peer's availability fills up
+    fn check_seed(&mut self) -> Result<(), ReceiverError> {
+        let seed = {
+            let bv = self.availability.lock().unwrap();
+            !bv.is_empty() && bv.iter().all(|b| b)
+        };
+        if !seed || self.state.read().unwrap().peer_seed {
+            return Ok(());
+        }
+        debug!("Peer {}: became a seed", self.peer_id);
+        self.state.write().unwrap().peer_seed = true;
+        self.send_command(Command::PeerBecameSeed)
     }
 
     fn request(&mut self, index: u32, begin: u32, length: u32) -> Result<(), ReceiverError> {
         let choked = { self.state.read().unwrap().client_choked };
-        if !choked {
+        let fast = self.peer_fast
+            && self.upload_allowed.load(Ordering::SeqCst)
+            && self.allowed_fast.contains(&index);
+        if !choked || fast {
             self.send_command(Command::SendChunk(index, begin, length))?
+        } else {
+            // Requests sent before our choke arrived are expected, so this is not an error
+            debug!(
+                "Peer {}: Ignoring request for piece {} while choked",
+                self.peer_id, index
+            );
         }
         Ok(())
     }
 
     fn bitfield(&mut self, mut bv: BitVec) -> Result<(), ReceiverError> {
         if !self.bitfield_received {
-            bv.truncate(self.metainfo.num_pieces() as usize);
+            // Bitfields are padded to whole bytes, but a short one is padded here too so that
+            // availability can always be indexed by piece
+            bv.resize(self.metainfo.num_pieces() as usize, false);
             // Store into mutex
             let mut availability = self.availability.lock().unwrap();
             *availability = bv;
@@ -226,36 +508,601 @@ impl Receiver {
             self.send_command(Command::BitFieldReceived)?;
 
             self.bitfield_received = true;
-            return Ok(());
+            return self.check_seed();
         }
-        Err(ReceiverError::DuplicateBitfield)
+        self.deviation(ReceiverError::DuplicateBitfield)
     }
 
     fn piece(&mut self, index: u32, begin: u32, piece: Vec<u8>) -> Result<(), ReceiverError> {
+        *self.downloaded_bytes.lock().unwrap() += piece.len() as u64;
         if index >= self.metainfo.num_pieces() {
-            return Err(ReceiverError::InvalidIndex(index));
+            return self.deviation(ReceiverError::InvalidIndex(index));
+        }
+        // Late blocks for requests given up on still show the peer is delivering
+        *self.last_delivery.lock().unwrap() = time::Instant::now();
+        if self.state.read().unwrap().snubbing {
+            info!("Peer {}: delivering again", self.peer_id);
+            self.state.write().unwrap().snubbing = false;
+            // Wake the sender to request from the peer again
+            self.send_command(Command::Ping)?;
+        }
+        if !self.pending.lock().unwrap().contains(&index) {
+            self.protocol_log.record(
+                &self.peer_id,
+                format!("dropped unsolicited block for piece {}", index),
+            );
+            return Ok(());
         }
         // Get bitvec of items already in store
         let bv = { self.store.read().unwrap().as_bitvec(false) };
         if bv[index as usize] {
-            return Err(ReceiverError::InvalidIndex(index));
+            return self.deviation(ReceiverError::InvalidIndex(index));
+        }
+        // Purge completed entries, and pieces no longer requested from this peer (after a choke,
+        // snub or reprioritize), so there is never more than one builder per outstanding request
+        {
+            let pending = self.pending.lock().unwrap();
+            self.piece_buffer
+                .retain(|k, _| !bv[*k as usize] && pending.contains(k));
         }
-        self.piece_buffer.retain(|k, _| !bv[*k as usize]); // Purge completed entries
+        self.sync_buffered();
+        if !self.piece_buffer.contains_key(&index) && self.piece_buffer.len() >= MAX_PIECE_BUILDERS
+        {
+            warn!(
+                "Peer {}: Dropping block for piece {} (too many pieces in flight)",
+                self.peer_id, index
+            );
+            return Ok(());
+        }
+        if !self.buffers.reserve(piece.len()) {
+            warn!(
+                "Peer {}: Dropping block for piece {} (buffer limit reached)",
+                self.peer_id, index
+            );
+            return Ok(());
+        }
+        self.buffered += piece.len();
+
         self.piece_buffer
             .entry(index)
             .or_insert(PieceBuilder::new(self.metainfo.clone(), index));
         let pb = self.piece_buffer.get_mut(&index).unwrap();
-        match pb.add(Chunk { begin, data: piece }) {
-            Ok(Some(v)) => {
-                let mut n = self.num_downloaded.lock().unwrap();
-                *n += 1;
-                drop(n);
-                let mut ps = self.store.write().unwrap();
-                ps.store(self.peer_id.as_str(), index, Arc::new(v));
+        let res = pb.add(Chunk { begin, data: piece });
+        let (received, total) = pb.progress();
+        let digest = pb.digest();
+        if let Ok(Some(v)) = &res {
+            self.piece_buffer.remove(&index);
+            // Stays reserved until verified
+            self.buffered -= v.len();
+        }
+        self.sync_buffered();
+        match res {
+            Ok(Some(v)) => self.verify(index, v, digest),
+            Ok(None) => {
+                self.store
+                    .read()
+                    .unwrap()
+                    .set_progress(index, received, total);
             }
-            Err(e) => return Err(ReceiverError::InvalidPiece(e)),
-            _ => {}
+            // The block was not kept, so the rest of the piece can still complete
+            Err(e) => return self.deviation(ReceiverError::InvalidPiece(e)),
         }
         Ok(())
     }
+
+    // Hash the piece off this thread, only storing (and so advertising) it once verified
+    // A bad piece is requested again, until too many close the connection through the sender
+    fn verify(&self, index: u32, piece: Vec<u8>, digest: Option<[u8; 20]>) {
+        // Requests arriving in the meantime are served from the unverified copy
+        let piece = Arc::new(piece);
+        self.store
+            .write()
+            .unwrap()
+            .set_verifying(index, piece.clone());
+        let store = self.store.clone();
+        let reputation = self.reputation.clone();
+        let num_downloaded = self.num_downloaded.clone();
+        let buffers = self.buffers.clone();
+        let peer_id = self.peer_id.clone();
+        let peer_ip = self.peer_ip;
+        let tx = self.tx.clone();
+        let pending = self.pending.clone();
+        let bad_pieces = self.bad_pieces.clone();
+        let max_bad_pieces = self.max_bad_pieces;
+        self.verifier
+            .verify_with_digest(index, piece, digest, move |piece, valid| {
+                buffers.release(piece.len());
+                if valid {
+                    *num_downloaded.lock().unwrap() += 1;
+                    reputation.lock().unwrap().good_piece(peer_ip);
+                    store.write().unwrap().store(peer_id.as_str(), index, piece);
+                } else {
+                    warn!("Peer {}: Piece {} failed verification", peer_id, index);
+                    reputation.lock().unwrap().bad_piece(peer_ip);
+                    {
+                        let mut store = store.write().unwrap();
+                        store.record_corrupt(piece.len() as u64);
+                        store.discard_unverified(index, &piece);
+                    }
+                    let bad = {
+                        let mut n = bad_pieces.lock().unwrap();
+                        *n += 1;
+                        *n
+                    };
+                    if bad >= max_bad_pieces {
+                        let _ = tx.send(Command::Shutdown);
+                    } else {
+                        pending.lock().unwrap().remove(&index);
+                        store
+                            .write()
+                            .unwrap()
+                            .release_piece(peer_id.as_str(), index);
+                    }
+                }
+            });
+    }
+
+    // Return bytes no longer held by the piece buffer to the global account
+    fn sync_buffered(&mut self) {
+        let buffered = self.piece_buffer.values().map(|pb| pb.buffered()).sum();
+        self.buffers.release(self.buffered - buffered);
+        self.buffered = buffered;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash;
+    use crate::peer::Reserved;
+    use crate::selection::Inorder;
+    use bitvec::bitvec;
+    use matches::matches;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn receiver(metainfo: Metainfo) -> (Receiver, mpsc::Receiver<Command>) {
+        let metainfo = Arc::new(metainfo);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let store = PieceStore::new(&metainfo, Box::new(Inorder::default()));
+        let r = Receiver {
+            tx,
+            piece_buffer: HashMap::new(),
+            pending: Arc::new(Mutex::new(HashSet::new())),
+            state: Arc::new(RwLock::new(State::default())),
+            store: Arc::new(RwLock::new(store)),
+            speed: Mutex::new(None),
+            availability: Arc::new(Mutex::new(BitVec::new())),
+            reader: BufReader::new(stream.into()),
+            peer_id: Arc::new("peer".to_owned()),
+            peer_ip: [127, 0, 0, 1].into(),
+            client_id: Arc::new("client".to_owned()),
+            metainfo: metainfo.clone(),
+            bitfield_received: false,
+            num_downloaded: Arc::new(Mutex::new(0)),
+            alive: Arc::new(AtomicBool::new(true)),
+            handshaked: false,
+            buffers: Arc::new(BufferAccount::default()),
+            reputation: Arc::new(Mutex::new(Reputation::default())),
+            buffered: 0,
+            verifier: Arc::new(Verifier::new(metainfo.clone(), 1)),
+            protocol_log: Arc::new(ProtocolLog::default()),
+            downloaded_bytes: Arc::new(Mutex::new(0)),
+            last_delivery: Arc::new(Mutex::new(time::Instant::now())),
+            message_counts: Arc::new(MessageCounts::default()),
+            log_sampler: LogSampler::default(),
+            allowed_fast: Arc::new(HashSet::new()),
+            peer_fast: false,
+            upload_allowed: Arc::new(AtomicBool::new(true)),
+            bad_pieces: Arc::new(Mutex::new(0)),
+            max_bad_pieces: 1,
+            strictness: ProtocolStrictness::Strict,
+            handshake_timeout: None,
+            handshake_slots: None,
+            duplicate_haves: 0,
+        };
+        (r, rx)
+    }
+
+    #[test]
+    fn test_log_sampler() {
+        let start = time::Instant::now();
+        let interval = time::Duration::from_secs(1);
+        let mut sampler = LogSampler::new(2, interval);
+        let logged: Vec<_> = (0..5)
+            .map(|_| sampler.sample(MessageKind::Piece, start))
+            .collect();
+        assert_eq!(logged, vec![Some(0), Some(0), None, None, None]);
+        // Each frequent kind has its own budget, and rare kinds are always logged
+        assert_eq!(sampler.sample(MessageKind::Have, start), Some(0));
+        for _ in 0..5 {
+            assert_eq!(sampler.sample(MessageKind::Choke, start), Some(0));
+        }
+
+        // The next interval reports how many were dropped
+        let later = start + interval;
+        assert_eq!(sampler.sample(MessageKind::Piece, later), Some(3));
+        assert_eq!(sampler.sample(MessageKind::Piece, later), Some(0));
+        assert_eq!(sampler.sample(MessageKind::Piece, later), None);
+    }
+
+    #[test]
+    fn test_piece_builder_cap() -> Result<(), failure::Error> {
+        let (mut r, _rx) = receiver(Metainfo::mock(4, 4 * 4 * MAX_PIECE_BUILDERS));
+        r.pending
+            .lock()
+            .unwrap()
+            .extend(0..(4 * MAX_PIECE_BUILDERS) as u32);
+        for index in 0..(4 * MAX_PIECE_BUILDERS) as u32 {
+            r.piece(index, 0, vec![0])?;
+        }
+        assert_eq!(r.piece_buffer.len(), MAX_PIECE_BUILDERS);
+        // Blocks for pieces already being built are still accepted
+        r.piece(0, 1, vec![0])?;
+        assert_eq!(r.piece_buffer[&0].remaining, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_builders_follow_requests() -> Result<(), failure::Error> {
+        let (mut r, _rx) = receiver(Metainfo::mock(4, 16));
+        r.pending.lock().unwrap().extend(0..3);
+        for index in 0..3 {
+            r.piece(index, 0, vec![0; 2])?;
+        }
+        assert_eq!(r.piece_buffer.len(), 3);
+        assert_eq!(r.buffers.used(), 6);
+
+        // The sender gave up on piece 1, its partial data is dropped with the next block
+        r.pending.lock().unwrap().remove(&1);
+        r.piece(2, 2, vec![0; 1])?;
+        assert_eq!(
+            r.piece_buffer.keys().collect::<HashSet<_>>(),
+            [0, 2].iter().collect()
+        );
+        assert_eq!(r.buffers.used(), 5);
+
+        r.pending.lock().unwrap().insert(3);
+        for (index, begin) in vec![(3, 0), (1, 2), (0, 2), (3, 2)] {
+            r.piece(index, begin, vec![0; 1])?;
+            assert!(r.piece_buffer.len() <= r.pending.lock().unwrap().len());
+        }
+        assert!(!r.piece_buffer.contains_key(&1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_incremental_hash() -> Result<(), failure::Error> {
+        let size = 3 * BLOCK_SIZE + 100;
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let block = |i: u32| {
+            let begin = i * BLOCK_SIZE;
+            let end = min(begin + BLOCK_SIZE, size);
+            Chunk {
+                begin,
+                data: data[begin as usize..end as usize].to_vec(),
+            }
+        };
+        let metainfo = Arc::new(Metainfo::mock(size as usize, size as usize));
+        let mut pb = PieceBuilder::new(metainfo, 0);
+
+        // Only the contiguous prefix is hashed, whatever order blocks arrive in
+        assert!(pb.add(block(2))?.is_none());
+        assert_eq!(pb.hashed, 0);
+        assert!(pb.add(block(0))?.is_none());
+        assert_eq!(pb.hashed, BLOCK_SIZE);
+        assert_eq!(pb.digest(), None);
+        assert!(pb.add(block(3))?.is_none());
+        assert_eq!(pb.hashed, BLOCK_SIZE);
+        let piece = pb.add(block(1))?.unwrap();
+        assert_eq!(pb.hashed, size);
+        assert_eq!(piece, data);
+        assert_eq!(pb.digest(), Some(hash::sha1(&data)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_progress() -> Result<(), failure::Error> {
+        let size = 2 * BLOCK_SIZE + 100;
+        let (mut r, _rx) = receiver(Metainfo::mock(size as usize, 2 * size as usize));
+        r.pending.lock().unwrap().insert(1);
+        r.store
+            .write()
+            .unwrap()
+            .request_pieces("peer", bitvec![0, 1], 1)
+            .unwrap();
+        r.piece(1, 0, vec![0; BLOCK_SIZE as usize])?;
+        assert_eq!(r.store.read().unwrap().block_progress(), vec![(1, 1, 3)]);
+        // Partial blocks are not counted
+        r.piece(1, 2 * BLOCK_SIZE - 10, vec![0; 50])?;
+        assert_eq!(r.piece_buffer[&1].progress(), (1, 3));
+        r.piece(1, BLOCK_SIZE, vec![0; BLOCK_SIZE as usize - 10])?;
+        assert_eq!(r.store.read().unwrap().block_progress(), vec![(1, 2, 3)]);
+        r.store.write().unwrap().clear_requests("peer");
+        assert!(r.store.read().unwrap().block_progress().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pipelined_handshake() -> Result<(), failure::Error> {
+        let metainfo = Metainfo::mock(1, 12);
+        let mut data = Vec::new();
+        Handshake::send(
+            &metainfo.info_hash()?,
+            Some(&[b'p'; 20]),
+            Reserved::default(),
+            &mut data,
+        )?;
+        let mut bv = bitvec![0; 16];
+        bv.set(3, true);
+        Message::BitField(bv).send(&mut data)?;
+        Message::Have(9).send(&mut data)?;
+
+        let (mut r, rx) = receiver(metainfo);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        r.reader = BufReader::new(TcpStream::connect(listener.local_addr()?)?.into());
+        let (mut peer, _) = listener.accept()?;
+        // Everything arrives in a single segment
+        peer.write_all(&data)?;
+        drop(peer);
+
+        assert!(matches!(
+            r._start(),
+            Err(ReceiverError::Message(peer::Error::IO(_)))
+        ));
+        let availability = r.availability.lock().unwrap().clone();
+        assert_eq!(
+            availability
+                .iter()
+                .enumerate()
+                .filter(|(_, b)| *b)
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>(),
+            vec![3, 9]
+        );
+        assert!(matches!(rx.try_recv(), Ok(Command::BitFieldReceived)));
+        assert!(matches!(rx.try_recv(), Ok(Command::PeerHave(9))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_have() -> Result<(), failure::Error> {
+        let (mut r, rx) = receiver(Metainfo::mock(1, 4));
+        r.bitfield(bitvec![1, 0, 0, 0])?;
+        assert!(matches!(rx.try_recv(), Ok(Command::BitFieldReceived)));
+
+        // Only the first have of a piece reaches the sender
+        r.have(1)?;
+        r.have(1)?;
+        r.have(0)?;
+        let haves: Vec<_> = rx
+            .try_iter()
+            .filter_map(|c| match c {
+                Command::PeerHave(i) => Some(i),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(haves, vec![1]);
+        assert_eq!(r.duplicate_haves, 2);
+
+        for _ in 2..MAX_DUPLICATE_HAVES {
+            r.have(0)?;
+        }
+        assert_eq!(r.protocol_log.drain().len(), 1);
+        assert!(rx.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_peer_became_seed() -> Result<(), failure::Error> {
+        let (mut r, rx) = receiver(Metainfo::mock(1, 3));
+        r.bitfield(bitvec![1, 0, 0])?;
+        r.have(1)?;
+        assert!(!r.state.read().unwrap().peer_seed);
+        r.have(2)?;
+        assert!(r.state.read().unwrap().peer_seed);
+        // A repeated have does not signal again
+        r.have(2)?;
+        let seeds = rx
+            .try_iter()
+            .filter(|c| matches!(c, Command::PeerBecameSeed))
+            .count();
+        assert_eq!(seeds, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_protocol_strictness() -> Result<(), failure::Error> {
+        let metainfo = Metainfo::mock(4, 48);
+        let mut data = Vec::new();
+        Handshake::send(
+            &metainfo.info_hash()?,
+            Some(&[b'p'; 20]),
+            Reserved::default(),
+            &mut data,
+        )?;
+        let mut bv = bitvec![0; 16];
+        bv.set(3, true);
+        Message::BitField(bv.clone()).send(&mut data)?;
+        // A deviating peer: a second bitfield, indices past the last piece and an oversized block
+        Message::BitField(bv).send(&mut data)?;
+        Message::Have(99).send(&mut data)?;
+        Message::Piece(99, 0, Arc::new(vec![0; 4])).send(&mut data)?;
+        Message::Piece(1, 2, Arc::new(vec![0; 4])).send(&mut data)?;
+        Message::Have(9).send(&mut data)?;
+
+        let run = |strictness| -> Result<_, failure::Error> {
+            let (mut r, _rx) = receiver(Metainfo::mock(4, 48));
+            r.strictness = strictness;
+            r.pending.lock().unwrap().insert(1);
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            r.reader = BufReader::new(TcpStream::connect(listener.local_addr()?)?.into());
+            let (mut peer, _) = listener.accept()?;
+            peer.write_all(&data)?;
+            drop(peer);
+            let res = r._start();
+            let have_9 = r.availability.lock().unwrap()[9];
+            Ok((res, have_9, r.protocol_log.drain()))
+        };
+
+        let (res, have_9, log) = run(ProtocolStrictness::Strict)?;
+        assert!(matches!(res, Err(ReceiverError::DuplicateBitfield)));
+        assert!(!have_9);
+        assert!(log.is_empty());
+
+        // Every deviation is recorded, and the connection lasts until the peer closes it
+        let (res, have_9, log) = run(ProtocolStrictness::Permissive)?;
+        assert!(matches!(
+            res,
+            Err(ReceiverError::Message(peer::Error::IO(_)))
+        ));
+        assert!(have_9);
+        assert_eq!(log.len(), 4);
+        assert_eq!(log[0], "ignored duplicate bitfield");
+        Ok(())
+    }
+
+    #[test]
+    fn test_verification() -> Result<(), failure::Error> {
+        let mut m = Metainfo::mock(2, 4);
+        m.info.pieces[..20].copy_from_slice(&crate::hash::sha1(b"ab"));
+        let (mut r, rx) = receiver(m);
+        r.pending.lock().unwrap().extend(0..2);
+        r.piece(0, 0, b"ab".to_vec())?;
+        r.piece(1, 0, b"cd".to_vec())?;
+
+        // The bad piece closes the connection without being stored
+        match rx.recv_timeout(time::Duration::from_secs(5))? {
+            Command::Shutdown => {}
+            c => panic!("unexpected command {:?}", c),
+        }
+        let start = time::Instant::now();
+        while r.buffers.used() != 0 || r.store.read().unwrap().get(0).is_none() {
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert!(r.store.read().unwrap().get(1).is_none());
+        assert_eq!(r.store.read().unwrap().corrupt(), 2);
+        assert_eq!(*r.num_downloaded.lock().unwrap(), 1);
+        let reputation = r.reputation.lock().unwrap();
+        assert_eq!(
+            reputation
+                .get([127, 0, 0, 1].into())
+                .map(|p| (p.good_pieces, p.bad_pieces)),
+            Some((1, 1))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_piece_limit() -> Result<(), failure::Error> {
+        let (mut r, rx) = receiver(Metainfo::mock(2, 4));
+        r.max_bad_pieces = 2;
+        r.pending.lock().unwrap().extend(0..2);
+        r.store
+            .write()
+            .unwrap()
+            .request_pieces("peer", bitvec![1; 2], 2)
+            .unwrap();
+        r.store.read().unwrap().register(r.tx.clone());
+
+        // The first bad piece is given up on so it can be requested again
+        r.piece(0, 0, b"ab".to_vec())?;
+        match rx.recv_timeout(time::Duration::from_secs(5))? {
+            Command::Needed => {}
+            c => panic!("unexpected command {:?}", c),
+        }
+        assert!(!r.pending.lock().unwrap().contains(&0));
+        assert!(r.store.read().unwrap().check_if_needed(0));
+        assert!(!r.store.read().unwrap().check_if_needed(1));
+
+        r.piece(1, 0, b"cd".to_vec())?;
+        match rx.recv_timeout(time::Duration::from_secs(5))? {
+            Command::Shutdown => {}
+            c => panic!("unexpected command {:?}", c),
+        }
+        assert_eq!(*r.bad_pieces.lock().unwrap(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_short_bitfield() -> Result<(), failure::Error> {
+        let (mut r, rx) = receiver(Metainfo::mock(1, 12));
+        r.bitfield(bitvec![1; 8])?;
+        assert!(matches!(rx.try_recv(), Ok(Command::BitFieldReceived)));
+        let availability = r.availability.lock().unwrap().clone();
+        assert_eq!(availability.len(), 12);
+        assert_eq!(availability.iter().filter(|b| *b).count(), 8);
+        assert!(!availability[11]);
+        r.have(11)?;
+        assert!(r.availability.lock().unwrap()[11]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsolicited_piece() -> Result<(), failure::Error> {
+        let (mut r, _rx) = receiver(Metainfo::mock(4, 8));
+        r.pending.lock().unwrap().insert(1);
+        r.piece(0, 0, vec![0; 4])?;
+        assert!(r.piece_buffer.is_empty());
+        assert_eq!(
+            r.protocol_log.drain(),
+            vec!["dropped unsolicited block for piece 0"]
+        );
+        assert_eq!(*r.num_downloaded.lock().unwrap(), 0);
+        assert!(r.store.read().unwrap().get(0).is_none());
+        // Requested pieces are still buffered
+        r.piece(1, 0, vec![0; 2])?;
+        assert!(r.piece_buffer.contains_key(&1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_choke_cleanup() -> Result<(), failure::Error> {
+        let (mut r, rx) = receiver(Metainfo::mock(4, 8));
+        r.pending.lock().unwrap().extend(0..2);
+        r.piece(0, 0, vec![0; 2])?;
+        r.piece(1, 0, vec![0; 2])?;
+        assert_eq!(r.piece_buffer.len(), 2);
+        assert_eq!(r.buffers.used(), 4);
+
+        r.choke(true)?;
+        assert!(matches!(rx.try_recv(), Ok(Command::PeerChoke(true))));
+        assert!(r.piece_buffer.is_empty());
+        assert!(r.pending.lock().unwrap().is_empty());
+        assert_eq!(r.buffers.used(), 0);
+
+        // Blocks still in flight when we were choked are not buffered
+        r.piece(0, 2, vec![0; 2])?;
+        assert!(r.piece_buffer.is_empty());
+        assert_eq!(r.buffers.used(), 0);
+        assert_eq!(*r.num_downloaded.lock().unwrap(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_limit() -> Result<(), failure::Error> {
+        let buffers = Arc::new(BufferAccount::new(6));
+        let (mut r1, _rx1) = receiver(Metainfo::mock(8, 16));
+        let (mut r2, _rx2) = receiver(Metainfo::mock(8, 16));
+        for r in [&mut r1, &mut r2].iter_mut() {
+            r.buffers = buffers.clone();
+            r.pending.lock().unwrap().extend(0..2);
+        }
+
+        r1.piece(0, 0, vec![0; 4])?;
+        assert_eq!(buffers.used(), 4);
+        // Over the global limit, so nothing is buffered
+        r2.piece(1, 0, vec![0; 4])?;
+        assert!(r2.piece_buffer.is_empty());
+        assert_eq!(buffers.used(), 4);
+
+        r1.choke(true)?;
+        assert_eq!(buffers.used(), 0);
+        r2.piece(1, 0, vec![0; 4])?;
+        assert_eq!(buffers.used(), 4);
+        Ok(())
+    }
 }
@@ -1,28 +1,71 @@
-use super::{Command, State};
+use super::tap::Tap;
+use super::{
+    reciprocation_bonus, Command, MessageCounts, ProtocolLog, ProtocolStrictness, State,
+    UploadSlots,
+};
 use crate::metainfo::Metainfo;
-use crate::peer::{Handshake, Message};
-use crate::storage::PieceStore;
+use crate::peer::{Handshake, Message, Reserved};
+use crate::storage::{PieceData, PieceStore};
 use bitvec::BitVec;
 use failure::Fail;
 use log::{self, debug, error, info, warn};
+use rand::seq::SliceRandom;
+use std::cmp::min;
 use std::collections::HashSet;
 use std::collections::VecDeque;
 use std::io::{self, BufWriter, Write};
-use std::net::{Shutdown, TcpStream};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::time;
 
-const QUEUE_LENGTH: usize = 5;
+pub(super) const QUEUE_LENGTH: usize = 5;
+// Pieces a peer that has not delivered anything yet may be asked for in one round
+const MIN_REQUEST_BUDGET: usize = 2;
+
+// How long a queued piece waits for an upload slot before commands are handled again
+const UPLOAD_SLOT_WAIT: time::Duration = time::Duration::from_millis(50);
+// How long to wait before asking the selector again after it ran short, unless a command arrives
+// that may have changed what there is to request
+const SELECTOR_BACKOFF: time::Duration = time::Duration::from_millis(500);
+// Write timeouts tolerated while flushing the handshake, since nothing happens until it arrives
+const HANDSHAKE_FLUSH_ATTEMPTS: usize = 3;
+// Time between Haves for pieces left out of the initial bitfield
+const REVEAL_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+// Clears all but cap of the set bits, chosen at random, returning the cleared pieces in the
+// random order they should be revealed in
+fn hide_pieces(bv: &mut BitVec, cap: usize) -> VecDeque<u32> {
+    let mut have: Vec<u32> = (0..bv.len()).filter(|&i| bv[i]).map(|i| i as u32).collect();
+    have.shuffle(&mut rand::thread_rng());
+    let hidden = have.split_off(min(cap, have.len()));
+    for &index in hidden.iter() {
+        bv.set(index as usize, false);
+    }
+    hidden.into()
+}
+
+// Number of new pieces to request from a peer in one round, growing with the number of pieces it
+// delivered since the last snapshot, so slow peers cannot claim many rare pieces at once. The
+// reciprocation bonus (in bytes) earns a request for every piece or part of one.
+fn request_budget(recently_downloaded: u64, bonus: u64, piece_length: u64, free: usize) -> usize {
+    let bonus = (bonus + piece_length - 1) / piece_length;
+    min(
+        free,
+        MIN_REQUEST_BUDGET + (recently_downloaded + bonus) as usize,
+    )
+}
 
 pub struct Piece {
     index: u32,
     begin: u32,
     length: u32,
-    data: Arc<Vec<u8>>,
+    data: PieceData,
 }
 
 impl Piece {
-    fn new(index: u32, begin: u32, length: u32, data: Arc<Vec<u8>>) -> Self {
+    fn new<D: Into<PieceData>>(index: u32, begin: u32, length: u32, data: D) -> Self {
+        let data = data.into();
         assert!(begin < data.len() as u32);
         assert!(begin + length <= data.len() as u32);
         Piece {
@@ -39,11 +82,12 @@ impl Into<Message> for Piece {
         // This special case is not required, but is an optimisation
         // Important because my client only requests full pieces, so this will be only case.
         // General case included for compatibility with other clients
-        if self.data.len() as u32 == self.length && self.begin == 0 {
-            return Message::Piece(self.index, self.begin, self.data);
+        if let PieceData::Memory(ref v) = self.data {
+            if v.len() as u32 == self.length && self.begin == 0 {
+                return Message::Piece(self.index, self.begin, v.clone());
+            }
         }
-        let mut v = Vec::with_capacity(self.length as usize);
-        v.copy_from_slice(&self.data[self.begin as usize..(self.begin + self.length) as usize]);
+        let v = self.data[self.begin as usize..(self.begin + self.length) as usize].to_vec();
         Message::Piece(self.index, self.begin, Arc::new(v))
     }
 }
@@ -58,18 +102,27 @@ pub enum SenderError {
     Shutdown,
     #[fail(display = "invalid piece request")]
     InvalidRequest,
+    #[fail(display = "write timed out")]
+    WriteTimeout,
+    #[fail(display = "peer and client are both seeds")]
+    BothSeeds,
 }
 
+// The sender only writes, so a timeout is always the peer not reading
 impl From<io::Error> for SenderError {
     fn from(e: io::Error) -> Self {
-        SenderError::IO(e)
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => SenderError::WriteTimeout,
+            _ => SenderError::IO(e),
+        }
     }
 }
 
 pub struct Sender {
     // Queues used to handle priority 1 messages
     pub requests: VecDeque<Message>,
-    pub pending: HashSet<u32>,
+    // Shared with the receiver to reject unsolicited pieces
+    pub pending: Arc<Mutex<HashSet<u32>>>,
     pub pieces: VecDeque<Piece>,
     // Command receiver
     pub rx: mpsc::Receiver<Command>,
@@ -86,77 +139,215 @@ pub struct Sender {
     // Client id - used for handshake
     pub client_id: Arc<String>,
     // Stream
-    pub writer: BufWriter<TcpStream>,
+    pub writer: BufWriter<Tap>,
     // Metrics exposed for seeding
     pub num_uploaded: Arc<Mutex<u64>>,
+    // Pieces received since the last snapshot, used to size request rounds
+    pub num_downloaded: Arc<Mutex<u64>>,
+    // Piece payload received since the last snapshot, shared with the receiver
+    pub downloaded_bytes: Arc<Mutex<u64>>,
+    // Request rounds since the last snapshot that found nothing to request
+    pub num_starved: Arc<Mutex<u64>>,
+    // Piece payload sent
+    pub uploaded_bytes: Arc<Mutex<u64>>,
+    // Shared liveness of the connection
+    pub alive: Arc<AtomicBool>,
+    // Skip sending the handshake
+    pub handshaked: bool,
+    // Port of our DHT node, if we run one
+    pub dht_port: Option<u16>,
+    pub protocol_log: Arc<ProtocolLog>,
+    // Pieces we had when registering with the store, sent after the handshake
+    pub bitfield: BitVec,
+    // When the peer last sent a block, or we started waiting for one
+    pub last_delivery: Arc<Mutex<time::Instant>>,
+    pub snub_timeout: Option<time::Duration>,
+    pub message_counts: Arc<MessageCounts>,
+    // Pieces served even while the peer is choked
+    pub allowed_fast: Arc<HashSet<u32>>,
+    pub uploads: Arc<UploadSlots>,
+    // Pieces sent per loop iteration before flushing
+    pub upload_batch: usize,
+    pub strictness: ProtocolStrictness,
+    // When the selector last returned fewer pieces than asked for
+    pub exhausted: Option<time::Instant>,
+    // Most pieces advertised in the initial bitfield, the rest are revealed one at a time, so
+    // peers cannot tell how much we have straight away
+    pub bitfield_cap: Option<usize>,
+    // Pieces left out of the bitfield and not yet revealed
+    pub hidden: VecDeque<u32>,
+    pub last_reveal: time::Instant,
 }
 
 impl Sender {
     fn _start(&mut self) -> Result<(), SenderError> {
-        Handshake::send(
-            &self.metainfo.info_hash().unwrap(),
-            Some(self.client_id.as_bytes()),
-            self.writer.by_ref(),
-        )?;
+        if !self.handshaked {
+            let mut reserved = Reserved::default();
+            reserved.set_dht(self.dht_port.is_some());
+            Handshake::send(
+                &self.metainfo.info_hash().unwrap(),
+                Some(self.client_id.as_bytes()),
+                reserved,
+                self.writer.by_ref(),
+            )?;
+        }
 
-        let bv = { self.store.read().unwrap().as_bitvec(false) };
-        self.send(Message::BitField(bv))?;
+        self.send_bitfield()?;
+        // Peers may wait for our handshake before sending theirs, so it cannot sit in the buffer
+        // while the loop blocks on anything
+        self.flush_handshake()?;
 
-        'main: loop {
-            self.handle_commands()?;
-            self.writer.flush()?;
+        loop {
+            self.step()?;
+        }
+    }
+
+    // The bitfield only ever leaves pieces out, so it is never wrong about a piece it claims.
+    // Hidden pieces are still served if requested.
+    fn send_bitfield(&mut self) -> Result<(), SenderError> {
+        let mut bv = std::mem::replace(&mut self.bitfield, BitVec::new());
+        if let Some(cap) = self.bitfield_cap {
+            self.hidden = hide_pieces(&mut bv, cap);
+            self.last_reveal = time::Instant::now();
+        }
+        self.send(Message::BitField(bv))
+    }
+
+    // Advertises the next hidden piece once REVEAL_INTERVAL has passed since the last
+    fn reveal(&mut self) -> Result<(), SenderError> {
+        if self.hidden.is_empty() || self.last_reveal.elapsed() < REVEAL_INTERVAL {
+            return Ok(());
+        }
+        self.last_reveal = time::Instant::now();
+        match self.hidden.pop_front() {
+            Some(index) => self.handle_client_have(index),
+            None => Ok(()),
+        }
+    }
 
-            match self.requests.pop_front() {
-                Some(msg) => {
-                    self.send(msg)?;
+    fn flush_handshake(&mut self) -> Result<(), SenderError> {
+        let mut attempts = 1;
+        loop {
+            match self.writer.flush().map_err(SenderError::from) {
+                Err(SenderError::WriteTimeout) if attempts < HANDSHAKE_FLUSH_ATTEMPTS => {
+                    warn!("Peer {}: handshake flush timed out, retrying", self.peer_id);
+                    attempts += 1;
                 }
-                None => {}
+                res => return res,
             }
-            if self.pending.len() <= QUEUE_LENGTH / 2 {
-                debug!("Queue pieces triggered by queue length");
-                self.queue_pieces()?;
+        }
+    }
+
+    // One iteration of the message loop. Everything written is flushed once at the end, or with
+    // the batch of pieces, rather than after each message, so small messages share segments.
+    fn step(&mut self) -> Result<(), SenderError> {
+        self.handle_commands()?;
+        self.check_snubbing()?;
+        self.reveal()?;
+
+        match self.requests.pop_front() {
+            Some(msg) => {
+                self.send(msg)?;
             }
+            None => {}
+        }
+        if self.num_pending() <= QUEUE_LENGTH / 2 {
+            debug!("Queue pieces triggered by queue length");
+            self.queue_pieces()?;
+        }
+
+        self.handle_commands()?;
 
-            self.handle_commands()?;
+        let sent = if self.pieces.is_empty() {
+            0
+        } else {
+            self.send_pieces()?
+        };
+        if sent == 0 {
             self.writer.flush()?;
+        }
 
-            match self.pieces.pop_front() {
-                Some(piece) => self.send(piece.into())?,
-                None => {}
+        if self.requests.len() == 0 && self.pieces.len() == 0 {
+            // Nothing is left in the buffer, so block until there is something to send
+            match self.rx.recv_timeout(self.idle_timeout()) {
+                Ok(cmd) => self.handle(cmd)?,
+                Err(mpsc::RecvTimeoutError::Timeout) => self.send(Message::KeepAlive)?,
+                Err(_) => return Err(SenderError::Shutdown),
             }
+        }
+        Ok(())
+    }
 
-            if self.requests.len() == 0 && self.pieces.len() == 0 {
-                loop {
-                    match self.rx.recv_timeout(time::Duration::from_secs(90)) {
-                        Ok(cmd) => {
-                            self.handle(cmd)?;
-                            continue 'main;
-                        }
-                        Err(mpsc::RecvTimeoutError::Timeout) => self.send(Message::KeepAlive)?,
-                        Err(_) => return Err(SenderError::Shutdown),
-                    }
-                }
+    // Sends up to upload_batch queued pieces with a single flush, returning how many were sent.
+    // The batch takes one upload slot, held until the pieces have left the write buffer.
+    fn send_pieces(&mut self) -> Result<usize, SenderError> {
+        if !self.uploads.acquire(time::Duration::from_secs(0)) {
+            // Messages already written must not wait for a slot to free up
+            self.writer.flush()?;
+            if !self.uploads.acquire(UPLOAD_SLOT_WAIT) {
+                return Ok(0);
             }
         }
+        let res = self._send_pieces();
+        self.uploads.release();
+        res
+    }
+
+    fn _send_pieces(&mut self) -> Result<usize, SenderError> {
+        let mut sent = 0;
+        while sent < self.upload_batch {
+            // Commands are still handled between pieces, so a choke stops the batch
+            if sent > 0 {
+                self.handle_commands()?;
+            }
+            let piece = match self.pieces.pop_front() {
+                Some(piece) => piece,
+                None => break,
+            };
+            *self.uploaded_bytes.lock().unwrap() += piece.length as u64;
+            self.send(piece.into())?;
+            sent += 1;
+        }
+        self.writer.flush()?;
+        Ok(sent)
     }
 
     fn send(&mut self, msg: Message) -> Result<(), SenderError> {
         msg.send(self.writer.by_ref())?;
+        self.message_counts.record_sent(msg.kind());
         Ok(())
     }
 
     fn handle(&mut self, cmd: Command) -> Result<(), SenderError> {
+        // Only the peer having more, released pieces or an unchoke make more pieces requestable
+        match cmd {
+            Command::PeerHave(_)
+            | Command::BitFieldReceived
+            | Command::Needed
+            | Command::Reprioritize
+            | Command::PeerChoke(false) => self.exhausted = None,
+            _ => {}
+        }
         match cmd {
             Command::Ping => {}
             Command::Shutdown => return Err(SenderError::Channel),
             Command::ClientHave(index) => self.handle_client_have(index)?,
             Command::PeerHave(index) => self.handle_peer_have(index)?,
-            Command::BitFieldReceived => self.handle_bitfield()?,
+            Command::BitFieldReceived | Command::Needed => self.handle_bitfield()?,
+            Command::BecomeSeed => self.handle_become_seed()?,
+            Command::PeerBecameSeed => self.handle_peer_became_seed()?,
+            Command::SendMessage(msg) => self.handle_send_message(msg)?,
+            Command::Reprioritize => self.handle_reprioritize()?,
             Command::Choke(b) => self.handle_client_choke(b)?,
             Command::PeerChoke(b) => self.handle_peer_choke(b)?,
             Command::SendChunk(index, begin, length) => {
                 self.handle_send_chunk(index, begin, length)?
             }
+            Command::PeerDht => {
+                if let Some(port) = self.dht_port {
+                    self.send(Message::Port(port))?;
+                }
+            }
         }
         Ok(())
     }
@@ -191,11 +382,15 @@ impl Sender {
             // Peer doesn't have piece
             self.send(Message::Have(index))?;
         }
-        self.pending.remove(&index);
+        self.pending.lock().unwrap().remove(&index);
         Ok(())
     }
 
     fn handle_peer_have(&mut self, index: u32) -> Result<(), SenderError> {
+        self.store
+            .write()
+            .unwrap()
+            .peer_known(self.peer_id.as_str());
         let needed = { self.store.read().unwrap().check_if_needed(index) };
         if needed {
             self.interested(true)?;
@@ -204,6 +399,10 @@ impl Sender {
     }
 
     fn handle_bitfield(&mut self) -> Result<(), SenderError> {
+        self.store
+            .write()
+            .unwrap()
+            .peer_known(self.peer_id.as_str());
         let mut needed = !self.store.read().unwrap().as_bitvec(true);
         needed &= self.availability.lock().unwrap().iter();
         if needed.iter().filter(|b| *b).take(1).next().is_some() {
@@ -212,6 +411,103 @@ impl Sender {
         Ok(())
     }
 
+    // Nothing more is wanted from the peer, so outstanding requests are dropped and it is told
+    // we are no longer interested. Pieces needed again later make us interested as usual.
+    fn handle_become_seed(&mut self) -> Result<(), SenderError> {
+        if self.state.read().unwrap().peer_seed {
+            info!("Peer {}: both seeds, disconnecting", self.peer_id);
+            return Err(SenderError::BothSeeds);
+        }
+        self.pending.lock().unwrap().clear();
+        self.requests.retain(|m| match m {
+            Message::Request(..) => false,
+            _ => true,
+        });
+        self.interested(false)
+    }
+
+    // Two seeds have nothing to exchange, so the connection is closed to free the slot for a
+    // peer we can trade with
+    fn handle_peer_became_seed(&mut self) -> Result<(), SenderError> {
+        if self.store.read().unwrap().is_complete() {
+            info!("Peer {}: both seeds, disconnecting", self.peer_id);
+            return Err(SenderError::BothSeeds);
+        }
+        Ok(())
+    }
+
+    // Choking, interest and the bitfield are left to the connection, so that its state matches
+    // what the peer was told, and pieces are only sent to unchoked peers
+    fn handle_send_message(&mut self, msg: Message) -> Result<(), SenderError> {
+        let allowed = match msg {
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::BitField(_) => false,
+            Message::Piece(..) => !self.state.read().unwrap().client_choked,
+            _ => true,
+        };
+        if !allowed {
+            warn!("Peer {}: Not sending raw {:?}", self.peer_id, msg);
+            return Ok(());
+        }
+        self.send(msg)
+    }
+
+    // The same number of pieces are chosen again as if none were requested. Pieces still chosen
+    // keep their requests, the rest are cancelled, or never sent if still queued.
+    fn handle_reprioritize(&mut self) -> Result<(), SenderError> {
+        let old: HashSet<u32> = self.pending.lock().unwrap().drain().collect();
+        if old.is_empty() {
+            return Ok(());
+        }
+        let chosen: HashSet<u32> = {
+            let mut store = self.store.write().unwrap();
+            store.clear_requests(self.peer_id.as_str());
+            store
+                .request_pieces(
+                    self.peer_id.as_str(),
+                    self.availability.lock().unwrap().clone(),
+                    old.len() as u32,
+                )
+                .unwrap_or_default()
+                .into_iter()
+                .collect()
+        };
+        let mut queued = HashSet::new();
+        self.requests.retain(|m| match m {
+            Message::Request(index, ..) => {
+                queued.insert(*index);
+                chosen.contains(index)
+            }
+            _ => true,
+        });
+        for index in old.difference(&chosen) {
+            if !queued.contains(index) {
+                let length = self.metainfo.get_piece_size(*index);
+                self.send(Message::Cancel(*index, 0, length))?;
+            }
+        }
+        let mut added: Vec<_> = chosen.difference(&old).cloned().collect();
+        added.sort();
+        for index in added {
+            self.requests.push_back(Message::Request(
+                index,
+                0,
+                self.metainfo.get_piece_size(index),
+            ));
+        }
+        debug!(
+            "Peer {}: reprioritized, cancelled {} of {} requests",
+            self.peer_id,
+            old.difference(&chosen).count(),
+            old.len()
+        );
+        self.pending.lock().unwrap().extend(chosen);
+        Ok(())
+    }
+
     fn handle_client_choke(&mut self, choke: bool) -> Result<(), SenderError> {
         {
             let mut s = self.state.write().unwrap();
@@ -219,7 +515,8 @@ impl Sender {
             info!("Peer {}: {:?}", self.peer_id, *s);
         }
         if choke {
-            self.pieces.clear();
+            let allowed_fast = &self.allowed_fast;
+            self.pieces.retain(|p| allowed_fast.contains(&p.index));
             self.send(Message::Choke)?;
         } else {
             self.send(Message::Unchoke)?;
@@ -230,7 +527,7 @@ impl Sender {
     fn handle_peer_choke(&mut self, choke: bool) -> Result<(), SenderError> {
         // On Choke, drop all queued and pending requests
         if choke {
-            self.pending.clear();
+            self.pending.lock().unwrap().clear();
             self.requests.clear();
             self.store
                 .write()
@@ -248,33 +545,139 @@ impl Sender {
         begin: u32,
         length: u32,
     ) -> Result<(), SenderError> {
-        if index >= self.metainfo.num_pieces()
-            || begin + length > self.metainfo.get_piece_size(index)
-        {
-            return Err(SenderError::InvalidRequest);
+        if index >= self.metainfo.num_pieces() {
+            if self.strictness == ProtocolStrictness::Strict {
+                return Err(SenderError::InvalidRequest);
+            }
+            self.protocol_log.record(
+                &self.peer_id,
+                format!("ignored request for piece {}", index),
+            );
+            return Ok(());
         }
-        let piece = match self.store.read().unwrap().get(index) {
-            Some(v) => v,
-            None => return Err(SenderError::InvalidRequest),
+        // Out of range requests are dropped and recorded rather than closing the connection
+        if begin.checked_add(length).map_or(true, |end| {
+            end > self.metainfo.get_piece_size(index) || begin >= end
+        }) {
+            self.protocol_log.record(
+                &self.peer_id,
+                format!(
+                    "rejected request for {} bytes at {} of piece {}",
+                    length, begin, index
+                ),
+            );
+            return Ok(());
+        }
+        // A piece being verified is served rather than treated as missing
+        let piece = {
+            let store = self.store.read().unwrap();
+            match store.get_data(index) {
+                Some(v) => v,
+                // The peer was told we have it before it failed verification
+                None if store.is_withdrawn(index) => {
+                    debug!(
+                        "Refused request from {} for bad piece {}",
+                        self.peer_id, index
+                    );
+                    return Ok(());
+                }
+                None => return Err(SenderError::InvalidRequest),
+            }
         };
         self.pieces
             .push_back(Piece::new(index, begin, length, piece));
         Ok(())
     }
 
+    fn num_pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    // Wake up in time to notice a peer snubbing us while we wait for its blocks
+    fn idle_timeout(&self) -> time::Duration {
+        let keep_alive = time::Duration::from_secs(90);
+        let timeout = match self.snub_timeout {
+            Some(t) if self.num_pending() > 0 => min(t, keep_alive),
+            _ => keep_alive,
+        };
+        if self.hidden.is_empty() {
+            timeout
+        } else {
+            min(timeout, REVEAL_INTERVAL)
+        }
+    }
+
+    // A peer that has not sent a block for snub_timeout while our requests are outstanding is
+    // snubbing us. Its requests are handed to other peers, and nothing more is requested from it
+    // until it delivers again.
+    fn check_snubbing(&mut self) -> Result<(), SenderError> {
+        let timeout = match self.snub_timeout {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        if self.num_pending() == 0
+            || self.state.read().unwrap().snubbing
+            || self.last_delivery.lock().unwrap().elapsed() < timeout
+        {
+            return Ok(());
+        }
+        info!("Peer {}: snubbing us, requesting elsewhere", self.peer_id);
+        self.state.write().unwrap().snubbing = true;
+        self.pending.lock().unwrap().clear();
+        self.requests.clear();
+        self.store
+            .write()
+            .unwrap()
+            .release_requests(self.peer_id.as_str());
+        Ok(())
+    }
+
     pub fn queue_pieces(&mut self) -> Result<(), SenderError> {
         let state = self.state.read().unwrap().clone();
-        if state.client_interested && !state.peer_choked && self.pending.len() <= QUEUE_LENGTH / 2 {
-            debug!("Requesting {} pieces", QUEUE_LENGTH - self.pending.len());
-            let res = self.store.write().unwrap().request_pieces(
+        let num_pending = self.num_pending();
+        if state.client_interested
+            && !state.peer_choked
+            && !state.snubbing
+            && num_pending <= QUEUE_LENGTH / 2
+            && self
+                .exhausted
+                .map_or(true, |t| t.elapsed() >= SELECTOR_BACKOFF)
+        {
+            let budget = request_budget(
+                *self.num_downloaded.lock().unwrap(),
+                reciprocation_bonus(*self.downloaded_bytes.lock().unwrap(), !state.client_choked),
+                u64::from(self.metainfo.get_piece_size(0)),
+                QUEUE_LENGTH - num_pending,
+            );
+            debug!("Requesting {} pieces", budget);
+            // The selector runs under a read lock, so the store is only locked for writing
+            // while the chosen pieces are marked
+            let availability = self.availability.lock().unwrap().clone();
+            let selected = self.store.read().unwrap().select_pieces(
+                self.peer_id.as_str(),
+                &availability,
+                budget as u32,
+            );
+            let res = self.store.write().unwrap().claim_pieces(
                 self.peer_id.as_str(),
-                self.availability.lock().unwrap().clone(),
-                (QUEUE_LENGTH - self.pending.len()) as u32,
+                availability,
+                selected,
             );
+            if res.as_ref().map_or(true, |v| v.is_empty()) {
+                *self.num_starved.lock().unwrap() += 1;
+            }
+            self.exhausted = match res {
+                Ok(ref v) if v.len() >= budget => None,
+                _ => Some(time::Instant::now()),
+            };
             match res {
                 Ok(v) => {
+                    if num_pending == 0 {
+                        // Start timing the peer's response from these requests
+                        *self.last_delivery.lock().unwrap() = time::Instant::now();
+                    }
+                    self.pending.lock().unwrap().extend(v.iter());
                     for element in v.iter() {
-                        self.pending.insert(*element);
                         self.requests.push_back(Message::Request(
                             *element,
                             0,
@@ -283,7 +686,7 @@ impl Sender {
                     }
                 }
                 _ => {
-                    if self.pending.len() == 0 {
+                    if num_pending == 0 {
                         self.interested(false)?;
                     }
                 }
@@ -297,20 +700,517 @@ impl Sender {
             Err(e) => warn!("{}: {}", self.peer_id, e),
             _ => unreachable!(),
         }
+        self.alive.store(false, Ordering::SeqCst);
         {
-            self.store
-                .write()
-                .unwrap()
-                .clear_requests(self.peer_id.as_str());
+            let mut store = self.store.write().unwrap();
+            store.clear_requests(self.peer_id.as_str());
+            store.peer_left(self.peer_id.as_str());
+        }
+
+        // Attempt to deliver buffered messages, but close the TCP connection even if that fails
+        if let Err(e) = self.writer.flush() {
+            warn!("{}: Failed to flush final messages: {}", self.peer_id, e);
+        }
+        if let Err(e) = self.writer.get_ref().get_ref().shutdown(Shutdown::Both) {
+            error!("{}: {}", self.peer_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::BLOCK_SIZE;
+    use crate::selection::{Inorder, Selector, State as SelectorState, Stream};
+    use crate::storage::Sink;
+    use bitvec::bitvec;
+    use matches::matches;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn test_request_budget() {
+        assert_eq!(request_budget(0, 0, 16, QUEUE_LENGTH), MIN_REQUEST_BUDGET);
+        assert_eq!(
+            request_budget(1, 0, 16, QUEUE_LENGTH),
+            MIN_REQUEST_BUDGET + 1
+        );
+        assert_eq!(request_budget(100, 0, 16, QUEUE_LENGTH), QUEUE_LENGTH);
+        assert_eq!(request_budget(100, 0, 16, 1), 1);
+        assert_eq!(request_budget(0, 0, 16, 0), 0);
+    }
+
+    #[test]
+    fn test_request_budget_reciprocation() {
+        let budget = |bytes, reciprocating| {
+            request_budget(
+                0,
+                reciprocation_bonus(bytes, reciprocating),
+                16,
+                QUEUE_LENGTH,
+            )
+        };
+        // Nothing received, nothing earned
+        assert_eq!(budget(0, true), MIN_REQUEST_BUDGET);
+        // Part of a piece of bonus still earns a request
+        assert_eq!(budget(16, true), MIN_REQUEST_BUDGET + 1);
+        assert_eq!(budget(16, false), MIN_REQUEST_BUDGET);
+        // The bonus grows with the bytes received
+        assert!(budget(128, true) > budget(16, true));
+        assert_eq!(budget(1024, true), QUEUE_LENGTH);
+    }
+
+    // Serving throughput of a read and a memory mapped seed, without the network: every block is
+    // fetched from the store and turned into a message, as handle_send_chunk and send_pieces do.
+    // Run with --ignored.
+    #[test]
+    #[ignore]
+    fn bench_mapped_upload() -> Result<(), failure::Error> {
+        let piece_length = 16 * BLOCK_SIZE as usize;
+        let pieces = 64;
+        let rounds = 10;
+        let metainfo = Metainfo::mock(piece_length, pieces * piece_length);
+        let path = std::env::temp_dir().join("continuity_bench_mapped_upload");
+        std::fs::write(&path, vec![7; pieces * piece_length])?;
+        for &mapped in [false, true].iter() {
+            let mut store = PieceStore::new(&metainfo, Box::new(Inorder::default()));
+            match mapped {
+                true => store.bootstrap_mapped(&metainfo, &path, false)?,
+                false => store.bootstrap_with(&metainfo, &path, false)?,
+            }
+            let mut sent = 0;
+            let start = time::Instant::now();
+            for _ in 0..rounds {
+                for index in 0..pieces as u32 {
+                    for begin in (0..piece_length as u32).step_by(BLOCK_SIZE as usize) {
+                        let data = store.get_data(index).unwrap();
+                        let message: Message = Piece::new(index, begin, BLOCK_SIZE, data).into();
+                        message.send(io::sink())?;
+                        sent += BLOCK_SIZE as usize;
+                    }
+                }
+            }
+            let elapsed = start.elapsed();
+            assert_eq!(sent, rounds * pieces * piece_length);
+            info!(
+                "mapped {}: {:.1} MiB/s",
+                mapped,
+                sent as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0)
+            );
+        }
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    fn sender(stream: TcpStream, rx: mpsc::Receiver<Command>) -> Sender {
+        let metainfo = Arc::new(Metainfo::mock(4, 8));
+        Sender {
+            requests: VecDeque::new(),
+            pending: Arc::new(Mutex::new(HashSet::new())),
+            pieces: VecDeque::new(),
+            rx,
+            state: Arc::new(RwLock::new(State::default())),
+            store: Arc::new(RwLock::new(PieceStore::new(
+                &metainfo,
+                Box::new(Inorder::default()),
+            ))),
+            availability: Arc::new(Mutex::new(BitVec::new())),
+            metainfo,
+            peer_id: Arc::new("peer".to_owned()),
+            client_id: Arc::new("client".to_owned()),
+            writer: BufWriter::new(stream.into()),
+            num_uploaded: Arc::new(Mutex::new(0)),
+            num_downloaded: Arc::new(Mutex::new(0)),
+            downloaded_bytes: Arc::new(Mutex::new(0)),
+            num_starved: Arc::new(Mutex::new(0)),
+            uploaded_bytes: Arc::new(Mutex::new(0)),
+            alive: Arc::new(AtomicBool::new(true)),
+            handshaked: false,
+            dht_port: None,
+            protocol_log: Arc::new(ProtocolLog::default()),
+            bitfield: BitVec::new(),
+            last_delivery: Arc::new(Mutex::new(time::Instant::now())),
+            snub_timeout: None,
+            message_counts: Arc::new(MessageCounts::default()),
+            allowed_fast: Arc::new(HashSet::new()),
+            uploads: Arc::new(UploadSlots::default()),
+            upload_batch: 1,
+            strictness: ProtocolStrictness::Strict,
+            exhausted: None,
+            bitfield_cap: None,
+            hidden: VecDeque::new(),
+            last_reveal: time::Instant::now(),
+        }
+    }
+
+    #[test]
+    fn test_shutdown_after_write_error() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let _remote = listener.accept()?;
+        let mut local = stream.try_clone()?;
+        let (tx, rx) = mpsc::channel();
+        let sender = sender(stream, rx);
+        // The handshake and bitfield are still buffered when the channel closes, and flushing
+        // them fails
+        drop(tx);
+        local.shutdown(Shutdown::Write)?;
+        sender.start();
+
+        // The read half was closed too, so this does not block
+        local.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+        assert_eq!(local.read(&mut [0; 1])?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_upload_batch() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (mut remote, _) = listener.accept()?;
+        remote.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+        let (tx, rx) = mpsc::channel();
+        let mut s = sender(stream, rx);
+        let data = Arc::new(vec![7; 100]);
+        let queue = |s: &mut Sender| {
+            for i in 0..10 {
+                s.pieces.push_back(Piece::new(i % 4, 0, 100, data.clone()));
+            }
+        };
+
+        // Length prefix, id, index and begin before each block
+        let message_len = 13 + data.len();
+        queue(&mut s);
+        for _ in 0..10 {
+            assert_eq!(s.send_pieces()?, 1);
+        }
+        s.upload_batch = 4;
+        queue(&mut s);
+        assert_eq!(s.send_pieces()?, 4);
+        // Each batch is flushed as a whole
+        let mut buf = vec![0; 14 * message_len];
+        remote.read_exact(&mut buf)?;
+        assert_eq!(s.send_pieces()?, 4);
+        assert_eq!(s.send_pieces()?, 2);
+        assert_eq!(s.send_pieces()?, 0);
+        assert_eq!(s.uploads.in_flight(), 0);
+        assert_eq!(*s.uploaded_bytes.lock().unwrap(), 20 * 100);
+
+        // A choke arriving mid batch drops the rest of the queue
+        queue(&mut s);
+        tx.send(Command::Choke(true))?;
+        assert_eq!(s.send_pieces()?, 1);
+        assert!(s.pieces.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_per_step() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (mut remote, _) = listener.accept()?;
+        remote.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+        let (_tx, rx) = mpsc::channel();
+        let mut s = sender(stream, rx);
+        s.upload_batch = 4;
+        let request_len = 17;
+        for i in 0..2 {
+            s.requests.push_back(Message::Request(i, 0, 4));
+        }
+
+        // Without pieces, the request is flushed on its own
+        s.step()?;
+        remote.read_exact(&mut vec![0; request_len])?;
+
+        // Otherwise it goes out with the batch
+        let data = Arc::new(vec![7; 100]);
+        for i in 0..5 {
+            s.pieces.push_back(Piece::new(i % 4, 0, 100, data.clone()));
+        }
+        s.step()?;
+        remote.read_exact(&mut vec![0; request_len + 4 * (13 + data.len())])?;
+        assert_eq!(s.pieces.len(), 1);
+        Ok(())
+    }
+
+    struct Counting(Arc<AtomicUsize>);
+
+    impl Selector for Counting {
+        fn request_pieces(&mut self, _: &str, _: SelectorState, _: u32) -> Vec<u32> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn test_selector_backoff() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let _remote = listener.accept()?;
+        let (_tx, rx) = mpsc::channel();
+        let mut s = sender(stream, rx);
+        let queries = Arc::new(AtomicUsize::new(0));
+        s.store
+            .write()
+            .unwrap()
+            .set_selector(Box::new(Counting(queries.clone())));
+        *s.availability.lock().unwrap() = bitvec![1; 2];
+        {
+            let mut state = s.state.write().unwrap();
+            state.client_interested = true;
+            state.peer_choked = false;
+        }
+        // Still waiting on a piece, so the peer stays interesting
+        s.pending.lock().unwrap().insert(0);
+
+        for _ in 0..5 {
+            s.queue_pieces()?;
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        // Other commands leave the backoff in place
+        s.handle(Command::Ping)?;
+        s.queue_pieces()?;
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        // A have from the peer may make something requestable
+        s.handle(Command::PeerHave(1))?;
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        s.queue_pieces()?;
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        s.exhausted = Some(time::Instant::now() - SELECTOR_BACKOFF);
+        s.queue_pieces()?;
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_handshake_flushed() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (mut remote, _) = listener.accept()?;
+        remote.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+        let (tx, rx) = mpsc::channel();
+        let mut s = sender(stream, rx);
+        s.bitfield = bitvec![0; 2];
+        // Protocol string, reserved bytes and info hash, then the client id
+        let mut handshake = vec![0; 48 + s.client_id.len()];
+        let handle = thread::spawn(move || s.start());
+
+        // The peer only answers once our handshake and bitfield arrive, while the sender waits
+        // for commands
+        remote.read_exact(&mut handshake)?;
+        assert_eq!(&handshake[1..20], b"BitTorrent Protocol");
+        assert!(matches!(Message::recv(&mut remote)?, Message::BitField(_)));
+        drop(tx);
+        handle.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitfield_cap() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (mut remote, _) = listener.accept()?;
+        remote.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+        let (_tx, rx) = mpsc::channel();
+        let mut s = sender(stream, rx);
+        s.availability = Arc::new(Mutex::new(bitvec![0; 4]));
+        s.bitfield = bitvec![1, 1, 0, 1];
+        s.bitfield_cap = Some(0);
+        s.send_bitfield()?;
+        s.writer.flush()?;
+        match Message::recv(&mut remote)? {
+            Message::BitField(bv) => assert!(bv.iter().all(|b| !b), "{:?}", bv),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        // Nothing until the interval passes, then one piece at a time
+        s.reveal()?;
+        assert_eq!(s.hidden.len(), 3);
+        let mut revealed = Vec::new();
+        while !s.hidden.is_empty() {
+            s.last_reveal -= REVEAL_INTERVAL;
+            s.reveal()?;
+            s.writer.flush()?;
+            match Message::recv(&mut remote)? {
+                Message::Have(index) => revealed.push(index),
+                msg => panic!("unexpected message {:?}", msg),
+            }
         }
+        revealed.sort();
+        assert_eq!(revealed, vec![0, 1, 3]);
 
-        // Attempt to close TCP connection
-        match self.writer.into_inner() {
-            Err(e) => error!("{}: {}", self.peer_id, e),
-            Ok(s) => match s.shutdown(Shutdown::Both) {
-                Ok(_) => {}
-                Err(e) => error!("{}: {}", self.peer_id, e),
-            },
+        let mut bv = bitvec![1; 4];
+        assert_eq!(hide_pieces(&mut bv, 3).len(), 1);
+        assert_eq!(bv.iter().filter(|b| *b).count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reprioritize() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (mut remote, _) = listener.accept()?;
+        remote.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+        let (_tx, rx) = mpsc::channel();
+        let mut s = sender(stream, rx);
+        s.metainfo = Arc::new(Metainfo::mock(4, 40));
+        s.store = Arc::new(RwLock::new(PieceStore::new(
+            &s.metainfo,
+            Box::new(Inorder::default()),
+        )));
+        *s.availability.lock().unwrap() = bitvec![1; 10];
+        let v = s
+            .store
+            .write()
+            .unwrap()
+            .request_pieces("peer", bitvec![1; 10], 4)
+            .unwrap();
+        assert_eq!(v, vec![0, 1, 2, 3]);
+        s.pending.lock().unwrap().extend(v);
+        // Requests for 0 and 2 were already sent
+        s.requests.push_back(Message::Request(1, 0, 4));
+        s.requests.push_back(Message::Request(3, 0, 4));
+
+        s.store
+            .write()
+            .unwrap()
+            .reprioritize(Box::new(Stream::new(2)));
+        s.handle(Command::Reprioritize)?;
+        s.writer.flush()?;
+        assert!(matches!(
+            Message::recv(&mut remote)?,
+            Message::Cancel(2, 0, 4)
+        ));
+        let queued: Vec<_> = s
+            .requests
+            .iter()
+            .map(|m| match m {
+                Message::Request(index, _, _) => *index,
+                m => panic!("unexpected message {:?}", m),
+            })
+            .collect();
+        assert_eq!(queued, vec![1, 8, 9]);
+        let mut pending: Vec<_> = s.pending.lock().unwrap().iter().cloned().collect();
+        pending.sort();
+        assert_eq!(pending, vec![0, 1, 8, 9]);
+        // The cancelled pieces can be requested from other peers
+        let requested = s.store.read().unwrap().as_bitvec(true);
+        assert!(!requested[2] && !requested[3] && requested[8]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_timeout() -> Result<(), failure::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        // Never read from, so the socket buffers fill up
+        let _remote = listener.accept()?;
+        stream.set_write_timeout(Some(time::Duration::from_millis(200)))?;
+        let (_tx, rx) = mpsc::channel();
+        let mut s = sender(stream, rx);
+        let data = Arc::new(vec![7; 1 << 20]);
+        let start = time::Instant::now();
+        let e = loop {
+            assert!(start.elapsed() < time::Duration::from_secs(30));
+            s.pieces
+                .push_back(Piece::new(0, 0, data.len() as u32, data.clone()));
+            if let Err(e) = s.send_pieces() {
+                break e;
+            }
+        };
+        assert!(matches!(e, SenderError::WriteTimeout), "{:?}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_past_last_piece() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_tx, rx) = mpsc::channel();
+        let mut s = sender(stream, rx);
+        assert!(matches!(
+            s.handle_send_chunk(99, 0, 1),
+            Err(SenderError::InvalidRequest)
+        ));
+        s.strictness = ProtocolStrictness::Permissive;
+        assert!(s.handle_send_chunk(99, 0, 1).is_ok());
+        assert!(s.pieces.is_empty());
+        assert_eq!(s.protocol_log.drain(), vec!["ignored request for piece 99"]);
+    }
+
+    #[test]
+    fn test_request_while_verifying() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_tx, rx) = mpsc::channel();
+        let mut s = sender(stream, rx);
+        assert!(matches!(
+            s.handle_send_chunk(1, 0, 2),
+            Err(SenderError::InvalidRequest)
+        ));
+
+        // Received in full but not yet hashed
+        let piece = Arc::new(vec![b'a'; 2]);
+        s.store.write().unwrap().set_verifying(1, piece.clone());
+        assert!(s.store.read().unwrap().get(1).is_none());
+        assert!(s.handle_send_chunk(1, 0, 2).is_ok());
+        let msg: Message = s.pieces.pop_front().unwrap().into();
+        assert_eq!(msg, Message::Piece(1, 0, piece.clone()));
+
+        // Gone once it fails verification
+        s.store.write().unwrap().discard_unverified(1, &piece);
+        assert!(s.handle_send_chunk(1, 0, 2).is_err());
+    }
+
+    #[test]
+    fn test_request_after_failed_recheck() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_tx, rx) = mpsc::channel();
+        let mut s = sender(stream, rx);
+        s.store
+            .write()
+            .unwrap()
+            .set_sink(Sink::Seekable(Box::new(io::Cursor::new(Vec::new()))));
+        // Does not match the mock hash, so it fails when everything is checked again
+        s.store
+            .write()
+            .unwrap()
+            .store("seed", 1, Arc::new(vec![b'a'; 4]));
+        assert_eq!(PieceStore::verify_stored(&s.store, &s.metainfo), vec![1]);
+
+        // Refused without closing the connection
+        assert!(s.handle_send_chunk(1, 0, 2).is_ok());
+        assert!(s.pieces.is_empty());
+    }
+
+    #[test]
+    fn test_piece_sub_ranges() {
+        // Short final piece, not a multiple of the block size
+        let data: Arc<Vec<u8>> = Arc::new((0..2 * BLOCK_SIZE + 100).map(|i| i as u8).collect());
+        let ranges = vec![
+            (0, data.len() as u32),
+            (0, BLOCK_SIZE),
+            (BLOCK_SIZE, BLOCK_SIZE),
+            (2 * BLOCK_SIZE, 100),
+            (BLOCK_SIZE - 10, 20),
+            (data.len() as u32 - 1, 1),
+        ];
+        for (begin, length) in ranges {
+            let msg: Message = Piece::new(3, begin, length, data.clone()).into();
+            let expected = &data[begin as usize..(begin + length) as usize];
+            match msg {
+                Message::Piece(3, b, v) => {
+                    assert_eq!(b, begin);
+                    assert_eq!(v.as_slice(), expected);
+                }
+                m => panic!("unexpected message {:?}", m),
+            }
         }
     }
 }
@@ -0,0 +1,89 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+
+// The stream of a connection, optionally copying every byte sent and received into files for
+// debugging the exchange with a peer. Without files, reads and writes go straight to the stream.
+pub struct Tap {
+    stream: TcpStream,
+    files: Option<(File, File)>,
+}
+
+impl Tap {
+    // Appends to DIR/ID.recv and DIR/ID.sent, so reconnections of a peer are kept
+    pub fn new(stream: TcpStream, dir: Option<&Path>, id: &str) -> io::Result<Self> {
+        let files = match dir {
+            Some(dir) => {
+                fs::create_dir_all(dir)?;
+                // Peer ids are arbitrary, so only keep characters that are safe in a file name
+                let name: String = id
+                    .chars()
+                    .map(|c| match c {
+                        'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+                        _ => '_',
+                    })
+                    .collect();
+                let open = |ext| {
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(dir.join(format!("{}.{}", name, ext)))
+                };
+                Some((open("recv")?, open("sent")?))
+            }
+            None => None,
+        };
+        Ok(Tap { stream, files })
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        let files = match &self.files {
+            Some((recv, sent)) => Some((recv.try_clone()?, sent.try_clone()?)),
+            None => None,
+        };
+        Ok(Tap {
+            stream: self.stream.try_clone()?,
+            files,
+        })
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+impl From<TcpStream> for Tap {
+    fn from(stream: TcpStream) -> Self {
+        Tap {
+            stream,
+            files: None,
+        }
+    }
+}
+
+// Only bytes that actually went over the wire are copied, and a failure to copy them is an
+// error on the connection rather than a silently incomplete dump
+impl Read for Tap {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stream.read(buf)?;
+        if let Some((recv, _)) = self.files.as_mut() {
+            recv.write_all(&buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Tap {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.stream.write(buf)?;
+        if let Some((_, sent)) = self.files.as_mut() {
+            sent.write_all(&buf[..n])?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
@@ -0,0 +1,201 @@
+use crate::connection::BufferAccount;
+use crate::selection;
+use crate::storage::PieceStore;
+use failure::{self, Fail};
+use log::{debug, info};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum Error {
+    #[fail(display = "unknown command: {}", _0)]
+    UnknownCommand(String),
+    #[fail(display = "missing argument for {}", _0)]
+    MissingArgument(String),
+    #[fail(display = "invalid argument: {}", _0)]
+    InvalidArgument(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    SetSelector(String),
+    SetEndgame(u32),
+    SetBufferLimit(usize),
+    Pause,
+    Resume,
+    Status,
+}
+
+fn argument<T: FromStr>(cmd: &str, arg: Option<&str>) -> Result<T, Error> {
+    let arg = arg.ok_or_else(|| Error::MissingArgument(cmd.to_owned()))?;
+    arg.parse()
+        .map_err(|_| Error::InvalidArgument(arg.to_owned()))
+}
+
+impl FromStr for Command {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let cmd = words.next().unwrap_or("");
+        let arg = words.next();
+        match cmd {
+            "set-selector" => {
+                let name: String = argument(cmd, arg)?;
+                match selection::from_name(&name) {
+                    Some(_) => Ok(Command::SetSelector(name)),
+                    None => Err(Error::InvalidArgument(name)),
+                }
+            }
+            "set-endgame" => Ok(Command::SetEndgame(argument(cmd, arg)?)),
+            "set-buffer-limit" => Ok(Command::SetBufferLimit(argument(cmd, arg)?)),
+            "pause" => Ok(Command::Pause),
+            "resume" => Ok(Command::Resume),
+            "status" => Ok(Command::Status),
+            _ => Err(Error::UnknownCommand(cmd.to_owned())),
+        }
+    }
+}
+
+// Applies line based commands from an admin to a running download
+pub struct Control {
+    pub store: Arc<RwLock<PieceStore>>,
+    pub buffers: Arc<BufferAccount>,
+    // Set while the download makes no progress despite peers to download from
+    pub stalled: Arc<AtomicBool>,
+}
+
+impl Control {
+    pub fn apply(&self, cmd: Command) {
+        info!("Control: {:?}", cmd);
+        match cmd {
+            Command::SetSelector(name) => {
+                let s = selection::from_name(&name).expect("Selector validated when parsed");
+                self.store.write().unwrap().reprioritize(s);
+            }
+            Command::SetEndgame(n) => self.store.write().unwrap().set_endgame_threshold(n),
+            Command::SetBufferLimit(n) => self.buffers.set_limit(n),
+            Command::Pause => self.store.write().unwrap().set_paused(true),
+            Command::Resume => self.store.write().unwrap().set_paused(false),
+            Command::Status => {}
+        }
+    }
+
+    pub fn status(&self) -> String {
+        let store = self.store.read().unwrap();
+        let first_piece = match store.time_to_first_piece() {
+            Some(d) => format!("{}ms", d.as_millis()),
+            None => "none".to_owned(),
+        };
+        let selection = store.selection_latency();
+        format!(
+            "left {} paused {} stalled {} first_piece {} unavailable {} select_us {}/{}",
+            store.left,
+            store.is_paused(),
+            self.stalled.load(Ordering::SeqCst),
+            first_piece,
+            store.unavailable().len(),
+            selection.mean().as_micros(),
+            selection.max.as_micros()
+        )
+    }
+
+    // Every line is acknowledged with "ok" or "error: <reason>" until the stream closes, except
+    // status which is answered with the status line
+    pub fn serve<S: Read + Write>(&self, stream: S) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while reader.read_line(&mut line)? != 0 {
+            debug!("Control command: {}", line.trim());
+            match line.parse() {
+                Ok(Command::Status) => writeln!(reader.get_mut(), "{}", self.status())?,
+                Ok(cmd) => {
+                    self.apply(cmd);
+                    writeln!(reader.get_mut(), "ok")?;
+                }
+                Err(e) => writeln!(reader.get_mut(), "error: {}", e)?,
+            }
+            line.clear();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::Metainfo;
+    use crate::selection::Inorder;
+    use bitvec::bitvec;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("pause".parse(), Ok(Command::Pause));
+        assert_eq!(
+            " set-selector  rarest\n".parse(),
+            Ok(Command::SetSelector("rarest".to_owned()))
+        );
+        assert_eq!(
+            "set-selector random".parse::<Command>(),
+            Err(Error::InvalidArgument("random".to_owned()))
+        );
+        assert_eq!(
+            "set-endgame".parse::<Command>(),
+            Err(Error::MissingArgument("set-endgame".to_owned()))
+        );
+        assert_eq!(
+            "set-max-down 10".parse::<Command>(),
+            Err(Error::UnknownCommand("set-max-down".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_serve() -> Result<(), failure::Error> {
+        let m = Metainfo::mock(1, 4);
+        let control = Control {
+            store: Arc::new(RwLock::new(PieceStore::new(
+                &m,
+                Box::new(Inorder::default()),
+            ))),
+            buffers: Arc::new(BufferAccount::default()),
+            stalled: Arc::new(AtomicBool::new(false)),
+        };
+        control.stalled.store(true, Ordering::SeqCst);
+        let store = control.store.clone();
+        let buffers = control.buffers.clone();
+        let (mut client, server) = UnixStream::pair()?;
+        let handle = thread::spawn(move || control.serve(server));
+
+        client.write_all(b"set-buffer-limit 1024\npause\nbogus\nstatus\n")?;
+        client.shutdown(std::net::Shutdown::Write)?;
+        let mut replies = String::new();
+        client.read_to_string(&mut replies)?;
+        handle.join().unwrap()?;
+
+        assert_eq!(
+            replies,
+            "ok\nok\nerror: unknown command: bogus\nleft 4 paused true stalled true first_piece none unavailable 0 select_us 0/0\n"
+        );
+        assert_eq!(buffers.limit(), 1024);
+        assert_eq!(
+            store
+                .write()
+                .unwrap()
+                .request_pieces("peer", bitvec![1; 4], 2),
+            Ok(vec![])
+        );
+        store.write().unwrap().set_paused(false);
+        assert_eq!(
+            store
+                .write()
+                .unwrap()
+                .request_pieces("peer", bitvec![1; 4], 2),
+            Ok(vec![0, 1])
+        );
+        Ok(())
+    }
+}
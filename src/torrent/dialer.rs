@@ -0,0 +1,165 @@
+use crate::tracker::PeerInfo;
+use log::warn;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+// Pool of threads connecting to peers, so a slow or dead peer only holds up one worker instead of
+// every dial after it. Workers exit once the pool is dropped and the queue is drained.
+pub struct Dialer {
+    tx: Mutex<mpsc::Sender<(PeerInfo, Slot)>>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+// A dial counted in in_flight, released however the dial ends, including when it is dropped from
+// the queue without being attempted
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn new(in_flight: &Arc<AtomicUsize>) -> Self {
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        Slot(in_flight.clone())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Dialer {
+    // Successful connections are sent to done, failures are only logged
+    pub fn new<T, F>(workers: usize, connect: F, done: mpsc::Sender<T>) -> Self
+    where
+        T: Send + 'static,
+        F: Fn(&PeerInfo) -> io::Result<T> + Send + Sync + 'static,
+    {
+        let (tx, rx) = mpsc::channel::<(PeerInfo, Slot)>();
+        let rx = Arc::new(Mutex::new(rx));
+        let connect = Arc::new(connect);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        for i in 0..workers {
+            let rx = rx.clone();
+            let connect = connect.clone();
+            let done = done.clone();
+            thread::Builder::new()
+                .name(format!("dial-{}", i))
+                .spawn(move || loop {
+                    // The lock is released before connecting so other workers can take peers. The
+                    // slot is held until the connection has been handed over.
+                    let (peer, _slot) = match rx.lock().unwrap().recv() {
+                        Ok(dial) => dial,
+                        Err(_) => return,
+                    };
+                    match connect(&peer) {
+                        Ok(conn) => {
+                            // Nobody is waiting for connections any more
+                            if done.send(conn).is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) => warn!("{}: {}", peer.addr, e),
+                    }
+                })
+                .expect("Failed to spawn dialing thread");
+        }
+        Dialer {
+            tx: Mutex::new(tx),
+            in_flight,
+        }
+    }
+
+    // Fails once every worker has exited, which they do when nobody is waiting for connections
+    pub fn dial(&self, peer: PeerInfo) -> Result<(), mpsc::SendError<PeerInfo>> {
+        let slot = Slot::new(&self.in_flight);
+        self.tx
+            .lock()
+            .unwrap()
+            .send((peer, slot))
+            .map_err(|mpsc::SendError((peer, _))| mpsc::SendError(peer))
+    }
+
+    // Peers queued or being connected to
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddrV4;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_concurrent_dials() {
+        let timeout = Duration::from_millis(200);
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(Mutex::new(0));
+        let (tx, rx) = mpsc::channel();
+        let dialer = {
+            let active = active.clone();
+            let max_active = max_active.clone();
+            Dialer::new(
+                10,
+                move |peer: &PeerInfo| {
+                    let n = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    {
+                        let mut max_active = max_active.lock().unwrap();
+                        *max_active = std::cmp::max(*max_active, n);
+                    }
+                    // Odd ports are dead peers that only fail once the connect timeout expires
+                    let res = match peer.addr.port() % 2 {
+                        0 => Ok(peer.addr.port()),
+                        _ => {
+                            thread::sleep(timeout);
+                            Err(io::ErrorKind::TimedOut.into())
+                        }
+                    };
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    res
+                },
+                tx,
+            )
+        };
+
+        let start = Instant::now();
+        for port in 0..20 {
+            dialer
+                .dial(PeerInfo {
+                    addr: SocketAddrV4::new([127, 0, 0, 1].into(), port).into(),
+                })
+                .unwrap();
+        }
+        let mut connected: Vec<_> = rx.iter().take(10).collect();
+        while dialer.in_flight() != 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Ten dead peers dialed one after another would take ten timeouts
+        assert!(start.elapsed() < 3 * timeout);
+        assert!(*max_active.lock().unwrap() <= 10);
+        connected.sort();
+        assert_eq!(connected, (0..20).step_by(2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_workers_exited() {
+        let (tx, rx) = mpsc::channel();
+        let dialer = Dialer::new(1, |peer: &PeerInfo| Ok(peer.addr.port()), tx);
+        drop(rx);
+        let peer = || PeerInfo {
+            addr: SocketAddrV4::new([127, 0, 0, 1].into(), 1).into(),
+        };
+        // The worker exits on its first connection, after which dialing fails
+        let start = Instant::now();
+        while dialer.dial(peer()).is_ok() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Peers queued when the worker exited are no longer counted either
+        assert_eq!(dialer.in_flight(), 0);
+    }
+}
@@ -0,0 +1,384 @@
+use crate::metainfo::{is_safe_component, Info};
+use crate::storage::Output;
+use log::debug;
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+// Files open at once when writing a multi-file torrent
+const MAX_OPEN_FILES: usize = 64;
+
+// Opens path for reading and writing, truncated and then extended to len so the file has its
+// final size before any piece is written. The extension is sparse, so no zeros are written.
+pub fn create_output<P: AsRef<Path>>(path: P, len: u64) -> io::Result<File> {
+    let path = path.as_ref();
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    file.set_len(len).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "cannot allocate {} bytes for {}: {}",
+                len,
+                path.display(),
+                e
+            ),
+        )
+    })?;
+    Ok(file)
+}
+
+// Joins untrusted path components from a torrent onto dir, refusing any path that could end up
+// outside it
+fn confine(dir: &Path, components: &[String]) -> io::Result<PathBuf> {
+    let mut path = dir.to_path_buf();
+    for c in components {
+        // Whatever the platform treats as a root, prefix or parent is more than one plain name
+        let mut parts = Path::new(c).components();
+        match (parts.next(), parts.next()) {
+            (Some(Component::Normal(_)), None) if is_safe_component(c) => path.push(c),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsafe path {:?} in torrent", components.join("/")),
+                ))
+            }
+        }
+    }
+    if components.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "empty path in torrent",
+        ));
+    }
+    Ok(path)
+}
+
+// Where each file of the torrent goes under dir, with its length. A single file torrent is the
+// file dir/NAME, a multi-file torrent the directory dir/NAME holding the file tree. Every path is
+// checked again here, so nothing is created outside dir even for an unvalidated info.
+pub fn layout<P: AsRef<Path>>(info: &Info, dir: P) -> io::Result<Vec<(PathBuf, u64)>> {
+    let root = confine(dir.as_ref(), &[info.name.clone()])?;
+    match info.files {
+        Some(ref files) => files
+            .iter()
+            .map(|f| Ok((confine(&root, &f.path)?, f.length as u64)))
+            .collect(),
+        None => Ok(vec![(root, info.length as u64)]),
+    }
+}
+
+// The files of a torrent as one seekable stream of its content, in torrent order
+pub struct MultiFile {
+    files: Vec<(PathBuf, u64)>,
+    pool: FilePool,
+    pos: u64,
+}
+
+impl MultiFile {
+    // Creates every file at its final size, and the directories holding them
+    pub fn create(files: Vec<(PathBuf, u64)>) -> io::Result<Self> {
+        for (path, len) in files.iter() {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            create_output(path, *len)?;
+        }
+        Ok(MultiFile {
+            files,
+            pool: FilePool::new(MAX_OPEN_FILES),
+            pos: 0,
+        })
+    }
+
+    fn len(&self) -> u64 {
+        self.files.iter().map(|(_, len)| len).sum()
+    }
+
+    // The file holding the current position and the offset into it, skipping empty files
+    fn locate(&self) -> Option<(usize, u64)> {
+        let mut start = 0;
+        for (i, (_, len)) in self.files.iter().enumerate() {
+            if self.pos < start + len {
+                return Some((i, self.pos - start));
+            }
+            start += len;
+        }
+        None
+    }
+}
+
+// Each call stays within one file, so reads and writes may be short at file boundaries
+impl Read for MultiFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (i, offset) = match self.locate() {
+            Some(x) => x,
+            None => return Ok(0),
+        };
+        let (ref path, len) = self.files[i];
+        let n = min(buf.len() as u64, len - offset) as usize;
+        let read = self.pool.with_file(path, |f| {
+            f.seek(SeekFrom::Start(offset))?;
+            f.read(&mut buf[..n])
+        })?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for MultiFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (i, offset) = match self.locate() {
+            Some(x) => x,
+            None if buf.is_empty() => return Ok(0),
+            None => return Err(io::ErrorKind::WriteZero.into()),
+        };
+        let (ref path, len) = self.files[i];
+        let n = min(buf.len() as u64, len - offset) as usize;
+        let written = self.pool.with_file(path, |f| {
+            f.seek(SeekFrom::Start(offset))?;
+            f.write(&buf[..n])
+        })?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MultiFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => add_offset(self.len(), n),
+            SeekFrom::Current(n) => add_offset(self.pos, n),
+        };
+        match pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the torrent",
+            )),
+        }
+    }
+}
+
+fn add_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset < 0 {
+        base.checked_sub(offset.wrapping_neg() as u64)
+    } else {
+        base.checked_add(offset as u64)
+    }
+}
+
+// Files the pool closed since they were written are left to the OS, which only matters for a
+// piece spanning more files than the pool keeps open
+impl Output for MultiFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.pool.sync_all()
+    }
+}
+
+// Files opened on demand, keeping at most max_open of them open by closing the least recently
+// used one, so that torrents with many files do not exhaust file descriptors
+pub struct FilePool {
+    max_open: usize,
+    files: HashMap<PathBuf, File>,
+    // Most recently used last
+    order: VecDeque<PathBuf>,
+    opened: u64,
+}
+
+impl FilePool {
+    pub fn new(max_open: usize) -> Self {
+        assert!(max_open > 0, "FilePool needs room for at least one file");
+        FilePool {
+            max_open,
+            files: HashMap::new(),
+            order: VecDeque::new(),
+            opened: 0,
+        }
+    }
+
+    // Run f on the file at path, opened for reading and writing and created if missing
+    pub fn with_file<P, F, T>(&mut self, path: P, f: F) -> io::Result<T>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut File) -> io::Result<T>,
+    {
+        let path = path.as_ref();
+        if self.files.contains_key(path) {
+            if let Some(pos) = self.order.iter().position(|p| p == path) {
+                self.order.remove(pos);
+            }
+        } else {
+            if self.files.len() == self.max_open {
+                if let Some(old) = self.order.pop_front() {
+                    debug!("Closing {}", old.display());
+                    self.files.remove(&old);
+                }
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(path)?;
+            self.opened += 1;
+            self.files.insert(path.to_owned(), file);
+        }
+        self.order.push_back(path.to_owned());
+        f(self.files.get_mut(path).unwrap())
+    }
+
+    pub fn sync_all(&self) -> io::Result<()> {
+        for file in self.files.values() {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    pub fn open_files(&self) -> usize {
+        self.files.len()
+    }
+
+    // Total number of times a file was opened, including reopens after being closed
+    pub fn opened(&self) -> u64 {
+        self.opened
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::{FileEntry, Metainfo};
+
+    #[test]
+    fn test_create_output() -> io::Result<()> {
+        let path = std::env::temp_dir().join("continuity_test_create_output");
+        fs::write(&path, vec![1; 100])?;
+        let mut file = create_output(&path, 10 << 20)?;
+        assert_eq!(fs::metadata(&path)?.len(), 10 << 20);
+        // Previous contents are gone and unwritten ranges read as zeros
+        let mut v = vec![1; 100];
+        file.read_exact(&mut v)?;
+        assert!(v.iter().all(|b| *b == 0));
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_layout() -> io::Result<()> {
+        let mut info = Metainfo::mock(4, 8).info;
+        assert_eq!(layout(&info, "out")?, vec![(PathBuf::from("out/test"), 8)]);
+        info.length = 0;
+        info.files = Some(vec![
+            FileEntry {
+                length: 3,
+                path: vec!["a".to_owned()],
+                extra: Default::default(),
+            },
+            FileEntry {
+                length: 5,
+                path: vec!["dir".to_owned(), "b".to_owned()],
+                extra: Default::default(),
+            },
+        ]);
+        assert_eq!(
+            layout(&info, "out")?,
+            vec![
+                (PathBuf::from("out/test/a"), 3),
+                (PathBuf::from("out/test/dir/b"), 5),
+            ]
+        );
+
+        // Malicious paths are refused rather than created somewhere else
+        for path in vec![
+            vec!["..", "..", "etc", "passwd"],
+            vec!["../../etc/passwd"],
+            vec!["/etc/passwd"],
+            vec!["C:\\Windows", "system.ini"],
+            vec!["dir", ".", "b"],
+            vec!["dir", ""],
+            vec![],
+        ] {
+            info.files.as_mut().unwrap()[1].path = path.iter().map(|c| c.to_string()).collect();
+            let e = layout(&info, "out").unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", path);
+        }
+        info.files = None;
+        info.name = "..".to_owned();
+        assert!(layout(&info, "out").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_file() -> io::Result<()> {
+        let dir = std::env::temp_dir().join("continuity_test_multi_file");
+        let _ = fs::remove_dir_all(&dir);
+        let files = vec![
+            (dir.join("a"), 3),
+            (dir.join("empty"), 0),
+            (dir.join("sub").join("b"), 4),
+        ];
+        let mut out = MultiFile::create(files)?;
+        assert_eq!(fs::metadata(dir.join("empty"))?.len(), 0);
+
+        // Pieces cross file boundaries
+        out.seek(SeekFrom::Start(2))?;
+        out.write_all(b"xyz")?;
+        out.seek(SeekFrom::End(-1))?;
+        out.write_all(b"w")?;
+        assert!(out.write_all(b"past the end").is_err());
+        out.sync()?;
+        assert_eq!(fs::read(dir.join("a"))?, b"\0\0x");
+        assert_eq!(fs::read(dir.join("sub").join("b"))?, b"yz\0w");
+
+        let mut v = Vec::new();
+        out.seek(SeekFrom::Start(0))?;
+        out.read_to_end(&mut v)?;
+        assert_eq!(v, b"\0\0xyz\0w");
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_pool() -> io::Result<()> {
+        let dir = std::env::temp_dir().join("continuity_test_file_pool");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir)?;
+        let paths: Vec<_> = (0..3).map(|i| dir.join(i.to_string())).collect();
+        let mut pool = FilePool::new(2);
+
+        for (i, path) in paths.iter().enumerate() {
+            pool.with_file(path, |f| f.write_all(&[i as u8]))?;
+            assert!(pool.open_files() <= 2);
+        }
+        assert_eq!(pool.opened(), 3);
+        // The most recently used file is still open
+        pool.with_file(&paths[2], |f| f.write_all(&[2]))?;
+        assert_eq!(pool.opened(), 3);
+        // The first file was closed to make room, so it is reopened
+        let mut v = Vec::new();
+        pool.with_file(&paths[0], |f| {
+            f.seek(SeekFrom::Start(0))?;
+            f.read_to_end(&mut v)
+        })?;
+        assert_eq!(v, vec![0]);
+        assert_eq!(pool.opened(), 4);
+        assert_eq!(pool.open_files(), 2);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+// Hashing backend used for info hashes and piece verification
+pub trait Hasher {
+    fn digest(&self, data: &[u8]) -> [u8; 20];
+}
+
+#[derive(Default)]
+pub struct Sha1;
+
+impl Hasher for Sha1 {
+    fn digest(&self, data: &[u8]) -> [u8; 20] {
+        ::sha1::Sha1::from(data).digest().bytes()
+    }
+}
+
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    Sha1.digest(data)
+}
+
+// SHA-1 of data fed in order a part at a time, so hashing can keep up with arriving blocks
+pub struct Sha1Stream(::sha1::Sha1);
+
+impl Sha1Stream {
+    pub fn new() -> Self {
+        Sha1Stream(::sha1::Sha1::new())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn digest(&self) -> [u8; 20] {
+        self.0.digest().bytes()
+    }
+}
+
+impl Default for Sha1Stream {
+    fn default() -> Self {
+        Sha1Stream::new()
+    }
+}
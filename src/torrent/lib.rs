@@ -2,8 +2,21 @@
 pub mod bitset;
 pub mod choking;
 pub mod connection;
+pub mod control;
+pub mod dialer;
+pub mod files;
+pub mod hash;
+pub mod logfile;
 pub mod metainfo;
 pub mod peer;
+pub mod pool;
+pub mod reputation;
+pub mod score;
 pub mod selection;
+pub mod stats;
 pub mod storage;
+pub mod swarm;
 pub mod tracker;
+pub mod verify;
+pub mod watchdog;
+pub mod webseed;
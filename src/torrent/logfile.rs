@@ -0,0 +1,149 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Logs to a file instead of stderr, so that long running clients do not mix logs with piece data
+// on stdout. Once the file reaches max_size it is renamed to PATH.1, replacing any older one,
+// and a new file is started.
+pub struct FileLogger {
+    path: PathBuf,
+    max_size: u64,
+    level: LevelFilter,
+    // Module prefixes that are logged, as with stderrlog
+    modules: Vec<String>,
+    file: Mutex<(File, u64)>,
+}
+
+impl FileLogger {
+    // Fails if the file cannot be opened for appending
+    pub fn new<P: AsRef<Path>>(path: P, max_size: u64) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let file = open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(FileLogger {
+            path,
+            max_size,
+            level: LevelFilter::Error,
+            modules: Vec::new(),
+            file: Mutex::new((file, len)),
+        })
+    }
+
+    // Same scale as stderrlog: 0 logs errors only, each step adds the next level
+    pub fn set_verbosity(&mut self, verbosity: usize) {
+        self.level = match verbosity {
+            0 => LevelFilter::Error,
+            1 => LevelFilter::Warn,
+            2 => LevelFilter::Info,
+            3 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+    }
+
+    pub fn add_module(&mut self, module: &str) {
+        self.modules.push(module.to_owned());
+    }
+
+    // Install as the global logger for the rest of the process
+    pub fn init(self) -> Result<(), log::SetLoggerError> {
+        let level = self.level;
+        log::set_logger(Box::leak(Box::new(self)))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    fn rotate(&self, file: &mut (File, u64)) -> io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        *file = (open(&self.path)?, 0);
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let target = metadata.target();
+        metadata.level() <= self.level
+            && self.modules.iter().any(|m| {
+                target == m
+                    || (target.starts_with(m.as_str()) && target[m.len()..].starts_with("::"))
+            })
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!("{} - {}\n", level_name(record.level()), record.args());
+        let mut file = self.file.lock().unwrap();
+        if file.1 > 0 && file.1 + line.len() as u64 > self.max_size {
+            // Keep logging to the full file rather than losing messages
+            let _ = self.rotate(&mut file);
+        }
+        if file.0.write_all(line.as_bytes()).is_ok() {
+            file.1 += line.len() as u64;
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().0.flush();
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "ERROR",
+        Level::Warn => "WARN",
+        Level::Info => "INFO",
+        Level::Debug => "DEBUG",
+        Level::Trace => "TRACE",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(logger: &FileLogger, level: Level, target: &str, msg: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .target(target)
+                .args(format_args!("{}", msg))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn test_file_logger() -> io::Result<()> {
+        let path = std::env::temp_dir().join("continuity_test_file_logger.log");
+        let rotated = std::env::temp_dir().join("continuity_test_file_logger.log.1");
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&rotated);
+
+        let mut logger = FileLogger::new(&path, 40)?;
+        logger.set_verbosity(2);
+        logger.add_module("torrent");
+        log(&logger, Level::Info, "torrent::storage", "stored piece");
+        // Too verbose, or from a module that is not logged
+        log(&logger, Level::Debug, "torrent::storage", "hidden");
+        log(&logger, Level::Error, "torrentx", "hidden");
+        log(&logger, Level::Error, "reqwest", "hidden");
+        assert_eq!(fs::read_to_string(&path)?, "INFO - stored piece\n");
+
+        // The next line does not fit, so the file is rotated first
+        log(&logger, Level::Warn, "torrent", "announce failed badly");
+        assert_eq!(fs::read_to_string(&rotated)?, "INFO - stored piece\n");
+        assert_eq!(fs::read_to_string(&path)?, "WARN - announce failed badly\n");
+        fs::remove_file(&path)?;
+        fs::remove_file(&rotated)?;
+        Ok(())
+    }
+}
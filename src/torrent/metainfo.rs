@@ -1,13 +1,25 @@
-use crypto::digest::Digest;
-use crypto::sha1::Sha1;
+use crate::hash;
 use failure::{self, Fail};
 use log::debug;
+use serde_bencode::value::Value;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 use std::str::FromStr;
 
+// One file of a multi-file torrent, stored at its path under a directory named after the torrent
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub length: usize,
+    pub path: Vec<String>,
+    // Keys such as md5sum, attr or path.utf-8 are part of the info hash too
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Info {
     pub name: String,
@@ -15,19 +27,87 @@ pub struct Info {
     pub piece_length: usize,
     #[serde(with = "serde_bytes")]
     pub pieces: Vec<u8>,
+    // Only set for single file torrents, use total_length for the size of the content
+    #[serde(default, skip_serializing_if = "is_zero")]
     pub length: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileEntry>>,
+    // Keys such as source or x_cross_seed change the info hash, so must be kept
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+// BEP 52 version of an info dict from its keys. v2 only torrents have no pieces or length, so this
+// must work before the info dict can be parsed.
+fn meta_version<'a, F: Fn(&str) -> Option<&'a Value>>(get: F) -> i64 {
+    match get("meta version") {
+        Some(Value::Int(v)) => *v,
+        // Files are listed in a file tree, with a pieces root per file, only from v2
+        _ if get("file tree").is_some() => 2,
+        _ => 1,
+    }
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+// Devices Windows opens in place of a file of that name, whatever the extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn is_reserved_name(c: &str) -> bool {
+    let stem = c.split('.').next().unwrap_or(c).trim_end_matches(' ');
+    RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem))
+}
+
+// A single component of an output path, which must not climb out of or escape the output
+// directory. Names come from the torrent, so are untrusted.
+pub fn is_safe_component(c: &str) -> bool {
+    !(c.is_empty()
+        || c == "."
+        || c == ".."
+        || c.contains(|ch| ch == '/' || ch == '\\' || ch == '\0')
+        // Drive prefixes such as C: and device names are only special on Windows
+        || (cfg!(windows) && (c.contains(':') || is_reserved_name(c))))
 }
 
 impl Info {
+    // Hybrid torrents have every v1 key as well, but are still version 2
+    pub fn meta_version(&self) -> i64 {
+        meta_version(|k| self.extra.get(k))
+    }
+
     fn validate(&self) -> Result<(), Error> {
-        if self.name == "" {
+        if self.meta_version() != 1 {
+            return Err(Error::UnsupportedTorrentVersion(self.meta_version()));
+        } else if !is_safe_component(&self.name) {
             return Err(Error::InvalidName);
-        } else if self.length == 0 {
+        } else if self.total_length() == 0 {
             return Err(Error::ZeroLength);
         } else if self.piece_length == 0 {
             return Err(Error::ZeroPieceLength);
         }
 
+        for file in self.files.iter().flatten() {
+            if file.path.is_empty() || !file.path.iter().all(|c| is_safe_component(c)) {
+                return Err(Error::InvalidPath(file.path.join("/")));
+            }
+        }
+
+        // Piece sizes and offsets within pieces are u32 on the wire, and the last piece is never
+        // larger than the others
+        if self.piece_length as u64 > u64::from(u32::max_value()) {
+            return Err(Error::PieceLengthTooLarge(self.piece_length as u64));
+        }
+
+        // Piece indices are u32 everywhere, including on the wire
+        if self.piece_count() > u64::from(u32::max_value()) {
+            return Err(Error::TooManyPieces(self.piece_count()));
+        }
+
         if self.pieces.len() % 20 != 0 {
             return Err(Error::InvalidPieceArrayLength(
                 "length of pieces array is not a multiple of 20".to_owned(),
@@ -41,34 +121,50 @@ impl Info {
         Ok(())
     }
 
+    fn piece_hash(&self, index: u32) -> &[u8] {
+        &self.pieces[index as usize * 20..(index as usize + 1) * 20]
+    }
+
     fn verify_piece(&self, index: u32, piece: &[u8]) -> bool {
-        let mut hash: [u8; 20] = [0; 20];
-        let mut hasher = Sha1::new();
-        hasher.input(piece);
-        hasher.result(&mut hash);
-        return hash == &self.pieces[index as usize * 20..(index as usize + 1) * 20];
+        hash::sha1(piece) == self.piece_hash(index)
     }
 
+    // Only called on validated infos, where every piece size fits in a u32
     fn piece_size(&self, index: u32) -> u32 {
-        let num_pieces = self.num_pieces() as usize;
-        if index as usize == num_pieces - 1 {
-            return (self.length - (num_pieces - 1) * self.piece_length) as u32;
+        let num_pieces = self.piece_count();
+        if u64::from(index) == num_pieces - 1 {
+            let size = (num_pieces - 1)
+                .checked_mul(self.piece_length as u64)
+                .and_then(|start| (self.total_length() as u64).checked_sub(start))
+                .expect("Last piece outside the torrent");
+            return u32::try_from(size).expect("Piece size larger than u32");
         }
-        self.piece_length as u32
+        u32::try_from(self.piece_length).expect("Piece size larger than u32")
     }
 
+    // Only exact for infos that passed validation
     fn num_pieces(&self) -> u32 {
-        (1 + (self.length - 1) / self.piece_length) as u32
+        self.piece_count() as u32
+    }
+
+    fn piece_count(&self) -> u64 {
+        1 + (self.total_length() as u64 - 1) / self.piece_length as u64
+    }
+
+    // Size of the content, summed over the files of a multi-file torrent
+    pub fn total_length(&self) -> usize {
+        match self.files {
+            Some(ref files) => files.iter().map(|f| f.length).sum(),
+            None => self.length,
+        }
     }
 
     fn hash(&self) -> Result<[u8; 20], Error> {
         self.validate()?;
         debug!("Calculating info_hash");
-        let mut hash: [u8; 20] = [0; 20];
-        let mut hasher = Sha1::new();
-        hasher.input(&serde_bencode::to_bytes(self).expect("Failed to serialize info hash"));
-        hasher.result(&mut hash);
-        Ok(hash)
+        Ok(hash::sha1(
+            &serde_bencode::to_bytes(self).expect("Failed to serialize info hash"),
+        ))
     }
 }
 
@@ -82,12 +178,37 @@ pub enum Error {
     ZeroLength,
     #[fail(display = "invalid name")]
     InvalidName,
+    #[fail(display = "invalid file path: {}", _0)]
+    InvalidPath(String),
+    #[fail(display = "piece length {} does not fit in 32 bits", _0)]
+    PieceLengthTooLarge(u64),
+    #[fail(display = "{} pieces, more than piece indices can address", _0)]
+    TooManyPieces(u64),
+    #[fail(display = "torrent is {} bytes, more than the limit of {}", _0, _1)]
+    TooLarge(u64, u64),
+    #[fail(display = "failed to parse metainfo: {}", _0)]
+    ParseMetainfo(String),
+    #[fail(
+        display = "torrent is meta version {}, only version 1 is supported",
+        _0
+    )]
+    UnsupportedTorrentVersion(i64),
+    #[fail(display = "torrent has neither an announce URL nor an announce list")]
+    NoTrackers,
 }
 
 #[derive(Debug, Default, Deserialize)]
 pub struct Metainfo {
-    pub announce: String,
+    // Missing or empty in some torrents that only have an announce list
+    #[serde(default)]
+    pub announce: Option<String>,
+    // Tiers of trackers (BEP 12), used instead of announce when present
+    #[serde(rename = "announce-list", default)]
+    pub announce_list: Vec<Vec<String>>,
     pub info: Info,
+    // Web seeds (BEP 19), either a single URL or a list of them
+    #[serde(rename = "url-list", default)]
+    pub url_list: Option<Value>,
     #[serde(rename = "creation date")]
     pub creation_date: Option<u64>,
     pub comment: Option<String>,
@@ -104,15 +225,48 @@ impl Metainfo {
         let mut f = File::open(path)?;
         let mut b: Vec<u8> = Vec::with_capacity(f.metadata()?.len() as usize);
         f.read_to_end(&mut b)?;
-        let m: Metainfo = serde_bencode::from_bytes(&b)?;
+        let m = Metainfo::from_bytes(&b)?;
+        m.info.validate()?;
         Ok(m)
     }
 
+    // Parse errors from serde are explained in terms of the torrent file where possible
+    pub fn from_bytes(b: &[u8]) -> Result<Self, Error> {
+        let e = match serde_bencode::from_bytes(b) {
+            Ok(m) => return Ok(m),
+            Err(e) => e,
+        };
+        let reason = match b.first() {
+            None => "empty file".to_owned(),
+            Some(c) if *c != b'd' => "not a bencode dictionary".to_owned(),
+            _ => match serde_bencode::from_bytes::<Value>(b) {
+                Err(_) => "truncated or malformed bencode".to_owned(),
+                Ok(Value::Dict(ref d)) if !d.contains_key(&b"info"[..]) => {
+                    "missing info key".to_owned()
+                }
+                Ok(Value::Dict(ref d)) => match d.get(&b"info"[..]) {
+                    Some(Value::Dict(info)) => match meta_version(|k| info.get(k.as_bytes())) {
+                        1 => format!("invalid torrent: {}", e),
+                        v => return Err(Error::UnsupportedTorrentVersion(v)),
+                    },
+                    _ => format!("invalid torrent: {}", e),
+                },
+                Ok(_) => format!("invalid torrent: {}", e),
+            },
+        };
+        Err(Error::ParseMetainfo(reason))
+    }
+
     // TODO: Test
     pub fn verify_piece(&self, index: u32, piece: &[u8]) -> bool {
         self.info.verify_piece(index, piece)
     }
 
+    // As verify_piece, for a piece that was already hashed
+    pub fn verify_digest(&self, index: u32, digest: &[u8; 20]) -> bool {
+        &digest[..] == self.info.piece_hash(index)
+    }
+
     // TODO: Test
     pub fn get_piece_size(&self, index: u32) -> u32 {
         self.info.piece_size(index)
@@ -121,6 +275,68 @@ impl Metainfo {
     pub fn num_pieces(&self) -> u32 {
         self.info.num_pieces()
     }
+
+    // Every distinct tracker, in tier order, falling back to announce without a list
+    pub fn trackers(&self) -> Result<Vec<&str>, Error> {
+        let mut v: Vec<&str> = Vec::new();
+        for url in self.announce_list.iter().flatten() {
+            if !url.is_empty() && !v.contains(&url.as_str()) {
+                v.push(url);
+            }
+        }
+        if v.is_empty() {
+            match self.announce.as_ref().map(|s| s.as_str()) {
+                Some("") | None => return Err(Error::NoTrackers),
+                Some(url) => v.push(url),
+            }
+        }
+        Ok(v)
+    }
+
+    // Rejects torrents with more than max bytes of content, before any storage is allocated
+    pub fn check_size(&self, max: u64) -> Result<(), Error> {
+        let length = self.info.total_length() as u64;
+        if length > max {
+            return Err(Error::TooLarge(length, max));
+        }
+        Ok(())
+    }
+
+    // Web seed URLs, ignoring entries that are not strings
+    pub fn web_seeds(&self) -> Vec<String> {
+        let urls = match &self.url_list {
+            Some(Value::Bytes(url)) => vec![url],
+            Some(Value::List(list)) => list
+                .iter()
+                .filter_map(|v| match v {
+                    Value::Bytes(url) => Some(url),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        urls.into_iter()
+            .filter_map(|url| String::from_utf8(url.clone()).ok())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl Metainfo {
+    // Metainfo with the given geometry and zeroed piece hashes
+    pub(crate) fn mock(piece_length: usize, length: usize) -> Self {
+        Metainfo {
+            info: Info {
+                name: "test".to_owned(),
+                piece_length,
+                pieces: vec![0; 20 * (1 + (length - 1) / piece_length)],
+                length,
+                files: None,
+                extra: BTreeMap::new(),
+            },
+            ..Default::default()
+        }
+    }
 }
 
 impl FromStr for Metainfo {
@@ -144,30 +360,40 @@ mod tests {
                 piece_length: 1,
                 pieces: vec![0; 40],
                 length: 2,
+                files: None,
+                extra: BTreeMap::new(),
             },
             Info {
                 name: "test".to_owned(),
                 piece_length: 1,
                 pieces: vec![0; 1],
                 length: 0,
+                files: None,
+                extra: BTreeMap::new(),
             },
             Info {
                 name: "test".to_owned(),
                 piece_length: 0,
                 pieces: vec![0; 1],
                 length: 10,
+                files: None,
+                extra: BTreeMap::new(),
             },
             Info {
                 name: "test".to_owned(),
                 piece_length: 100,
                 pieces: vec![0; 20],
                 length: 200,
+                files: None,
+                extra: BTreeMap::new(),
             },
             Info {
                 name: "test".to_owned(),
                 piece_length: 100,
                 pieces: vec![0; 20],
                 length: 100,
+                files: None,
+                extra: BTreeMap::new(),
             },
         ];
 
@@ -189,6 +415,118 @@ mod tests {
         }
         assert!(infos[4].validate().is_ok());
     }
+
+    // Lengths this large do not fit in usize on 32 bit targets
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_too_many_pieces() {
+        let mut info = Info {
+            name: "test".to_owned(),
+            piece_length: 1,
+            pieces: Vec::new(),
+            length: u32::max_value() as usize + 1,
+            files: None,
+            extra: BTreeMap::new(),
+        };
+        match info.validate() {
+            Err(Error::TooManyPieces(n)) => assert_eq!(n, 1 << 32),
+            r => panic!("unexpected result {:?}", r),
+        }
+        // The largest piece count that still fits
+        info.length -= 1;
+        assert!(!matches!(info.validate(), Err(Error::TooManyPieces(_))));
+    }
+
+    #[test]
+    fn test_check_size() {
+        let m = Metainfo::mock(4, 100);
+        assert!(m.check_size(100).is_ok());
+        assert!(matches!(m.check_size(99), Err(Error::TooLarge(100, 99))));
+    }
+
+    #[test]
+    fn test_parse_errors() -> Result<(), failure::Error> {
+        let torrent = std::fs::read("data/test.torrent")?;
+        let path = std::env::temp_dir().join("continuity_test_parse_errors");
+        let cases: Vec<(&[u8], &str)> = vec![
+            (
+                &torrent[..torrent.len() / 2],
+                "truncated or malformed bencode",
+            ),
+            (b"<html>Not Found</html>", "not a bencode dictionary"),
+            (b"", "empty file"),
+            (b"d8:announce3:urle", "missing info key"),
+        ];
+        for (data, reason) in cases {
+            std::fs::write(&path, data)?;
+            let e = Metainfo::from_file(&path).unwrap_err();
+            match e.downcast::<Error>()? {
+                Error::ParseMetainfo(r) => assert_eq!(r, reason),
+                e => panic!("unexpected error {:?}", e),
+            }
+        }
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_meta_version() -> Result<(), failure::Error> {
+        let v1 = b"6:lengthi5e4:name4:test12:piece lengthi5e6:pieces20:aaaaaaaaaaaaaaaaaaaa";
+        let v2 = b"9:file treed4:testd0:d6:lengthi5e11:pieces root32:bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbeee12:meta versioni2e";
+        let parse = |keys: &[&[u8]]| {
+            let mut torrent = b"d8:announce21:http://localhost/annc4:infod".to_vec();
+            for k in keys {
+                torrent.extend_from_slice(k);
+            }
+            torrent.extend_from_slice(b"ee");
+            Metainfo::from_bytes(&torrent)
+        };
+        assert_eq!(parse(&[v1])?.info.meta_version(), 1);
+        // v2 only torrents fail to parse as v1, hybrid ones parse but are not valid
+        for keys in vec![vec![&v2[..]], vec![&v1[..], &v2[..]]] {
+            match parse(&keys).and_then(|m| m.info.validate()) {
+                Err(Error::UnsupportedTorrentVersion(2)) => (),
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_piece_size() {
+        let info = Metainfo::mock(3, 5).info;
+        assert!(info.validate().is_ok());
+        assert_eq!((info.piece_size(0), info.piece_size(1)), (3, 2));
+        let info = Metainfo::mock(3, 6).info;
+        assert_eq!((info.piece_size(0), info.piece_size(1)), (3, 3));
+    }
+
+    // Lengths this large do not fit in usize on 32 bit targets
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_piece_size_u32_boundary() {
+        let max = u32::max_value() as usize;
+        let mut info = Info {
+            name: "test".to_owned(),
+            piece_length: max,
+            pieces: vec![0; 40],
+            length: 2 * max - 1,
+            files: None,
+            extra: BTreeMap::new(),
+        };
+        assert!(info.validate().is_ok());
+        assert_eq!(info.piece_size(0), u32::max_value());
+        assert_eq!(info.piece_size(1), u32::max_value() - 1);
+
+        info.piece_length = max + 1;
+        info.length = max + 1;
+        info.pieces = vec![0; 20];
+        match info.validate() {
+            Err(Error::PieceLengthTooLarge(n)) => assert_eq!(n, 1 << 32),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
     #[test]
     fn test_info_hash() -> Result<(), failure::Error> {
         let m = Metainfo::from_file("data/test.torrent")?;
@@ -201,4 +539,176 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_verify_piece() {
+        let mut info = Metainfo::mock(3, 5).info;
+        info.pieces[20..].copy_from_slice(&hash::sha1(b"ab"));
+        assert!(info.verify_piece(1, b"ab"));
+        assert!(!info.verify_piece(1, b"ac"));
+        assert!(!info.verify_piece(0, b"abc"));
+    }
+
+    #[test]
+    fn test_info_hash_extra_keys() -> Result<(), failure::Error> {
+        let info = b"d6:lengthi5e4:name4:test12:piece lengthi5e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source3:PTPe";
+        let mut torrent = b"d8:announce21:http://localhost/annc4:info".to_vec();
+        torrent.extend_from_slice(info);
+        torrent.push(b'e');
+
+        let m: Metainfo = serde_bencode::from_bytes(&torrent)?;
+        assert_eq!(
+            m.info.extra.get("source"),
+            Some(&Value::Bytes(b"PTP".to_vec()))
+        );
+        assert_eq!(m.info_hash()?, hash::sha1(info));
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_file() -> Result<(), failure::Error> {
+        let parse = |files: &[u8]| -> Result<Metainfo, failure::Error> {
+            let mut info = b"d5:filesl".to_vec();
+            info.extend_from_slice(files);
+            info.extend_from_slice(b"e4:name4:test12:piece lengthi5e6:pieces40:");
+            info.extend_from_slice(&[b'a'; 40]);
+            info.push(b'e');
+            let mut torrent = b"d8:announce21:http://localhost/annc4:info".to_vec();
+            torrent.extend_from_slice(&info);
+            torrent.push(b'e');
+            let m = Metainfo::from_bytes(&torrent)?;
+            m.info.validate()?;
+            assert_eq!(m.info_hash()?, hash::sha1(&info));
+            Ok(m)
+        };
+        let m = parse(b"d6:lengthi3e4:pathl1:aeed6:lengthi4e4:pathl3:dir1:bee")?;
+        assert_eq!(m.info.total_length(), 7);
+        assert_eq!(m.num_pieces(), 2);
+        assert_eq!(
+            m.info.files.unwrap()[1],
+            FileEntry {
+                length: 4,
+                path: vec!["dir".to_owned(), "b".to_owned()],
+                extra: BTreeMap::new(),
+            }
+        );
+
+        // Paths that would be written outside the torrent's directory
+        for path in vec![
+            &b"l2:..6:passwde"[..],
+            b"l2:..2:..3:etc6:passwde",
+            b"l16:../../etc/passwde",
+            b"l0:3:etce",
+            b"l4:/etce",
+            b"l4:a\\bce",
+            b"l10:C:\\Windowse",
+            b"le",
+        ] {
+            let mut files = b"d6:lengthi7e4:path".to_vec();
+            files.extend_from_slice(path);
+            files.push(b'e');
+            match parse(&files).unwrap_err().downcast::<Error>()? {
+                Error::InvalidPath(_) => (),
+                e => panic!("unexpected error {:?}", e),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_extra_keys() -> Result<(), failure::Error> {
+        // BEP 47 padding and optional per file keys from other clients
+        let files = [
+            &b"d4:attr1:p6:lengthi3e4:pathl4:.pad1:0ee"[..],
+            b"d6:lengthi5e6:md5sum32:0123456789abcdef0123456789abcdef4:pathl1:ae10:path.utf-8l1:aee",
+        ];
+        let mut info = b"d5:filesl".to_vec();
+        files.iter().for_each(|f| info.extend_from_slice(f));
+        info.extend_from_slice(b"e4:name4:test12:piece lengthi8e6:pieces20:");
+        info.extend_from_slice(&[b'a'; 20]);
+        info.push(b'e');
+        let mut torrent = b"d8:announce21:http://localhost/annc4:info".to_vec();
+        torrent.extend_from_slice(&info);
+        torrent.push(b'e');
+
+        let m = Metainfo::from_bytes(&torrent)?;
+        let entries = m.info.files.as_ref().unwrap();
+        assert_eq!(
+            entries[0].extra.get("attr"),
+            Some(&Value::Bytes(b"p".to_vec()))
+        );
+        assert!(entries[1].extra.contains_key("md5sum"));
+        assert_eq!(m.info_hash()?, hash::sha1(&info));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        for name in &["CON", "nul", "Com1.txt", "lpt9.tar.gz", "AUX .log"] {
+            assert!(is_reserved_name(name), "{}", name);
+        }
+        for name in &["CONSOLE", "COM10", "a.nul", "lpt"] {
+            assert!(!is_reserved_name(name), "{}", name);
+        }
+        assert_eq!(is_safe_component("nul.txt"), !cfg!(windows));
+        assert_eq!(is_safe_component("C:"), !cfg!(windows));
+    }
+
+    #[test]
+    fn test_web_seeds() -> Result<(), failure::Error> {
+        let info = b"d6:lengthi5e4:name4:test12:piece lengthi5e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let parse = |url_list: &[u8]| -> Result<Metainfo, serde_bencode::Error> {
+            let mut torrent = b"d8:announce21:http://localhost/annc4:info".to_vec();
+            torrent.extend_from_slice(info);
+            torrent.extend_from_slice(url_list);
+            torrent.push(b'e');
+            serde_bencode::from_bytes(&torrent)
+        };
+        assert!(parse(b"")?.web_seeds().is_empty());
+        assert_eq!(
+            parse(b"8:url-list13:http://a/test")?.web_seeds(),
+            vec!["http://a/test"]
+        );
+        assert_eq!(
+            parse(b"8:url-listl9:http://a/i1e9:http://b/e")?.web_seeds(),
+            vec!["http://a/", "http://b/"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_announce_list_only() -> Result<(), failure::Error> {
+        let info = b"d6:lengthi5e4:name4:test12:piece lengthi5e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let parse = |announce: &[u8]| -> Result<Metainfo, Error> {
+            let mut torrent = b"d".to_vec();
+            torrent.extend_from_slice(announce);
+            torrent.extend_from_slice(b"4:info");
+            torrent.extend_from_slice(info);
+            torrent.push(b'e');
+            Metainfo::from_bytes(&torrent)
+        };
+        let list =
+            b"13:announce-listll17:http://a/announceel17:http://b/announce17:http://a/announceee";
+        let m = parse(list)?;
+        assert_eq!(m.announce, None);
+        assert_eq!(
+            m.trackers()?,
+            vec!["http://a/announce", "http://b/announce"]
+        );
+        // An empty announce is treated as missing
+        let mut empty = b"8:announce0:".to_vec();
+        empty.extend_from_slice(list);
+        assert_eq!(parse(&empty)?.trackers()?.len(), 2);
+
+        assert!(matches!(parse(b"")?.trackers(), Err(Error::NoTrackers)));
+        assert!(matches!(
+            parse(b"8:announce0:13:announce-listle")?.trackers(),
+            Err(Error::NoTrackers)
+        ));
+        assert_eq!(
+            parse(b"8:announce17:http://a/announce")?.trackers()?,
+            vec!["http://a/announce"]
+        );
+        Ok(())
+    }
 }
@@ -1,9 +1,12 @@
+use crate::hash;
 use bitvec::{bitvec, BitVec};
 use byteorder::{ReadBytesExt, WriteBytesExt, BE};
 use failure::{self, Fail};
 use log::{self, debug, error, info, warn};
+use std::cmp::min;
 use std::fmt;
 use std::io::{self, Read, Write};
+use std::net::Ipv4Addr;
 use std::str;
 use std::sync::Arc;
 
@@ -46,6 +49,22 @@ pub enum Message {
     Port(u16),
 }
 
+// Type of a message without its payload, used to count messages per type
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MessageKind {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have,
+    Request,
+    Cancel,
+    BitField,
+    Piece,
+    Port,
+}
+
 impl fmt::Debug for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -65,6 +84,22 @@ impl fmt::Debug for Message {
 }
 
 impl Message {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Message::KeepAlive => MessageKind::KeepAlive,
+            Message::Choke => MessageKind::Choke,
+            Message::Unchoke => MessageKind::Unchoke,
+            Message::Interested => MessageKind::Interested,
+            Message::NotInterested => MessageKind::NotInterested,
+            Message::Have(_) => MessageKind::Have,
+            Message::Request(_, _, _) => MessageKind::Request,
+            Message::Cancel(_, _, _) => MessageKind::Cancel,
+            Message::BitField(_) => MessageKind::BitField,
+            Message::Piece(_, _, _) => MessageKind::Piece,
+            Message::Port(_) => MessageKind::Port,
+        }
+    }
+
     fn len(&self) -> u32 {
         match self {
             Message::KeepAlive => 0,
@@ -189,21 +224,146 @@ impl Message {
     }
 }
 
-// TODO: Test
+const PSTR: &str = "BitTorrent Protocol";
+// Granularity at which progress within a piece is reported
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+// (begin, length) of each block of a piece, only the last of which may be shorter than block_size
+pub fn block_layout(piece_size: u32, block_size: u32) -> impl Iterator<Item = (u32, u32)> {
+    assert!(block_size > 0, "blocks must not be empty");
+    let blocks = piece_size / block_size + (piece_size % block_size != 0) as u32;
+    (0..blocks).map(move |b| {
+        let begin = b * block_size;
+        (begin, min(block_size, piece_size - begin))
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HandshakeKind {
+    Plaintext,
+    Encrypted,
+}
+
+impl HandshakeKind {
+    /// Classify a stream from the first bytes received.
+    /// MSE starts with a random public key, so anything that is not a prefix of a BitTorrent handshake is assumed to be encrypted.
+    pub fn detect(prefix: &[u8]) -> Self {
+        let pstr = PSTR.as_bytes();
+        match prefix.split_first() {
+            None => HandshakeKind::Plaintext,
+            Some((&len, rest)) if len as usize == pstr.len() => {
+                let n = min(rest.len(), pstr.len());
+                if rest[..n].eq_ignore_ascii_case(&pstr[..n]) {
+                    HandshakeKind::Plaintext
+                } else {
+                    HandshakeKind::Encrypted
+                }
+            }
+            _ => HandshakeKind::Encrypted,
+        }
+    }
+}
+
+// BEP 6: up to k pieces a peer may download while choked. The set only depends on the peer's /24
+// and the torrent, so it is the same whichever client computes it.
+pub fn allowed_fast(ip: Ipv4Addr, info_hash: &[u8], num_pieces: u32, k: usize) -> Vec<u32> {
+    let k = min(k, num_pieces as usize);
+    let mut set = Vec::with_capacity(k);
+    let mut x = (u32::from(ip) & 0xffff_ff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while set.len() < k {
+        let h = hash::sha1(&x);
+        for mut y in h.chunks(4) {
+            let index = y.read_u32::<BE>().unwrap() % num_pieces;
+            if set.len() < k && !set.contains(&index) {
+                set.push(index);
+            }
+        }
+        x = h.to_vec();
+    }
+    set
+}
+
+// Reserved bytes of the handshake, used to advertise protocol extensions
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Reserved(pub [u8; 8]);
+
+impl Reserved {
+    // BEP 5: the peer runs a DHT node and accepts Port messages
+    pub fn supports_dht(&self) -> bool {
+        self.get(7, 0x01)
+    }
+
+    // BEP 6: the peer understands the fast extension messages
+    pub fn supports_fast(&self) -> bool {
+        self.get(7, 0x04)
+    }
+
+    // BEP 10: the peer understands extended messages
+    pub fn supports_extended(&self) -> bool {
+        self.get(5, 0x10)
+    }
+
+    pub fn set_dht(&mut self, dht: bool) -> &mut Self {
+        self.set(7, 0x01, dht)
+    }
+
+    pub fn set_fast(&mut self, fast: bool) -> &mut Self {
+        self.set(7, 0x04, fast)
+    }
+
+    pub fn set_extended(&mut self, extended: bool) -> &mut Self {
+        self.set(5, 0x10, extended)
+    }
+
+    fn get(&self, byte: usize, mask: u8) -> bool {
+        self.0[byte] & mask != 0
+    }
+
+    fn set(&mut self, byte: usize, mask: u8, value: bool) -> &mut Self {
+        if value {
+            self.0[byte] |= mask;
+        } else {
+            self.0[byte] &= !mask;
+        }
+        self
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum HandshakeError {
+    #[fail(display = "truncated handshake: {}", _0)]
+    Truncated(#[fail(cause)] io::Error),
+    #[fail(display = "invalid info hash (expected: {:x?}, actual: {:x?})", _0, _1)]
+    InfoHashMismatch(Vec<u8>, [u8; 20]),
+    #[fail(display = "connected to ourselves")]
+    SelfConnection,
+}
+
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> Self {
+        HandshakeError::Truncated(e)
+    }
+}
+
+// A handshake received from a peer, already checked against our torrent and id
+#[derive(Debug, PartialEq)]
 pub struct Handshake {
-    info_hash: [u8; 20],
-    peer_id: [u8; 20],
+    pub reserved: Reserved,
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
 }
 
 impl Handshake {
     pub fn send<W: Write>(
         info_hash: &[u8],
         peer_id: Option<&[u8]>,
+        reserved: Reserved,
         mut writer: W,
     ) -> io::Result<()> {
-        writer.write_u8(19)?;
-        writer.write("BitTorrent Protocol".as_bytes())?;
-        writer.write(&[0; 8])?;
+        writer.write_u8(PSTR.len() as u8)?;
+        writer.write(PSTR.as_bytes())?;
+        writer.write(&reserved.0)?;
         writer.write(info_hash)?;
         if let Some(pid) = peer_id {
             writer.write(pid)?;
@@ -211,38 +371,68 @@ impl Handshake {
         Ok(())
     }
 
-    pub fn recv<R: Read>(info_hash: &[u8], client_id: &[u8], mut reader: R) -> bool {
-        let pstr_len = reader.read_u8().unwrap();
-        let mut sent_data: Vec<u8> = vec![0; pstr_len as usize];
-        reader.read_exact(&mut sent_data).unwrap();
-        debug!("pstr: {}", str::from_utf8(&sent_data).unwrap());
-
-        if let Err(_) = io::copy(&mut reader.by_ref().take(8), &mut io::sink()) {
-            return false;
+    // Reads exactly the handshake, so messages pipelined after it are left in the reader
+    pub fn read<R: Read>(
+        info_hash: &[u8],
+        client_id: &[u8],
+        mut reader: R,
+    ) -> Result<Handshake, HandshakeError> {
+        let pstr_len = reader.read_u8()?;
+        let mut pstr = vec![0; pstr_len as usize];
+        reader.read_exact(&mut pstr)?;
+        debug!("pstr: {}", String::from_utf8_lossy(&pstr));
+
+        let mut handshake = Handshake {
+            reserved: Reserved::default(),
+            info_hash: [0; 20],
+            peer_id: [0; 20],
+        };
+        reader.read_exact(&mut handshake.reserved.0)?;
+        reader.read_exact(&mut handshake.info_hash)?;
+        if &handshake.info_hash != info_hash {
+            return Err(HandshakeError::InfoHashMismatch(
+                info_hash.to_vec(),
+                handshake.info_hash,
+            ));
         }
+        debug!("Verified info hash");
 
-        let mut sent_data = [0; 20];
-
-        if let Err(_) = reader.read_exact(&mut sent_data) {
-            return false;
-        } else if &sent_data != info_hash {
-            error!(
-                "Invalid info hash (expected: {:x?}, actual: {:x?})",
-                info_hash, sent_data
-            );
-            return false;
+        reader.read_exact(&mut handshake.peer_id)?;
+        if client_id == &handshake.peer_id {
+            return Err(HandshakeError::SelfConnection);
         }
-        debug!("Verified info hash");
+        debug!(
+            "Verified peer id {}",
+            String::from_utf8_lossy(&handshake.peer_id)
+        );
+        Ok(handshake)
+    }
 
-        sent_data = [0; 20];
-        if let Err(_) = reader.read_exact(&mut sent_data) {
-            return false;
-        } else if client_id == &sent_data {
-            return false;
+    // Returns the peer's reserved bytes if the handshake is valid
+    pub fn recv<R: Read>(info_hash: &[u8], client_id: &[u8], reader: R) -> Option<Reserved> {
+        match Handshake::read(info_hash, client_id, reader) {
+            Ok(handshake) => Some(handshake.reserved),
+            Err(e @ HandshakeError::InfoHashMismatch(..)) => {
+                error!("{}", e);
+                None
+            }
+            Err(e) => {
+                debug!("Handshake failed: {}", e);
+                None
+            }
         }
-        debug!("Verified peer id {}", str::from_utf8(&sent_data).unwrap());
+    }
 
-        true
+    // Sends our handshake and then reads the peer's, over any stream
+    pub fn exchange<S: Read + Write>(
+        info_hash: &[u8],
+        client_id: &[u8],
+        reserved: Reserved,
+        mut stream: S,
+    ) -> Result<Handshake, HandshakeError> {
+        Handshake::send(info_hash, Some(client_id), reserved, &mut stream)?;
+        stream.flush()?;
+        Handshake::read(info_hash, client_id, stream)
     }
 }
 
@@ -250,6 +440,7 @@ impl Handshake {
 mod tests {
     use super::*;
     use bitvec::bitvec;
+    use matches::matches;
     use std::io::Cursor;
 
     #[test]
@@ -365,4 +556,206 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_detect_handshake() -> Result<(), failure::Error> {
+        let mut d = Vec::new();
+        Handshake::send(&[0; 20], None, Reserved::default(), &mut d)?;
+        assert_eq!(HandshakeKind::detect(&d), HandshakeKind::Plaintext);
+        assert_eq!(HandshakeKind::detect(&d[..5]), HandshakeKind::Plaintext);
+        assert_eq!(
+            HandshakeKind::detect(b"\x13BitTorrent protocol"),
+            HandshakeKind::Plaintext
+        );
+        assert_eq!(HandshakeKind::detect(&[]), HandshakeKind::Plaintext);
+
+        assert_eq!(
+            HandshakeKind::detect(&[19, 200, 3, 42, 7]),
+            HandshakeKind::Encrypted
+        );
+        assert_eq!(
+            HandshakeKind::detect(&[96, 1, 2, 3]),
+            HandshakeKind::Encrypted
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_layout() {
+        assert_eq!(
+            block_layout(3 * BLOCK_SIZE, BLOCK_SIZE).collect::<Vec<_>>(),
+            vec![
+                (0, BLOCK_SIZE),
+                (BLOCK_SIZE, BLOCK_SIZE),
+                (2 * BLOCK_SIZE, BLOCK_SIZE)
+            ]
+        );
+        assert_eq!(
+            block_layout(2 * BLOCK_SIZE + 100, BLOCK_SIZE).collect::<Vec<_>>(),
+            vec![
+                (0, BLOCK_SIZE),
+                (BLOCK_SIZE, BLOCK_SIZE),
+                (2 * BLOCK_SIZE, 100)
+            ]
+        );
+        assert_eq!(
+            block_layout(100, BLOCK_SIZE).collect::<Vec<_>>(),
+            vec![(0, 100)]
+        );
+        assert_eq!(block_layout(0, BLOCK_SIZE).count(), 0);
+        // No overflow for the largest pieces
+        assert_eq!(
+            block_layout(u32::max_value(), BLOCK_SIZE).last(),
+            Some((u32::max_value() - BLOCK_SIZE + 1, BLOCK_SIZE - 1))
+        );
+    }
+
+    #[test]
+    fn test_allowed_fast() {
+        // Example from BEP 6
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = [0xaa; 20];
+        assert_eq!(
+            allowed_fast(ip, &info_hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast(ip, &info_hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        // Same subnet, same set
+        assert_eq!(
+            allowed_fast(Ipv4Addr::new(80, 4, 4, 1), &info_hash, 1313, 7),
+            allowed_fast(ip, &info_hash, 1313, 7)
+        );
+        // Never more pieces than the torrent has
+        let mut small = allowed_fast(ip, &info_hash, 3, 7);
+        small.sort();
+        assert_eq!(small, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_reserved_bits() -> Result<(), failure::Error> {
+        let mut r = Reserved::default();
+        r.set_dht(true);
+        assert_eq!(r.0, [0, 0, 0, 0, 0, 0, 0, 0x01]);
+        r.set_fast(true).set_dht(false);
+        assert_eq!(r.0, [0, 0, 0, 0, 0, 0, 0, 0x04]);
+        r.set_extended(true);
+        assert_eq!(r.0, [0, 0, 0, 0, 0, 0x10, 0, 0x04]);
+        assert!(!r.supports_dht() && r.supports_fast() && r.supports_extended());
+
+        // Reserved bytes from a real client handshake (DHT, fast and extended)
+        let r = Reserved([0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+        assert!(r.supports_dht() && r.supports_fast() && r.supports_extended());
+
+        // The peer's reserved bytes are returned from the handshake
+        let mut d = Vec::new();
+        Handshake::send(&[1; 20], Some(&[b'p'; 20]), r, &mut d)?;
+        assert_eq!(&d[20..28], &r.0);
+        assert_eq!(
+            Handshake::recv(&[1; 20], &[b'c'; 20], d.as_slice()),
+            Some(r)
+        );
+        Ok(())
+    }
+
+    // One end of a connection: reads what the peer sent and collects what we write
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn peer_handshake(info_hash: &[u8], peer_id: &[u8], reserved: Reserved) -> Duplex {
+        let mut input = Vec::new();
+        Handshake::send(info_hash, Some(peer_id), reserved, &mut input).unwrap();
+        // A message pipelined after the handshake
+        Message::Interested.send(&mut input).unwrap();
+        Duplex {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_handshake_exchange() -> Result<(), failure::Error> {
+        let info_hash = [1; 20];
+        let client_id = [b'c'; 20];
+        let mut reserved = Reserved::default();
+        reserved.set_fast(true);
+
+        let mut stream = peer_handshake(&info_hash, &[b'p'; 20], reserved);
+        let handshake =
+            Handshake::exchange(&info_hash, &client_id, Reserved::default(), &mut stream)?;
+        assert_eq!(
+            handshake,
+            Handshake {
+                reserved,
+                info_hash,
+                peer_id: [b'p'; 20],
+            }
+        );
+        let mut ours = Vec::new();
+        Handshake::send(&info_hash, Some(&client_id), Reserved::default(), &mut ours)?;
+        assert_eq!(stream.output, ours);
+        assert_eq!(Message::recv(&mut stream.input)?, Message::Interested);
+
+        // Every other torrent is refused
+        let mut stream = peer_handshake(&[2; 20], &[b'p'; 20], reserved);
+        match Handshake::exchange(&info_hash, &client_id, reserved, &mut stream) {
+            Err(HandshakeError::InfoHashMismatch(expected, actual)) => {
+                assert_eq!(expected, info_hash.to_vec());
+                assert_eq!(actual, [2; 20]);
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        // Our own id comes back when we dial one of our own addresses
+        let mut stream = peer_handshake(&info_hash, &client_id, reserved);
+        assert!(matches!(
+            Handshake::exchange(&info_hash, &client_id, reserved, &mut stream),
+            Err(HandshakeError::SelfConnection)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_handshake() {
+        let info_hash = [1; 20];
+        let mut full = Vec::new();
+        Handshake::send(
+            &info_hash,
+            Some(&[b'p'; 20]),
+            Reserved::default(),
+            &mut full,
+        )
+        .unwrap();
+        assert!(Handshake::read(&info_hash, &[b'c'; 20], full.as_slice()).is_ok());
+        for len in 0..full.len() {
+            let res = Handshake::read(&info_hash, &[b'c'; 20], &full[..len]);
+            assert!(
+                matches!(res, Err(HandshakeError::Truncated(_))),
+                "{} bytes: {:?}",
+                len,
+                res
+            );
+            assert_eq!(Handshake::recv(&info_hash, &[b'c'; 20], &full[..len]), None);
+        }
+    }
 }
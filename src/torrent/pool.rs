@@ -0,0 +1,150 @@
+use crate::tracker::PeerInfo;
+use log::debug;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+
+// Where a peer address was learnt from, in the order candidates are handed out
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    Tracker,
+    Dht,
+    Pex,
+    Lsd,
+    // Sources given with --discover
+    Custom,
+    // Connected in a previous session
+    Saved,
+}
+
+// Which address families are dialed, and which first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Family {
+    Any,
+    // IPv6 peers are handed out before IPv4 peers from any source
+    PreferIpv6,
+    // IPv6 peers are dropped when added
+    Ipv4Only,
+}
+
+impl Default for Family {
+    fn default() -> Self {
+        Family::Any
+    }
+}
+
+// Peer addresses from every discovery source, each handed out for dialing at most once
+#[derive(Default)]
+pub struct PeerPool {
+    queues: BTreeMap<Source, VecDeque<PeerInfo>>,
+    // Every address ever added, so repeats from any source are ignored
+    known: HashSet<SocketAddr>,
+    family: Family,
+}
+
+impl PeerPool {
+    pub fn set_family(&mut self, family: Family) {
+        self.family = family;
+    }
+
+    pub fn add<I: IntoIterator<Item = PeerInfo>>(&mut self, source: Source, peers: I) {
+        let queue = self.queues.entry(source).or_default();
+        for peer in peers {
+            if self.family == Family::Ipv4Only && peer.addr.is_ipv6() {
+                debug!("Ignoring IPv6 peer {} from {:?}", peer.addr, source);
+            } else if self.known.insert(peer.addr) {
+                queue.push_back(peer);
+            } else {
+                debug!("Ignoring known peer {} from {:?}", peer.addr, source);
+            }
+        }
+    }
+
+    // Up to n peers that have not been handed out before, highest priority source first. A
+    // preferred family goes before every other peer regardless of source.
+    pub fn candidates(&mut self, n: usize) -> Vec<PeerInfo> {
+        let mut v = Vec::new();
+        if self.family == Family::PreferIpv6 {
+            self.take(n, &mut v, |p| p.addr.is_ipv6());
+        }
+        self.take(n, &mut v, |_| true);
+        v
+    }
+
+    fn take<F: Fn(&PeerInfo) -> bool>(&mut self, n: usize, v: &mut Vec<PeerInfo>, wanted: F) {
+        for queue in self.queues.values_mut() {
+            let mut i = 0;
+            while v.len() < n && i < queue.len() {
+                if wanted(&queue[i]) {
+                    v.extend(queue.remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    // Whether the address was ever added, from any source
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.known.contains(addr)
+    }
+
+    // Number of peers not yet handed out
+    pub fn len(&self) -> usize {
+        self.queues.values().map(|q| q.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> PeerInfo {
+        PeerInfo {
+            addr: ([127, 0, 0, 1], port).into(),
+        }
+    }
+
+    fn peer6(port: u16) -> PeerInfo {
+        PeerInfo {
+            addr: ([0, 0, 0, 0, 0, 0, 0, 1], port).into(),
+        }
+    }
+
+    #[test]
+    fn test_dedup() {
+        let mut pool = PeerPool::default();
+        pool.add(Source::Tracker, vec![peer(1), peer(2), peer(2)]);
+        pool.add(Source::Dht, vec![peer(3), peer(1)]);
+        assert_eq!(pool.len(), 3);
+
+        // Tracker peers first, each address dialed once
+        assert_eq!(pool.candidates(2), vec![peer(1), peer(2)]);
+        pool.add(Source::Pex, vec![peer(2), peer(4)]);
+        assert_eq!(pool.candidates(5), vec![peer(3), peer(4)]);
+        assert!(pool.candidates(5).is_empty());
+    }
+
+    #[test]
+    fn test_family() {
+        let mut pool = PeerPool::default();
+        pool.add(Source::Tracker, vec![peer(1), peer6(1)]);
+        pool.add(Source::Dht, vec![peer6(2)]);
+        assert_eq!(pool.candidates(2), vec![peer(1), peer6(1)]);
+
+        let mut pool = PeerPool::default();
+        pool.set_family(Family::PreferIpv6);
+        pool.add(Source::Tracker, vec![peer(1), peer6(1)]);
+        pool.add(Source::Dht, vec![peer6(2)]);
+        assert_eq!(pool.candidates(2), vec![peer6(1), peer6(2)]);
+        assert_eq!(pool.candidates(2), vec![peer(1)]);
+
+        // IPv6 peers are never dialed
+        let mut pool = PeerPool::default();
+        pool.set_family(Family::Ipv4Only);
+        pool.add(Source::Tracker, vec![peer6(1), peer(1)]);
+        pool.add(Source::Dht, vec![peer6(2)]);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.candidates(5), vec![peer(1)]);
+        assert!(!pool.contains(&peer6(1).addr));
+    }
+}
@@ -0,0 +1,165 @@
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+// A peer is banned once it has sent this many bad pieces and more bad pieces than good ones
+const BAN_THRESHOLD: u64 = 2;
+// A bad piece wastes a full piece of bandwidth, so counts for more than a good one
+const BAD_PIECE_WEIGHT: i64 = 4;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub good_pieces: u64,
+    pub bad_pieces: u64,
+    pub disconnects: u64,
+}
+
+impl Record {
+    fn merge(&mut self, other: &Record) {
+        self.good_pieces += other.good_pieces;
+        self.bad_pieces += other.bad_pieces;
+        self.disconnects += other.disconnects;
+    }
+}
+
+// Historical behaviour of peers keyed by IP address, persisted between sessions
+// The port is left out because an inbound peer connects from a new port every time
+#[derive(Debug, Default, PartialEq)]
+pub struct Reputation {
+    records: BTreeMap<IpAddr, Record>,
+}
+
+impl Reputation {
+    // A missing file is treated as an empty history
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let stored: BTreeMap<String, Record> = serde_bencode::from_bytes(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        // Files written before records were keyed by IP use "ip:port", which are merged
+        let mut records = BTreeMap::new();
+        for (key, record) in stored {
+            let ip = match key.parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(_) => match key.parse::<SocketAddr>() {
+                    Ok(addr) => addr.ip(),
+                    Err(_) => continue,
+                },
+            };
+            records
+                .entry(ip)
+                .or_insert_with(Record::default)
+                .merge(&record);
+        }
+        Ok(Self { records })
+    }
+
+    // Written to a temporary file first, so a crash mid-write keeps the previous history
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let stored: BTreeMap<String, &Record> = self
+            .records
+            .iter()
+            .map(|(ip, record)| (ip.to_string(), record))
+            .collect();
+        let data = serde_bencode::to_bytes(&stored)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let tmp = path.as_ref().with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, path)
+    }
+
+    pub fn get(&self, ip: IpAddr) -> Option<&Record> {
+        self.records.get(&ip)
+    }
+
+    pub fn good_piece(&mut self, ip: IpAddr) {
+        self.records.entry(ip).or_default().good_pieces += 1;
+    }
+
+    pub fn bad_piece(&mut self, ip: IpAddr) {
+        debug!("{}: Recording bad piece", ip);
+        self.records.entry(ip).or_default().bad_pieces += 1;
+    }
+
+    pub fn disconnect(&mut self, ip: IpAddr) {
+        self.records.entry(ip).or_default().disconnects += 1;
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        match self.records.get(&ip) {
+            Some(r) => r.bad_pieces >= BAN_THRESHOLD && r.bad_pieces > r.good_pieces,
+            None => false,
+        }
+    }
+
+    // Higher is better, unknown peers score 0
+    pub fn score(&self, ip: IpAddr) -> i64 {
+        match self.records.get(&ip) {
+            Some(r) => {
+                r.good_pieces as i64 - BAD_PIECE_WEIGHT * r.bad_pieces as i64 - r.disconnects as i64
+            }
+            None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persistence() -> io::Result<()> {
+        let path = std::env::temp_dir().join("continuity_test_reputation");
+        let _ = fs::remove_file(&path);
+        assert_eq!(Reputation::load(&path)?, Reputation::default());
+
+        let good = "10.0.0.1".parse().unwrap();
+        let bad = "10.0.0.2".parse().unwrap();
+        let mut rep = Reputation::default();
+        rep.good_piece(good);
+        rep.good_piece(good);
+        rep.bad_piece(bad);
+        rep.bad_piece(bad);
+        rep.disconnect(bad);
+        rep.save(&path)?;
+
+        let rep = Reputation::load(&path)?;
+        fs::remove_file(&path)?;
+        assert_eq!(rep.score(good), 2);
+        assert_eq!(rep.score(bad), -9);
+        assert_eq!(rep.score("10.0.0.3".parse().unwrap()), 0);
+        assert!(rep.is_banned(bad));
+        assert!(!rep.is_banned(good));
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_socket_addr_keys() -> io::Result<()> {
+        let path = std::env::temp_dir().join("continuity_test_reputation_ports");
+        let mut stored = BTreeMap::new();
+        for port in 1..3 {
+            let record = Record {
+                bad_pieces: 1,
+                ..Default::default()
+            };
+            stored.insert(format!("10.0.0.1:{}", port), record);
+        }
+        stored.insert("[::1]:3".to_owned(), Record::default());
+        fs::write(&path, serde_bencode::to_bytes(&stored).unwrap())?;
+
+        // Each connection from the same address counts against it
+        let rep = Reputation::load(&path)?;
+        fs::remove_file(&path)?;
+        assert!(rep.is_banned("10.0.0.1".parse().unwrap()));
+        assert!(rep.get("::1".parse().unwrap()).is_some());
+        assert_eq!(rep.records.len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use crate::connection::PeerView;
+use crate::reputation::Record;
+use std::str::FromStr;
+
+// Bytes delivered in one snapshot interval at which the rate term is worth half its weight
+const RATE_SCALE: f64 = 1024.0 * 1024.0;
+
+// Relative importance of each part of a peer's score
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScoreWeights {
+    pub rate: f64,
+    pub reliability: f64,
+    pub availability: f64,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        ScoreWeights {
+            rate: 1.0,
+            reliability: 1.0,
+            availability: 1.0,
+        }
+    }
+}
+
+// RATE,RELIABILITY,AVAILABILITY
+impl FromStr for ScoreWeights {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let weights: Vec<f64> = s
+            .split(',')
+            .map(|w| w.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("{} is not a list of numbers", s))?;
+        match weights[..] {
+            [rate, reliability, availability]
+                if weights.iter().all(|w| w.is_finite() && *w >= 0.0) =>
+            {
+                Ok(ScoreWeights {
+                    rate,
+                    reliability,
+                    availability,
+                })
+            }
+            _ => Err(format!(
+                "{} is not three non-negative weights: rate,reliability,availability",
+                s
+            )),
+        }
+    }
+}
+
+// How valuable a peer is to us, as of its last snapshot. Each part is scaled to [0, 1) before
+// weighting, so the weights alone decide how they trade off.
+pub fn peer_score(peer: &PeerView, record: Option<&Record>, weights: &ScoreWeights) -> f64 {
+    let snapshot = peer.snapshot();
+    score(
+        snapshot.downloaded_bytes,
+        record,
+        snapshot.needed,
+        snapshot.left,
+        weights,
+    )
+}
+
+fn score(
+    downloaded_bytes: u64,
+    record: Option<&Record>,
+    needed: u32,
+    left: u32,
+    weights: &ScoreWeights,
+) -> f64 {
+    let bytes = downloaded_bytes as f64;
+    let rate = bytes / (bytes + RATE_SCALE);
+    // Unknown peers start halfway between a good and a bad one
+    let (good, bad) = record.map_or((0, 0), |r| (r.good_pieces, r.bad_pieces));
+    let reliability = (good as f64 + 1.0) / ((good + bad) as f64 + 2.0);
+    let availability = match left {
+        0 => 0.0,
+        left => f64::from(needed) / f64::from(left),
+    };
+    weights.rate * rate + weights.reliability * reliability + weights.availability * availability
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(good_pieces: u64, bad_pieces: u64) -> Option<Record> {
+        Some(Record {
+            good_pieces,
+            bad_pieces,
+            disconnects: 0,
+        })
+    }
+
+    #[test]
+    fn test_ordering() {
+        let w = ScoreWeights::default();
+        let mb = RATE_SCALE as u64;
+        let mut peers = vec![
+            ("idle", score(0, None, 0, 10, &w)),
+            ("fast", score(4 * mb, record(5, 0).as_ref(), 2, 10, &w)),
+            (
+                "unreliable",
+                score(4 * mb, record(1, 5).as_ref(), 2, 10, &w),
+            ),
+            ("seed", score(0, None, 10, 10, &w)),
+            ("slow", score(mb / 2, record(5, 0).as_ref(), 2, 10, &w)),
+        ];
+        peers.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        let order: Vec<_> = peers.iter().map(|(id, _)| *id).collect();
+        assert_eq!(order, vec!["fast", "seed", "slow", "unreliable", "idle"]);
+
+        // Only rate counts when it is the only weight
+        let w: ScoreWeights = "1,0,0".parse().unwrap();
+        assert!(score(mb, None, 0, 10, &w) > score(0, record(9, 0).as_ref(), 10, 10, &w));
+        assert_eq!(score(0, None, 10, 0, &w), 0.0);
+        assert!("1,2".parse::<ScoreWeights>().is_err());
+        assert!("1,-1,0".parse::<ScoreWeights>().is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use super::{Inorder, Rare, Selector, State};
 use rand::prelude::*;
+use rand::rngs::StdRng;
 use smart_default::SmartDefault;
 
 #[derive(SmartDefault)]
@@ -8,11 +9,30 @@ pub struct Bitos {
     rare: Rare,
     #[default = 0.8]
     pub inorder_p: f64,
+    // Splits requests between the selectors, thread_rng is used when unset
+    rng: Option<StdRng>,
+}
+
+impl Bitos {
+    // Deterministic selection for reproducible runs, the rare selector is seeded from rng
+    pub fn with_rng(mut rng: StdRng) -> Self {
+        Bitos {
+            rare: Rare::with_rng(StdRng::seed_from_u64(rng.gen())),
+            rng: Some(rng),
+            ..Default::default()
+        }
+    }
 }
 
 impl Selector for Bitos {
-    fn request_pieces(&mut self, id: &str, state: State, n: u32) -> Vec<u32> {
-        let mut rng = rand::thread_rng();
+    fn request_pieces(&mut self, id: &str, mut state: State, n: u32) -> Vec<u32> {
+        // Pieces picked in order are removed from the rare selector's required set by index
+        state.fit();
+        let mut thread_rng = rand::thread_rng();
+        let rng: &mut dyn RngCore = match self.rng.as_mut() {
+            Some(rng) => rng,
+            None => &mut thread_rng,
+        };
         let mut num_inorder = 0;
         let mut num_rare = 0;
         for _ in 0..n {
@@ -31,4 +51,16 @@ impl Selector for Bitos {
         v.extend(self.rare.request_pieces(id, state_c, num_rare));
         v
     }
+
+    fn rarity(&self) -> Option<Vec<usize>> {
+        self.rare.rarity()
+    }
+
+    fn reset(&mut self) {
+        self.rare.reset()
+    }
+
+    fn peer_left(&mut self, id: &str) {
+        self.rare.peer_left(id)
+    }
 }
@@ -5,6 +5,7 @@ pub struct Inorder {}
 
 impl Selector for Inorder {
     fn request_pieces(&mut self, _: &str, mut state: State, n: u32) -> Vec<u32> {
+        state.fit();
         state.available &= state.required;
         // let v: Vec<_> = availability
         //     .iter()
@@ -1,4 +1,7 @@
 use bitvec::BitVec;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::cmp::max;
 
 pub mod bitos;
 pub use bitos::Bitos;
@@ -6,13 +9,104 @@ pub mod inorder;
 pub use inorder::Inorder;
 pub mod rare;
 pub use rare::Rare;
+pub mod smart;
+pub use smart::Smart;
+pub mod stream;
+pub use stream::Stream;
 
 #[derive(Clone)]
 pub struct State {
     pub required: BitVec,  // Vector of pieces client needs
     pub available: BitVec, // Vector of available pieces
+    pub downloaded: u32,   // Number of pieces stored, not counting those in flight
+}
+
+impl State {
+    // Pad the shorter vector with unset bits, so both can be indexed by any piece and pieces past
+    // the end of either are never chosen
+    pub fn fit(&mut self) {
+        let len = max(self.required.len(), self.available.len());
+        self.required.resize(len, false);
+        self.available.resize(len, false);
+    }
+}
+
+// Construct a selector from its command line name
+pub fn from_name(name: &str) -> Option<Box<dyn Selector + Send + Sync>> {
+    from_name_seeded(name, None)
+}
+
+// As from_name, but with randomised choices reproducible from seed when given
+pub fn from_name_seeded(name: &str, seed: Option<u64>) -> Option<Box<dyn Selector + Send + Sync>> {
+    let rng = seed.map(StdRng::seed_from_u64);
+    match (name, rng) {
+        ("inorder", _) => Some(Box::new(Inorder::default())),
+        ("rarest", Some(rng)) => Some(Box::new(Rare::with_rng(rng))),
+        ("rarest", None) => Some(Box::new(Rare::default())),
+        ("bitos", Some(rng)) => Some(Box::new(Bitos::with_rng(rng))),
+        ("bitos", None) => Some(Box::new(Bitos::default())),
+        ("stream", _) => Some(Box::new(Stream::default())),
+        ("smart", Some(rng)) => Some(Box::new(Smart::with_rng(rng))),
+        ("smart", None) => Some(Box::new(Smart::default())),
+        _ => None,
+    }
 }
 
 pub trait Selector {
     fn request_pieces(&mut self, id: &str, state: State, n: u32) -> Vec<u32>;
+
+    // Diagnostics for rarity based selectors
+    fn rarity(&self) -> Option<Vec<usize>> {
+        None
+    }
+
+    fn reset(&mut self) {}
+
+    // Called when a peer disconnects, so its availability no longer counts
+    fn peer_left(&mut self, _id: &str) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitvec::bitvec;
+
+    fn selections(name: &str, seed: u64) -> Vec<Vec<u32>> {
+        let mut s = from_name_seeded(name, Some(seed)).unwrap();
+        (0..8)
+            .map(|i| {
+                let state = State {
+                    required: bitvec![1; 64],
+                    available: bitvec![1; 64],
+                    downloaded: 0,
+                };
+                s.request_pieces(&i.to_string(), state, 4)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_mismatched_lengths() {
+        for name in ["inorder", "rarest", "bitos", "stream", "smart"].iter() {
+            let mut s = from_name_seeded(name, Some(0)).unwrap();
+            for (required, available) in vec![(8, 12), (12, 8)] {
+                let state = State {
+                    required: bitvec![1; required],
+                    available: bitvec![1; available],
+                    downloaded: 0,
+                };
+                let v = s.request_pieces("peer", state, 16);
+                assert!(!v.is_empty(), "{} selected nothing", name);
+                assert!(v.iter().all(|i| *i < 8), "{} selected {:?}", name, v);
+            }
+        }
+    }
+
+    #[test]
+    fn test_seeded_selection() {
+        for name in ["rarest", "bitos"].iter() {
+            assert_eq!(selections(name, 7), selections(name, 7));
+            assert_ne!(selections(name, 7), selections(name, 8));
+        }
+    }
 }
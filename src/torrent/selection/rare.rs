@@ -2,24 +2,45 @@ use super::{Selector, State};
 use bitvec::BitVec;
 use log::{self, debug, error, info, warn};
 use rand::prelude::*;
+use rand::rngs::StdRng;
 use std::cmp::min;
 use std::collections::HashMap;
 
+// How to choose between pieces that are equally rare
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TieBreak {
+    // Randomly, so that peers do not all request the same pieces
+    Shuffle,
+    // Lowest index first, for output that depends only on the state
+    LowestIndex,
+}
+
+impl Default for TieBreak {
+    fn default() -> Self {
+        TieBreak::Shuffle
+    }
+}
+
 #[derive(Default)]
 pub struct Rare {
     pub history: HashMap<String, BitVec>,
     pub rarity: Vec<usize>,
+    pub tie_break: TieBreak,
+    // Breaks ties between equally rare pieces, thread_rng is used when unset
+    rng: Option<StdRng>,
 }
 
 impl Selector for Rare {
-    fn request_pieces(&mut self, id: &str, state: State, n: u32) -> Vec<u32> {
+    fn request_pieces(&mut self, id: &str, mut state: State, n: u32) -> Vec<u32> {
+        state.fit();
         // Satisfy integrity of internal data
         let required = state.required;
+        let available = state.available.clone();
         if self.history.contains_key(id) {
             let availability = self.history.get_mut(id).unwrap();
-            // Set difference of historically available and currently available
-            let mut bv = !state.available.clone();
-            bv &= availability.iter();
+            // Set difference of currently available and historically available
+            let mut bv = !availability.clone();
+            bv &= state.available.iter();
             // Update history
             *availability = state.available;
             self.update_rarity(&bv);
@@ -28,9 +49,9 @@ impl Selector for Rare {
             self.history.insert(id.to_owned(), state.available);
         }
 
-        // Create a vector of piece indices, filtered by required and sorted by rarity
+        // Create a vector of piece indices, filtered by required and available and sorted by rarity
         let mut v: Vec<u32> = (0..required.len())
-            .filter(|i| required[*i])
+            .filter(|i| required[*i] && available[*i])
             .map(|i| i as u32)
             .collect();
         if v.len() == 0 {
@@ -46,22 +67,82 @@ impl Selector for Rare {
             .map(|i| *i)
             .collect();
         let mut v = v.split_off(ret.len());
-        let mut rng = rand::thread_rng();
-        v.shuffle(&mut rng);
+        // The sort by rarity is stable, so equally rare pieces are already lowest index first
+        if self.tie_break == TieBreak::Shuffle {
+            let mut thread_rng = rand::thread_rng();
+            let rng: &mut dyn RngCore = match self.rng.as_mut() {
+                Some(rng) => rng,
+                None => &mut thread_rng,
+            };
+            v.shuffle(rng);
+            debug!("Shuffled {:?}", v);
+        }
         v.truncate(n as usize - ret.len());
-        debug!("Shuffled {:?}", v);
         ret.extend(v.into_iter());
 
         // Shrink vector to required size and return
         debug!("Piece Requests: {:?}", ret);
         ret
     }
+
+    fn rarity(&self) -> Option<Vec<usize>> {
+        Some(self.rarity_snapshot())
+    }
+
+    fn reset(&mut self) {
+        Rare::reset(self)
+    }
+
+    fn peer_left(&mut self, id: &str) {
+        Rare::peer_left(self, id)
+    }
 }
 
 impl Rare {
+    // Deterministic selection for reproducible runs
+    pub fn with_rng(rng: StdRng) -> Self {
+        Rare {
+            rng: Some(rng),
+            ..Default::default()
+        }
+    }
+
+    // Equally rare pieces are picked lowest index first rather than shuffled
+    pub fn deterministic() -> Self {
+        Rare {
+            tie_break: TieBreak::LowestIndex,
+            ..Default::default()
+        }
+    }
+
+    pub fn rarity_snapshot(&self) -> Vec<usize> {
+        self.rarity.clone()
+    }
+
+    // Forget all peer history, e.g. when the swarm has changed significantly
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.rarity.clear();
+    }
+
+    // Remove everything the peer contributed to rarity
+    pub fn peer_left(&mut self, id: &str) {
+        if let Some(availability) = self.history.remove(id) {
+            self.rarity
+                .iter_mut()
+                .zip(availability.iter())
+                .for_each(|(rarity, available)| {
+                    if available {
+                        *rarity -= 1;
+                    }
+                });
+        }
+    }
+
     fn update_rarity(&mut self, bv: &BitVec) {
-        if self.rarity.len() == 0 {
-            self.rarity = vec![0; bv.len()]
+        // Longer availability than seen before must still be indexable
+        if self.rarity.len() < bv.len() {
+            self.rarity.resize(bv.len(), 0);
         }
         self.rarity
             .iter_mut()
@@ -77,3 +158,81 @@ impl Rare {
 // Compiler cannot implement sync automatically because BitVec is not sync
 // This is a library issue; BitVec should be safe to synchronise
 unsafe impl Sync for Rare {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitvec::bitvec;
+
+    fn state(available: BitVec) -> State {
+        State {
+            required: bitvec![1; available.len()],
+            available,
+            downloaded: 0,
+        }
+    }
+
+    #[test]
+    fn test_known_peer_gains() {
+        let mut r = Rare::default();
+        r.request_pieces("a", state(bitvec![1, 0, 0, 1]), 1);
+        // A Have from a known peer adds only the new piece
+        r.request_pieces("a", state(bitvec![1, 0, 1, 1]), 1);
+        assert_eq!(r.rarity_snapshot(), vec![1, 0, 1, 1]);
+        // Unchanged availability adds nothing
+        r.request_pieces("a", state(bitvec![1, 0, 1, 1]), 1);
+        assert_eq!(r.rarity_snapshot(), vec![1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_rarity() {
+        let mut r = Rare::default();
+        r.request_pieces("a", state(bitvec![1, 0, 0, 1]), 1);
+        assert_eq!(r.rarity_snapshot(), vec![1, 0, 0, 1]);
+        r.request_pieces("b", state(bitvec![1, 1, 0, 0]), 1);
+        assert_eq!(r.rarity_snapshot(), vec![2, 1, 0, 1]);
+
+        r.reset();
+        assert!(r.rarity_snapshot().is_empty());
+        r.request_pieces("a", state(bitvec![0, 1, 0, 0]), 1);
+        assert_eq!(r.rarity_snapshot(), vec![0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let select = || {
+            let mut r = Rare::deterministic();
+            r.request_pieces("a", state(bitvec![1, 1, 1, 1, 1, 1]), 1);
+            r.request_pieces("b", state(bitvec![1, 0, 1, 1, 0, 1]), 1);
+            (0..4)
+                .map(|_| r.request_pieces("c", state(bitvec![1; 6]), 3))
+                .collect::<Vec<_>>()
+        };
+        let v = select();
+        // The rarest pieces first, then the lowest index among the equally rare
+        assert_eq!(v[0], vec![1, 4, 0]);
+        assert!(v.iter().all(|s| *s == v[0]));
+        assert_eq!(select(), v);
+    }
+
+    #[test]
+    fn test_peer_left() {
+        let mut r = Rare::default();
+        r.request_pieces("a", state(bitvec![1, 0, 0, 1]), 1);
+        r.request_pieces("b", state(bitvec![1, 1, 0, 0]), 1);
+        r.request_pieces("a", state(bitvec![1, 0, 1, 1]), 1);
+        assert_eq!(r.rarity_snapshot(), vec![2, 1, 1, 1]);
+
+        r.peer_left("a");
+        assert_eq!(r.rarity_snapshot(), vec![1, 1, 0, 0]);
+        assert!(!r.history.contains_key("a"));
+        // Unknown or already departed peers change nothing
+        r.peer_left("a");
+        r.peer_left("c");
+        assert_eq!(r.rarity_snapshot(), vec![1, 1, 0, 0]);
+
+        // A returning peer is counted afresh
+        r.request_pieces("a", state(bitvec![0, 0, 1, 0]), 1);
+        assert_eq!(r.rarity_snapshot(), vec![1, 1, 1, 0]);
+    }
+}
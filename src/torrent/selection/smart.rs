@@ -0,0 +1,153 @@
+use super::{Inorder, Rare, Selector, State};
+use rand::rngs::StdRng;
+use smart_default::SmartDefault;
+use std::cmp::min;
+
+// In order for the first pieces, so a preview can start and we quickly have something to
+// upload, then rarest first for the bulk of the download. The store's endgame takes over from
+// both once few pieces are left.
+#[derive(SmartDefault)]
+pub struct Smart {
+    inorder: Inorder,
+    rare: Rare,
+    // Pieces downloaded in order before switching to rarest first
+    #[default = 4]
+    pub warmup: u32,
+}
+
+#[derive(Debug, PartialEq)]
+enum Phase {
+    Warmup,
+    Rarest,
+}
+
+impl Smart {
+    pub fn new(warmup: u32) -> Self {
+        Smart {
+            warmup,
+            ..Default::default()
+        }
+    }
+
+    // Deterministic selection for reproducible runs
+    pub fn with_rng(rng: StdRng) -> Self {
+        Smart {
+            rare: Rare::with_rng(rng),
+            ..Default::default()
+        }
+    }
+
+    // Only stored pieces end the warmup, pieces in flight may still fail or be given up on
+    fn phase(&self, state: &State) -> Phase {
+        if state.downloaded < self.warmup {
+            Phase::Warmup
+        } else {
+            Phase::Rarest
+        }
+    }
+}
+
+impl Selector for Smart {
+    fn request_pieces(&mut self, id: &str, mut state: State, n: u32) -> Vec<u32> {
+        state.fit();
+        match self.phase(&state) {
+            Phase::Warmup => {
+                // Pieces stored or in flight are no longer required, and only the rest of the
+                // warmup is requested in order
+                let left = state.required.iter().filter(|b| *b).count() as u32;
+                let claimed = state.required.len() as u32 - left;
+                let inorder = min(n, self.warmup.saturating_sub(claimed));
+                let mut v = self.inorder.request_pieces(id, state.clone(), inorder);
+                for &i in v.iter() {
+                    state.required.set(i as usize, false);
+                }
+                // Also keeps rarity current for when the warmup ends
+                let rest = n - v.len() as u32;
+                v.extend(self.rare.request_pieces(id, state, rest));
+                v
+            }
+            Phase::Rarest => self.rare.request_pieces(id, state, n),
+        }
+    }
+
+    fn rarity(&self) -> Option<Vec<usize>> {
+        self.rare.rarity()
+    }
+
+    fn reset(&mut self) {
+        self.rare.reset()
+    }
+
+    fn peer_left(&mut self, id: &str) {
+        self.rare.peer_left(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitvec::{bitvec, BitVec};
+    use rand::SeedableRng;
+
+    fn state(required: BitVec, downloaded: u32) -> State {
+        State {
+            available: bitvec![1; required.len()],
+            required,
+            downloaded,
+        }
+    }
+
+    #[test]
+    fn test_phases() {
+        let mut s = Smart::with_rng(StdRng::seed_from_u64(0));
+        s.warmup = 2;
+        let mut required = bitvec![1; 8];
+        assert_eq!(s.phase(&state(required.clone(), 0)), Phase::Warmup);
+        assert_eq!(
+            s.request_pieces("a", state(required.clone(), 0), 2),
+            vec![0, 1]
+        );
+
+        // Pieces in flight do not end the warmup
+        required.set(0, false);
+        required.set(1, false);
+        assert_eq!(s.phase(&state(required.clone(), 0)), Phase::Warmup);
+        assert_eq!(s.phase(&state(required.clone(), 1)), Phase::Warmup);
+
+        // Rarest first once warmup pieces are stored
+        assert_eq!(s.phase(&state(required.clone(), 2)), Phase::Rarest);
+        let rare = State {
+            required: required.clone(),
+            available: bitvec![1, 1, 1, 1, 1, 1, 1, 0],
+            downloaded: 2,
+        };
+        s.request_pieces("b", rare, 1);
+        // Piece 7 is the only one that peer b lacks, so it is rarer than the rest
+        assert_eq!(
+            s.request_pieces("c", state(required.clone(), 2), 1),
+            vec![7]
+        );
+        assert_eq!(s.rarity().unwrap(), vec![3, 3, 3, 3, 3, 3, 3, 2]);
+    }
+
+    #[test]
+    fn test_warmup_clamped() {
+        let mut s = Smart::with_rng(StdRng::seed_from_u64(0));
+        s.warmup = 2;
+        let available = State {
+            required: bitvec![1; 8],
+            available: bitvec![1, 1, 1, 1, 1, 1, 1, 0],
+            downloaded: 0,
+        };
+        s.request_pieces("b", available, 0);
+        // Only the warmup pieces are requested in order, the rest rarest first
+        let v = s.request_pieces("c", state(bitvec![1; 8], 0), 3);
+        assert_eq!(v, vec![0, 1, 7]);
+
+        // Once the warmup pieces are in flight, nothing more is requested in order
+        let mut required = bitvec![1; 8];
+        required.set(0, false);
+        required.set(1, false);
+        assert_eq!(s.request_pieces("c", state(required, 0), 1), vec![7]);
+    }
+}
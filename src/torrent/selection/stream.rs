@@ -0,0 +1,76 @@
+use super::{Selector, State};
+use std::cmp::{max, min};
+
+// Pieces at each end of the torrent requested before the rest when streaming
+const DEFAULT_EDGE: usize = 1;
+
+// In order selection for playing media while it downloads. The pieces at both ends come first,
+// since containers keep headers at the start and indexes (e.g. the MP4 moov box) at the end.
+pub struct Stream {
+    edge: usize,
+}
+
+impl Stream {
+    pub fn new(edge: usize) -> Self {
+        Stream { edge }
+    }
+}
+
+impl Default for Stream {
+    fn default() -> Self {
+        Stream::new(DEFAULT_EDGE)
+    }
+}
+
+impl Selector for Stream {
+    fn request_pieces(&mut self, _: &str, mut state: State, n: u32) -> Vec<u32> {
+        state.fit();
+        let mut available = state.available;
+        available &= state.required;
+        let len = available.len();
+        let head = min(self.edge, len);
+        // Starts no earlier than the head ends, so short torrents have no piece twice
+        let tail = max(len.saturating_sub(self.edge), head);
+        (0..head)
+            .chain(tail..len)
+            .chain(head..tail)
+            .filter(|i| available[*i])
+            .map(|i| i as u32)
+            .take(n as usize)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitvec::bitvec;
+
+    #[test]
+    fn test_edges_first() {
+        let mut s = Stream::new(2);
+        let mut state = State {
+            required: bitvec![1; 10],
+            available: bitvec![1; 10],
+            downloaded: 0,
+        };
+        assert_eq!(s.request_pieces("peer", state.clone(), 4), vec![0, 1, 8, 9]);
+        for i in vec![0, 1, 8, 9] {
+            state.required.set(i, false);
+        }
+        assert_eq!(s.request_pieces("peer", state.clone(), 3), vec![2, 3, 4]);
+
+        // Edge pieces the peer does not have are skipped
+        state.required = bitvec![1; 10];
+        state.available.set(0, false);
+        assert_eq!(s.request_pieces("peer", state, 4), vec![1, 8, 9, 2]);
+
+        // Edges overlapping on a short torrent
+        let state = State {
+            required: bitvec![1; 3],
+            available: bitvec![1; 3],
+            downloaded: 0,
+        };
+        assert_eq!(s.request_pieces("peer", state, 4), vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,84 @@
+use std::borrow::Cow;
+use std::io::{self, BufWriter, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+const FLUSH_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+#[derive(Debug, PartialEq)]
+pub struct Datapoint {
+    pub index: u32,
+    pub elapsed_ms: u128,
+    pub peer_id: String,
+    pub size: usize,
+}
+
+impl Datapoint {
+    fn write_row<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(
+            writer,
+            "{},{},{},{}",
+            self.index,
+            self.elapsed_ms,
+            quote(&self.peer_id),
+            self.size
+        )
+    }
+}
+
+// Peer ids come from the network, so they are quoted if they could break the row
+fn quote(field: &str) -> Cow<'_, str> {
+    match field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        true => Cow::Owned(format!("\"{}\"", field.replace('"', "\"\""))),
+        false => Cow::Borrowed(field),
+    }
+}
+
+// Writes datapoints as CSV rows on a dedicated thread, so that the store never blocks on IO
+// The writer is returned once all senders have been dropped
+pub fn spawn<W: Write + Send + 'static>(
+    writer: W,
+) -> (mpsc::Sender<Datapoint>, thread::JoinHandle<io::Result<W>>) {
+    let (tx, rx) = mpsc::channel::<Datapoint>();
+    let handle = thread::spawn(move || {
+        let mut writer = BufWriter::new(writer);
+        writeln!(writer, "piece_index,elapsed_ms,peer_id,size")?;
+        loop {
+            match rx.recv_timeout(FLUSH_INTERVAL) {
+                Ok(dp) => dp.write_row(writer.by_ref())?,
+                Err(mpsc::RecvTimeoutError::Timeout) => writer.flush()?,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        writer.flush()?;
+        writer
+            .into_inner()
+            .map_err(|e| io::Error::new(e.error().kind(), e.to_string()))
+    });
+    (tx, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(peer_id: &str) -> String {
+        let dp = Datapoint {
+            index: 1,
+            elapsed_ms: 2,
+            peer_id: peer_id.to_owned(),
+            size: 3,
+        };
+        let mut v = Vec::new();
+        dp.write_row(&mut v).unwrap();
+        String::from_utf8(v).unwrap()
+    }
+
+    #[test]
+    fn test_quoted_peer_id() {
+        assert_eq!(row("peer"), "1,2,peer,3\n");
+        assert_eq!(row("a,b"), "1,2,\"a,b\",3\n");
+        assert_eq!(row("say \"hi\"\n"), "1,2,\"say \"\"hi\"\"\n\",3\n");
+    }
+}
@@ -1,21 +1,260 @@
 use crate::connection::Command;
 use crate::metainfo::Metainfo;
-use crate::selection::{Selector, State};
+use crate::selection::{Inorder, Selector, State};
+use crate::stats::Datapoint;
 use bitvec::BitVec;
 use log::{self, debug, error, info, warn};
+use memmap2::Mmap;
 use std::collections::{HashMap, HashSet};
 use std::default::Default;
 use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, Range};
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::RwLock;
+use std::thread;
 use std::time;
 
 pub enum PieceStatus {
     Requested(String),
     Downloaded(Arc<Vec<u8>>),
+    // Held in the memory mapped bootstrap file rather than in memory
+    Mapped,
+}
+
+// Bytes of a stored piece for uploading. Mapped pieces are only copied out of the page cache
+// one block at a time as peers request them.
+#[derive(Clone)]
+pub enum PieceData {
+    Memory(Arc<Vec<u8>>),
+    // The mapping and the range of the piece within it
+    Mapped(Arc<Mmap>, Range<usize>),
+}
+
+impl Deref for PieceData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            PieceData::Memory(v) => v,
+            PieceData::Mapped(m, range) => &m[range.clone()],
+        }
+    }
+}
+
+impl From<Arc<Vec<u8>>> for PieceData {
+    fn from(v: Arc<Vec<u8>>) -> Self {
+        PieceData::Memory(v)
+    }
+}
+
+// Read back when verifying the output after completion
+pub trait Output: Read + Write + Seek + Send + Sync {
+    // Persist written data to the underlying device
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl Output for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+// In memory output for tests
+#[cfg(test)]
+impl Output for io::Cursor<Vec<u8>> {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Durability of pieces written to a seekable sink
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriteStrategy {
+    // Leave pieces in the OS cache
+    Buffered,
+    // Sync after every piece
+    Sync,
+    // Sync after every n pieces
+    Batched(usize),
+}
+
+impl Default for WriteStrategy {
+    fn default() -> Self {
+        WriteStrategy::Buffered
+    }
+}
+
+impl FromStr for WriteStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "buffered" => Ok(WriteStrategy::Buffered),
+            "sync" => Ok(WriteStrategy::Sync),
+            _ if s.starts_with("batch:") => match s["batch:".len()..].parse() {
+                Ok(0) | Err(_) => Err(format!("invalid batch size in {}", s)),
+                Ok(n) => Ok(WriteStrategy::Batched(n)),
+            },
+            _ => Err(format!("{} is not a valid write strategy", s)),
+        }
+    }
+}
+
+pub enum Sink {
+    // Contiguous pieces are written in order, suitable for pipes
+    Stdout,
+    // Pieces are written at their offset as soon as they complete, so readers can skip ahead of gaps
+    Seekable(Box<dyn Output>),
+}
+
+// Requests to the thread that owns the sink
+enum WriteCommand {
+    // A piece and its offset, which stdout ignores as pieces are sent to it in order
+    Piece(u32, u64, Arc<Vec<u8>>),
+    Strategy(WriteStrategy),
+    // Read back a range once every piece sent before it has been written
+    Read(u64, usize, mpsc::Sender<io::Result<Vec<u8>>>),
+}
+
+// Pieces are written on their own thread, so that connections storing a piece never wait on
+// disk I/O while holding the store lock. Dropping the writer waits for queued pieces.
+struct Writer {
+    tx: Mutex<Option<mpsc::Sender<WriteCommand>>>,
+    handle: Option<thread::JoinHandle<()>>,
+    seekable: bool,
+}
+
+impl Writer {
+    fn spawn(sink: Sink) -> Self {
+        let seekable = match sink {
+            Sink::Stdout => false,
+            Sink::Seekable(_) => true,
+        };
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || write_sink(sink, rx));
+        Writer {
+            tx: Mutex::new(Some(tx)),
+            handle: Some(handle),
+            seekable,
+        }
+    }
+
+    fn send(&self, command: WriteCommand) {
+        if let Some(tx) = &*self.tx.lock().unwrap() {
+            // Only fails if the thread panicked, which has been reported already
+            let _ = tx.send(command);
+        }
+    }
+
+    // For reading back without holding the store lock
+    fn reader(&self) -> Option<mpsc::Sender<WriteCommand>> {
+        match self.seekable {
+            true => self.tx.lock().unwrap().clone(),
+            false => None,
+        }
+    }
+}
+
+fn read_back(tx: &mpsc::Sender<WriteCommand>, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let (reply, rx) = mpsc::channel();
+    let _ = tx.send(WriteCommand::Read(offset, len, reply));
+    rx.recv()
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "writer exited")))
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.tx.lock().unwrap().take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn write_sink(mut sink: Sink, rx: mpsc::Receiver<WriteCommand>) {
+    let mut strategy = WriteStrategy::default();
+    let mut unsynced = 0;
+    for command in rx {
+        match (command, &mut sink) {
+            (WriteCommand::Piece(index, _, v), Sink::Stdout) => {
+                if let Err(e) = io::stdout().write_all(&v) {
+                    error!("Failed to write piece {}: {}", index, e);
+                }
+            }
+            (WriteCommand::Piece(index, offset, v), Sink::Seekable(w)) => {
+                let mut res = w
+                    .seek(SeekFrom::Start(offset))
+                    .and_then(|_| w.write_all(&v))
+                    .and_then(|_| w.flush());
+                if res.is_ok() {
+                    unsynced += 1;
+                    let sync = match strategy {
+                        WriteStrategy::Buffered => false,
+                        WriteStrategy::Sync => true,
+                        WriteStrategy::Batched(n) => unsynced >= n,
+                    };
+                    if sync {
+                        res = w.sync();
+                        unsynced = 0;
+                    }
+                }
+                if let Err(e) = res {
+                    error!("Failed to write piece {}: {}", index, e);
+                }
+            }
+            (WriteCommand::Strategy(s), _) => strategy = s,
+            (WriteCommand::Read(offset, len, tx), Sink::Seekable(w)) => {
+                let mut buf = vec![0; len];
+                let res = w
+                    .seek(SeekFrom::Start(offset))
+                    .and_then(|_| w.read_exact(&mut buf))
+                    .map(|_| buf);
+                let _ = tx.send(res);
+            }
+            (WriteCommand::Read(_, _, tx), Sink::Stdout) => {
+                let _ = tx.send(Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "stdout cannot be read back",
+                )));
+            }
+        }
+    }
+    // The last pieces of a batch, once the output is closed or replaced
+    if let Sink::Seekable(w) = &mut sink {
+        if unsynced > 0 && strategy != WriteStrategy::Buffered {
+            if let Err(e) = w.sync() {
+                error!("Failed to sync output: {}", e);
+            }
+        }
+    }
+}
+
+// Time spent in the selector, the costliest part of choosing pieces to request
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SelectionLatency {
+    pub calls: u64,
+    pub total: time::Duration,
+    pub max: time::Duration,
+}
+
+impl SelectionLatency {
+    fn record(&mut self, d: time::Duration) {
+        self.calls += 1;
+        self.total += d;
+        self.max = self.max.max(d);
+    }
+
+    pub fn mean(&self) -> time::Duration {
+        match self.calls {
+            0 => time::Duration::default(),
+            n => self.total / n as u32,
+        }
+    }
 }
 
 pub struct PieceStore {
@@ -23,9 +262,41 @@ pub struct PieceStore {
     inprogress: HashMap<String, HashSet<u32>>, // Used to deal with choke requests efficiently
     pub left: u32,                             // Used to deal with completion checks efficiently
     handlers: Mutex<Vec<mpsc::Sender<Command>>>,
+    // Received and total blocks of requested pieces, updated by connections as blocks arrive
+    progress: Mutex<HashMap<u32, (u32, u32)>>,
     next: usize,
-    selector: Box<dyn Selector + Send + Sync>,
+    // None until a sink is set, and once the output has been closed
+    writer: Option<Writer>,
+    // The file served by --mmap bootstraps, which must not be truncated while mapped
+    mapping: Option<Arc<Mmap>>,
+    write_strategy: WriteStrategy,
+    piece_length: u64,
+    length: u64,
+    // Locked on its own so that pieces can be chosen under a read lock on the store
+    selector: Mutex<Box<dyn Selector + Send + Sync>>,
+    selection_latency: Mutex<SelectionLatency>,
     start: time::Instant,
+    // When the first piece was stored, for judging how quickly a download gets going
+    first_piece: Option<time::Instant>,
+    stats: Option<mpsc::Sender<Datapoint>>,
+    // Below this many remaining pieces, rarity is ignored and any needed piece is requested
+    endgame_threshold: u32,
+    // No new pieces are requested while paused
+    paused: bool,
+    // Nothing is requested until this many peers have told us what they have
+    min_peers: usize,
+    known_peers: HashSet<String>,
+    // Bytes of downloaded pieces discarded for failing verification
+    corrupt: u64,
+    // Complete pieces waiting for their hash to be checked, which can already be uploaded
+    verifying: HashMap<u32, Arc<Vec<u8>>>,
+    // Last availability of each connected peer that asked for pieces
+    peer_availability: HashMap<String, BitVec>,
+    // Needed pieces whose every source has disconnected, until a peer has them again
+    unavailable: HashSet<u32>,
+    // Pieces that failed verification after completion. Peers were told we have them, so
+    // requests for them are refused rather than treated as invalid until they are stored again.
+    withdrawn: HashSet<u32>,
 }
 
 impl PieceStore {
@@ -37,14 +308,165 @@ impl PieceStore {
             data,
             inprogress: HashMap::new(),
             handlers: Mutex::new(Vec::new()),
+            progress: Mutex::new(HashMap::new()),
             next: 0,
-            selector: s,
+            writer: None,
+            mapping: None,
+            write_strategy: WriteStrategy::default(),
+            piece_length: mi.info.piece_length as u64,
+            length: mi.info.total_length() as u64,
+            selector: Mutex::new(s),
+            selection_latency: Mutex::new(SelectionLatency::default()),
             start: time::Instant::now(),
+            first_piece: None,
+            stats: None,
+            endgame_threshold: 0,
+            paused: false,
+            min_peers: 1,
+            known_peers: HashSet::new(),
+            corrupt: 0,
+            verifying: HashMap::new(),
+            peer_availability: HashMap::new(),
+            unavailable: HashSet::new(),
+            withdrawn: HashSet::new(),
+        }
+    }
+
+    pub fn set_sink(&mut self, sink: Sink) {
+        let writer = Writer::spawn(sink);
+        writer.send(WriteCommand::Strategy(self.write_strategy));
+        self.writer = Some(writer);
+    }
+
+    pub fn set_write_strategy(&mut self, strategy: WriteStrategy) {
+        self.write_strategy = strategy;
+        if let Some(w) = &self.writer {
+            w.send(WriteCommand::Strategy(strategy));
         }
     }
 
-    pub fn register(&self, tx: mpsc::Sender<Command>) {
-        self.handlers.lock().unwrap().push(tx)
+    // Wait for every stored piece to be written. Pieces stored afterwards are not written.
+    pub fn close_output(&mut self) {
+        self.writer = None;
+    }
+
+    pub fn set_stats(&mut self, tx: mpsc::Sender<Datapoint>) {
+        self.stats = Some(tx);
+    }
+
+    // Time from the start of the session to the first piece stored, if there has been one
+    pub fn time_to_first_piece(&self) -> Option<time::Duration> {
+        self.first_piece.map(|t| t.duration_since(self.start))
+    }
+
+    pub fn set_endgame_threshold(&mut self, threshold: u32) {
+        self.endgame_threshold = threshold;
+    }
+
+    pub fn set_min_peers(&mut self, min_peers: usize) {
+        self.min_peers = min_peers;
+    }
+
+    // Record a peer that sent its availability. Connections idle while waiting for enough peers
+    // are woken once the minimum is reached.
+    pub fn peer_known(&mut self, id: &str) {
+        if self.known_peers.contains(id) {
+            return;
+        }
+        self.known_peers.insert(id.to_owned());
+        if self.known_peers.len() == self.min_peers {
+            info!("{} peers known, starting to request", self.min_peers);
+            self.handlers
+                .lock()
+                .unwrap()
+                .retain(|t| t.send(Command::Ping).is_ok());
+        }
+    }
+
+    // Requests already in flight are kept, so nothing downloaded so far is lost
+    pub fn set_selector(&mut self, s: Box<dyn Selector + Send + Sync>) {
+        self.selector = Mutex::new(s);
+    }
+
+    // Unlike set_selector, connections choose their requests in flight again, cancelling those
+    // for pieces the new selector would not have picked
+    pub fn reprioritize(&mut self, s: Box<dyn Selector + Send + Sync>) {
+        self.selector = Mutex::new(s);
+        self.handlers
+            .lock()
+            .unwrap()
+            .retain(|t| t.send(Command::Reprioritize).is_ok());
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Called with the size of each piece that fails verification
+    pub fn record_corrupt(&mut self, bytes: u64) {
+        self.corrupt += bytes;
+    }
+
+    pub fn corrupt(&self) -> u64 {
+        self.corrupt
+    }
+
+    pub fn rarity(&self) -> Option<Vec<usize>> {
+        self.selector.lock().unwrap().rarity()
+    }
+
+    pub fn reset_selector(&mut self) {
+        self.selector.lock().unwrap().reset()
+    }
+
+    pub fn selection_latency(&self) -> SelectionLatency {
+        *self.selection_latency.lock().unwrap()
+    }
+
+    pub fn peer_left(&mut self, id: &str) {
+        self.selector.lock().unwrap().peer_left(id);
+        let had = match self.peer_availability.remove(id) {
+            Some(bv) => bv,
+            None => return,
+        };
+        let have = self.as_bitvec(false);
+        let lost: Vec<u32> = (0..had.len().min(have.len()))
+            .filter(|&i| had[i] && !have[i])
+            .filter(|&i| !self.peer_availability.values().any(|bv| bv[i]))
+            .map(|i| i as u32)
+            .collect();
+        if !lost.is_empty() {
+            warn!(
+                "Peer {} was the only source of {} needed pieces: {:?}",
+                id,
+                lost.len(),
+                lost
+            );
+            self.unavailable.extend(lost);
+        }
+    }
+
+    // Needed pieces that no connected peer has since their last source left, in index order
+    pub fn unavailable(&self) -> Vec<u32> {
+        let mut v: Vec<u32> = self.unavailable.iter().cloned().collect();
+        v.sort();
+        v
+    }
+
+    // Dropping the sender lets the writer thread flush and exit
+    pub fn close_stats(&mut self) {
+        self.stats = None;
+    }
+
+    // Returns the pieces completed so far. Pieces only complete under the write lock, so each
+    // later piece reaches tx as exactly one ClientHave and no earlier piece does.
+    pub fn register(&self, tx: mpsc::Sender<Command>) -> BitVec {
+        self.handlers.lock().unwrap().push(tx);
+        self.as_bitvec(false)
     }
 
     pub fn as_bitvec(&self, include_requested: bool) -> BitVec {
@@ -58,25 +480,138 @@ impl PieceStore {
             .collect()
     }
 
+    // A mapped piece is copied, use get_data to upload without copying the whole piece
     pub fn get(&self, index: u32) -> Option<Arc<Vec<u8>>> {
-        match &self.data[index as usize] {
-            Some(PieceStatus::Downloaded(v)) => Some(v.clone()),
+        match self.get_stored(index)? {
+            PieceData::Memory(v) => Some(v),
+            d => Some(Arc::new(d.to_vec())),
+        }
+    }
+
+    // As get_unverified, but without copying mapped pieces
+    pub fn get_data(&self, index: u32) -> Option<PieceData> {
+        self.get_stored(index)
+            .or_else(|| self.verifying.get(&index).cloned().map(PieceData::Memory))
+    }
+
+    fn get_stored(&self, index: u32) -> Option<PieceData> {
+        match (&self.data[index as usize], &self.mapping) {
+            (Some(PieceStatus::Mapped), Some(m)) => {
+                let begin = (index as u64 * self.piece_length) as usize;
+                let end = (self.length.min(begin as u64 + self.piece_length)) as usize;
+                Some(PieceData::Mapped(m.clone(), begin..end))
+            }
+            (Some(PieceStatus::Downloaded(v)), _) => Some(PieceData::Memory(v.clone())),
             _ => None,
         }
     }
 
+    pub fn is_withdrawn(&self, index: u32) -> bool {
+        self.withdrawn.contains(&index)
+    }
+
+    // As get, but also a piece that is still being verified. Peers check every piece they
+    // receive, so serving one that turns out bad costs them a retry rather than corrupting them.
+    pub fn get_unverified(&self, index: u32) -> Option<Arc<Vec<u8>>> {
+        self.get(index)
+            .or_else(|| self.verifying.get(&index).cloned())
+    }
+
+    pub fn set_verifying(&mut self, index: u32, piece: Arc<Vec<u8>>) {
+        self.verifying.insert(index, piece);
+    }
+
+    // Forget a piece that failed verification, unless another copy has replaced it since
+    pub fn discard_unverified(&mut self, index: u32, piece: &Arc<Vec<u8>>) {
+        if self
+            .verifying
+            .get(&index)
+            .map_or(false, |v| Arc::ptr_eq(v, piece))
+        {
+            self.verifying.remove(&index);
+        }
+    }
+
+    // The store is only modified once the whole file has been read successfully
     pub fn bootstrap<P: AsRef<Path>>(&mut self, metainfo: &Metainfo, path: P) -> io::Result<()> {
+        self.bootstrap_with(metainfo, path, false)
+    }
+
+    // As bootstrap, but when verify is set every piece is hashed first and the file is rejected,
+    // listing the pieces that do not match, unless all of them do
+    pub fn bootstrap_with<P: AsRef<Path>>(
+        &mut self,
+        metainfo: &Metainfo,
+        path: P,
+        verify: bool,
+    ) -> io::Result<()> {
         let mut f = File::open(path)?;
-        for (i, e) in self.data.iter_mut().enumerate() {
+        check_bootstrap_length(metainfo, f.metadata()?.len())?;
+
+        let mut pieces = Vec::with_capacity(self.data.len());
+        for i in 0..self.data.len() {
             let mut v = vec![0; metainfo.get_piece_size(i as u32) as usize];
             f.read_exact(&mut v)?;
-            *e = Some(PieceStatus::Downloaded(Arc::new(v)));
+            pieces.push(v);
+        }
+        if verify {
+            check_bootstrap_pieces(metainfo, pieces.iter().map(|v| &v[..]))?;
+        }
+        self.data = pieces
+            .into_iter()
+            .map(|v| Some(PieceStatus::Downloaded(Arc::new(v))))
+            .collect();
+        self.left = 0;
+        self.next = self.data.len();
+        Ok(())
+    }
+
+    // As bootstrap_with, but the file is memory mapped rather than read into memory, so a seed
+    // does not hold the whole torrent on the heap
+    pub fn bootstrap_mapped<P: AsRef<Path>>(
+        &mut self,
+        metainfo: &Metainfo,
+        path: P,
+        verify: bool,
+    ) -> io::Result<()> {
+        let f = File::open(path)?;
+        check_bootstrap_length(metainfo, f.metadata()?.len())?;
+        // The mapping is read only, but reading past the end of the file faults, so the file must
+        // not be truncated while it is seeded
+        let mapping = unsafe { Mmap::map(&f)? };
+        if verify {
+            check_bootstrap_pieces(metainfo, mapping.chunks(self.piece_length as usize))?;
         }
+        self.data = (0..self.data.len())
+            .map(|_| Some(PieceStatus::Mapped))
+            .collect();
+        self.mapping = Some(Arc::new(mapping));
         self.left = 0;
         self.next = self.data.len();
         Ok(())
     }
 
+    // Every piece has been downloaded, though it may not have been written out yet
+    pub fn is_complete(&self) -> bool {
+        self.left == 0
+    }
+
+    // Bytes of the pieces not downloaded yet, counting the final piece at its actual length
+    pub fn remaining_bytes(&self) -> u64 {
+        self.data
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| match p {
+                Some(PieceStatus::Downloaded(_)) | Some(PieceStatus::Mapped) => false,
+                _ => true,
+            })
+            .map(|(i, _)| {
+                let begin = i as u64 * self.piece_length;
+                self.piece_length.min(self.length - begin)
+            })
+            .sum()
+    }
+
     pub fn check_if_needed(&self, index: u32) -> bool {
         match &self.data[index as usize] {
             Some(_) => false,
@@ -84,7 +619,101 @@ impl PieceStore {
         }
     }
 
+    pub fn set_progress(&self, index: u32, received: u32, total: u32) {
+        if let Some(PieceStatus::Requested(_)) = self.data[index as usize] {
+            self.progress
+                .lock()
+                .unwrap()
+                .insert(index, (received, total));
+        }
+    }
+
+    // (index, received blocks, total blocks) of every in-progress piece, ordered by index
+    pub fn block_progress(&self) -> Vec<(u32, u32, u32)> {
+        let mut v: Vec<_> = self
+            .progress
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&i, &(received, total))| (i, received, total))
+            .collect();
+        v.sort();
+        v
+    }
+
+    // Hash every downloaded piece again, as read back from the seekable sink. Pieces in memory
+    // were hashed before they were stored, and pieces written to stdout cannot be replaced, so
+    // nothing is checked without one. The store is only read locked while the pieces are
+    // gathered, so connections carry on while hashing. Bad pieces are marked as missing so they
+    // are downloaded again, and connections are told to re-evaluate their interest.
+    pub fn verify_stored(store: &RwLock<PieceStore>, metainfo: &Metainfo) -> Vec<u32> {
+        let (pieces, reader, piece_length) = {
+            let s = store.read().unwrap();
+            let pieces: Vec<u32> = (0..s.data.len() as u32)
+                .filter(|&i| s.get_stored(i).is_some())
+                .collect();
+            let reader = s.writer.as_ref().and_then(Writer::reader);
+            (pieces, reader, s.piece_length)
+        };
+        let reader = match reader {
+            Some(reader) => reader,
+            None => {
+                warn!("No seekable output to read pieces back from, not verifying");
+                return Vec::new();
+            }
+        };
+        let mut bad = Vec::new();
+        for index in pieces {
+            let valid = read_back(
+                &reader,
+                index as u64 * piece_length,
+                metainfo.get_piece_size(index) as usize,
+            )
+            .map(|buf| metainfo.verify_piece(index, &buf))
+            .unwrap_or_else(|e| {
+                error!("Failed to read back piece {}: {}", index, e);
+                false
+            });
+            if !valid {
+                bad.push(index);
+            }
+        }
+        if bad.is_empty() {
+            return bad;
+        }
+
+        let mut s = store.write().unwrap();
+        for &index in &bad {
+            if s.data[index as usize].is_none() {
+                continue;
+            }
+            s.data[index as usize] = None;
+            s.left += 1;
+            s.withdrawn.insert(index);
+        }
+        s.handlers
+            .lock()
+            .unwrap()
+            .retain(|t| t.send(Command::Needed).is_ok());
+        bad
+    }
+
     pub fn store(&mut self, id: &str, index: u32, piece: Arc<Vec<u8>>) {
+        self.verifying.remove(&index);
+        self.withdrawn.remove(&index);
+        self.unavailable.remove(&index);
+        // Duplicate deliveries in endgame can both pass the receiver's check
+        if let Some(PieceStatus::Downloaded(_)) | Some(PieceStatus::Mapped) =
+            self.data[index as usize]
+        {
+            debug!("Piece {} from {} already stored", index, id);
+            if let Some(hs) = self.inprogress.get_mut(id) {
+                hs.remove(&index);
+            }
+            return;
+        }
+        let size = piece.len();
+        self.progress.lock().unwrap().remove(&index);
         self.data[index as usize] = Some(PieceStatus::Downloaded(piece));
         match self.inprogress.get_mut(id) {
             Some(hs) => {
@@ -92,23 +721,60 @@ impl PieceStore {
             }
             None => {}
         };
-        self.left -= 1;
+        self.left = self.left.saturating_sub(1);
         // Inform connections that new piece received and get rid of closed connections
         self.handlers
             .lock()
             .unwrap()
             .retain(|t| t.send(Command::ClientHave(index)).is_ok());
-        info!("Datapoint {} {}", index, self.start.elapsed().as_millis());
-        self.write_to_stdout();
+        let elapsed_ms = self.start.elapsed().as_millis();
+        info!("Datapoint {} {}", index, elapsed_ms);
+        if self.first_piece.is_none() {
+            info!("First piece after {} ms", elapsed_ms);
+            self.first_piece = Some(time::Instant::now());
+        }
+        if let Some(tx) = &self.stats {
+            // Writer thread exiting should not affect the download
+            let _ = tx.send(Datapoint {
+                index,
+                elapsed_ms,
+                peer_id: id.to_owned(),
+                size,
+            });
+        }
+        self.write_output(index);
     }
 
-    fn write_to_stdout(&mut self) {
-        if self.next >= self.data.len() {
+    // Queue a piece for the writer thread, only cloning the Arc under the lock
+    fn write_output(&mut self, index: u32) {
+        let w = match &self.writer {
+            Some(w) => w,
+            None => return,
+        };
+        if !w.seekable {
+            self.write_to_stdout();
             return;
         }
-        let mut stdout = io::stdout();
+        if let Some(PieceStatus::Downloaded(v)) = &self.data[index as usize] {
+            w.send(WriteCommand::Piece(
+                index,
+                index as u64 * self.piece_length,
+                v.clone(),
+            ));
+        }
+    }
+
+    fn write_to_stdout(&mut self) {
+        let w = match &self.writer {
+            Some(w) => w,
+            None => return,
+        };
         while let Some(Some(PieceStatus::Downloaded(v))) = &self.data.get(self.next) {
-            stdout.write(&v).unwrap();
+            w.send(WriteCommand::Piece(
+                self.next as u32,
+                self.next as u64 * self.piece_length,
+                v.clone(),
+            ));
             self.next += 1;
         }
     }
@@ -132,7 +798,8 @@ impl PieceStore {
             for index in hs.into_iter() {
                 match self.data[index as usize] {
                     Some(PieceStatus::Requested(ref x)) if x.as_str() == id => {
-                        self.data[index as usize] = None
+                        self.data[index as usize] = None;
+                        self.progress.lock().unwrap().remove(&index);
                     }
                     _ => {}
                 }
@@ -140,28 +807,625 @@ impl PieceStore {
         }
     }
 
+    // Give up on the pieces requested from a peer and let other connections request them
+    pub fn release_requests(&mut self, id: &str) {
+        self.clear_requests(id);
+        self.handlers
+            .lock()
+            .unwrap()
+            .retain(|t| t.send(Command::Needed).is_ok());
+    }
+
+    // Let a piece that failed verification be requested again, unless another peer delivered it
+    pub fn release_piece(&mut self, id: &str, index: u32) {
+        if let Some(hs) = self.inprogress.get_mut(id) {
+            hs.remove(&index);
+        }
+        if let Some(PieceStatus::Requested(_)) = self.data[index as usize] {
+            self.data[index as usize] = None;
+            self.progress.lock().unwrap().remove(&index);
+        }
+        self.handlers
+            .lock()
+            .unwrap()
+            .retain(|t| t.send(Command::Needed).is_ok());
+    }
+
+    // Selection and claiming under a single write lock
     pub fn request_pieces(
         &mut self,
         id: &str,
-        mut availability: BitVec,
+        availability: BitVec,
         n: u32,
     ) -> Result<Vec<u32>, ()> {
-        availability |= self.as_bitvec(false);
-        let v = self.selector.request_pieces(
-            id,
-            State {
-                required: !self.as_bitvec(true),
-                available: availability,
-            },
-            n,
-        );
+        let v = self.select_pieces(id, &availability, n);
+        self.claim_pieces(id, availability, v)
+    }
 
+    // The expensive half of request_pieces, which only needs a read lock on the store so that
+    // other connections are not held up while the selector runs. None when nothing may be
+    // requested yet.
+    pub fn select_pieces(&self, id: &str, availability: &BitVec, n: u32) -> Option<Vec<u32>> {
+        // The asking peer is known once its pieces are claimed
+        let known = self.known_peers.len() + !self.known_peers.contains(id) as usize;
+        // Connections stay interested so that requesting resumes as soon as possible
+        if self.paused || known < self.min_peers {
+            return None;
+        }
+        let mut available = availability.clone();
+        available |= self.as_bitvec(false);
+        let state = State {
+            required: !self.as_bitvec(true),
+            available,
+            downloaded: (self.data.len() as u32).saturating_sub(self.left),
+        };
+        if self.left < self.endgame_threshold {
+            debug!("Endgame: requesting any available piece");
+            return Some(Inorder::default().request_pieces(id, state, n));
+        }
+        let start = time::Instant::now();
+        let v = self.selector.lock().unwrap().request_pieces(id, state, n);
+        self.selection_latency
+            .lock()
+            .unwrap()
+            .record(start.elapsed());
+        Some(v)
+    }
+
+    // Marks the pieces chosen by select_pieces as requested by id. Pieces another connection
+    // claimed since they were chosen are left out.
+    pub fn claim_pieces(
+        &mut self,
+        id: &str,
+        availability: BitVec,
+        selected: Option<Vec<u32>>,
+    ) -> Result<Vec<u32>, ()> {
+        // A peer asking for pieces has sent its availability
+        self.peer_known(id);
+        if !self.unavailable.is_empty() {
+            self.unavailable.retain(|&i| !availability[i as usize]);
+        }
+        self.peer_availability.insert(id.to_owned(), availability);
+        let mut v = match selected {
+            Some(v) => v,
+            None => return Ok(Vec::new()),
+        };
         if v.len() == 0 {
             return Err(());
         }
 
+        v.retain(|&i| self.data[i as usize].is_none());
         v.iter().for_each(|i| self.mark(id, *i));
 
         Ok(v)
     }
 }
+
+fn check_bootstrap_length(metainfo: &Metainfo, file_len: u64) -> io::Result<()> {
+    if file_len != metainfo.info.total_length() as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "bootstrap file length does not match torrent (expected: {}, actual: {})",
+                metainfo.info.total_length(),
+                file_len
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Rejects the file listing the pieces that do not match, unless all of them do
+fn check_bootstrap_pieces<'a, I: ExactSizeIterator<Item = &'a [u8]>>(
+    metainfo: &Metainfo,
+    pieces: I,
+) -> io::Result<()> {
+    let total = pieces.len();
+    let bad: Vec<_> = pieces
+        .enumerate()
+        .filter(|(i, v)| !metainfo.verify_piece(*i as u32, v))
+        .map(|(i, _)| i.to_string())
+        .collect();
+    if !bad.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "bootstrap file does not match torrent ({} of {} pieces bad: {})",
+                bad.len(),
+                total,
+                bad.join(", ")
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash;
+    use crate::selection::Inorder;
+    use crate::stats;
+    use bitvec::bitvec;
+    use matches::matches;
+    use std::sync::RwLock;
+    use std::thread;
+
+    #[test]
+    fn test_stats_csv() {
+        let m = Metainfo::mock(2, 3);
+        let mut ps = PieceStore::new(&m, Box::new(Inorder::default()));
+        let (tx, handle) = stats::spawn(Vec::new());
+        ps.set_stats(tx);
+        ps.store("peer1", 1, Arc::new(vec![b'b']));
+        ps.store("peer2", 0, Arc::new(vec![b'a', b'a']));
+        drop(ps);
+
+        let csv = String::from_utf8(handle.join().unwrap().unwrap()).unwrap();
+        let rows: Vec<Vec<&str>> = csv.lines().map(|l| l.split(',').collect()).collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(
+            rows[0],
+            vec!["piece_index", "elapsed_ms", "peer_id", "size"]
+        );
+        assert_eq!((rows[1][0], rows[1][2], rows[1][3]), ("1", "peer1", "1"));
+        assert_eq!((rows[2][0], rows[2][2], rows[2][3]), ("0", "peer2", "2"));
+    }
+
+    #[test]
+    fn test_bootstrap_short_file() -> Result<(), failure::Error> {
+        let m = Metainfo::mock(2, 5);
+        let path = std::env::temp_dir().join("continuity_test_bootstrap_short_file");
+        File::create(&path)?.write_all(&[0; 3])?;
+        let mut ps = PieceStore::new(&m, Box::new(Inorder::default()));
+        let res = ps.bootstrap(&m, &path);
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(ps.left, 3);
+        assert!(ps.as_bitvec(true).iter().all(|b| !b));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bootstrap_verify() -> Result<(), failure::Error> {
+        let data = b"abcdef";
+        let mut m = Metainfo::mock(2, data.len());
+        for (i, piece) in data.chunks(2).enumerate() {
+            m.info.pieces[20 * i..20 * (i + 1)].copy_from_slice(&hash::sha1(piece));
+        }
+        let path = std::env::temp_dir().join("continuity_test_bootstrap_verify");
+        let mut ps = PieceStore::new(&m, Box::new(Inorder::default()));
+
+        File::create(&path)?.write_all(b"abXdeY")?;
+        let e = ps.bootstrap_with(&m, &path, true).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            e.to_string().ends_with("(2 of 3 pieces bad: 1, 2)"),
+            "{}",
+            e
+        );
+        assert_eq!(ps.left, 3);
+
+        File::create(&path)?.write_all(data)?;
+        ps.bootstrap_with(&m, &path, true)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(ps.left, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bootstrap_mapped() -> Result<(), failure::Error> {
+        let data = b"abcde";
+        let mut m = Metainfo::mock(2, data.len());
+        for (i, piece) in data.chunks(2).enumerate() {
+            m.info.pieces[20 * i..20 * (i + 1)].copy_from_slice(&hash::sha1(piece));
+        }
+        let path = std::env::temp_dir().join("continuity_test_bootstrap_mapped");
+        let mut ps = PieceStore::new(&m, Box::new(Inorder::default()));
+
+        File::create(&path)?.write_all(b"abXde")?;
+        let e = ps.bootstrap_mapped(&m, &path, true).unwrap_err();
+        assert!(e.to_string().ends_with("(1 of 3 pieces bad: 1)"), "{}", e);
+        assert_eq!(ps.left, 3);
+
+        File::create(&path)?.write_all(data)?;
+        ps.bootstrap_mapped(&m, &path, true)?;
+        std::fs::remove_file(&path)?;
+        assert!(ps.is_complete());
+        assert_eq!(ps.remaining_bytes(), 0);
+        // Served from the mapping, including the short final piece
+        assert!(matches!(ps.get_data(1), Some(PieceData::Mapped(..))));
+        assert_eq!(&*ps.get_data(2).unwrap(), b"e");
+        assert_eq!(ps.get(1), Some(Arc::new(b"cd".to_vec())));
+        Ok(())
+    }
+
+    struct Never;
+
+    impl Selector for Never {
+        fn request_pieces(&mut self, _: &str, _: State, _: u32) -> Vec<u32> {
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn test_endgame() {
+        let m = Metainfo::mock(1, 4);
+        let mut ps = PieceStore::new(&m, Box::new(Never));
+        ps.set_endgame_threshold(3);
+        assert!(ps.request_pieces("peer", bitvec![1; 4], 2).is_err());
+
+        ps.store("peer", 1, Arc::new(vec![b'b']));
+        ps.store("peer", 3, Arc::new(vec![b'd']));
+        assert_eq!(ps.request_pieces("peer", bitvec![1; 4], 2), Ok(vec![0, 2]));
+    }
+
+    #[test]
+    fn test_verify_stored() -> Result<(), failure::Error> {
+        let data = b"abcdef";
+        let mut m = Metainfo::mock(2, data.len());
+        for (i, piece) in data.chunks(2).enumerate() {
+            m.info.pieces[20 * i..20 * (i + 1)].copy_from_slice(&hash::sha1(piece));
+        }
+        let path = std::env::temp_dir().join("continuity_test_verify_stored");
+        let mut ps = PieceStore::new(&m, Box::new(Inorder::default()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        ps.set_sink(Sink::Seekable(Box::new(file)));
+        for (i, piece) in data.chunks(2).enumerate() {
+            ps.store("peer", i as u32, Arc::new(piece.to_vec()));
+        }
+        let ps = RwLock::new(ps);
+        assert!(PieceStore::verify_stored(&ps, &m).is_empty());
+
+        // Corrupt the second piece on disk
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path)?;
+        file.seek(SeekFrom::Start(3))?;
+        file.write_all(b"x")?;
+        drop(file);
+        let (tx, rx) = mpsc::channel();
+        ps.read().unwrap().register(tx);
+        assert_eq!(PieceStore::verify_stored(&ps, &m), vec![1]);
+        let mut ps = ps.into_inner().unwrap();
+        assert_eq!(ps.left, 1);
+        assert!(matches!(rx.try_recv(), Ok(Command::Needed)));
+        // Still advertised to connected peers, but no longer served
+        assert!(ps.is_withdrawn(1));
+        assert!(ps.get_data(1).is_none());
+
+        // Downloaded again and written over the corrupted data
+        assert_eq!(ps.request_pieces("peer", bitvec![1; 3], 3), Ok(vec![1]));
+        ps.store("peer", 1, Arc::new(b"cd".to_vec()));
+        assert!(!ps.is_withdrawn(1));
+        let ps = RwLock::new(ps);
+        assert!(PieceStore::verify_stored(&ps, &m).is_empty());
+        drop(ps);
+        assert_eq!(std::fs::read(&path)?, data.to_vec());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_min_peers() {
+        let m = Metainfo::mock(1, 4);
+        let mut ps = PieceStore::new(&m, Box::new(Inorder::default()));
+        ps.set_min_peers(2);
+        let (tx, rx) = mpsc::channel();
+        ps.register(tx);
+
+        ps.peer_known("a");
+        ps.peer_known("a");
+        assert_eq!(ps.request_pieces("a", bitvec![1; 4], 2), Ok(vec![]));
+        assert!(rx.try_recv().is_err());
+
+        ps.peer_known("b");
+        assert!(matches!(rx.try_recv(), Ok(Command::Ping)));
+        assert_eq!(ps.request_pieces("a", bitvec![1; 4], 2), Ok(vec![0, 1]));
+    }
+
+    #[test]
+    fn test_register_during_completion() {
+        let num_pieces = 256;
+        let m = Metainfo::mock(1, num_pieces);
+        let mut ps = PieceStore::new(&m, Box::new(Inorder::default()));
+        ps.set_sink(Sink::Seekable(Box::new(Counted {
+            inner: io::Cursor::new(Vec::new()),
+            syncs: Arc::new(Mutex::new(0)),
+        })));
+        let ps = Arc::new(RwLock::new(ps));
+
+        let completer = {
+            let ps = ps.clone();
+            thread::spawn(move || {
+                for i in 0..num_pieces as u32 {
+                    ps.write().unwrap().store("peer", i, Arc::new(vec![0]));
+                }
+            })
+        };
+        let mut peers = Vec::new();
+        // Keep registering until every piece has completed
+        while ps.read().unwrap().left != 0 {
+            let (tx, rx) = mpsc::channel();
+            let bv = ps.read().unwrap().register(tx);
+            peers.push((bv, rx));
+        }
+        completer.join().unwrap();
+        drop(ps);
+
+        for (bv, rx) in peers {
+            let mut view = bv.clone();
+            for command in rx.iter() {
+                if let Command::ClientHave(i) = command {
+                    assert!(!view[i as usize], "duplicate have for piece {}", i);
+                    view.set(i as usize, true);
+                }
+            }
+            assert!(
+                view.iter().all(|b| b),
+                "missed have after bitfield {:?}",
+                bv
+            );
+        }
+    }
+
+    #[test]
+    fn test_store_twice() {
+        let m = Metainfo::mock(1, 2);
+        let mut ps = PieceStore::new(&m, Box::new(Inorder::default()));
+        ps.set_sink(Sink::Seekable(Box::new(Counted {
+            inner: io::Cursor::new(Vec::new()),
+            syncs: Arc::new(Mutex::new(0)),
+        })));
+        let (tx, rx) = mpsc::channel();
+        ps.register(tx);
+
+        ps.store("a", 0, Arc::new(vec![b'a']));
+        ps.store("b", 0, Arc::new(vec![b'b']));
+        assert_eq!(ps.left, 1);
+        assert!(matches!(rx.try_recv(), Ok(Command::ClientHave(0))));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(ps.get(0), Some(Arc::new(vec![b'a'])));
+
+        ps.store("a", 1, Arc::new(vec![b'c']));
+        ps.store("a", 1, Arc::new(vec![b'c']));
+        assert_eq!(ps.left, 0);
+    }
+
+    // A selector that is slow, as Rare is on a large torrent
+    struct Slow(time::Duration);
+
+    impl Selector for Slow {
+        fn request_pieces(&mut self, id: &str, state: State, n: u32) -> Vec<u32> {
+            thread::sleep(self.0);
+            Inorder::default().request_pieces(id, state, n)
+        }
+    }
+
+    // How long a reader waits for the store while another thread selects pieces with locked
+    fn reader_wait(
+        store: &Arc<RwLock<PieceStore>>,
+        locked: fn(&Arc<RwLock<PieceStore>>),
+    ) -> time::Duration {
+        let selecting = {
+            let store = store.clone();
+            thread::spawn(move || locked(&store))
+        };
+        thread::sleep(time::Duration::from_millis(50));
+        let start = time::Instant::now();
+        assert!(store.read().unwrap().get(3).is_none());
+        let wait = start.elapsed();
+        selecting.join().unwrap();
+        wait
+    }
+
+    #[test]
+    fn test_select_under_read_lock() {
+        let m = Metainfo::mock(2, 8);
+        let delay = time::Duration::from_millis(300);
+        let store = Arc::new(RwLock::new(PieceStore::new(&m, Box::new(Slow(delay)))));
+
+        // Under the write lock readers wait out the selector, under the read lock they do not
+        let write_wait = reader_wait(&store, |store| {
+            store
+                .write()
+                .unwrap()
+                .request_pieces("a", bitvec![1; 4], 1)
+                .unwrap();
+        });
+        let read_wait = reader_wait(&store, |store| {
+            store
+                .read()
+                .unwrap()
+                .select_pieces("b", &bitvec![1; 4], 1)
+                .unwrap();
+        });
+        assert!(write_wait >= delay / 2, "{:?}", write_wait);
+        assert!(read_wait < delay / 2, "{:?}", read_wait);
+
+        let latency = store.read().unwrap().selection_latency();
+        assert_eq!(latency.calls, 2);
+        assert!(latency.max >= delay && latency.mean() >= delay);
+
+        // A piece claimed by another peer between selection and claiming is left out
+        let selected = store.read().unwrap().select_pieces("b", &bitvec![1; 4], 2);
+        assert_eq!(selected, Some(vec![1, 2]));
+        store.write().unwrap().mark("c", 1);
+        assert_eq!(
+            store
+                .write()
+                .unwrap()
+                .claim_pieces("b", bitvec![1; 4], selected),
+            Ok(vec![2])
+        );
+    }
+
+    #[test]
+    fn test_time_to_first_piece() {
+        let m = Metainfo::mock(2, 4);
+        let mut ps = PieceStore::new(&m, Box::new(Inorder::default()));
+        assert_eq!(ps.time_to_first_piece(), None);
+        // Backdate the session rather than sleep
+        ps.start -= time::Duration::from_secs(10);
+
+        ps.store("a", 1, Arc::new(vec![b'a'; 2]));
+        let first = ps.time_to_first_piece().unwrap();
+        assert!(first >= time::Duration::from_secs(10));
+        let stored = ps.first_piece;
+        ps.store("a", 0, Arc::new(vec![b'b'; 2]));
+        // Later pieces leave it alone
+        assert_eq!(ps.first_piece, stored);
+        assert_eq!(ps.time_to_first_piece(), Some(first));
+    }
+
+    #[test]
+    fn test_sole_source_left() {
+        let m = Metainfo::mock(1, 4);
+        let mut ps = PieceStore::new(&m, Box::new(Inorder::default()));
+        ps.request_pieces("a", bitvec![1, 1, 0, 0], 1).unwrap();
+        ps.request_pieces("b", bitvec![0, 1, 1, 0], 1).unwrap();
+        ps.request_pieces("c", bitvec![0, 0, 1, 1], 0).unwrap_err();
+        ps.store("a", 0, Arc::new(vec![b'a']));
+
+        // Piece 1 is still held by b, and piece 0 is no longer needed
+        ps.peer_left("a");
+        assert!(ps.unavailable().is_empty());
+        // Requests to the peer are returned to the pool, but nobody else can serve them
+        ps.clear_requests("b");
+        ps.peer_left("b");
+        assert_eq!(ps.unavailable(), vec![1]);
+        ps.peer_left("c");
+        assert_eq!(ps.unavailable(), vec![1, 2, 3]);
+
+        // A new peer with the piece clears the flag
+        ps.request_pieces("d", bitvec![0, 0, 1, 0], 1).unwrap();
+        assert_eq!(ps.unavailable(), vec![1, 3]);
+        ps.store("d", 2, Arc::new(vec![b'c']));
+        ps.store("e", 3, Arc::new(vec![b'd']));
+        assert_eq!(ps.unavailable(), vec![1]);
+    }
+
+    #[test]
+    fn test_remaining_bytes() {
+        let m = Metainfo::mock(2, 5);
+        let mut ps = PieceStore::new(&m, Box::new(Inorder::default()));
+        assert_eq!(ps.remaining_bytes(), 5);
+        // Requested pieces still count as remaining
+        ps.request_pieces("a", bitvec![1; 3], 3).unwrap();
+        assert_eq!(ps.remaining_bytes(), 5);
+
+        ps.store("a", 2, Arc::new(vec![b'c']));
+        assert_eq!(ps.remaining_bytes(), 4);
+        ps.store("a", 0, Arc::new(vec![b'a'; 2]));
+        assert_eq!(ps.remaining_bytes(), 2);
+        assert!(!ps.is_complete());
+        ps.store("a", 1, Arc::new(vec![b'b'; 2]));
+        assert_eq!(ps.remaining_bytes(), 0);
+        assert!(ps.is_complete());
+    }
+
+    #[test]
+    fn test_seekable_sink() -> Result<(), failure::Error> {
+        let m = Metainfo::mock(2, 5);
+        let path = std::env::temp_dir().join("continuity_test_seekable_sink");
+        let mut ps = PieceStore::new(&m, Box::new(Inorder::default()));
+        ps.set_sink(Sink::Seekable(Box::new(File::create(&path)?)));
+        ps.store("peer", 2, Arc::new(vec![b'e']));
+        ps.store("peer", 1, Arc::new(vec![b'c', b'd']));
+        drop(ps);
+
+        let mut v = Vec::new();
+        File::open(&path)?.read_to_end(&mut v)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(v, vec![0, 0, b'c', b'd', b'e']);
+        Ok(())
+    }
+
+    // Seekable output counting the number of syncs
+    struct Counted {
+        inner: io::Cursor<Vec<u8>>,
+        syncs: Arc<Mutex<usize>>,
+    }
+
+    impl Write for Counted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl Read for Counted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for Counted {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl Output for Counted {
+        fn sync(&mut self) -> io::Result<()> {
+            *self.syncs.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    fn count_syncs(strategy: WriteStrategy, pieces: u32) -> usize {
+        let m = Metainfo::mock(1, 8);
+        let syncs = Arc::new(Mutex::new(0));
+        let mut ps = PieceStore::new(&m, Box::new(Inorder::default()));
+        ps.set_sink(Sink::Seekable(Box::new(Counted {
+            inner: io::Cursor::new(Vec::new()),
+            syncs: syncs.clone(),
+        })));
+        ps.set_write_strategy(strategy);
+        for i in 0..pieces {
+            ps.store("peer", i, Arc::new(vec![0]));
+        }
+        // Wait for the writer thread
+        drop(ps);
+        let n = *syncs.lock().unwrap();
+        n
+    }
+
+    #[test]
+    fn test_sync_replaced_sink() {
+        let m = Metainfo::mock(1, 8);
+        let syncs = Arc::new(Mutex::new(0));
+        let mut ps = PieceStore::new(&m, Box::new(Inorder::default()));
+        ps.set_sink(Sink::Seekable(Box::new(Counted {
+            inner: io::Cursor::new(Vec::new()),
+            syncs: syncs.clone(),
+        })));
+        ps.set_write_strategy(WriteStrategy::Batched(4));
+        for i in 0..5 {
+            ps.store("peer", i, Arc::new(vec![0]));
+        }
+        ps.set_sink(Sink::Stdout);
+        assert_eq!(*syncs.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_write_strategy() {
+        assert_eq!(count_syncs(WriteStrategy::Buffered, 4), 0);
+        assert_eq!(count_syncs(WriteStrategy::Sync, 4), 4);
+        assert_eq!(count_syncs(WriteStrategy::Batched(3), 2), 1);
+        assert_eq!(count_syncs(WriteStrategy::Batched(3), 3), 1);
+        assert_eq!(count_syncs(WriteStrategy::Batched(3), 7), 3);
+
+        assert_eq!("sync".parse(), Ok(WriteStrategy::Sync));
+        assert_eq!("batch:8".parse(), Ok(WriteStrategy::Batched(8)));
+        assert!("batch:0".parse::<WriteStrategy>().is_err());
+        assert!("direct".parse::<WriteStrategy>().is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use crate::tracker::PeerInfo;
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+
+// Best peers kept when saving, so the file stays small however large the swarm was
+pub const MAX_SAVED_PEERS: usize = 200;
+// Peers last seen longer ago than this are unlikely to still be around
+pub const MAX_AGE_SECS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedPeer {
+    // Seconds since the unix epoch
+    pub last_seen: u64,
+    pub score: i64,
+}
+
+// Peers connected in previous sessions keyed by address, persisted so that a restart can dial
+// them before the tracker has answered
+#[derive(Debug, Default, PartialEq)]
+pub struct SavedPeers {
+    peers: BTreeMap<String, SavedPeer>,
+}
+
+impl SavedPeers {
+    // A missing file is treated as no saved peers
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let peers = serde_bencode::from_bytes(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(Self { peers })
+    }
+
+    // Only the MAX_SAVED_PEERS best peers are written
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut best: Vec<_> = self.peers.iter().collect();
+        best.sort_by_key(|(_, p)| (Reverse(p.score), Reverse(p.last_seen)));
+        best.truncate(MAX_SAVED_PEERS);
+        let peers: BTreeMap<_, _> = best.into_iter().collect();
+        let data = serde_bencode::to_bytes(&peers)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        fs::write(path, data)
+    }
+
+    pub fn record(&mut self, addr: SocketAddr, score: i64, now: u64) {
+        self.peers.insert(
+            addr.to_string(),
+            SavedPeer {
+                last_seen: now,
+                score,
+            },
+        );
+    }
+
+    // Forget peers last seen more than MAX_AGE_SECS before now
+    pub fn expire(&mut self, now: u64) {
+        self.peers
+            .retain(|_, p| now.saturating_sub(p.last_seen) <= MAX_AGE_SECS);
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    // Peers seen within MAX_AGE_SECS of now, best first
+    pub fn candidates(&self, now: u64) -> Vec<PeerInfo> {
+        let mut v: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, p)| now.saturating_sub(p.last_seen) <= MAX_AGE_SECS)
+            .filter_map(|(addr, p)| addr.parse().ok().map(|addr| (addr, p)))
+            .collect();
+        v.sort_by_key(|(_, p)| Reverse(p.score));
+        v.into_iter().map(|(addr, _)| PeerInfo { addr }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    #[test]
+    fn test_saved_peers() -> io::Result<()> {
+        let path = std::env::temp_dir().join("continuity_test_saved_peers");
+        let _ = fs::remove_file(&path);
+        assert_eq!(SavedPeers::load(&path)?, SavedPeers::default());
+
+        let now = 10 * MAX_AGE_SECS;
+        let mut saved = SavedPeers::default();
+        saved.record(addr(1), 0, now);
+        saved.record(addr(2), 5, now - 1);
+        saved.record(addr(3), 9, now - MAX_AGE_SECS - 1);
+        for port in 100..100 + MAX_SAVED_PEERS as u16 {
+            saved.record(addr(port), -1, now);
+        }
+        saved.save(&path)?;
+
+        let mut saved = SavedPeers::load(&path)?;
+        fs::remove_file(&path)?;
+        assert_eq!(saved.len(), MAX_SAVED_PEERS);
+        // The expired peer is never handed out, and the worst peers did not fit
+        let candidates = saved.candidates(now);
+        assert_eq!(candidates.len(), MAX_SAVED_PEERS - 1);
+        assert_eq!(candidates[0].addr, addr(2));
+        assert_eq!(candidates[1].addr, addr(1));
+        saved.expire(now);
+        assert_eq!(saved.len(), MAX_SAVED_PEERS - 1);
+        Ok(())
+    }
+}
@@ -1,15 +1,20 @@
 use super::{Discover, PeerInfo, TorrentState};
-use crate::metainfo::Metainfo;
+use crate::metainfo::{self, Metainfo};
 use failure::{self, Fail};
-use log::debug;
-use reqwest::{self, Client, Method, Url};
+use log::{debug, warn};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, LOCATION, USER_AGENT};
+use reqwest::{self, Client, Method, StatusCode, Url};
 use serde_derive::{Deserialize, Serialize};
 use serde_urlencoded;
 use std::io::Read;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use url::percent_encoding::{percent_encode, USERINFO_ENCODE_SET};
 
 const DEFAULT_NUM_PEERS: u64 = 30;
+const MAX_REDIRECTS: usize = 5;
+// Wait between early announces to a tracker that has not told us an interval yet
+const EARLY_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -31,6 +36,24 @@ pub enum Error {
     Reqwest(#[fail(cause)] reqwest::Error),
     #[fail(display = "tracker error: {}", _0)]
     Tracker(String),
+    #[fail(display = "unsupported tracker scheme: {}", _0)]
+    UnsupportedScheme(String),
+    #[fail(display = "redirect without location")]
+    Redirect,
+    #[fail(display = "too many redirects")]
+    TooManyRedirects,
+    #[fail(display = "invalid header: {}", _0)]
+    InvalidHeader(String),
+    #[fail(display = "malformed tracker response")]
+    MalformedResponse,
+    #[fail(display = "{}", _0)]
+    Metainfo(#[fail(cause)] metainfo::Error),
+}
+
+impl From<metainfo::Error> for Error {
+    fn from(e: metainfo::Error) -> Self {
+        Error::Metainfo(e)
+    }
 }
 
 impl From<serde_urlencoded::ser::Error> for Error {
@@ -92,22 +115,37 @@ impl Request<'_, '_, '_> {
 struct Valid {
     warning_message: Option<String>,
     interval: u64,
+    min_interval: Option<u64>,
     tracker_id: Option<String>,
     peers: Vec<PeerInfo>,
 }
 
 impl Valid {
-    fn from_response(res: Response) -> Result<Self, Response> {
-        if res.peers.is_none() || res.interval.is_none() {
-            return Err(res);
+    // A failure reason wins over any other data in the response
+    fn from_response(res: Response) -> Result<Self, Error> {
+        if let Some(reason) = res.failure_reason {
+            return Err(Error::Tracker(reason));
+        }
+        match (res.interval, res.peers) {
+            (Some(interval), Some(peers)) => {
+                let mut peers = PeerInfo::deserialize(&mut peers.as_slice())
+                    .map_err(|_| Error::MalformedResponse)?;
+                if let Some(peers6) = res.peers6 {
+                    peers.extend(
+                        PeerInfo::deserialize6(&mut peers6.as_slice())
+                            .map_err(|_| Error::MalformedResponse)?,
+                    );
+                }
+                Ok(Valid {
+                    warning_message: res.warning_message,
+                    interval,
+                    min_interval: res.min_interval,
+                    tracker_id: res.tracker_id,
+                    peers,
+                })
+            }
+            _ => Err(Error::MalformedResponse),
         }
-
-        Ok(Valid {
-            warning_message: res.warning_message,
-            interval: res.interval.unwrap(),
-            tracker_id: res.tracker_id,
-            peers: PeerInfo::deserialize(&mut res.peers.unwrap().as_slice()).unwrap(),
-        })
     }
 }
 
@@ -120,35 +158,165 @@ struct Response {
     #[serde(rename = "warning message")]
     warning_message: Option<String>,
     interval: Option<u64>,
-    #[serde(with = "serde_bytes")]
+    #[serde(rename = "min interval")]
+    min_interval: Option<u64>,
+    #[serde(default, with = "serde_bytes")]
     peers: Option<Vec<u8>>,
+    #[serde(default, with = "serde_bytes")]
+    peers6: Option<Vec<u8>>,
+}
+
+// Parse a tracker URL, rejecting schemes no tracker protocol exists for
+pub fn parse_announce(s: &str) -> Result<Url, Error> {
+    let url = Url::parse(s)?;
+    match url.scheme() {
+        "http" | "https" | "udp" => Ok(url),
+        scheme => Err(Error::UnsupportedScheme(scheme.to_owned())),
+    }
 }
 
+// Redirects are followed by HTTP itself so that the announce query is preserved, so the client
+// should be built with RedirectPolicy::none()
 pub struct HTTP<'a> {
     pub metainfo: Arc<Metainfo>,
+    // One of the trackers of the metainfo, or the tracker given on the command line. Replaced by
+    // permanent redirects.
+    pub announce: Url,
     pub peer_id: Arc<String>,
     pub port: u16,
     pub client: &'a Client,
     announced: bool,
+    // The tracker knows we have everything, from the started or completed announce
+    complete: bool,
     tracker_id: Option<String>,
+    interval: Option<Duration>,
+    min_interval: Option<Duration>,
+    // When an announce was last sent, whether or not it succeeded
+    last_announce: Option<Instant>,
     info_hash: Option<String>,
+    // Sent with every announce, e.g. for private trackers that whitelist clients
+    headers: HeaderMap,
 }
 
 impl<'a> HTTP<'a> {
     pub fn new(
         metainfo: Arc<Metainfo>,
+        announce: Url,
         peer_id: Arc<String>,
         port: u16,
         client: &'a Client,
     ) -> Self {
         HTTP {
             metainfo,
+            announce,
             peer_id,
             port,
             client,
             announced: false,
+            complete: false,
             tracker_id: None,
+            interval: None,
+            min_interval: None,
+            last_announce: None,
             info_hash: None,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    pub fn set_user_agent(&mut self, user_agent: &str) -> Result<(), Error> {
+        let value = HeaderValue::from_str(user_agent)
+            .map_err(|_| Error::InvalidHeader(user_agent.to_owned()))?;
+        self.headers.insert(USER_AGENT, value);
+        Ok(())
+    }
+
+    pub fn add_header(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| Error::InvalidHeader(name.to_owned()))?;
+        let value =
+            HeaderValue::from_str(value).map_err(|_| Error::InvalidHeader(value.to_owned()))?;
+        self.headers.append(name, value);
+        Ok(())
+    }
+}
+
+impl<'a> HTTP<'a> {
+    // Send the announce, following redirects with the original query
+    fn execute(&mut self, url: &mut Url) -> Result<reqwest::Response, Error> {
+        let query = url.query().map(|q| q.to_owned());
+        for _ in 0..MAX_REDIRECTS {
+            let mut http_request = reqwest::Request::new(Method::GET, url.clone());
+            *http_request.headers_mut() = self.headers.clone();
+            let http_response = self.client.execute(http_request)?;
+            let status = http_response.status();
+            if !status.is_redirection() {
+                return Ok(http_response.error_for_status()?);
+            }
+
+            let location = http_response
+                .headers()
+                .get(LOCATION)
+                .and_then(|l| l.to_str().ok())
+                .ok_or(Error::Redirect)?;
+            let mut next = url.join(location)?;
+            debug!("Tracker redirected to {}", next);
+            if status == StatusCode::MOVED_PERMANENTLY || status == StatusCode::PERMANENT_REDIRECT {
+                self.announce = next.clone();
+            }
+            next.set_query(query.as_ref().map(|q| q.as_str()));
+            *url = next;
+        }
+        Err(Error::TooManyRedirects)
+    }
+
+    pub fn announced(&self) -> bool {
+        self.announced
+    }
+
+    // Time the tracker asked us to wait between announces, known after the first one
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    // Announces outside the regular schedule wait for the min interval since the last announce,
+    // or the interval when the tracker gave no minimum
+    pub fn may_announce_early(&self, now: Instant) -> bool {
+        let wait = self
+            .min_interval
+            .or(self.interval)
+            .unwrap_or(EARLY_ANNOUNCE_INTERVAL);
+        self.last_announce.map_or(true, |last| now >= last + wait)
+    }
+
+    pub fn tracker_id(&self) -> Option<&str> {
+        self.tracker_id.as_ref().map(|x| x.as_str())
+    }
+
+    // Tell the tracker the download finished. Not sent if we already had everything when
+    // starting, as the spec only sends it for a download finishing while announced.
+    pub fn completed(&mut self, state: &TorrentState) -> Result<(), Error> {
+        if self.complete {
+            debug!("Tracker already knows the download is complete");
+            return Ok(());
+        }
+        self.announce(state, Some(0), Some(Event::Completed))?;
+        self.complete = true;
+        Ok(())
+    }
+
+    // Tell the tracker we are leaving the swarm, so it stops handing us out
+    pub fn stopped(&mut self, state: &TorrentState) -> Result<(), Error> {
+        self.announce(state, Some(0), Some(Event::Stopped))
+            .map(|_| ())?;
+        self.announced = false;
+        Ok(())
+    }
+
+    // UDP trackers use their own protocol, so are refused here rather than sent an HTTP request
+    fn announce_url(&self) -> Result<Url, Error> {
+        match self.announce.scheme() {
+            "http" | "https" => Ok(self.announce.clone()),
+            scheme => Err(Error::UnsupportedScheme(scheme.to_owned())),
         }
     }
 }
@@ -159,6 +327,26 @@ impl<'a> Discover for HTTP<'a> {
         &mut self,
         state: &TorrentState,
         num_peers: Option<u64>,
+    ) -> Result<Vec<PeerInfo>, Error> {
+        let event = match self.announced {
+            true => None,
+            false => {
+                self.complete = state.left == 0;
+                Some(Event::Started)
+            }
+        };
+        let peers = self.announce(state, num_peers, event)?;
+        self.announced = true;
+        Ok(peers)
+    }
+}
+
+impl<'a> HTTP<'a> {
+    fn announce(
+        &mut self,
+        state: &TorrentState,
+        num_peers: Option<u64>,
+        event: Option<Event>,
     ) -> Result<Vec<PeerInfo>, Error> {
         if self.info_hash.is_none() {
             self.info_hash = Some(
@@ -174,40 +362,40 @@ impl<'a> Discover for HTTP<'a> {
         }
 
         let req = Request {
-            base: Url::parse(&self.metainfo.announce)?,
+            base: self.announce_url()?,
             info_hash: self.info_hash.as_ref().unwrap(),
             peer_id: self.peer_id.as_str(),
             tracker_id: self.tracker_id.as_ref().map(|x| x.as_str()),
             port: self.port,
             torrent_state: state,
             num_peers: num_peers.unwrap_or(DEFAULT_NUM_PEERS),
-            event: match self.announced {
-                true => None,
-                false => {
-                    self.announced = true;
-                    Some(Event::Started)
-                }
-            },
+            event,
         };
 
-        let http_request = reqwest::Request::new(Method::GET, req.into_url()?);
-        let mut http_response = self.client.execute(http_request)?.error_for_status()?;
+        let mut url = req.into_url()?;
+        self.last_announce = Some(Instant::now());
+        let mut http_response = self.execute(&mut url)?;
         let mut v = Vec::new();
         http_response.read_to_end(&mut v).unwrap();
         let res: Response = serde_bencode::de::from_bytes(&v)?;
-        match Valid::from_response(res) {
-            Ok(v) => {
-                self.tracker_id = v.tracker_id;
-                Ok(v.peers)
-            }
-            Err(res) => Err(Error::Tracker(res.failure_reason.unwrap())),
+        let v = Valid::from_response(res)?;
+        if let Some(warning) = &v.warning_message {
+            warn!("Tracker warning: {}", warning);
         }
+        // Trackers may only send the id once, it is kept until replaced
+        if v.tracker_id.is_some() {
+            self.tracker_id = v.tracker_id;
+        }
+        self.interval = Some(Duration::from_secs(v.interval));
+        self.min_interval = v.min_interval.map(Duration::from_secs);
+        Ok(v.peers)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use matches::matches;
     use mockito::{self, mock, Matcher};
     use std::borrow::Cow;
     use std::net::{Ipv4Addr, SocketAddrV4};
@@ -223,6 +411,7 @@ mod tests {
             uploaded: 0,
             downloaded: 0,
             left: 1000,
+            corrupt: 0,
         };
         let num_peers = 10;
         let event = Some(Event::Started);
@@ -278,22 +467,46 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_corrupt_serialization() -> Result<(), failure::Error> {
+        let req = Request {
+            base: Url::parse("https://localhost/announce")?,
+            info_hash: "test",
+            peer_id: "CN1",
+            tracker_id: None,
+            port: 1000,
+            torrent_state: &TorrentState {
+                uploaded: 0,
+                downloaded: 0,
+                left: 1000,
+                corrupt: 16384,
+            },
+            num_peers: 10,
+            event: None,
+        };
+        let url = req.into_url()?;
+        assert!(url
+            .query_pairs()
+            .any(|(k, v)| k == "corrupt" && v == "16384"));
+        Ok(())
+    }
+
     #[test]
     fn test_announce() -> Result<(), failure::Error> {
-        let mut m = Metainfo::from_file("data/test.torrent")?;
-        m.announce = mockito::server_url() + "/announce";
+        let m = Metainfo::from_file("data/test.torrent")?;
         let _mck = mock("GET", Matcher::Any)
             .with_status(200)
             .with_header("content-type", "text/plain")
             .with_body_from_file("data/test_response")
             .create();
         let r = Client::new();
-        let mut h = HTTP::new(Arc::new(m), Arc::new(String::from("test")), 1000, &r);
+        let mut h = http(m, &(mockito::server_url() + "/announce"), &r);
         let v = h.get_peers(
             &TorrentState {
                 downloaded: 0,
                 uploaded: 0,
                 left: 1000,
+                corrupt: 0,
             },
             Some(2),
         )?;
@@ -302,15 +515,170 @@ mod tests {
         assert_eq!(
             it.next(),
             Some(&PeerInfo {
-                addr: SocketAddrV4::new(Ipv4Addr::from_str("91.64.137.190").unwrap(), 51413)
+                addr: SocketAddrV4::new(Ipv4Addr::from_str("91.64.137.190").unwrap(), 51413).into()
             })
         );
         assert_eq!(
             it.next(),
             Some(&PeerInfo {
-                addr: SocketAddrV4::new(Ipv4Addr::from_str("92.62.63.75").unwrap(), 6881)
+                addr: SocketAddrV4::new(Ipv4Addr::from_str("92.62.63.75").unwrap(), 6881).into()
             })
         );
         Ok(())
     }
+
+    fn http<'a>(m: Metainfo, announce: &str, client: &'a Client) -> HTTP<'a> {
+        let announce = parse_announce(announce).unwrap();
+        HTTP::new(
+            Arc::new(m),
+            announce,
+            Arc::new(String::from("test")),
+            1000,
+            client,
+        )
+    }
+
+    fn response(b: &[u8]) -> Result<Valid, Error> {
+        Valid::from_response(serde_bencode::de::from_bytes(b).unwrap())
+    }
+
+    #[test]
+    fn test_response_shapes() {
+        assert!(matches!(
+            response(b"d14:failure reason6:banned8:intervali60ee"),
+            Err(Error::Tracker(ref r)) if r == "banned"
+        ));
+        let v = response(b"d15:warning message4:slow8:intervali60e5:peers6:\x7f\0\0\x01\x1a\xe1e")
+            .unwrap();
+        assert_eq!(v.warning_message, Some("slow".to_owned()));
+        assert_eq!(v.peers.len(), 1);
+        assert!(matches!(
+            response(b"d15:warning message4:slowe"),
+            Err(Error::MalformedResponse)
+        ));
+        assert!(matches!(
+            response(b"d8:intervali60e5:peers4:abcde"),
+            Err(Error::MalformedResponse)
+        ));
+        let v = response(
+            b"d8:intervali60e5:peers0:6:peers618:\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe1e",
+        )
+        .unwrap();
+        assert_eq!(
+            v.peers,
+            vec![PeerInfo {
+                addr: "[::1]:6881".parse().unwrap()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_announce_override() -> Result<(), failure::Error> {
+        let m = Metainfo::from_file("data/test.torrent")?;
+        let announce = m.announce.clone().unwrap();
+        let r = Client::new();
+        let mut h = http(m, &announce, &r);
+        assert_eq!(h.announce_url()?.as_str(), announce);
+        h.announce = parse_announce("http://localhost:6969/announce")?;
+        assert_eq!(h.announce_url()?.as_str(), "http://localhost:6969/announce");
+        assert!(parse_announce("udp://localhost:6969").is_ok());
+        assert!(matches!(
+            parse_announce("ftp://localhost/announce"),
+            Err(Error::UnsupportedScheme(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_udp_announce() -> Result<(), failure::Error> {
+        let state = TorrentState {
+            uploaded: 0,
+            downloaded: 0,
+            left: 1000,
+            corrupt: 0,
+        };
+        let m = Metainfo::from_file("data/test.torrent")?;
+        let r = Client::new();
+        let mut h = http(m, "udp://localhost:6969", &r);
+        // Refused before any request is made, rather than failing inside reqwest
+        match h.get_peers(&state, None) {
+            Err(Error::UnsupportedScheme(scheme)) => assert_eq!(scheme, "udp"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(!h.announced());
+        h.announce = parse_announce("udp://localhost:6970")?;
+        assert!(matches!(
+            h.get_peers(&state, None),
+            Err(Error::UnsupportedScheme(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_announce_headers() -> Result<(), failure::Error> {
+        let m = Metainfo::from_file("data/test.torrent")?;
+        let _mck = mock("GET", Matcher::Regex(r"^/private\?".to_owned()))
+            .match_header("user-agent", "Transmission/2.94")
+            .match_header("x-passkey", "secret")
+            .with_status(200)
+            .with_header("content-type", "text/plain")
+            .with_body_from_file("data/test_response")
+            .create();
+        let r = Client::new();
+        let mut h = http(m, &(mockito::server_url() + "/private"), &r);
+        h.set_user_agent("Transmission/2.94")?;
+        h.add_header("X-Passkey", "secret")?;
+        assert!(matches!(
+            h.add_header("bad header", "x"),
+            Err(Error::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            h.set_user_agent("bad\nagent"),
+            Err(Error::InvalidHeader(_))
+        ));
+        let v = h.get_peers(
+            &TorrentState {
+                downloaded: 0,
+                uploaded: 0,
+                left: 1000,
+                corrupt: 0,
+            },
+            Some(2),
+        )?;
+        assert_eq!(v.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_announce_redirect() -> Result<(), failure::Error> {
+        let m = Metainfo::from_file("data/test.torrent")?;
+        let _redirect = mock("GET", Matcher::Regex(r"^/old\?".to_owned()))
+            .with_status(301)
+            .with_header("location", "/announce")
+            .create();
+        let _mck = mock(
+            "GET",
+            Matcher::Regex(r"^/announce\?.*info_hash=".to_owned()),
+        )
+        .with_status(200)
+        .with_header("content-type", "text/plain")
+        .with_body_from_file("data/test_response")
+        .create();
+        let r = Client::builder()
+            .redirect(reqwest::RedirectPolicy::none())
+            .build()?;
+        let mut h = http(m, &(mockito::server_url() + "/old"), &r);
+        let v = h.get_peers(
+            &TorrentState {
+                downloaded: 0,
+                uploaded: 0,
+                left: 1000,
+                corrupt: 0,
+            },
+            Some(2),
+        )?;
+        assert_eq!(v.len(), 2);
+        assert_eq!(h.announce.path(), "/announce");
+        Ok(())
+    }
 }
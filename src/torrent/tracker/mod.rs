@@ -1,8 +1,14 @@
 pub mod http;
+pub mod source;
+use crate::metainfo::Metainfo;
 use byteorder::{ReadBytesExt, BE};
 use failure::Fail;
+use log::{debug, warn};
+use reqwest::{Client, Url};
 use serde_derive::Serialize;
-use std::net::SocketAddrV4;
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::Arc;
+use std::time::Instant;
 
 pub trait Discover {
     type Error;
@@ -13,26 +19,166 @@ pub trait Discover {
     ) -> Result<Vec<PeerInfo>, Self::Error>;
 }
 
+// Every tracker of a torrent, each with its own announce state
+pub struct TrackerSet<'a> {
+    trackers: Vec<http::HTTP<'a>>,
+}
+
+impl<'a> TrackerSet<'a> {
+    // One tracker per distinct URL of the announce list. UDP trackers are skipped since only HTTP
+    // is implemented, as are malformed URLs, so long as one usable tracker is left.
+    pub fn new(
+        metainfo: Arc<Metainfo>,
+        peer_id: Arc<String>,
+        port: u16,
+        client: &'a Client,
+    ) -> Result<Self, http::Error> {
+        let mut urls = Vec::new();
+        let mut error = None;
+        for url in metainfo.trackers()? {
+            match http::parse_announce(url) {
+                Ok(ref url) if url.scheme() == "udp" => debug!("Skipping UDP tracker {}", url),
+                Ok(url) => urls.push(url),
+                Err(e) => {
+                    warn!("Skipping tracker {}: {}", url, e);
+                    error = Some(e);
+                }
+            }
+        }
+        if urls.is_empty() {
+            return Err(error.unwrap_or_else(|| http::Error::UnsupportedScheme("udp".to_owned())));
+        }
+        Ok(Self::with_urls(metainfo, peer_id, port, client, urls))
+    }
+
+    pub fn with_urls(
+        metainfo: Arc<Metainfo>,
+        peer_id: Arc<String>,
+        port: u16,
+        client: &'a Client,
+        urls: Vec<Url>,
+    ) -> Self {
+        let trackers = urls
+            .into_iter()
+            .map(|url| http::HTTP::new(metainfo.clone(), url, peer_id.clone(), port, client))
+            .collect();
+        TrackerSet { trackers }
+    }
+
+    pub fn trackers_mut(&mut self) -> impl Iterator<Item = &mut http::HTTP<'a>> {
+        self.trackers.iter_mut()
+    }
+
+    pub fn completed(&mut self, state: &TorrentState) {
+        for h in self.trackers.iter_mut().filter(|h| h.announced()) {
+            if let Err(e) = h.completed(state) {
+                warn!("Failed to announce completion: {}", e);
+            }
+        }
+    }
+
+    pub fn stopped(&mut self, state: &TorrentState) {
+        for h in self.trackers.iter_mut().filter(|h| h.announced()) {
+            if let Err(e) = h.stopped(state) {
+                warn!("Failed to announce stop: {}", e);
+            }
+        }
+    }
+
+    // Whether any tracker may be sent an announce outside the regular schedule
+    pub fn may_announce_early(&self, now: Instant) -> bool {
+        self.trackers.iter().any(|h| h.may_announce_early(now))
+    }
+
+    // As get_peers, but only from the trackers that may be announced to early
+    pub fn get_peers_early(
+        &mut self,
+        state: &TorrentState,
+        now: Instant,
+    ) -> Result<Vec<PeerInfo>, http::Error> {
+        let trackers = self
+            .trackers
+            .iter_mut()
+            .filter(|h| h.may_announce_early(now));
+        announce_all(trackers, state, None)
+    }
+}
+
+impl<'a> Discover for TrackerSet<'a> {
+    type Error = http::Error;
+
+    fn get_peers(
+        &mut self,
+        state: &TorrentState,
+        num_peers: Option<u64>,
+    ) -> Result<Vec<PeerInfo>, http::Error> {
+        announce_all(self.trackers.iter_mut(), state, num_peers)
+    }
+}
+
+// Peers from every tracker that answered, an error only if none did
+fn announce_all<'a: 'b, 'b>(
+    trackers: impl Iterator<Item = &'b mut http::HTTP<'a>>,
+    state: &TorrentState,
+    num_peers: Option<u64>,
+) -> Result<Vec<PeerInfo>, http::Error> {
+    let mut peers: Vec<PeerInfo> = Vec::new();
+    let mut error = None;
+    let mut answered = false;
+    for h in trackers {
+        match h.get_peers(state, num_peers) {
+            Ok(v) => {
+                answered = true;
+                for p in v {
+                    if !peers.contains(&p) {
+                        peers.push(p);
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Tracker announce failed: {}", e);
+                error = Some(e);
+            }
+        }
+    }
+    match (answered, error) {
+        (false, Some(e)) => Err(e),
+        _ => Ok(peers),
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub struct TorrentState {
     pub uploaded: u64,
     pub downloaded: u64,
     pub left: u64,
+    // Bytes discarded for failing verification, only sent when there are any
+    #[serde(skip_serializing_if = "is_zero")]
+    pub corrupt: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
 }
 
 #[derive(Fail, Debug)]
 pub enum Error {
     #[fail(display = "Length not multiple of 6")]
     InvalidLength,
+    #[fail(display = "Length not multiple of 18")]
+    InvalidLength6,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct PeerInfo {
-    pub addr: SocketAddrV4,
+    pub addr: SocketAddr,
 }
 
 impl PeerInfo {
     fn deserialize(serialized: &mut &[u8]) -> Result<Vec<Self>, Error> {
+        if serialized.len() % 6 != 0 {
+            return Err(Error::InvalidLength);
+        }
         let mut v = Vec::with_capacity(serialized.len() / 6);
         let mut to_read = serialized.len();
         while to_read != 0 {
@@ -40,9 +186,205 @@ impl PeerInfo {
             let port = serialized.read_u16::<BE>().unwrap();
             to_read -= 6;
             v.push(PeerInfo {
-                addr: SocketAddrV4::new(ip, port),
+                addr: SocketAddrV4::new(ip, port).into(),
+            });
+        }
+        Ok(v)
+    }
+
+    // The compact IPv6 form of BEP 7, 16 address bytes and a port per peer
+    fn deserialize6(serialized: &mut &[u8]) -> Result<Vec<Self>, Error> {
+        if serialized.len() % 18 != 0 {
+            return Err(Error::InvalidLength6);
+        }
+        let mut v = Vec::with_capacity(serialized.len() / 18);
+        while !serialized.is_empty() {
+            let ip = Ipv6Addr::from(serialized.read_u128::<BE>().unwrap());
+            let port = serialized.read_u16::<BE>().unwrap();
+            v.push(PeerInfo {
+                addr: SocketAddrV6::new(ip, port, 0, 0).into(),
             });
         }
         Ok(v)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{self, mock, Matcher};
+    use std::time::Duration;
+
+    fn tracker(path: &str, query: &str, tracker_id: &str) -> mockito::Mock {
+        let body = format!(
+            "d8:intervali1800e5:peers6:\x01\x02\x03\x04AB10:tracker id{}:{}e",
+            tracker_id.len(),
+            tracker_id
+        );
+        mock("GET", Matcher::Regex(format!(r"^{}\?{}", path, query)))
+            .with_status(200)
+            .with_header("content-type", "text/plain")
+            .with_body(body)
+            .create()
+    }
+
+    #[test]
+    fn test_tracker_set() -> Result<(), failure::Error> {
+        let mut m = Metainfo::from_file("data/test.torrent")?;
+        let first = mockito::server_url() + "/first";
+        let second = mockito::server_url() + "/second";
+        m.announce_list = vec![
+            vec![first.clone()],
+            vec![second.clone(), first, "udp://localhost:6969".to_owned()],
+        ];
+        let r = Client::new();
+        let mut set = TrackerSet::new(Arc::new(m), Arc::new(String::from("test")), 1000, &r)?;
+        assert_eq!(set.trackers.len(), 2);
+        let state = TorrentState {
+            downloaded: 0,
+            uploaded: 0,
+            left: 1000,
+            corrupt: 0,
+        };
+
+        let started = vec![
+            tracker("/first", "(.*&)?event=started", "one"),
+            tracker("/second", "(.*&)?event=started", "two"),
+        ];
+        let peers = set.get_peers(&state, None)?;
+        // Both trackers returned the same peer
+        assert_eq!(peers.len(), 1);
+        for mck in started.iter() {
+            mck.assert();
+        }
+        drop(started);
+        let ids: Vec<_> = set.trackers_mut().map(|h| h.tracker_id()).collect();
+        assert_eq!(ids, vec![Some("one"), Some("two")]);
+
+        // Each tracker is sent back its own id
+        let again = vec![
+            tracker("/first", "(.*&)?trackerid=one", "one"),
+            tracker("/second", "(.*&)?trackerid=two", "two"),
+        ];
+        set.get_peers(&state, None)?;
+        for mck in again.iter() {
+            mck.assert();
+        }
+        drop(again);
+
+        let stopped = vec![
+            tracker("/first", "(.*&)?event=stopped", "one"),
+            tracker("/second", "(.*&)?event=stopped", "two"),
+        ];
+        set.stopped(&state);
+        for mck in stopped.iter() {
+            mck.assert();
+        }
+        assert!(set.trackers_mut().all(|h| !h.announced()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_early_announce() -> Result<(), failure::Error> {
+        let mut m = Metainfo::from_file("data/test.torrent")?;
+        m.announce = Some(mockito::server_url() + "/early");
+        m.announce_list = Vec::new();
+        let r = Client::new();
+        let mut set = TrackerSet::new(Arc::new(m), Arc::new(String::from("test")), 1000, &r)?;
+        let state = TorrentState {
+            downloaded: 0,
+            uploaded: 0,
+            left: 1000,
+            corrupt: 0,
+        };
+        assert!(set.may_announce_early(Instant::now()));
+
+        let mck = mock("GET", Matcher::Regex(r"^/early\?".to_owned()))
+            .with_status(200)
+            .with_body("d8:intervali1800e12:min intervali60e5:peers6:\x01\x02\x03\x04ABe")
+            .expect(2)
+            .create();
+        set.get_peers(&state, None)?;
+        // Nothing is sent until the min interval has passed
+        let now = Instant::now();
+        assert!(!set.may_announce_early(now));
+        assert!(set.get_peers_early(&state, now)?.is_empty());
+        let later = now + Duration::from_secs(60);
+        assert!(set.may_announce_early(later));
+        assert_eq!(set.get_peers_early(&state, later)?.len(), 1);
+        mck.assert();
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_tracker_skipped() -> Result<(), failure::Error> {
+        let mut m = Metainfo::from_file("data/test.torrent")?;
+        let good = mockito::server_url() + "/good";
+        m.announce = None;
+        m.announce_list = vec![vec!["not a url".to_owned()], vec![good.clone()]];
+        let r = Client::new();
+        let mut set = TrackerSet::new(Arc::new(m), Arc::new(String::from("test")), 1000, &r)?;
+        let urls: Vec<_> = set.trackers_mut().map(|h| h.announce.to_string()).collect();
+        assert_eq!(urls, vec![good]);
+
+        // With nothing usable left, the error is reported
+        let mut m = Metainfo::from_file("data/test.torrent")?;
+        m.announce = None;
+        m.announce_list = vec![vec!["not a url".to_owned(), "ftp://localhost/".to_owned()]];
+        match TrackerSet::new(Arc::new(m), Arc::new(String::from("test")), 1000, &r) {
+            Err(http::Error::UnsupportedScheme(ref s)) if s == "ftp" => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("no usable tracker"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_completed_event() -> Result<(), failure::Error> {
+        let r = Client::new();
+        let incomplete = TorrentState {
+            downloaded: 0,
+            uploaded: 0,
+            left: 1000,
+            corrupt: 0,
+        };
+        let complete = TorrentState {
+            downloaded: 1000,
+            uploaded: 0,
+            left: 0,
+            corrupt: 0,
+        };
+
+        // Started with everything, so there is no completion to announce
+        let mut m = Metainfo::from_file("data/test.torrent")?;
+        m.announce = Some(mockito::server_url() + "/seeding");
+        let mut set = TrackerSet::new(Arc::new(m), Arc::new(String::from("test")), 1000, &r)?;
+        let started = tracker("/seeding", "(.*&)?event=started", "seeding");
+        set.get_peers(&complete, None)?;
+        started.assert();
+        drop(started);
+        let completed = mock(
+            "GET",
+            Matcher::Regex(r"^/seeding\?(.*&)?event=completed".to_owned()),
+        )
+        .expect(0)
+        .create();
+        set.completed(&complete);
+        completed.assert();
+        drop(completed);
+
+        // Completed during the session, announced exactly once
+        let mut m = Metainfo::from_file("data/test.torrent")?;
+        m.announce = Some(mockito::server_url() + "/leeching");
+        let mut set = TrackerSet::new(Arc::new(m), Arc::new(String::from("test")), 1000, &r)?;
+        let started = tracker("/leeching", "(.*&)?event=started", "leeching");
+        set.get_peers(&incomplete, None)?;
+        started.assert();
+        drop(started);
+        let completed = tracker("/leeching", "(.*&)?event=completed", "leeching");
+        set.completed(&complete);
+        set.completed(&complete);
+        completed.assert();
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use super::{Discover, PeerInfo, TorrentState};
+use failure::Fail;
+use reqwest::{self, Client, Url};
+use std::fs;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "io error: {}", _0)]
+    IO(#[fail(cause)] io::Error),
+    #[fail(display = "reqwest error: {}", _0)]
+    Reqwest(#[fail(cause)] reqwest::Error),
+    #[fail(display = "invalid peer list: {}", _0)]
+    InvalidPeers(#[fail(cause)] serde_json::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::IO(e)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Reqwest(e)
+    }
+}
+
+// A peer source outside the torrent's trackers, answering with a JSON list of "ip:port" strings
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerSource {
+    // Read again on every query, so the list can be changed while running
+    File(PathBuf),
+    Http(Url),
+}
+
+// file:PATH or an http(s) URL
+impl FromStr for PeerSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("file:") && s.len() > "file:".len() {
+            return Ok(PeerSource::File(PathBuf::from(&s["file:".len()..])));
+        }
+        match Url::parse(s) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+                Ok(PeerSource::Http(url))
+            }
+            _ => Err(format!(
+                "{} is not a peer source: expected file:PATH or an http(s) URL",
+                s
+            )),
+        }
+    }
+}
+
+pub struct Custom<'a> {
+    source: PeerSource,
+    client: &'a Client,
+}
+
+impl<'a> Custom<'a> {
+    pub fn new(source: PeerSource, client: &'a Client) -> Self {
+        Custom { source, client }
+    }
+
+    pub fn source(&self) -> &PeerSource {
+        &self.source
+    }
+
+    fn fetch(&self) -> Result<Vec<u8>, Error> {
+        match self.source {
+            PeerSource::File(ref path) => Ok(fs::read(path)?),
+            PeerSource::Http(ref url) => {
+                let mut res = self.client.get(url.clone()).send()?.error_for_status()?;
+                let mut v = Vec::new();
+                res.read_to_end(&mut v)?;
+                Ok(v)
+            }
+        }
+    }
+}
+
+impl<'a> Discover for Custom<'a> {
+    type Error = Error;
+
+    // The list is handed back as is, the torrent state is not sent anywhere
+    fn get_peers(
+        &mut self,
+        _: &TorrentState,
+        num_peers: Option<u64>,
+    ) -> Result<Vec<PeerInfo>, Error> {
+        let addrs: Vec<SocketAddr> =
+            serde_json::from_slice(&self.fetch()?).map_err(Error::InvalidPeers)?;
+        let mut peers: Vec<_> = addrs.into_iter().map(|addr| PeerInfo { addr }).collect();
+        if let Some(n) = num_peers {
+            peers.truncate(n as usize);
+        }
+        Ok(peers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matches::matches;
+
+    #[test]
+    fn test_file_source() -> Result<(), failure::Error> {
+        assert!("file:".parse::<PeerSource>().is_err());
+        assert!("udp://localhost:6969".parse::<PeerSource>().is_err());
+        assert!(matches!(
+            "https://localhost/peers".parse(),
+            Ok(PeerSource::Http(_))
+        ));
+
+        let path = std::env::temp_dir().join("continuity_test_file_source");
+        fs::write(&path, r#"["127.0.0.1:6881", "10.0.0.2:51413"]"#)?;
+        let source: PeerSource = format!("file:{}", path.display()).parse().unwrap();
+        assert_eq!(source, PeerSource::File(path.clone()));
+        let client = Client::new();
+        let mut custom = Custom::new(source, &client);
+        let state = TorrentState {
+            uploaded: 0,
+            downloaded: 0,
+            left: 1000,
+            corrupt: 0,
+        };
+        let peers = custom.get_peers(&state, None)?;
+        assert_eq!(
+            peers,
+            vec![
+                PeerInfo {
+                    addr: "127.0.0.1:6881".parse()?
+                },
+                PeerInfo {
+                    addr: "10.0.0.2:51413".parse()?
+                },
+            ]
+        );
+        assert_eq!(custom.get_peers(&state, Some(1))?.len(), 1);
+
+        fs::write(&path, "127.0.0.1:6881")?;
+        let res = custom.get_peers(&state, None);
+        fs::remove_file(&path)?;
+        assert!(matches!(res, Err(Error::InvalidPeers(_))));
+        Ok(())
+    }
+}
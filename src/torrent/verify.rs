@@ -0,0 +1,116 @@
+use crate::metainfo::Metainfo;
+use log::debug;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+struct Job {
+    index: u32,
+    data: Arc<Vec<u8>>,
+    // Hash of data computed while it arrived, if any
+    digest: Option<[u8; 20]>,
+    done: Box<dyn FnOnce(Arc<Vec<u8>>, bool) + Send>,
+}
+
+// Pool of threads hashing completed pieces, so receivers can keep reading while a piece is
+// verified. Workers exit once the pool is dropped and the queue is drained.
+pub struct Verifier {
+    tx: Mutex<mpsc::Sender<Job>>,
+}
+
+impl Verifier {
+    pub fn new(metainfo: Arc<Metainfo>, workers: usize) -> Self {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..workers {
+            let rx = rx.clone();
+            let metainfo = metainfo.clone();
+            thread::Builder::new()
+                .name(format!("verify-{}", i))
+                .spawn(move || loop {
+                    // The lock is released before hashing so other workers can take jobs
+                    let job = match rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    let valid = match job.digest {
+                        Some(ref digest) => metainfo.verify_digest(job.index, digest),
+                        None => metainfo.verify_piece(job.index, &job.data),
+                    };
+                    debug!("Verified piece {}: {}", job.index, valid);
+                    (job.done)(job.data, valid);
+                })
+                .expect("Failed to spawn verification thread");
+        }
+        Verifier { tx: Mutex::new(tx) }
+    }
+
+    // done is called from a worker thread with the piece and whether its hash matched
+    pub fn verify<F: FnOnce(Arc<Vec<u8>>, bool) + Send + 'static>(
+        &self,
+        index: u32,
+        data: Arc<Vec<u8>>,
+        done: F,
+    ) {
+        self.verify_with_digest(index, data, None, done)
+    }
+
+    // As verify, but a given digest of data is compared instead of hashing it again
+    pub fn verify_with_digest<F: FnOnce(Arc<Vec<u8>>, bool) + Send + 'static>(
+        &self,
+        index: u32,
+        data: Arc<Vec<u8>>,
+        digest: Option<[u8; 20]>,
+        done: F,
+    ) {
+        let job = Job {
+            index,
+            data,
+            digest,
+            done: Box::new(done),
+        };
+        self.tx
+            .lock()
+            .unwrap()
+            .send(job)
+            .expect("Verification threads exited");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash;
+
+    #[test]
+    fn test_verify() {
+        let mut m = Metainfo::mock(2, 4);
+        m.info.pieces[20..40].copy_from_slice(&hash::sha1(b"ab"));
+        let verifier = Verifier::new(Arc::new(m), 2);
+        let (tx, rx) = mpsc::channel();
+        for (index, data) in vec![(1, b"ab"), (1, b"ac"), (0, b"ab")] {
+            let tx = tx.clone();
+            verifier.verify(index, Arc::new(data.to_vec()), move |_, valid| {
+                tx.send((index, valid, thread::current().id())).unwrap();
+            });
+        }
+        let mut results: Vec<_> = rx.iter().take(3).collect();
+        assert!(results
+            .iter()
+            .all(|(_, _, id)| *id != thread::current().id()));
+        results.sort_by_key(|(index, valid, _)| (*index, *valid));
+        let results: Vec<_> = results.into_iter().map(|(i, v, _)| (i, v)).collect();
+        assert_eq!(results, vec![(0, false), (1, false), (1, true)]);
+
+        // A digest from incremental hashing is compared as is
+        let (tx, rx) = mpsc::channel();
+        for digest in vec![hash::sha1(b"ab"), hash::sha1(b"ac")] {
+            let tx = tx.clone();
+            verifier.verify_with_digest(1, Arc::new(Vec::new()), Some(digest), move |_, valid| {
+                tx.send(valid).unwrap();
+            });
+        }
+        let mut results: Vec<_> = rx.iter().take(2).collect();
+        results.sort();
+        assert_eq!(results, vec![false, true]);
+    }
+}
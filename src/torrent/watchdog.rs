@@ -0,0 +1,78 @@
+use std::time::{Duration, Instant};
+
+// Notices a download that has made no progress for a whole window while peers were letting us
+// download, which points at something wrong (every peer snubbing us, bad selection) rather than
+// just a slow swarm
+pub struct StallWatchdog {
+    window: Duration,
+    left: u32,
+    // Last time the number of pieces left changed
+    progress: Instant,
+    stalled: bool,
+}
+
+impl StallWatchdog {
+    pub fn new(window: Duration, left: u32, now: Instant) -> Self {
+        StallWatchdog {
+            window,
+            left,
+            progress: now,
+            stalled: false,
+        }
+    }
+
+    // Returns true only when the download becomes stalled, not on every check while it stays so.
+    // downloading is the number of peers we are interested in that have unchoked us.
+    pub fn update(&mut self, left: u32, downloading: usize, now: Instant) -> bool {
+        // Pieces found bad after completion raise left again, which also restarts the window
+        if left != self.left || left == 0 {
+            self.left = left;
+            self.progress = now;
+            self.stalled = false;
+            return false;
+        }
+        let stalled = downloading > 0 && now.duration_since(self.progress) >= self.window;
+        let became_stalled = stalled && !self.stalled;
+        self.stalled = stalled;
+        became_stalled
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        self.stalled
+    }
+
+    // Time since the last piece, as of now
+    pub fn since_progress(&self, now: Instant) -> Duration {
+        now.duration_since(self.progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stall() {
+        let start = Instant::now();
+        let window = Duration::from_secs(60);
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut w = StallWatchdog::new(window, 10, start);
+
+        assert!(!w.update(10, 2, at(59)));
+        assert!(w.update(10, 2, at(60)));
+        assert!(w.is_stalled());
+        // Signalled once per stall
+        assert!(!w.update(10, 2, at(90)));
+        assert!(w.is_stalled());
+
+        // A piece ends the stall and restarts the window
+        assert!(!w.update(9, 2, at(100)));
+        assert!(!w.is_stalled());
+        assert!(!w.update(9, 2, at(159)));
+        // Without peers to download from, waiting is expected
+        assert!(!w.update(9, 0, at(200)));
+        assert!(!w.is_stalled());
+        assert!(w.update(9, 1, at(201)));
+        assert_eq!(w.since_progress(at(201)), Duration::from_secs(101));
+    }
+}
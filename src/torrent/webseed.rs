@@ -0,0 +1,367 @@
+use crate::metainfo::Metainfo;
+use crate::storage::PieceStore;
+use bitvec::bitvec;
+use failure::{self, Fail};
+use log::{debug, warn};
+use reqwest::header::RANGE;
+use reqwest::{self, Client, StatusCode, Url};
+use std::io::{self, Read};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "reqwest error: {}", _0)]
+    Reqwest(#[fail(cause)] reqwest::Error),
+    #[fail(display = "io error: {}", _0)]
+    IO(#[fail(cause)] io::Error),
+    #[fail(display = "url cannot be a base: {}", _0)]
+    InvalidUrl(String),
+    #[fail(display = "response too short for {} bytes at {}", _1, _0)]
+    ShortResponse(u64, usize),
+    #[fail(display = "response from {} longer than {} bytes", _0, _1)]
+    LongResponse(String, u64),
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Reqwest(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::IO(e)
+    }
+}
+
+// URL of a file on a web seed (BEP 19). A single file is fetched from the URL itself, unless it
+// ends in '/' and so names the directory holding the file. Files of a multi-file torrent are
+// always under a directory named after the torrent.
+pub fn file_url(base: &Url, name: &str, path: &[&str]) -> Result<Url, Error> {
+    if path.is_empty() && !base.path().ends_with('/') {
+        return Ok(base.clone());
+    }
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .map_err(|_| Error::InvalidUrl(base.to_string()))?
+        .pop_if_empty()
+        .push(name)
+        .extend(path);
+    Ok(url)
+}
+
+// Consecutive failures after which a web seed is no longer used
+const MAX_FAILURES: u32 = 5;
+// Wait after a failed piece, and while there is nothing to request
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+const IDLE_DELAY: Duration = Duration::from_secs(1);
+
+// One file of the torrent on a web seed
+struct SeedFile {
+    url: Url,
+    // Position of the file in the torrent's content
+    offset: u64,
+    length: u64,
+    // The whole file, once the server answered a range request with all of it
+    full: Option<Vec<u8>>,
+}
+
+impl SeedFile {
+    // Servers without range support send the whole file, which is kept for later ranges
+    fn fetch(&mut self, client: &Client, begin: u64, length: usize) -> Result<Vec<u8>, Error> {
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+        if self.full.is_none() {
+            let range = format!("bytes={}-{}", begin, begin + length as u64 - 1);
+            let res = client
+                .get(self.url.clone())
+                .header(RANGE, range)
+                .send()?
+                .error_for_status()?;
+            // A byte past what was asked for shows the server sent too much
+            let (limit, partial) = match res.status() {
+                StatusCode::PARTIAL_CONTENT => (length as u64, true),
+                _ => (self.length, false),
+            };
+            let mut body = Vec::new();
+            res.take(limit + 1).read_to_end(&mut body)?;
+            if body.len() as u64 > limit {
+                return Err(Error::LongResponse(self.url.to_string(), limit));
+            }
+            if partial {
+                if body.len() != length {
+                    return Err(Error::ShortResponse(begin, length));
+                }
+                return Ok(body);
+            }
+            debug!(
+                "{} does not support ranges, keeping the whole file",
+                self.url
+            );
+            self.full = Some(body);
+        }
+        let full = self.full.as_ref().unwrap();
+        full.get(begin as usize..begin as usize + length)
+            .map(|range| range.to_vec())
+            .ok_or(Error::ShortResponse(begin, length))
+    }
+}
+
+// Pieces fetched over HTTP from the files of a web seed
+pub struct WebSeed {
+    client: Client,
+    base: Url,
+    files: Vec<SeedFile>,
+}
+
+impl WebSeed {
+    pub fn new(client: Client, base: &Url, metainfo: &Metainfo) -> Result<Self, Error> {
+        let info = &metainfo.info;
+        let files = match &info.files {
+            None => vec![SeedFile {
+                url: file_url(base, &info.name, &[])?,
+                offset: 0,
+                length: info.length as u64,
+                full: None,
+            }],
+            Some(entries) => {
+                let mut offset = 0;
+                let mut files = Vec::with_capacity(entries.len());
+                for entry in entries {
+                    let path: Vec<&str> = entry.path.iter().map(String::as_str).collect();
+                    files.push(SeedFile {
+                        url: file_url(base, &info.name, &path)?,
+                        offset,
+                        length: entry.length as u64,
+                        full: None,
+                    });
+                    offset += entry.length as u64;
+                }
+                files
+            }
+        };
+        Ok(WebSeed {
+            client,
+            base: base.clone(),
+            files,
+        })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.base
+    }
+
+    pub fn fetch_piece(&mut self, metainfo: &Metainfo, index: u32) -> Result<Vec<u8>, Error> {
+        self.fetch(
+            index as u64 * metainfo.info.piece_length as u64,
+            metainfo.get_piece_size(index) as usize,
+        )
+    }
+
+    // A range of the torrent's content, from each file it overlaps
+    pub fn fetch(&mut self, offset: u64, length: usize) -> Result<Vec<u8>, Error> {
+        let end = offset + length as u64;
+        let mut data = Vec::with_capacity(length);
+        for f in &mut self.files {
+            if f.offset >= end || f.offset + f.length <= offset {
+                continue;
+            }
+            let begin = offset.max(f.offset) - f.offset;
+            let stop = end.min(f.offset + f.length) - f.offset;
+            data.extend(f.fetch(&self.client, begin, (stop - begin) as usize)?);
+        }
+        if data.len() != length {
+            return Err(Error::ShortResponse(offset, length));
+        }
+        Ok(data)
+    }
+
+    // Download pieces alongside peers until the torrent is complete. Pieces are claimed from the
+    // store one at a time, so they are not also requested from peers.
+    pub fn download(&mut self, metainfo: &Metainfo, store: &RwLock<PieceStore>) {
+        let id = self.base.to_string();
+        let availability = bitvec![1; metainfo.num_pieces() as usize];
+        let mut failures = 0;
+        while !store.read().unwrap().is_complete() {
+            let index = match store
+                .write()
+                .unwrap()
+                .request_pieces(&id, availability.clone(), 1)
+            {
+                Ok(v) if !v.is_empty() => v[0],
+                // Paused, or every needed piece is already requested from peers
+                _ => {
+                    thread::sleep(IDLE_DELAY);
+                    continue;
+                }
+            };
+            let res = self.fetch_piece(metainfo, index);
+            match res {
+                Ok(piece) if metainfo.verify_piece(index, &piece) => {
+                    failures = 0;
+                    store.write().unwrap().store(&id, index, Arc::new(piece));
+                    continue;
+                }
+                Ok(_) => warn!("Piece {} from web seed {} is corrupt", index, id),
+                Err(e) => warn!("Web seed {} failed for piece {}: {}", id, index, e),
+            }
+            store.write().unwrap().release_piece(&id, index);
+            failures += 1;
+            if failures == MAX_FAILURES {
+                warn!("Giving up on web seed {}", id);
+                break;
+            }
+            thread::sleep(RETRY_DELAY);
+        }
+        store.write().unwrap().peer_left(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash;
+    use crate::metainfo::FileEntry;
+    use crate::selection::Inorder;
+    use matches::matches;
+    use mockito::{self, mock};
+
+    #[test]
+    fn test_file_url() -> Result<(), failure::Error> {
+        let file = Url::parse("http://seed.example/files/test.iso")?;
+        let dir = Url::parse("http://seed.example/files/")?;
+        assert_eq!(file_url(&file, "test.iso", &[])?, file);
+        assert_eq!(
+            file_url(&dir, "test.iso", &[])?.as_str(),
+            "http://seed.example/files/test.iso"
+        );
+
+        // Multi-file torrents are always under the torrent name, with or without the slash
+        let expected = "http://seed.example/files/album/disc%201/track%2301.flac";
+        for base in vec![&dir, &Url::parse("http://seed.example/files")?] {
+            assert_eq!(
+                file_url(base, "album", &["disc 1", "track#01.flac"])?.as_str(),
+                expected
+            );
+        }
+        assert!(file_url(&Url::parse("data:text/plain,x")?, "a", &["b"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch() -> Result<(), failure::Error> {
+        let m = Metainfo::mock(4, 10);
+        let r = Client::new();
+        let base = Url::parse(&(mockito::server_url() + "/ranged/"))?;
+        let mut seed = WebSeed::new(r.clone(), &base, &m)?;
+        let mck = mock("GET", "/ranged/test")
+            .match_header("range", "bytes=8-9")
+            .with_status(206)
+            .with_body("ij")
+            .create();
+        assert_eq!(seed.fetch_piece(&m, 2)?, b"ij".to_vec());
+        mck.assert();
+        drop(mck);
+
+        // The whole file is only downloaded once from servers ignoring the range
+        let base = Url::parse(&(mockito::server_url() + "/whole"))?;
+        let mut seed = WebSeed::new(r, &base, &m)?;
+        let mck = mock("GET", "/whole")
+            .with_status(200)
+            .with_body("abcdefghij")
+            .expect(1)
+            .create();
+        assert_eq!(seed.fetch_piece(&m, 1)?, b"efgh".to_vec());
+        assert_eq!(seed.fetch_piece(&m, 2)?, b"ij".to_vec());
+        mck.assert();
+        assert!(matches!(seed.fetch(8, 4), Err(Error::ShortResponse(8, 4))));
+        // Nothing to request
+        assert_eq!(seed.fetch(3, 0)?, Vec::<u8>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_bounded() -> Result<(), failure::Error> {
+        let m = Metainfo::mock(4, 10);
+        let r = Client::new();
+        let base = Url::parse(&(mockito::server_url() + "/long_range"))?;
+        let mut seed = WebSeed::new(r.clone(), &base, &m)?;
+        let _mck = mock("GET", "/long_range")
+            .with_status(206)
+            .with_body("abcdefghij")
+            .create();
+        assert!(matches!(
+            seed.fetch_piece(&m, 0),
+            Err(Error::LongResponse(_, 4))
+        ));
+
+        // Larger than the file
+        let base = Url::parse(&(mockito::server_url() + "/long_whole"))?;
+        let mut seed = WebSeed::new(r, &base, &m)?;
+        let _mck = mock("GET", "/long_whole")
+            .with_status(200)
+            .with_body("abcdefghijk")
+            .create();
+        assert!(matches!(
+            seed.fetch_piece(&m, 0),
+            Err(Error::LongResponse(_, 10))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_multi_file() -> Result<(), failure::Error> {
+        let mut m = Metainfo::mock(4, 10);
+        m.info.length = 0;
+        let entry = |length, name: &str| FileEntry {
+            length,
+            path: vec![name.to_owned()],
+            extra: Default::default(),
+        };
+        m.info.files = Some(vec![entry(6, "a"), entry(0, "empty"), entry(4, "b")]);
+        let base = Url::parse(&(mockito::server_url() + "/multi"))?;
+        let mut seed = WebSeed::new(Client::new(), &base, &m)?;
+        let a = mock("GET", "/multi/test/a")
+            .match_header("range", "bytes=4-5")
+            .with_status(206)
+            .with_body("ef")
+            .create();
+        let b = mock("GET", "/multi/test/b")
+            .match_header("range", "bytes=0-1")
+            .with_status(206)
+            .with_body("gh")
+            .create();
+        // The piece spans the end of a, the empty file and the start of b
+        assert_eq!(seed.fetch_piece(&m, 1)?, b"efgh".to_vec());
+        a.assert();
+        b.assert();
+        Ok(())
+    }
+
+    #[test]
+    fn test_download() -> Result<(), failure::Error> {
+        let data = b"abcdefghij";
+        let mut m = Metainfo::mock(4, data.len());
+        for (i, piece) in data.chunks(4).enumerate() {
+            m.info.pieces[20 * i..20 * (i + 1)].copy_from_slice(&hash::sha1(piece));
+        }
+        let store = RwLock::new(PieceStore::new(&m, Box::new(Inorder::default())));
+        let base = Url::parse(&(mockito::server_url() + "/download"))?;
+        let mut seed = WebSeed::new(Client::new(), &base, &m)?;
+        let _mck = mock("GET", "/download")
+            .with_status(200)
+            .with_body(&data[..])
+            .expect(1)
+            .create();
+        seed.download(&m, &store);
+        let store = store.into_inner().unwrap();
+        assert!(store.is_complete());
+        for (i, piece) in data.chunks(4).enumerate() {
+            assert_eq!(store.get(i as u32), Some(Arc::new(piece.to_vec())));
+        }
+        Ok(())
+    }
+}